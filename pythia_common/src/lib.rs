@@ -16,6 +16,8 @@ mod budget;
 pub mod osprofiler;
 pub mod jaeger;
 pub mod skywalking;
+pub mod zipkin;
+pub mod otlp;
 pub mod reqtype;
 
 use std::error::Error;
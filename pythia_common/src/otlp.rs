@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+pub struct OTLPRequestType {
+    pub rt: String
+}
+
+impl fmt::Display for OTLPRequestType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "OTLPRT({})", self.rt)
+    }
+}
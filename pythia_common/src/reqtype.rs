@@ -4,6 +4,8 @@ use crate::PythiaError;
 use crate::osprofiler::OSPRequestType;
 use crate::jaeger::JaegerRequestType;
 use crate::skywalking::SWRequestType;
+use crate::zipkin::ZipkinRequestType;
+use crate::otlp::OTLPRequestType;
 
 use std::fmt;
 
@@ -12,6 +14,8 @@ pub enum RequestType {
     OSP(OSPRequestType),
     Jaeger(JaegerRequestType),
     SW(SWRequestType),
+    Zipkin(ZipkinRequestType),
+    OTLP(OTLPRequestType),
     Unknown,
 }
 
@@ -30,6 +34,12 @@ impl RequestType {
             "SkyWalking" => Ok(RequestType::SW(SWRequestType {
                 rt: typ.to_string()
             })),
+            "Zipkin" => Ok(RequestType::Zipkin(ZipkinRequestType {
+                rt: typ.to_string()
+            })),
+            "OTLP" => Ok(RequestType::OTLP(OTLPRequestType {
+                rt: typ.to_string()
+            })),
             _ => Err(("Unknown request type!").to_string())
         }
     }
@@ -39,6 +49,8 @@ impl RequestType {
             RequestType::OSP(osprt) => osprt.to_string(),
             RequestType::Jaeger(jrt) => jrt.rt.clone(),
             RequestType::SW(swrt) => swrt.rt.clone(),
+            RequestType::Zipkin(zrt) => zrt.rt.clone(),
+            RequestType::OTLP(ort) => ort.rt.clone(),
             _ => "".to_string()
         }
     }
@@ -50,6 +62,8 @@ impl fmt::Display for RequestType {
             RequestType::OSP(ort) => write!(f, "{:?}", ort),
             RequestType::Jaeger(jrt) => write!(f, "{:?}", jrt),
             RequestType::SW(swrt) => write!(f, "{:?}", swrt),
+            RequestType::Zipkin(zrt) => write!(f, "{:?}", zrt),
+            RequestType::OTLP(ort) => write!(f, "{:?}", ort),
             RequestType::Unknown => write!(f, "UnknownRT"),
         }
     }
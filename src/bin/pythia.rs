@@ -6,16 +6,77 @@ Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
 All rights reserved.
 */
 
-use clap::{App, Arg, SubCommand};
-use std::time::Instant;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::time::{Duration, Instant};
 
+use pythia::filter::Filter;
+use pythia::store::{parse_time, Query};
+use pythia::whatif::Speedup;
 use pythia::{
-    disable_all, disable_tracepoint, dump_traces, enable_all, enable_skeleton, get_crit,
-    get_manifest, get_trace, group_folder, group_from_ids, manifest_from_folder, manifest_stats,
-    measure_search_space_feasibility, read_trace_file, recent_traces, show_config,
-    show_key_value_pairs, show_manifest,
+    compare_crit, diff, disable_all, disable_tracepoint, dump_traces, enable_all, enable_skeleton, export,
+    generate_traces, get_crit, get_manifest, get_trace, group_folder, group_from_ids, manifest_from_folder,
+    manifest_stats, measure_search_space_feasibility, read_trace_file, recent_traces, render_trace,
+    show_config, show_key_value_pairs, show_manifest, store_export, store_ingest, store_list, store_prune,
+    what_if, RenderView, StoreSource,
 };
 
+/// Conditions on stored traces shared by the `store` subcommands
+fn query_args() -> Vec<Arg<'static, 'static>> {
+    let arg = |name| Arg::with_name(name).long(name).takes_value(true);
+    vec![
+        arg("request-type"),
+        arg("since").help("RFC 3339, \"%Y-%m-%d %H:%M:%S\" (UTC) or an age like 7d"),
+        arg("until"),
+        arg("min-duration").help("in milliseconds"),
+        arg("max-duration").help("in milliseconds"),
+        arg("host"),
+        arg("tracepoint"),
+        arg("limit"),
+    ]
+}
+
+fn query_from_matches(matches: &ArgMatches) -> Query {
+    let millis = |name| {
+        matches
+            .value_of(name)
+            .map(|ms: &str| Duration::from_secs_f64(ms.parse::<f64>().unwrap() / 1000.0))
+    };
+    Query {
+        request_type: None,
+        since: matches.value_of("since").map(|t| parse_time(t).unwrap()),
+        until: matches.value_of("until").map(|t| parse_time(t).unwrap()),
+        min_duration: millis("min-duration"),
+        max_duration: millis("max-duration"),
+        host: matches.value_of("host").map(|h| h.to_string()),
+        tracepoint: matches.value_of("tracepoint").map(|tp| tp.to_string()),
+        limit: matches.value_of("limit").map(|l| l.parse().unwrap()),
+    }
+}
+
+fn filter_arg() -> Arg<'static, 'static> {
+    Arg::with_name("filter")
+        .long("filter")
+        .takes_value(true)
+        .help("Only use traces matching an expression like 'duration > 2s && host == \"cp-2\"'")
+}
+
+fn filter_from_matches(matches: &ArgMatches) -> Option<Filter> {
+    matches.value_of("filter").map(|f| match f.parse() {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    })
+}
+
+fn dot_arg() -> Arg<'static, 'static> {
+    Arg::with_name("dot")
+        .long("dot")
+        .takes_value(true)
+        .help("Draw the group with the highest variance to this file, as SVG if it ends with .svg")
+}
+
 fn main() {
     let now = Instant::now();
     let matches = App::new("Pythia")
@@ -34,15 +95,20 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name("manifest-folder")
-                .arg(Arg::with_name("trace-folder").required(true).index(1)),
+                .arg(Arg::with_name("trace-folder").required(true).index(1))
+                .arg(filter_arg()),
         )
         .subcommand(
             SubCommand::with_name("group-folder")
-                .arg(Arg::with_name("trace-folder").required(true).index(1)),
+                .arg(Arg::with_name("trace-folder").required(true).index(1))
+                .arg(filter_arg())
+                .arg(dot_arg()),
         )
         .subcommand(
             SubCommand::with_name("group-ids")
-                .arg(Arg::with_name("traceid-file").required(true).index(1)),
+                .arg(Arg::with_name("traceid-file").required(true).index(1))
+                .arg(filter_arg())
+                .arg(dot_arg()),
         )
         .subcommand(
             SubCommand::with_name("read-file")
@@ -50,11 +116,100 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name("dump-traces")
-                .arg(Arg::with_name("trace-file").required(true).index(1)),
+                .arg(Arg::with_name("trace-file").required(true).index(1))
+                .arg(filter_arg()),
         )
         .subcommand(
             SubCommand::with_name("get-crit")
-                .arg(Arg::with_name("trace-id").required(true).index(1)),
+                .arg(Arg::with_name("trace-id").required(true).index(1).help("Trace file or ID"))
+                .arg(
+                    Arg::with_name("algorithm")
+                        .long("algorithm")
+                        .takes_value(true)
+                        .possible_values(&["latest_predecessor", "longest_path", "compare"])
+                        .help("Critical path algorithm, the one from the settings by default; compare runs all of them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Align two traces and show how their structure and latencies differ")
+                .arg(Arg::with_name("left").required(true).index(1).help("Trace file or ID"))
+                .arg(Arg::with_name("right").required(true).index(2).help("Trace file or ID")),
+        )
+        .subcommand(
+            SubCommand::with_name("dot")
+                .about("Draw a trace, its critical path or its search space path with Graphviz")
+                .arg(Arg::with_name("trace").required(true).index(1).help("Trace file or ID"))
+                .arg(
+                    Arg::with_name("view")
+                        .long("view")
+                        .takes_value(true)
+                        .possible_values(&["trace", "crit", "hierarchical"])
+                        .default_value("trace"),
+                )
+                .arg(
+                    Arg::with_name("svg")
+                        .long("svg")
+                        .takes_value(true)
+                        .help("Write SVG with a local Graphviz instead of printing DOT"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export traces for Perfetto/chrome://tracing or as flame graph stacks")
+                .arg(
+                    Arg::with_name("sources")
+                        .required(true)
+                        .multiple(true)
+                        .help("Trace folders, files or IDs"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["chrome", "folded"])
+                        .default_value("chrome"),
+                )
+                .arg(
+                    Arg::with_name("spans")
+                        .long("spans")
+                        .help("Export each span of Jaeger, Zipkin or OTLP files as a slice (chrome format)"),
+                )
+                .arg(Arg::with_name("output").long("output").short("o").takes_value(true))
+                .arg(filter_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("what-if")
+                .about("Predict the latency of a group if some edges were faster")
+                .arg(
+                    Arg::with_name("sources")
+                        .required(true)
+                        .multiple(true)
+                        .help("Trace folders, files or IDs"),
+                )
+                .arg(
+                    Arg::with_name("edge")
+                        .long("edge")
+                        .required(true)
+                        .multiple(true)
+                        .number_of_values(2)
+                        .value_names(&["from", "to"])
+                        .help("Tracepoints at the ends of the edge, ... matches any text"),
+                )
+                .arg(
+                    Arg::with_name("speedup")
+                        .long("speedup")
+                        .takes_value(true)
+                        .default_value("50")
+                        .help("How much faster the edges get, in percent"),
+                )
+                .arg(
+                    Arg::with_name("group")
+                        .long("group")
+                        .takes_value(true)
+                        .help("Hash prefix of the group, the one with the highest variance by default"),
+                )
+                .arg(filter_arg()),
         )
         .subcommand(
             SubCommand::with_name("key-value")
@@ -81,6 +236,41 @@ fn main() {
             SubCommand::with_name("manifest-stats")
                 .arg(Arg::with_name("manifest-file").required(true).index(1)),
         )
+        .subcommand(
+            SubCommand::with_name("generate")
+                .about("Generate traces from a service topology with injected faults")
+                .arg(Arg::with_name("topology-file").required(true).index(1))
+                .arg(Arg::with_name("out-dir").required(true).index(2))
+                .arg(
+                    Arg::with_name("count")
+                        .long("count")
+                        .takes_value(true)
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::with_name("ground-truth")
+                        .long("ground-truth")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("store")
+                .about("Keep traces in the local trace store and query them")
+                .subcommand(
+                    SubCommand::with_name("ingest")
+                        .about("Store traces from a file or folder, an ID list or recent requests")
+                        .arg(Arg::with_name("source").index(1).required_unless("recent"))
+                        .arg(Arg::with_name("ids").long("ids").help("The source lists request IDs"))
+                        .arg(Arg::with_name("recent").long("recent")),
+                )
+                .subcommand(SubCommand::with_name("query").args(&query_args()))
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .arg(Arg::with_name("out-dir").required(true).index(1))
+                        .args(&query_args()),
+                )
+                .subcommand(SubCommand::with_name("prune").args(&query_args())),
+        )
         .subcommand(
             SubCommand::with_name("enable-mult")
                 .arg(Arg::with_name("to-enable").required(true).min_values(0)),
@@ -94,13 +284,24 @@ fn main() {
             );
         }
         ("manifest-folder", Some(matches)) => {
-            manifest_from_folder(matches.value_of("trace-folder").unwrap());
+            manifest_from_folder(
+                matches.value_of("trace-folder").unwrap(),
+                filter_from_matches(matches).as_ref(),
+            );
         }
         ("group-folder", Some(matches)) => {
-            group_folder(matches.value_of("trace-folder").unwrap());
+            group_folder(
+                matches.value_of("trace-folder").unwrap(),
+                filter_from_matches(matches).as_ref(),
+                matches.value_of("dot"),
+            );
         }
         ("group-ids", Some(matches)) => {
-            group_from_ids(matches.value_of("traceid-file").unwrap());
+            group_from_ids(
+                matches.value_of("traceid-file").unwrap(),
+                filter_from_matches(matches).as_ref(),
+                matches.value_of("dot"),
+            );
         }
         ("read-file", Some(matches)) => {
             read_trace_file(matches.value_of("trace-file").unwrap());
@@ -112,7 +313,10 @@ fn main() {
             show_manifest(matches.value_of("request-type").unwrap());
         }
         ("dump-traces", Some(matches)) => {
-            dump_traces(matches.value_of("trace-file").unwrap());
+            dump_traces(
+                matches.value_of("trace-file").unwrap(),
+                filter_from_matches(matches).as_ref(),
+            );
         }
         ("get-trace", Some(matches)) => {
             get_trace(
@@ -121,8 +325,55 @@ fn main() {
                 matches.occurrences_of("prune") > 0,
             );
         }
+        ("diff", Some(matches)) => {
+            diff(
+                matches.value_of("left").unwrap(),
+                matches.value_of("right").unwrap(),
+            );
+        }
+        ("export", Some(matches)) => {
+            export(
+                &matches.values_of("sources").unwrap().collect::<Vec<_>>(),
+                matches.value_of("format").unwrap().parse().unwrap(),
+                matches.is_present("spans"),
+                matches.value_of("output"),
+                filter_from_matches(matches).as_ref(),
+            );
+        }
+        ("what-if", Some(matches)) => {
+            let percent: f64 = matches.value_of("speedup").unwrap().parse().unwrap();
+            let ends: Vec<&str> = matches.values_of("edge").unwrap().collect();
+            let speedups = ends
+                .chunks(2)
+                .map(|e| match Speedup::new(e[0], e[1], percent) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                })
+                .collect::<Vec<_>>();
+            what_if(
+                &matches.values_of("sources").unwrap().collect::<Vec<_>>(),
+                &speedups,
+                matches.value_of("group"),
+                filter_from_matches(matches).as_ref(),
+            );
+        }
+        ("dot", Some(matches)) => {
+            let view = match matches.value_of("view").unwrap() {
+                "crit" => RenderView::CriticalPath,
+                "hierarchical" => RenderView::Hierarchical,
+                _ => RenderView::Trace,
+            };
+            render_trace(matches.value_of("trace").unwrap(), view, matches.value_of("svg"));
+        }
         ("get-crit", Some(matches)) => {
-            get_crit(matches.value_of("trace-id").unwrap());
+            let trace_id = matches.value_of("trace-id").unwrap();
+            match matches.value_of("algorithm") {
+                Some("compare") => compare_crit(trace_id),
+                algorithm => get_crit(trace_id, algorithm.map(|a| a.parse().unwrap())),
+            }
         }
         ("disable-tracepoint", Some(matches)) => {
             disable_tracepoint(matches.value_of("tracepoint-id").unwrap());
@@ -150,9 +401,41 @@ fn main() {
         ("show-config", Some(_)) => {
             show_config();
         }
+        ("generate", Some(matches)) => {
+            generate_traces(
+                matches.value_of("topology-file").unwrap(),
+                matches.value_of("out-dir").unwrap(),
+                matches.value_of("count").unwrap().parse().unwrap(),
+                matches.value_of("ground-truth"),
+            );
+        }
         ("manifest-stats", Some(matches)) => {
             manifest_stats(matches.value_of("manifest-file").unwrap());
         }
+        ("store", Some(matches)) => match matches.subcommand() {
+            ("ingest", Some(matches)) => {
+                let source = match matches.value_of("source") {
+                    Some(s) if matches.is_present("ids") => StoreSource::IdFile(s),
+                    Some(s) if !matches.is_present("recent") => StoreSource::Path(s),
+                    _ => StoreSource::Recent,
+                };
+                store_ingest(source);
+            }
+            ("query", Some(matches)) => {
+                store_list(matches.value_of("request-type"), query_from_matches(matches));
+            }
+            ("export", Some(matches)) => {
+                store_export(
+                    matches.value_of("out-dir").unwrap(),
+                    matches.value_of("request-type"),
+                    query_from_matches(matches),
+                );
+            }
+            ("prune", Some(matches)) => {
+                store_prune(matches.value_of("request-type"), query_from_matches(matches));
+            }
+            _ => panic!("Must provide a store subcommand: ingest, query, export or prune"),
+        },
         _ => panic!("Must provide a subcommand, see --help for commands"),
     };
     eprintln!("Overall Pythia took {}us", now.elapsed().as_micros());
@@ -37,16 +37,24 @@ use pythia::controller::Controller;
 use pythia::critical::CriticalPath;
 use pythia::critical::Path;
 use pythia::grouping::{Group, GroupEdge, GroupManager};
+use pythia::rules::learn_rules;
 use pythia::manifest::Manifest;
 use pythia::reader::reader_from_settings;
+use pythia::render::{Highlights, ToDot};
+use pythia::receiver::start_otlp_receiver;
 use pythia::search::get_strategy;
 use pythia::settings::{ApplicationType, Settings};
+use pythia::skew::offset_estimates;
 use pythia::spantrace::{Feature, Feature2, Span, SpanTrace};
 use pythia::trace::{DAGEdge, Event, IDType, Trace, TraceNode, TracepointID};
+use pythia::validate::{checked_critical_path, checked_span_critical_path, take_quality_stats};
 
 // // use keccak_hash::keccak256;
 // use sha3;
 
+/// Edge groups of each ranking highlighted in the DOT dumps
+const RANKED_EDGE_GROUPS_SHOWN: usize = 5;
+
 // These are static because search strategy expects static references.
 lazy_static! {
     static ref SETTINGS: Settings = Settings::read();
@@ -70,6 +78,20 @@ fn reset_reader() {
 // to
 // -- nova/usr/local/lib/python3.6/dist-packages/nova/hooks.py:2046:nova.compute.manager.ComputeManager._do_build_and_run_instance
 
+/// Writes the slowest critical path to `<output>.<ranking>.dot`, with the ranked edge groups
+/// highlighted
+fn write_ranked_dot(output: &str, ranking: &str, paths: &[CriticalPath], ranked: &[(String, String)]) {
+    let slowest = match paths.iter().max_by_key(|p| p.duration) {
+        Some(p) => p,
+        None => return,
+    };
+    let file = format!("{}.{}.dot", output, ranking);
+    match std::fs::write(&file, slowest.to_dot(&Highlights::from_names(ranked))) {
+        Ok(()) => println!("Wrote the {} ranking to {}", ranking, file),
+        Err(e) => eprintln!("Could not write {}: {}", file, e),
+    }
+}
+
 fn cacti_no_loop() -> bool {
     true
 }
@@ -87,7 +109,7 @@ fn main() {
         let filename = std::env::args().nth(1).unwrap();
         eprintln!("Printing results to {}", filename);
         // eprintln!("All args = [{:?}]", std::env::args().into_iter().collect::<Vec<String>>());
-        let mut output_file = File::create(filename).unwrap();
+        let mut output_file = File::create(&filename).unwrap();
         writeln!(output_file, "{:?}", *SETTINGS).ok();
         writeln!(output_file, "Targets: {:?}", targets).ok();
 
@@ -502,12 +524,9 @@ fn main() {
                     latencies: vec![],
                 };
 
-                // TODO: fix this hacky stuff soon
-                if (edge.duration.as_nanos() as u64) < 10000000000000000000 {
-                    to_return.latencies.push(
-                        (req_id.clone(), edge.duration.as_nanos() as u64, parent_lat));
-                    // to_return.mean = edge.duration.as_nanos() as u64;
-                }
+                to_return.latencies.push(
+                    (req_id.clone(), edge.duration.as_nanos() as u64, parent_lat));
+                // to_return.mean = edge.duration.as_nanos() as u64;
 
                 // return EdgeGroup{
                 //     ts: ts.tracepoint_id.to_string(),
@@ -521,11 +540,8 @@ fn main() {
             }
 
             pub fn add_edge(&mut self, edge: &DAGEdge, trace_id: &IDType, trace_lat: u64) {
-                // TODO: fix this hacky stuff soon
-                if (edge.duration.as_nanos() as u64) < 10000000000000000000 {
-                    self.latencies.push(
-                        (trace_id.clone(), edge.duration.as_nanos() as u64, trace_lat));
-                }
+                self.latencies.push(
+                    (trace_id.clone(), edge.duration.as_nanos() as u64, trace_lat));
             }
 
             pub fn compute_stats(&mut self, victim_ids: &HashSet<IDType>) {
@@ -806,6 +822,26 @@ fn main() {
         println!();
         println!();
 
+        // Point out the top edge groups of each ranking on the slowest path
+        let top_edge_groups = |sorted: &[(String, EdgeGroup)]| -> Vec<(String, String)> {
+            sorted.iter().take(RANKED_EDGE_GROUPS_SHOWN).map(|(k, _)| {
+                let parts = k.split("::").collect::<Vec<&str>>();
+                (parts[0].to_string(), parts[1].to_string())
+            }).collect()
+        };
+        write_ranked_dot(&filename, "pcc", &pt_crits, &top_edge_groups(&eg_pcc_sorted));
+        write_ranked_dot(&filename, "cov", &pt_crits, &top_edge_groups(&eg_cov_sorted));
+        println!();
+        println!();
+
+        println!("HHE Rules = [");
+        for (pos, rule) in learn_rules(&pt_crits, &victim_rid_set).iter().enumerate() {
+            println!("HHE (Rule) Pos {} = {}", pos, rule);
+        }
+        println!("]");
+        println!();
+        println!();
+
         latencies_sorted = eg_cov_sorted[0].1.latencies.clone();
         latencies_sorted.sort_by(|a, b| {
             a.1.partial_cmp(&b.1).unwrap()
@@ -819,16 +855,7 @@ fn main() {
 
         /*~ End edge grouping code ~*/
 
-        let mut hhe_index = 0;
-        loop {
-            if eg_diff_sorted[hhe_index].1.slow_med_diff() < 10000000000000000000 {
-                break;
-            }
-
-            hhe_index += 1;
-        }
-
-        println!("HHE Metric (Diff) = {}", eg_diff_sorted[hhe_index].1.slow_med_diff());
+        println!("HHE Metric (Diff) = {}", eg_diff_sorted[0].1.slow_med_diff());
         println!("HHE Winner Len (Diff) = {}", eg_diff_sorted[0].1.latencies.len());
         // println!("HHE Metric = {}", eg_diff_sorted[1].1.slow_med_diff());
         // println!("HHE Metric = {}", eg_diff_sorted[2].1.slow_med_diff());
@@ -849,14 +876,14 @@ fn main() {
         // println!();
         // println!();
 
-        let hhe_parts_diff = eg_diff_sorted[hhe_index].0.split("::").collect::<Vec<&str>>();
+        let hhe_parts_diff = eg_diff_sorted[0].0.split("::").collect::<Vec<&str>>();
         let (hhe_start_diff, hhe_end_diff) = (hhe_parts_diff[0].to_string(), hhe_parts_diff[1].to_string());
         println!();
         println!();
         println!("HHE (Diff) = ({}, {})", hhe_start_diff, hhe_end_diff);
         println!();
         println!("HHE List (Diff) = [");
-        let mut hhe_diff_ctr = hhe_index;
+        let mut hhe_diff_ctr = 0;
         loop {
             if hhe_diff_ctr >= eg_diff_sorted.len() {
                 break;
@@ -865,7 +892,7 @@ fn main() {
             let diff_parts = eg_diff_sorted[hhe_diff_ctr].0.split("::").collect::<Vec<&str>>();
             let (diff_start, diff_end) = (diff_parts[0].to_string(), diff_parts[1].to_string());
 
-            println!("HHE (Diff) Pos {} = ({}, {}) --- Score = [ {} ]", hhe_diff_ctr-hhe_index, diff_start, diff_end, eg_diff_sorted[hhe_diff_ctr].1.slow_med_diff());
+            println!("HHE (Diff) Pos {} = ({}, {}) --- Score = [ {} ]", hhe_diff_ctr, diff_start, diff_end, eg_diff_sorted[hhe_diff_ctr].1.slow_med_diff());
 
             hhe_diff_ctr += 1;
         }
@@ -873,7 +900,7 @@ fn main() {
         println!();
         println!();
 
-        latencies_sorted = eg_diff_sorted[hhe_index].1.latencies.clone();
+        latencies_sorted = eg_diff_sorted[0].1.latencies.clone();
         latencies_sorted.sort_by(|a, b| {
             a.1.partial_cmp(&b.1).unwrap()
         });
@@ -974,12 +1001,12 @@ fn main() {
         // }
 
         loop {
-            if (hhe_index + (diff_index as usize)) == eg_pcc_sorted.len() {
+            if (diff_index as usize) == eg_pcc_sorted.len() {
                 diff_index = -1;
                 break;
             }
 
-            let hhe_parts_diff = eg_diff_sorted[hhe_index + (diff_index as usize)].0.split("::").collect::<Vec<&str>>();
+            let hhe_parts_diff = eg_diff_sorted[diff_index as usize].0.split("::").collect::<Vec<&str>>();
             let (hhe_start_diff, hhe_end_diff) = (hhe_parts_diff[0].to_string(), hhe_parts_diff[1].to_string());
 
             // if hhe_start_diff.contains("ts-order-service") && hhe_end_diff.contains("ts-order-service") {
@@ -1607,7 +1634,22 @@ fn main() {
 
         let pool = ThreadPool::new(SETTINGS.n_workers + 2);
         let (tx_in, rx_in) = channel();
-        for _ in 0..SETTINGS.n_workers {
+        // Spans pushed over OTLP replace polling the reader
+        let n_pollers = match &SETTINGS.otlp_receiver_addr {
+            Some(addr) => {
+                let tx = tx_in.clone();
+                start_otlp_receiver(&SETTINGS, move |trace: SpanTrace| {
+                    if let Some(path) = checked_span_critical_path(&trace, SETTINGS.critical_path_algorithm) {
+                        tx.send((trace.to_trace(), path))
+                            .expect("channel will be there waiting for the pool");
+                    }
+                }).expect("Could not start the OTLP receiver");
+                println!("Receiving OTLP spans on {}", addr);
+                0
+            }
+            None => SETTINGS.n_workers,
+        };
+        for _ in 0..n_pollers {
             let tx = tx_in.clone();
             // Asynchronously loop and continuously fetch recent traces, and then send them to "rx"
             // in order to be able to read later on in "Main pythia loop" section
@@ -1633,16 +1675,20 @@ fn main() {
                     // }
 
                     match SETTINGS.application {
-                        ApplicationType::Jaeger | ApplicationType::Zipkin => {
+                        ApplicationType::Jaeger | ApplicationType::Zipkin | ApplicationType::OTLP => {
                             for trace in reader.get_recent_span_traces() {
-                                tx.send(CriticalPath::from_trace(&trace.to_critical_path()).unwrap())
-                                    .expect("channel will be there waiting for the pool");
+                                if let Some(path) = checked_span_critical_path(&trace, SETTINGS.critical_path_algorithm) {
+                                    tx.send((trace.to_trace(), path))
+                                        .expect("channel will be there waiting for the pool");
+                                }
                             }
                         }
                         _ => {
                             for trace in reader.get_recent_traces() {
-                                tx.send(CriticalPath::from_trace(&trace).unwrap())
-                                    .expect("channel will be there waiting for the pool");
+                                if let Some(path) = checked_critical_path(&trace, SETTINGS.critical_path_algorithm) {
+                                    tx.send((trace, path))
+                                        .expect("channel will be there waiting for the pool");
+                                }
                             }
                         }
                     }
@@ -1662,7 +1708,7 @@ fn main() {
             let mut jiffy_no = 0;
             pool.execute(move || {
                 let strategy = get_strategy(&SETTINGS, &MANIFEST, &CONTROLLER);
-                let mut groups = GroupManager::new();
+                let mut groups = GroupManager::with_mode(SETTINGS.grouping_mode);
                 let mut used_groups_archive : Vec<Group> = Vec::new();
 
                 loop {
@@ -1688,9 +1734,11 @@ fn main() {
                     let over_budget = budget_manager.overrun();
 
                     // Collect traces, add traces to groups
-                    let critical_paths: Vec<CriticalPath> = rx_in.try_iter().collect::<Vec<CriticalPath>>().into_iter().filter(
-                        | cp: &CriticalPath | cp.request_type == SETTINGS.problem_type
+                    let traced_paths: Vec<(Trace, CriticalPath)> = rx_in.try_iter().filter(
+                        | (_, cp): &(Trace, CriticalPath) | cp.request_type == SETTINGS.problem_type
                     ).collect();
+                    let critical_paths: Vec<CriticalPath> =
+                        traced_paths.iter().map(|(_, cp)| cp.clone()).collect();
 
                     // // TODO: use critical_paths to get edge IDs of problematic edge types and send via tx_across
                     // for cp in critical_paths.iter() {
@@ -1698,7 +1746,7 @@ fn main() {
                     //         .expect("CACTI will be receiving on a channel");
                     // }
 
-                    groups.update(&critical_paths);
+                    groups.update_with_traces(&traced_paths);
                     budget_manager.update_new_paths(&critical_paths);
                     println!(
                         "Got {} paths of duration {:?} at time {}us",
@@ -1715,6 +1763,19 @@ fn main() {
                     println!();
                     println!();
                     writeln!(output_file, "New traces: {}", critical_paths.len()).ok();
+                    let quality = take_quality_stats();
+                    println!("HHE Trace quality = {}", quality);
+                    writeln!(output_file, "Trace quality: {}", quality).ok();
+                    for offset in offset_estimates() {
+                        println!("HHE Clock offset {}", offset);
+                        writeln!(output_file, "Clock offset: {}", offset).ok();
+                    }
+                    if quality.degraded_ratio() > 0.0 {
+                        eprintln!(
+                            "{:.1}% of traces in this jiffy were unusable, diagnosis may be degraded",
+                            quality.degraded_ratio() * 100.0
+                        );
+                    }
                     writeln!(
                         output_file,
                         "New tracepoints: {}",
@@ -1842,6 +1903,20 @@ fn main() {
                             println!();
                             problematic_req_types.push(g.request_type.clone());
 
+                            // An attribute that explains the variance on its own (e.g., a slow
+                            // host) is an answer that needs no further instrumentation
+                            let splits = g.attribute_splits();
+                            for split in splits.iter().take(3) {
+                                println!("{}", split);
+                            }
+                            if let Some(split) = splits.first() {
+                                if split.explained >= SETTINGS.attribute_split_threshold {
+                                    writeln!(output_file, "Group {} explained by {}", g.hash(), split).ok();
+                                    used_groups.push(g.hash().to_string());
+                                    continue;
+                                }
+                            }
+
                             let problem_edges = g.problem_edges();
 
                             // Grab 10 top edges from group; TODO: why 10 specifically?
@@ -52,6 +52,7 @@ pub fn controller_from_settings(settings: &Settings) -> Box<dyn Controller> {
         ApplicationType::Zipkin => Box::new(OTelController::from_settings(settings)),
         ApplicationType::Jaeger => Box::new(OTelController::from_settings(settings)),
         ApplicationType::SkyWalking => Box::new(OTelController::from_settings(settings)),
+        ApplicationType::OTLP => Box::new(OTelController::from_settings(settings)),
     }
 }
 
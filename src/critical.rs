@@ -8,21 +8,25 @@ All rights reserved.
 
 //! Critical path-related stuff
 
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::error::Error;
+use std::str::FromStr;
 use std::time::Duration;
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use genawaiter::{rc::gen, yield_};
+use petgraph::algo::toposort;
 use petgraph::visit::{EdgeRef, IntoEdgesDirected};
-use petgraph::{dot::Dot, graph::NodeIndex, Direction, Outgoing};
+use petgraph::{graph::EdgeIndex, graph::NodeIndex, Direction, Outgoing};
 use petgraph::data::DataMap;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use pythia_common::{OSPRequestType, RequestType};
 
+use crate::skew::event_host;
 use crate::trace::{DAGEdge, IDType};
 use crate::trace::EdgeType;
 use crate::trace::Event;
@@ -31,6 +35,118 @@ use crate::trace::Trace;
 use crate::trace::TracepointID;
 use crate::PythiaError;
 
+/// How the critical path is picked out of a trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CriticalPathAlgorithm {
+    /// Walk back from the end event, always to the predecessor with the latest timestamp
+    LatestPredecessor,
+    /// The path from the start to the end event that spends the most time within single hosts.
+    /// Edges between hosts count for nothing, so the path does not depend on the clocks of
+    /// different hosts agreeing, but it also ignores network latency.
+    LongestPath,
+}
+
+impl CriticalPathAlgorithm {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CriticalPathAlgorithm::LatestPredecessor => "latest_predecessor",
+            CriticalPathAlgorithm::LongestPath => "longest_path",
+        }
+    }
+}
+
+impl FromStr for CriticalPathAlgorithm {
+    type Err = PythiaError;
+
+    fn from_str(s: &str) -> Result<CriticalPathAlgorithm, PythiaError> {
+        match s {
+            "latest_predecessor" => Ok(CriticalPathAlgorithm::LatestPredecessor),
+            "longest_path" => Ok(CriticalPathAlgorithm::LongestPath),
+            _ => Err(PythiaError(format!("Unknown critical path algorithm {}", s))),
+        }
+    }
+}
+
+fn disjoint(dag: &Trace) -> Box<dyn Error> {
+    Box::new(PythiaError(format!("Disjoint trace {}", dag.base_id)))
+}
+
+/// The events of the critical path from the end event back to the start event, walking back
+/// to the predecessor that finished last
+fn latest_predecessor_events(dag: &Trace) -> Result<Vec<NodeIndex>, Box<dyn Error>> {
+    let mut events = vec![dag.end_node];
+    let mut cur_node = dag.end_node;
+    loop {
+        let next_node = match dag
+            .g
+            .neighbors_directed(cur_node, Direction::Incoming)
+            .max_by_key(|&nidx| dag.g[nidx].timestamp)
+        {
+            Some(nidx) => nidx,
+            None => return Err(disjoint(dag)),
+        };
+        events.push(next_node);
+        if next_node == dag.start_node {
+            return Ok(events);
+        }
+        cur_node = next_node;
+    }
+}
+
+/// Duration of an edge as measured by a single clock: zero if its events are on different hosts
+fn host_local_duration(dag: &Trace, edge: EdgeIndex) -> Duration {
+    let (from, to) = dag.g.edge_endpoints(edge).unwrap();
+    if event_host(dag, from) == event_host(dag, to) {
+        dag.g[edge].duration
+    } else {
+        Duration::new(0, 0)
+    }
+}
+
+/// The events of the path from the start event to the end event with the largest sum of
+/// host-local edge durations, from the end event back. Ties go to the predecessor that finished
+/// last, then to the one with the lower index, so the same trace always gives the same path.
+///
+/// Summing plain edge durations would not do: they are timestamp differences, so every path adds
+/// up to the duration of the trace and the tie-breaking alone would pick the path.
+fn longest_path_events(dag: &Trace) -> Result<Vec<NodeIndex>, Box<dyn Error>> {
+    let order = match toposort(&dag.g, None) {
+        Ok(order) => order,
+        Err(_) => {
+            return Err(Box::new(PythiaError(format!("Cyclic trace {}", dag.base_id))))
+        }
+    };
+    // Length of the longest path from the start event to each event reachable from it, and the
+    // event before it on that path
+    let mut longest: HashMap<NodeIndex, (Duration, Option<NodeIndex>)> = HashMap::new();
+    longest.insert(dag.start_node, (Duration::new(0, 0), None));
+    for nidx in order {
+        if nidx == dag.start_node {
+            continue;
+        }
+        let best = dag
+            .g
+            .edges_directed(nidx, Direction::Incoming)
+            .filter_map(|e| {
+                longest
+                    .get(&e.source())
+                    .map(|&(length, _)| (length + host_local_duration(dag, e.id()), e.source()))
+            })
+            .max_by_key(|&(length, prev)| (length, dag.g[prev].timestamp, Reverse(prev.index())));
+        if let Some((length, prev)) = best {
+            longest.insert(nidx, (length, Some(prev)));
+        }
+    }
+    if !longest.contains_key(&dag.end_node) || dag.end_node == dag.start_node {
+        return Err(disjoint(dag));
+    }
+    let mut events = vec![dag.end_node];
+    while let Some(prev) = longest[events.last().unwrap()].1 {
+        events.push(prev);
+    }
+    Ok(events)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CriticalPath {
     /// This is the actual critical path
@@ -48,7 +164,19 @@ pub struct CriticalPath {
 }
 
 impl CriticalPath {
+    /// Extracts the critical path by walking back to the latest predecessor
     pub fn from_trace(dag: &Trace) -> Result<CriticalPath, Box<dyn Error>> {
+        CriticalPath::from_trace_with(dag, CriticalPathAlgorithm::LatestPredecessor)
+    }
+
+    pub fn from_trace_with(
+        dag: &Trace,
+        algorithm: CriticalPathAlgorithm,
+    ) -> Result<CriticalPath, Box<dyn Error>> {
+        let events = match algorithm {
+            CriticalPathAlgorithm::LatestPredecessor => latest_predecessor_events(dag)?,
+            CriticalPathAlgorithm::LongestPath => longest_path_events(dag)?,
+        };
         let mut path = CriticalPath {
             duration: Duration::new(0, 0),
             g: Trace::new(&dag.base_id),
@@ -59,35 +187,19 @@ impl CriticalPath {
             request_type: dag.request_type.clone(),
             request_id: dag.base_id.clone()
         };
-        let mut cur_node = dag.end_node;
-        let mut end_nidx = path.g.g.add_node(dag.g[cur_node].clone());
+        let mut end_nidx = path.g.g.add_node(dag.g[events[0]].clone());
         path.end_node = end_nidx;
-        loop {
-            let next_node = match dag
-                .g
-                .neighbors_directed(cur_node, Direction::Incoming)
-                .max_by_key(|&nidx| dag.g[nidx].timestamp)
-            {
-                Some(nidx) => nidx,
-                None => {
-                    return Err(Box::new(PythiaError(
-                        format!("Disjoint trace {}", dag.base_id).into(),
-                    )))
-                }
-            };
+        for pair in events.windows(2) {
+            let (cur_node, next_node) = (pair[0], pair[1]);
             let start_nidx = path.g.g.add_node(dag.g[next_node].clone());
             path.g.g.add_edge(
                 start_nidx,
                 end_nidx,
                 dag.g[dag.g.find_edge(next_node, cur_node).unwrap()].clone(),
             );
-            if next_node == dag.start_node {
-                path.start_node = start_nidx;
-                break;
-            }
-            cur_node = next_node;
             end_nidx = start_nidx;
         }
+        path.start_node = end_nidx;
         path.add_synthetic_nodes(dag)?;
         path.duration = (path.g.g[path.end_node].timestamp - path.g.g[path.start_node].timestamp)
             .to_std()
@@ -713,3 +825,76 @@ impl Path for CriticalPath {
         self.g.g.node_count()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{span, span_trace};
+
+    /// The tracepoints of the path, leaving out synthetic events
+    fn events(path: &CriticalPath) -> Vec<String> {
+        let mut result = vec![path.start_node];
+        while let Some(nidx) = path.next_node(*result.last().unwrap()) {
+            result.push(nidx);
+        }
+        result
+            .iter()
+            .filter(|&&n| !path.g.g[n].is_synthetic)
+            .map(|&n| path.g.g[n].tracepoint_id.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn longest_path_ignores_skewed_timestamps() {
+        // The cache call really takes 20-120ms, but the clock of its host is 50ms ahead, so it
+        // looks like it finished after the longer database call
+        let trace = span_trace(
+            "t1",
+            vec![
+                span("root", "", "front", "h0", "get", 0, 200),
+                span("db", "root", "db", "h1", "read", 10, 150),
+                span("cache", "root", "cache", "h2", "read", 70, 100),
+            ],
+        )
+        .to_trace();
+        let latest = CriticalPath::from_trace_with(&trace, CriticalPathAlgorithm::LatestPredecessor).unwrap();
+        let longest = CriticalPath::from_trace_with(&trace, CriticalPathAlgorithm::LongestPath).unwrap();
+        assert_eq!(
+            events(&latest),
+            vec!["front:get_start", "db:read_start", "cache:read_start", "cache:read_end", "front:get_end"]
+        );
+        assert_eq!(events(&longest), vec!["front:get_start", "db:read_start", "db:read_end", "front:get_end"]);
+        assert!("fastest".parse::<CriticalPathAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn longest_path_weighs_only_host_local_edges() {
+        let trace = span_trace(
+            "t1",
+            vec![
+                span("root", "", "front", "h0", "get", 0, 200),
+                span("db", "root", "db", "h1", "read", 10, 150),
+                span("cache", "root", "cache", "h2", "read", 70, 100),
+            ],
+        )
+        .to_trace();
+        // Edge durations are timestamp differences, so weighing them as they are makes every path
+        // from start to end equally long and leaves the choice to the tie-breaking
+        let weights: Vec<Duration> = CriticalPath::all_possible_paths(&trace)
+            .map(|p| p.g.g.edge_weights().map(|e| e.duration).sum())
+            .collect();
+        assert!(weights.len() > 1);
+        // (up to the nanoseconds that separate events with the same timestamp)
+        assert!(weights.iter().all(|w| w.as_micros() == trace.duration.as_micros()), "{:?}", weights);
+        // Edges between hosts count for nothing
+        for edge in trace.g.edge_indices() {
+            let (from, to) = trace.g.edge_endpoints(edge).unwrap();
+            let local = host_local_duration(&trace, edge);
+            if event_host(&trace, from) == event_host(&trace, to) {
+                assert_eq!(local, trace.g[edge].duration);
+            } else {
+                assert_eq!(local, Duration::new(0, 0));
+            }
+        }
+    }
+}
@@ -0,0 +1,405 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Structural diff between two traces or critical paths.
+//!
+//! Two requests of the same type end up in different groups when the tracepoint sequences of
+//! their critical paths differ. This module aligns the two sequences (the longest common
+//! subsequence of events, the same matching `Path::contains` does) and reports the tracepoints
+//! only one side has, the latency of every pair of consecutive aligned events on both sides and,
+//! for whole traces, the edges between tracepoints that appear a different number of times.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+use petgraph::graph::NodeIndex;
+use pythia_common::RequestType;
+
+use crate::critical::{CriticalPath, CriticalPathAlgorithm, Path};
+use crate::trace::{IDType, Trace, TracepointID};
+
+/// Most aligned edges shown when printing a diff
+const SHOWN_EDGES: usize = 20;
+
+/// One step of the alignment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffOp {
+    Same(TracepointID),
+    Removed(TracepointID),
+    Inserted(TracepointID),
+}
+
+/// The latency between two consecutive aligned events on both sides
+#[derive(Debug, Clone)]
+pub struct EdgeDelta {
+    pub from: TracepointID,
+    pub to: TracepointID,
+    pub left: Duration,
+    pub right: Duration,
+    /// Removed and inserted events between the two
+    pub skipped: usize,
+}
+
+impl EdgeDelta {
+    /// How much slower the right side is, in seconds
+    pub fn delta(&self) -> f64 {
+        self.right.as_secs_f64() - self.left.as_secs_f64()
+    }
+}
+
+/// An edge between two tracepoints that appears a different number of times on each side
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchChange {
+    pub from: TracepointID,
+    pub to: TracepointID,
+    pub left: usize,
+    pub right: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceDiff {
+    pub left_id: IDType,
+    pub right_id: IDType,
+    pub left_type: RequestType,
+    pub right_type: RequestType,
+    pub left_duration: Duration,
+    pub right_duration: Duration,
+    pub ops: Vec<DiffOp>,
+    pub edges: Vec<EdgeDelta>,
+    /// Only filled when diffing whole traces, critical paths do not branch
+    pub branches: Vec<BranchChange>,
+}
+
+impl TraceDiff {
+    /// Whether the two critical paths have the same tracepoint sequence
+    pub fn same_structure(&self) -> bool {
+        self.ops.iter().all(|op| matches!(op, DiffOp::Same(_))) && self.branches.is_empty()
+    }
+}
+
+fn sequence(path: &CriticalPath) -> Vec<NodeIndex> {
+    let mut result = vec![path.start_node()];
+    while let Some(nidx) = path.next_node(*result.last().unwrap()) {
+        result.push(nidx);
+    }
+    result
+}
+
+/// Aligns the events of two sequences, returning the index pairs of the matched ones
+fn align(left: &CriticalPath, right: &CriticalPath, a: &[NodeIndex], b: &[NodeIndex]) -> Vec<(usize, usize)> {
+    align_by(a.len(), b.len(), |i, j| left.g.g[a[i]] == right.g.g[b[j]])
+}
+
+/// Finds a longest common subsequence of two sequences of lengths `a_len` and `b_len`, where
+/// `same(i, j)` tells whether the i-th element of the first one matches the j-th of the second.
+/// Returns the index pairs of the matched elements in order.
+///
+/// Uses Hirschberg's algorithm, so the memory needed is linear in the length of the sequences
+/// even for traces with many thousands of events.
+pub(crate) fn align_by(a_len: usize, b_len: usize, same: impl Fn(usize, usize) -> bool) -> Vec<(usize, usize)> {
+    // Common prefixes and suffixes are matched directly to keep the search small
+    let mut prefix = 0;
+    while prefix < a_len && prefix < b_len && same(prefix, prefix) {
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    while suffix < a_len - prefix
+        && suffix < b_len - prefix
+        && same(a_len - 1 - suffix, b_len - 1 - suffix)
+    {
+        suffix += 1;
+    }
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    hirschberg(
+        (prefix, a_len - suffix),
+        (prefix, b_len - suffix),
+        &same,
+        &mut pairs,
+    );
+    pairs.extend((0..suffix).map(|k| (a_len - suffix + k, b_len - suffix + k)));
+    pairs
+}
+
+/// Adds the matched pairs of a longest common subsequence of `a[a.0..a.1]` and `b[b.0..b.1]`,
+/// splitting the first range in half and the second where the two halves' subsequences meet.
+fn hirschberg(
+    a: (usize, usize),
+    b: (usize, usize),
+    same: &impl Fn(usize, usize) -> bool,
+    pairs: &mut Vec<(usize, usize)>,
+) {
+    if a.0 == a.1 || b.0 == b.1 {
+        return;
+    }
+    if a.1 - a.0 == 1 {
+        if let Some(j) = (b.0..b.1).find(|&j| same(a.0, j)) {
+            pairs.push((a.0, j));
+        }
+        return;
+    }
+    let mid = (a.0 + a.1) / 2;
+    let m = b.1 - b.0;
+    let forward = lcs_lengths(a.0..mid, m, |i, j| same(i, b.0 + j));
+    let backward = lcs_lengths((mid..a.1).rev(), m, |i, j| same(i, b.1 - 1 - j));
+    let split = (0..=m)
+        .max_by_key(|&j| (forward[j] + backward[m - j], std::cmp::Reverse(j)))
+        .unwrap();
+    hirschberg((a.0, mid), (b.0, b.0 + split), same, pairs);
+    hirschberg((mid, a.1), (b.0 + split, b.1), same, pairs);
+}
+
+/// The length of the longest common subsequence of the rows and each prefix of `0..m`
+fn lcs_lengths(rows: impl Iterator<Item = usize>, m: usize, same: impl Fn(usize, usize) -> bool) -> Vec<u32> {
+    let mut current = vec![0u32; m + 1];
+    for i in rows {
+        // The value of the previous row at j - 1
+        let mut diagonal = 0;
+        for j in 0..m {
+            let above = current[j + 1];
+            current[j + 1] = if same(i, j) {
+                diagonal + 1
+            } else {
+                above.max(current[j])
+            };
+            diagonal = above;
+        }
+    }
+    current
+}
+
+/// Aligns two critical paths by their tracepoint sequence
+pub fn diff_paths(left: &CriticalPath, right: &CriticalPath) -> TraceDiff {
+    let a = sequence(left);
+    let b = sequence(right);
+    let pairs = align(left, right, &a, &b);
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for &(pi, pj) in pairs.iter().chain([(a.len(), b.len())].iter()) {
+        ops.extend(a[i..pi].iter().map(|&n| DiffOp::Removed(left.at(n))));
+        ops.extend(b[j..pj].iter().map(|&n| DiffOp::Inserted(right.at(n))));
+        if pi < a.len() {
+            ops.push(DiffOp::Same(left.at(a[pi])));
+        }
+        i = pi + 1;
+        j = pj + 1;
+    }
+
+    let edges = pairs
+        .windows(2)
+        .map(|w| {
+            let ((i1, j1), (i2, j2)) = (w[0], w[1]);
+            let elapsed = |path: &CriticalPath, from: NodeIndex, to: NodeIndex| {
+                (path.g.g[to].timestamp - path.g.g[from].timestamp)
+                    .to_std()
+                    .unwrap_or_default()
+            };
+            EdgeDelta {
+                from: left.at(a[i1]),
+                to: left.at(a[i2]),
+                left: elapsed(left, a[i1], a[i2]),
+                right: elapsed(right, b[j1], b[j2]),
+                skipped: (i2 - i1 - 1) + (j2 - j1 - 1),
+            }
+        })
+        .collect();
+
+    TraceDiff {
+        left_id: left.request_id.clone(),
+        right_id: right.request_id.clone(),
+        left_type: left.request_type.clone(),
+        right_type: right.request_type.clone(),
+        left_duration: left.duration,
+        right_duration: right.duration,
+        ops,
+        edges,
+        branches: Vec::new(),
+    }
+}
+
+/// Diffs the critical paths of two traces, and compares the edges of the whole traces
+pub fn diff_traces(
+    left: &Trace,
+    right: &Trace,
+    algorithm: CriticalPathAlgorithm,
+) -> Result<TraceDiff, Box<dyn Error>> {
+    let mut diff = diff_paths(
+        &CriticalPath::from_trace_with(left, algorithm)?,
+        &CriticalPath::from_trace_with(right, algorithm)?,
+    );
+    diff.branches = branch_changes(left, right);
+    Ok(diff)
+}
+
+fn edge_counts(trace: &Trace) -> HashMap<(TracepointID, TracepointID), usize> {
+    let mut counts = HashMap::new();
+    for edge in trace.g.edge_indices() {
+        let (from, to) = trace.g.edge_endpoints(edge).unwrap();
+        *counts
+            .entry((trace.g[from].tracepoint_id, trace.g[to].tracepoint_id))
+            .or_default() += 1;
+    }
+    counts
+}
+
+fn branch_changes(left: &Trace, right: &Trace) -> Vec<BranchChange> {
+    let left_counts = edge_counts(left);
+    let right_counts = edge_counts(right);
+    let mut changes: Vec<BranchChange> = left_counts
+        .keys()
+        .chain(right_counts.keys().filter(|k| !left_counts.contains_key(k)))
+        .filter_map(|&(from, to)| {
+            let l = left_counts.get(&(from, to)).cloned().unwrap_or_default();
+            let r = right_counts.get(&(from, to)).cloned().unwrap_or_default();
+            if l == r {
+                None
+            } else {
+                Some(BranchChange {
+                    from,
+                    to,
+                    left: l,
+                    right: r,
+                })
+            }
+        })
+        .collect();
+    changes.sort_by_key(|c| (c.from.to_string(), c.to.to_string()));
+    changes
+}
+
+fn signed_ms(seconds: f64) -> String {
+    format!("{:+.3}ms", seconds * 1000.0)
+}
+
+impl Display for TraceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "--- {} {} {:?}", self.left_id, self.left_type, self.left_duration)?;
+        writeln!(f, "+++ {} {} {:?}", self.right_id, self.right_type, self.right_duration)?;
+        writeln!(
+            f,
+            "Duration change: {}",
+            signed_ms(self.right_duration.as_secs_f64() - self.left_duration.as_secs_f64())
+        )?;
+
+        let count = |pred: fn(&DiffOp) -> bool| self.ops.iter().filter(|op| pred(op)).count();
+        writeln!(
+            f,
+            "\nCritical path alignment: {} common, {} removed, {} inserted",
+            count(|op| matches!(op, DiffOp::Same(_))),
+            count(|op| matches!(op, DiffOp::Removed(_))),
+            count(|op| matches!(op, DiffOp::Inserted(_))),
+        )?;
+        let mut common = 0;
+        for op in &self.ops {
+            if let DiffOp::Same(_) = op {
+                common += 1;
+                continue;
+            }
+            if common > 0 {
+                writeln!(f, "  ... {} common tracepoints", common)?;
+                common = 0;
+            }
+            match op {
+                DiffOp::Removed(tp) => writeln!(f, "- {}", tp)?,
+                DiffOp::Inserted(tp) => writeln!(f, "+ {}", tp)?,
+                DiffOp::Same(_) => {}
+            }
+        }
+        if common > 0 {
+            writeln!(f, "  ... {} common tracepoints", common)?;
+        }
+
+        let mut edges: Vec<&EdgeDelta> = self.edges.iter().collect();
+        edges.sort_by(|a, b| b.delta().abs().partial_cmp(&a.delta().abs()).unwrap());
+        writeln!(f, "\nLatency of aligned edges, largest change first:")?;
+        for edge in edges.iter().take(SHOWN_EDGES) {
+            write!(
+                f,
+                "({} -> {}): {:?} vs {:?} ({})",
+                edge.from,
+                edge.to,
+                edge.left,
+                edge.right,
+                signed_ms(edge.delta())
+            )?;
+            if edge.skipped > 0 {
+                write!(f, " across {} unaligned events", edge.skipped)?;
+            }
+            writeln!(f)?;
+        }
+        if edges.len() > SHOWN_EDGES {
+            writeln!(f, "... and {} more", edges.len() - SHOWN_EDGES)?;
+        }
+
+        if !self.branches.is_empty() {
+            writeln!(f, "\nEdges that appear a different number of times:")?;
+            for change in &self.branches {
+                writeln!(
+                    f,
+                    "({} -> {}): {} vs {}",
+                    change.from, change.to, change.left, change.right
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::chain;
+
+    #[test]
+    fn aligns_paths() {
+        let fast = chain("fast", &[("api", 0), ("cache", 1), ("db", 2), ("reply", 3)]);
+        let slow = chain("slow", &[("api", 0), ("db", 50), ("retry", 60), ("db", 900), ("reply", 901)]);
+        let diff = diff_traces(&fast, &slow, CriticalPathAlgorithm::LatestPredecessor).unwrap();
+        let tp = TracepointID::from_str;
+        assert_eq!(
+            diff.ops,
+            vec![
+                DiffOp::Same(tp("api")),
+                DiffOp::Removed(tp("cache")),
+                DiffOp::Inserted(tp("db")),
+                DiffOp::Inserted(tp("retry")),
+                DiffOp::Same(tp("db")),
+                DiffOp::Same(tp("reply")),
+            ]
+        );
+        let slowest = diff
+            .edges
+            .iter()
+            .max_by(|a, b| a.delta().partial_cmp(&b.delta()).unwrap())
+            .unwrap();
+        assert_eq!((slowest.from, slowest.to, slowest.skipped), (tp("api"), tp("db"), 3));
+        assert_eq!(slowest.right, Duration::from_millis(900));
+        assert!(diff.branches.contains(&BranchChange {
+            from: tp("db"),
+            to: tp("retry"),
+            left: 0,
+            right: 1
+        }));
+        assert!(!diff.same_structure());
+    }
+
+    #[test]
+    fn aligns_long_sequences() {
+        // A few tracepoints repeated with shifting gaps, like loops of different lengths
+        let a: Vec<usize> = (0..1500).map(|i| (i * 7 / 3) % 5).collect();
+        let b: Vec<usize> = (0..1200).map(|i| (i * 3 / 2) % 5).collect();
+        let pairs = align_by(a.len(), b.len(), |i, j| a[i] == b[j]);
+        assert!(pairs.iter().all(|&(i, j)| a[i] == b[j]));
+        assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+        let expected = lcs_lengths(0..a.len(), b.len(), |i, j| a[i] == b[j])[b.len()];
+        assert_eq!(pairs.len(), expected as usize);
+    }
+}
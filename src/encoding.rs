@@ -0,0 +1,575 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Compact on-disk format for traces and manifests.
+//!
+//! Serialized traces repeat the full name of a `TracepointID` in every event, and the field names
+//! of every struct in every element of a list, so manifests of large search spaces get big and
+//! parsing them dominates controller startup. The binary format keeps every string once in a
+//! table and refers to it by index, and compresses the result with zlib.
+//!
+//! A binary file starts with the magic bytes `PYTB` and a version byte, followed by the compressed
+//! body: the string table (a count, then each string as a length and UTF-8 bytes) and one value
+//! encoded as a tag byte and its payload. Integers are LEB128 varints. Values are written through
+//! `serde_json::Value` and read back in the same shape serde_json produces, so anything that can
+//! be written as JSON can be written in this format, and `read` accepts either kind of file.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::fmt::Display;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde::de;
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use serde::{forward_to_deserialize_any, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::PythiaError;
+
+const MAGIC: &[u8; 4] = b"PYTB";
+const VERSION: u8 = 1;
+
+const TAG_NULL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_UNSIGNED: u8 = 3;
+const TAG_SIGNED: u8 = 4;
+const TAG_FLOAT: u8 = 5;
+const TAG_STRING: u8 = 6;
+const TAG_ARRAY: u8 = 7;
+const TAG_OBJECT: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageFormat {
+    Json,
+    Binary,
+}
+
+impl FromStr for StorageFormat {
+    type Err = PythiaError;
+
+    fn from_str(s: &str) -> Result<StorageFormat, PythiaError> {
+        match s {
+            "json" => Ok(StorageFormat::Json),
+            "binary" => Ok(StorageFormat::Binary),
+            _ => Err(PythiaError(format!("Unknown storage format {}", s))),
+        }
+    }
+}
+
+impl StorageFormat {
+    /// File extension for files written in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StorageFormat::Json => "json",
+            StorageFormat::Binary => "pyt",
+        }
+    }
+}
+
+/// Writes the value to the file in the given format
+pub fn write<T: Serialize>(value: &T, file: &Path, format: StorageFormat) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(file)?);
+    match format {
+        StorageFormat::Json => serde_json::to_writer(&mut writer, value)?,
+        StorageFormat::Binary => {
+            writer.write_all(MAGIC)?;
+            writer.write_u8(VERSION)?;
+            let mut encoder = ZlibEncoder::new(writer, Compression::default());
+            encode(&serde_json::to_value(value)?, &mut encoder)?;
+            writer = encoder.finish()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns whether the file is in the binary format
+pub fn is_binary(file: &Path) -> bool {
+    let mut magic = [0; 4];
+    File::open(file)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && &magic == MAGIC
+}
+
+/// Reads a value from a file in either format
+pub fn read<T: DeserializeOwned>(file: &Path) -> Result<T, Box<dyn Error>> {
+    let mut contents = Vec::new();
+    File::open(file)?.read_to_end(&mut contents)?;
+    if !contents.starts_with(MAGIC) {
+        return Ok(serde_json::from_slice(&contents)?);
+    }
+    match contents.get(MAGIC.len()) {
+        Some(&VERSION) => {}
+        version => {
+            return Err(Box::new(PythiaError(format!(
+                "{} has format version {:?}, expected {}",
+                file.display(),
+                version,
+                VERSION
+            ))))
+        }
+    }
+    let mut body = Vec::new();
+    ZlibDecoder::new(&contents[MAGIC.len() + 1..]).read_to_end(&mut body)?;
+    Ok(from_slice(&body)?)
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut n: u64) -> std::io::Result<()> {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return writer.write_u8(byte);
+        }
+        writer.write_u8(byte | 0x80)?;
+    }
+}
+
+#[derive(Default)]
+struct StringTable<'a> {
+    strings: Vec<&'a str>,
+    indices: HashMap<&'a str, u64>,
+}
+
+impl<'a> StringTable<'a> {
+    fn add(&mut self, s: &'a str) {
+        if !self.indices.contains_key(s) {
+            self.indices.insert(s, self.strings.len() as u64);
+            self.strings.push(s);
+        }
+    }
+
+    fn collect(&mut self, value: &'a Value) {
+        match value {
+            Value::String(s) => self.add(s),
+            Value::Array(items) => items.iter().for_each(|v| self.collect(v)),
+            Value::Object(map) => {
+                for (k, v) in map {
+                    self.add(k);
+                    self.collect(v);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn encode<W: Write>(value: &Value, writer: &mut W) -> std::io::Result<()> {
+    let mut table = StringTable::default();
+    table.collect(value);
+    write_varint(writer, table.strings.len() as u64)?;
+    for s in &table.strings {
+        write_varint(writer, s.len() as u64)?;
+        writer.write_all(s.as_bytes())?;
+    }
+    encode_value(value, &table, writer)
+}
+
+fn encode_value<W: Write>(value: &Value, table: &StringTable, writer: &mut W) -> std::io::Result<()> {
+    match value {
+        Value::Null => writer.write_u8(TAG_NULL),
+        Value::Bool(false) => writer.write_u8(TAG_FALSE),
+        Value::Bool(true) => writer.write_u8(TAG_TRUE),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                writer.write_u8(TAG_UNSIGNED)?;
+                write_varint(writer, u)
+            } else if let Some(i) = n.as_i64() {
+                // Zigzag, so small negative numbers stay short
+                writer.write_u8(TAG_SIGNED)?;
+                write_varint(writer, ((i << 1) ^ (i >> 63)) as u64)
+            } else {
+                writer.write_u8(TAG_FLOAT)?;
+                writer.write_f64::<LittleEndian>(n.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        Value::String(s) => {
+            writer.write_u8(TAG_STRING)?;
+            write_varint(writer, table.indices[s.as_str()])
+        }
+        Value::Array(items) => {
+            writer.write_u8(TAG_ARRAY)?;
+            write_varint(writer, items.len() as u64)?;
+            for item in items {
+                encode_value(item, table, writer)?;
+            }
+            Ok(())
+        }
+        Value::Object(map) => {
+            writer.write_u8(TAG_OBJECT)?;
+            write_varint(writer, map.len() as u64)?;
+            for (k, v) in map {
+                write_varint(writer, table.indices[k.as_str()])?;
+                encode_value(v, table, writer)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl de::Error for PythiaError {
+    fn custom<T: Display>(msg: T) -> Self {
+        PythiaError(msg.to_string())
+    }
+}
+
+fn decode_error<T>(message: &str) -> Result<T, PythiaError> {
+    Err(PythiaError(message.to_string()))
+}
+
+/// Deserializes the decompressed body of a binary file, borrowing strings from its table
+struct Deserializer<'de> {
+    input: &'de [u8],
+    strings: Vec<&'de str>,
+}
+
+fn from_slice<'de, T: Deserialize<'de>>(body: &'de [u8]) -> Result<T, PythiaError> {
+    let mut de = Deserializer {
+        input: body,
+        strings: Vec::new(),
+    };
+    let count = de.varint()? as usize;
+    de.strings.reserve(count.min(de.input.len()));
+    for _ in 0..count {
+        let len = de.varint()? as usize;
+        if len > de.input.len() {
+            return decode_error("Truncated string table");
+        }
+        let (s, rest) = de.input.split_at(len);
+        de.strings.push(std::str::from_utf8(s).map_err(de::Error::custom)?);
+        de.input = rest;
+    }
+    T::deserialize(&mut de)
+}
+
+impl<'de> Deserializer<'de> {
+    fn peek(&self) -> Result<u8, PythiaError> {
+        match self.input.first() {
+            Some(&b) => Ok(b),
+            None => decode_error("Unexpected end of input"),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, PythiaError> {
+        let b = self.peek()?;
+        self.input = &self.input[1..];
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64, PythiaError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        decode_error("Varint is too long")
+    }
+
+    fn string(&mut self) -> Result<&'de str, PythiaError> {
+        let idx = self.varint()? as usize;
+        match self.strings.get(idx) {
+            Some(&s) => Ok(s),
+            None => Err(PythiaError(format!("String {} is not in the table", idx))),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = PythiaError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PythiaError> {
+        match self.byte()? {
+            TAG_NULL => visitor.visit_unit(),
+            TAG_FALSE => visitor.visit_bool(false),
+            TAG_TRUE => visitor.visit_bool(true),
+            TAG_UNSIGNED => visitor.visit_u64(self.varint()?),
+            TAG_SIGNED => {
+                let z = self.varint()?;
+                visitor.visit_i64(((z >> 1) as i64) ^ -((z & 1) as i64))
+            }
+            TAG_FLOAT => {
+                if self.input.len() < 8 {
+                    return decode_error("Unexpected end of input");
+                }
+                let (bytes, rest) = self.input.split_at(8);
+                self.input = rest;
+                visitor.visit_f64(LittleEndian::read_f64(bytes))
+            }
+            TAG_STRING => visitor.visit_borrowed_str(self.string()?),
+            TAG_ARRAY => {
+                let len = self.varint()?;
+                visitor.visit_seq(Elements { de: self, left: len })
+            }
+            TAG_OBJECT => {
+                let len = self.varint()?;
+                visitor.visit_map(Elements { de: self, left: len })
+            }
+            tag => Err(PythiaError(format!("Unknown value tag {}", tag))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PythiaError> {
+        if self.peek()? == TAG_NULL {
+            self.byte()?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, PythiaError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Enums are stored the way serde_json writes them: unit variants as their name, and other
+    /// variants as an object with the name as its only key
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PythiaError> {
+        match self.byte()? {
+            TAG_STRING => visitor.visit_enum(self.string()?.into_deserializer()),
+            TAG_OBJECT if self.varint()? == 1 => visitor.visit_enum(Variant { de: self }),
+            _ => decode_error("Expected an enum"),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Elements of an array, or entries of an object
+struct Elements<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    left: u64,
+}
+
+impl<'de, 'a> SeqAccess<'de> for Elements<'a, 'de> {
+    type Error = PythiaError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, PythiaError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'de, 'a> MapAccess<'de> for Elements<'a, 'de> {
+    type Error = PythiaError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, PythiaError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(Key(self.de.string()?)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, PythiaError> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+/// The variant of an enum that has content
+struct Variant<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de, 'a> EnumAccess<'de> for Variant<'a, 'de> {
+    type Error = PythiaError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), PythiaError> {
+        let name = self.de.string()?;
+        Ok((seed.deserialize(name.into_deserializer())?, self))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for Variant<'a, 'de> {
+    type Error = PythiaError;
+
+    fn unit_variant(self) -> Result<(), PythiaError> {
+        de::Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, PythiaError> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, PythiaError> {
+        de::Deserializer::deserialize_seq(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PythiaError> {
+        de::Deserializer::deserialize_map(self.de, visitor)
+    }
+}
+
+/// Object keys are strings, as in JSON; like serde_json, numeric keys are parsed from them
+struct Key<'de>(&'de str);
+
+macro_rules! parse_key {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PythiaError> {
+                match self.0.parse() {
+                    Ok(n) => visitor.$visit(n),
+                    Err(_) => Err(PythiaError(format!("Expected a number as key, got {}", self.0))),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Key<'de> {
+    type Error = PythiaError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, PythiaError> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, PythiaError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, PythiaError> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    parse_key! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf option unit unit_struct seq tuple tuple_struct
+        map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pythia_common::jaeger::JaegerRequestType;
+    use pythia_common::RequestType;
+
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::testutil::{span, span_trace};
+    use crate::trace::{Trace, Value as KeyValue};
+
+    #[test]
+    fn round_trips_values() {
+        let value = serde_json::json!({
+            "tracepoint": "nova/compute/manager.py:1793:nova.compute.manager.ComputeManager.build",
+            "nodes": [
+                {"tracepoint": "nova/compute/manager.py:1793:nova.compute.manager.ComputeManager.build", "ns": -12},
+                {"tracepoint": "nova/api", "ns": u64::MAX, "ratio": 0.25, "synthetic": true, "next": null}
+            ]
+        });
+        let mut bytes = Vec::new();
+        encode(&value, &mut bytes).unwrap();
+        assert_eq!(from_slice::<Value>(&bytes).unwrap(), value);
+        // The long name is stored once
+        let name = b"nova/compute/manager.py";
+        assert_eq!(bytes.windows(name.len()).filter(|w| w == name).count(), 1);
+    }
+
+    #[test]
+    fn round_trips_traces_and_manifests() {
+        let mut root = span("a", "", "api", "host-1", "get", 0, 10);
+        root.tags.insert("retries".to_string(), KeyValue::SignedInt(-1));
+        root.tags.insert("ratio".to_string(), KeyValue::Float(0.5));
+        root.tags.insert("cached".to_string(), KeyValue::Bool(true));
+        root.tags.insert("ids".to_string(), KeyValue::List(vec![KeyValue::UnsignedInt(7)]));
+        let spans = vec![root, span("b", "a", "db", "host-2", "read", 2, 5)];
+        let mut trace = span_trace("t1", spans).to_trace();
+        trace.request_type = RequestType::Jaeger(JaegerRequestType { rt: "api:get".to_string() });
+        let manifest = Manifest::from_trace_list(&vec![trace.clone()]);
+
+        let dir = std::env::temp_dir().join(format!("pythia-encoding-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for format in [StorageFormat::Json, StorageFormat::Binary] {
+            let trace_file = dir.join(format!("trace.{}", format.extension()));
+            trace.to_file(&trace_file, format).unwrap();
+            assert_eq!(is_binary(&trace_file), format == StorageFormat::Binary);
+            let read = Trace::from_file(&trace_file).unwrap();
+            assert_eq!(read.base_id, trace.base_id);
+            assert_eq!(read.request_type, trace.request_type);
+            assert_eq!(read.duration, trace.duration);
+            assert_eq!((read.start_node, read.end_node), (trace.start_node, trace.end_node));
+            for nidx in trace.g.node_indices() {
+                let (expected, event) = (&trace.g[nidx], &read.g[nidx]);
+                assert_eq!(event.tracepoint_id, expected.tracepoint_id);
+                assert_eq!(event.variant, expected.variant);
+                assert_eq!(event.timestamp, expected.timestamp);
+                assert_eq!(event.key_value_pair, expected.key_value_pair);
+            }
+            assert_eq!(read.g.edge_count(), trace.g.edge_count());
+
+            let manifest_file = dir.join(format!("manifest.{}", format.extension()));
+            manifest.to_file(&manifest_file, format).unwrap();
+            let read = Manifest::from_file(&manifest_file).unwrap();
+            assert_eq!(read.get_per_request_types(), manifest.get_per_request_types());
+            assert_eq!(read.request_type_tracepoints, manifest.request_type_tracepoints);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
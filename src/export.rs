@@ -0,0 +1,350 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Exporters for trace viewers.
+//!
+//! * Chrome trace-event JSON, which Perfetto and chrome://tracing load. Each trace is a process,
+//!   and the spans of each host or service are spread over as few threads as possible while
+//!   keeping the slices of a thread properly nested.
+//! * Folded stacks for flame graphs (`flamegraph.pl`, speedscope, inferno). The stacks are the
+//!   spans entered and not yet exited along each critical path, and the time between two
+//!   consecutive events of the path is counted, in microseconds, towards the stack open at the
+//!   first of them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use itertools::Itertools;
+use petgraph::Direction;
+use serde_json::{json, Map, Value as JsonValue};
+
+use crate::critical::{CriticalPath, Path};
+use crate::spantrace::SpanTrace;
+use crate::trace::{EventType, IDType, Trace, Value};
+use crate::PythiaError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// Chrome trace-event JSON
+    Chrome,
+    /// Folded stacks of the critical paths
+    Folded,
+}
+
+impl FromStr for ExportFormat {
+    type Err = PythiaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chrome" => Ok(ExportFormat::Chrome),
+            "folded" => Ok(ExportFormat::Folded),
+            _ => Err(PythiaError(format!("Unknown export format {}", s))),
+        }
+    }
+}
+
+/// A span to draw as a complete ("X") event
+struct Slice {
+    name: String,
+    /// Spans with the same key share threads
+    key: String,
+    start_us: f64,
+    duration_us: f64,
+    args: Map<String, JsonValue>,
+}
+
+fn micros(t: NaiveDateTime) -> f64 {
+    t.and_utc().timestamp_nanos_opt().unwrap_or_default() as f64 / 1000.0
+}
+
+/// Spreads the slices of each key over threads so that slices of a thread nest, and returns the
+/// events of one process
+fn process_events(pid: usize, name: &str, mut slices: Vec<Slice>, instants: Vec<JsonValue>) -> Vec<JsonValue> {
+    let mut events = vec![json!({
+        "ph": "M", "pid": pid, "tid": 0, "name": "process_name", "args": {"name": name}
+    })];
+    slices.sort_by(|a, b| {
+        a.start_us
+            .partial_cmp(&b.start_us)
+            .unwrap()
+            .then(b.duration_us.partial_cmp(&a.duration_us).unwrap())
+    });
+    // The end times of the open slices of each thread, innermost last
+    let mut threads: Vec<(String, Vec<f64>)> = Vec::new();
+    let mut lanes: HashMap<String, usize> = HashMap::new();
+    for slice in slices {
+        let end = slice.start_us + slice.duration_us;
+        let mut tid = None;
+        for (idx, (key, open)) in threads.iter_mut().enumerate() {
+            if *key != slice.key {
+                continue;
+            }
+            while open.last().is_some_and(|&e| e <= slice.start_us) {
+                open.pop();
+            }
+            if open.last().is_none_or(|&e| e >= end) {
+                open.push(end);
+                tid = Some(idx + 1);
+                break;
+            }
+        }
+        let tid = match tid {
+            Some(tid) => tid,
+            None => {
+                let lane = lanes.entry(slice.key.clone()).or_default();
+                let thread_name = if *lane == 0 {
+                    slice.key.clone()
+                } else {
+                    format!("{} #{}", slice.key, *lane + 1)
+                };
+                *lane += 1;
+                threads.push((slice.key.clone(), vec![end]));
+                events.push(json!({
+                    "ph": "M", "pid": pid, "tid": threads.len(), "name": "thread_name",
+                    "args": {"name": thread_name}
+                }));
+                threads.len()
+            }
+        };
+        events.push(json!({
+            "ph": "X",
+            "pid": pid,
+            "tid": tid,
+            "name": slice.name,
+            "cat": slice.key,
+            "ts": slice.start_us,
+            "dur": slice.duration_us,
+            "args": slice.args,
+        }));
+    }
+    events.extend(instants.into_iter().map(|mut i| {
+        i["pid"] = json!(pid);
+        i
+    }));
+    events
+}
+
+fn document(events: Vec<JsonValue>) -> JsonValue {
+    json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+}
+
+/// Converts traces into Chrome trace-event JSON. Matching entry and exit events become slices,
+/// annotations become instant events.
+pub fn chrome_trace_events(traces: &[Trace]) -> JsonValue {
+    let mut events = Vec::new();
+    for (idx, trace) in traces.iter().enumerate() {
+        let mut entries = HashMap::new();
+        let mut slices = Vec::new();
+        let mut instants = Vec::new();
+        let mut nodes: Vec<_> = trace.g.node_indices().collect();
+        nodes.sort_by_key(|&n| trace.g[n].timestamp);
+        for nidx in nodes {
+            let event = &trace.g[nidx];
+            let mut args = Map::new();
+            for (key, value) in &event.key_value_pair {
+                args.insert(key.clone(), json!(value.to_string()));
+            }
+            match event.variant {
+                EventType::Entry => {
+                    entries.insert(event.trace_id.clone(), (nidx, args));
+                }
+                EventType::Exit => {
+                    let (entry, mut entry_args) = match entries.remove(&event.trace_id) {
+                        Some(e) => e,
+                        None => continue,
+                    };
+                    entry_args.extend(args);
+                    let start = &trace.g[entry];
+                    slices.push(Slice {
+                        name: start.tracepoint_id.to_string(),
+                        key: lane_key(trace, entry),
+                        start_us: micros(start.timestamp),
+                        duration_us: micros(event.timestamp) - micros(start.timestamp),
+                        args: entry_args,
+                    });
+                }
+                EventType::Annotation => instants.push(json!({
+                    "ph": "i",
+                    "s": "p",
+                    "tid": 0,
+                    "name": event.tracepoint_id.to_string(),
+                    "ts": micros(event.timestamp),
+                    "args": args,
+                })),
+            }
+        }
+        let name = format!("{} {}", trace.request_type, trace.base_id);
+        events.extend(process_events(idx + 1, &name, slices, instants));
+    }
+    document(events)
+}
+
+/// The host of an event, or else the service or host of the edge leading to it
+fn lane_key(trace: &Trace, nidx: petgraph::graph::NodeIndex) -> String {
+    for key in ["host", "Host"].iter() {
+        if let Some(Value::Str(h)) = trace.g[nidx].key_value_pair.get(*key) {
+            return h.clone();
+        }
+    }
+    for edge in trace.g.edges_directed(nidx, Direction::Incoming) {
+        let edge = edge.weight();
+        if let Some(s) = edge.host.as_ref().or(edge.service.as_ref()) {
+            return s.clone();
+        }
+    }
+    "trace".to_string()
+}
+
+/// Converts span traces into Chrome trace-event JSON, one slice per span
+pub fn chrome_span_trace_events(traces: &[SpanTrace]) -> JsonValue {
+    let mut events = Vec::new();
+    for (idx, trace) in traces.iter().enumerate() {
+        let slices = trace
+            .spans
+            .values()
+            .map(|span| {
+                let mut args = Map::new();
+                args.insert("span_id".to_string(), json!(span.span_id));
+                args.insert("parent".to_string(), json!(span.parent));
+                args.insert("host".to_string(), json!(span.host));
+                for (key, value) in &span.tags {
+                    args.insert(key.clone(), json!(value.to_string()));
+                }
+                Slice {
+                    name: span.operation.clone(),
+                    key: format!("{}@{}", span.service, span.host),
+                    start_us: micros(span.start),
+                    duration_us: span.duration.as_secs_f64() * 1e6,
+                    args,
+                }
+            })
+            .collect();
+        let name = format!("{} {}", trace.endpoint_type, trace.req_id);
+        events.extend(process_events(idx + 1, &name, slices, Vec::new()));
+    }
+    document(events)
+}
+
+/// Folded-stack frames cannot contain the separator
+fn frame(name: &str) -> String {
+    name.replace(';', ":")
+}
+
+/// Converts critical paths into folded stacks, one line per stack with its total microseconds
+pub fn folded_stacks(paths: &[CriticalPath]) -> String {
+    let mut totals: BTreeMap<String, i64> = BTreeMap::new();
+    for path in paths {
+        let root = match path.request_type.to_string() {
+            rt if rt.is_empty() => "request".to_string(),
+            rt => frame(&rt),
+        };
+        // The spans entered and not yet exited, outermost first
+        let mut open: Vec<(&IDType, String)> = Vec::new();
+        let mut cur = path.start_node;
+        loop {
+            let event = &path.g.g[cur];
+            match event.variant {
+                EventType::Entry => {
+                    open.push((&event.trace_id, frame(&event.tracepoint_id.to_string())))
+                }
+                // An exit closes the entry of its own span, even if spans entered after it are
+                // still open, and nothing if that entry is not on the path
+                EventType::Exit => {
+                    if let Some(pos) = open.iter().rposition(|(id, _)| **id == event.trace_id) {
+                        open.remove(pos);
+                    }
+                }
+                EventType::Annotation => {}
+            }
+            let next = match path.next_node(cur) {
+                Some(n) => n,
+                None => break,
+            };
+            let elapsed = (path.g.g[next].timestamp - event.timestamp)
+                .num_microseconds()
+                .unwrap_or_default();
+            if elapsed > 0 {
+                let stack = std::iter::once(root.as_str())
+                    .chain(open.iter().map(|(_, f)| f.as_str()))
+                    .join(";");
+                *totals.entry(stack).or_default() += elapsed;
+            }
+            cur = next;
+        }
+    }
+    totals
+        .iter()
+        .map(|(stack, us)| format!("{} {}\n", stack, us))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{event, span, span_trace, trace};
+
+    #[test]
+    fn exports_spans_and_stacks() {
+        let spans = vec![
+            span("a", "", "svc", "host-1", "api", 0, 10),
+            span("b", "a", "svc", "host-1", "db", 1, 4),
+            span("c", "a", "svc", "host-1", "cache", 3, 4),
+        ];
+        let trace = span_trace("t1", spans);
+        let doc = chrome_span_trace_events(&[trace.clone()]);
+        let slices: Vec<&JsonValue> = doc["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|e| e["ph"] == "X")
+            .collect();
+        assert_eq!(slices.len(), 3);
+        let tid = |name: &str| slices.iter().find(|e| e["name"] == name).unwrap()["tid"].clone();
+        // cache overlaps db without nesting in it, so it needs a second thread
+        assert_eq!(tid("api"), tid("db"));
+        assert_ne!(tid("db"), tid("cache"));
+
+        let path = CriticalPath::from_trace(&trace.to_critical_path()).unwrap();
+        let folded = folded_stacks(&[path]);
+        let total: i64 = folded
+            .lines()
+            .map(|l| l.rsplit(' ').next().unwrap().parse::<i64>().unwrap())
+            .sum();
+        assert_eq!(total, 10_000, "{}", folded);
+        assert!(folded.lines().all(|l| l.starts_with("api;")), "{}", folded);
+    }
+
+    #[test]
+    fn exits_close_their_own_entry() {
+        // b is entered inside a but exits after it
+        let events = vec![
+            event("a", "a_start", EventType::Entry, 0),
+            event("b", "b_start", EventType::Entry, 1),
+            event("a", "a_end", EventType::Exit, 3),
+            event("b", "b_end", EventType::Exit, 6),
+        ];
+        let path = CriticalPath::from_trace(&trace("t1", events, &[(0, 1), (1, 2), (2, 3)])).unwrap();
+        let stacks: Vec<(String, i64)> = folded_stacks(&[path])
+            .lines()
+            .map(|l| {
+                let (stack, us) = l.rsplit_once(' ').unwrap();
+                let frames: Vec<&str> = stack.split(';').skip(1).collect();
+                (frames.join(";"), us.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                ("a_start".to_string(), 1_000),
+                ("a_start;b_start".to_string(), 2_000),
+                ("b_start".to_string(), 3_000),
+            ]
+        );
+    }
+}
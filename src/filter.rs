@@ -0,0 +1,462 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Filter expressions over traces.
+//!
+//! A filter is compiled once from an expression such as
+//! `request_type == "ServerCreate" && duration > 2s && contains("nova/...manager.py:1972")` and
+//! then evaluated against each `Trace` or `CriticalPath`. The language has:
+//! * `request_type`, `id` and `host` compared with `==` and `!=` to a string. `host == "cp-2"`
+//!   holds when the trace visited that host, `host != "cp-2"` when it did not.
+//! * `duration` compared with `==`, `!=`, `<`, `<=`, `>` and `>=` to a duration such as `250ms`,
+//!   `2s` or `1.5m` (units are `ns`, `us`, `ms`, `s`, `m` and `h`).
+//! * `contains("pattern")`, which holds when the pattern is part of one of the tracepoints of the
+//!   trace. A `...` in the pattern stands for any text, so `nova/...manager.py:1972` matches
+//!   `nova/compute/manager.py:1972`.
+//! * `kv("key")`, which holds when some event has the key, and `kv("key", "value")`, when some
+//!   event has it with that value.
+//! * `!`, `&&` and `||` (in increasing order of precedence: `||`, `&&`, `!`) and parentheses.
+
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::critical::CriticalPath;
+use crate::trace::Trace;
+use crate::PythiaError;
+
+/// A compiled filter expression
+#[derive(Debug, Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Text(TextField, bool, String),
+    Duration(Comparison, Duration),
+    Contains(Vec<String>),
+    KeyValue(String, Option<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextField {
+    RequestType,
+    Id,
+    Host,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Duration(Duration),
+    Op(Comparison),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Comma,
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Filter, PythiaError> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        if let Some((token, offset)) = parser.tokens.get(parser.pos) {
+            return Err(error(*offset, &format!("unexpected {:?}", token)));
+        }
+        Ok(Filter {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    pub fn matches_trace(&self, trace: &Trace) -> bool {
+        self.expr.eval(&Subject {
+            trace,
+            id: trace.base_id.to_string(),
+            request_type: trace.request_type.to_string(),
+            duration: trace.duration,
+        })
+    }
+
+    pub fn matches_path(&self, path: &CriticalPath) -> bool {
+        self.expr.eval(&Subject {
+            trace: &path.g,
+            id: path.request_id.to_string(),
+            request_type: path.request_type.to_string(),
+            duration: path.duration,
+        })
+    }
+}
+
+impl FromStr for Filter {
+    type Err = PythiaError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Filter::parse(s)
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// What an expression is evaluated against
+struct Subject<'a> {
+    trace: &'a Trace,
+    id: String,
+    request_type: String,
+    duration: Duration,
+}
+
+impl Expr {
+    fn eval(&self, subject: &Subject) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(subject) && b.eval(subject),
+            Expr::Or(a, b) => a.eval(subject) || b.eval(subject),
+            Expr::Not(a) => !a.eval(subject),
+            Expr::Text(field, equal, value) => {
+                let found = match field {
+                    TextField::RequestType => subject.request_type == *value,
+                    TextField::Id => subject.id == *value,
+                    TextField::Host => subject.trace.hosts().contains(value),
+                };
+                found == *equal
+            }
+            Expr::Duration(op, value) => match op {
+                Comparison::Eq => subject.duration == *value,
+                Comparison::Ne => subject.duration != *value,
+                Comparison::Lt => subject.duration < *value,
+                Comparison::Le => subject.duration <= *value,
+                Comparison::Gt => subject.duration > *value,
+                Comparison::Ge => subject.duration >= *value,
+            },
+            Expr::Contains(parts) => subject
+                .trace
+                .g
+                .node_weights()
+                .any(|event| matches_pattern(&event.tracepoint_id.to_string(), parts)),
+            Expr::KeyValue(key, value) => subject.trace.g.node_weights().any(|event| {
+                match (event.key_value_pair.get(key), value) {
+                    (Some(_), None) => true,
+                    (Some(v), Some(value)) => v.to_string() == *value,
+                    (None, _) => false,
+                }
+            }),
+        }
+    }
+}
+
+/// Splits a pattern at its `...` wildcards
+pub(crate) fn pattern_parts(pattern: &str) -> Vec<String> {
+    pattern.split("...").map(|p| p.to_string()).collect()
+}
+
+/// Whether the parts of a `...` pattern appear in `text` in order
+pub(crate) fn matches_pattern(text: &str, parts: &[String]) -> bool {
+    let mut remaining = text;
+    for part in parts {
+        match remaining.find(part.as_str()) {
+            Some(pos) => remaining = &remaining[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+fn error(offset: usize, message: &str) -> PythiaError {
+    PythiaError(format!("filter error at character {}: {}", offset, message))
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, PythiaError> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (offset, c) = chars[i];
+        let next = chars.get(i + 1).map(|&(_, c)| c);
+        let (token, width) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Op(Comparison::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Comparison::Ne), 2),
+            ('<', Some('=')) => (Token::Op(Comparison::Le), 2),
+            ('>', Some('=')) => (Token::Op(Comparison::Ge), 2),
+            ('<', _) => (Token::Op(Comparison::Lt), 1),
+            ('>', _) => (Token::Op(Comparison::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            (',', _) => (Token::Comma, 1),
+            ('"', _) => {
+                let mut value = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j).map(|&(_, c)| c) {
+                        None => return Err(error(offset, "unterminated string")),
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.get(j + 1).map(|&(_, c)| c) {
+                                Some(escaped) => value.push(escaped),
+                                None => return Err(error(offset, "unterminated string")),
+                            }
+                            j += 2;
+                        }
+                        Some(c) => {
+                            value.push(c);
+                            j += 1;
+                        }
+                    }
+                }
+                (Token::Str(value), j + 1 - i)
+            }
+            (c, _) if c.is_ascii_digit() || c == '.' => {
+                let mut j = i;
+                while chars.get(j).is_some_and(|&(_, c)| c.is_ascii_digit() || c == '.') {
+                    j += 1;
+                }
+                let number: String = chars[i..j].iter().map(|&(_, c)| c).collect();
+                let unit_start = j;
+                while chars.get(j).is_some_and(|&(_, c)| c.is_ascii_alphabetic()) {
+                    j += 1;
+                }
+                let unit: String = chars[unit_start..j].iter().map(|&(_, c)| c).collect();
+                let number: f64 = number
+                    .parse()
+                    .map_err(|_| error(offset, &format!("bad number {}", number)))?;
+                let scale = match unit.as_str() {
+                    "ns" => 1e-9,
+                    "us" => 1e-6,
+                    "ms" => 1e-3,
+                    "s" => 1.0,
+                    "m" => 60.0,
+                    "h" => 3600.0,
+                    "" => return Err(error(offset, "durations need a unit such as ms or s")),
+                    _ => return Err(error(offset, &format!("unknown duration unit {}", unit))),
+                };
+                let duration = Duration::try_from_secs_f64(number * scale)
+                    .map_err(|_| error(offset, &format!("duration {}{} is out of range", number, unit)))?;
+                (Token::Duration(duration), j - i)
+            }
+            (c, _) if c.is_ascii_alphabetic() || c == '_' => {
+                let mut j = i;
+                while chars
+                    .get(j)
+                    .is_some_and(|&(_, c)| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    j += 1;
+                }
+                (Token::Ident(chars[i..j].iter().map(|&(_, c)| c).collect()), j - i)
+            }
+            (c, _) => return Err(error(offset, &format!("unexpected character {:?}", c))),
+        };
+        tokens.push((token, offset));
+        i += width;
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser over the tokens
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    /// Where the next token starts, or the end of the input
+    fn offset(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some(&(_, offset)) => offset,
+            None => self.tokens.last().map_or(0, |&(_, offset)| offset + 1),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, PythiaError> {
+        match self.tokens.get(self.pos) {
+            Some((token, _)) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(error(self.offset(), "unexpected end of filter")),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), PythiaError> {
+        let offset = self.offset();
+        let token = self.next()?;
+        if token != expected {
+            return Err(error(offset, &format!("expected {:?}, got {:?}", expected, token)));
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, PythiaError> {
+        let offset = self.offset();
+        match self.next()? {
+            Token::Str(s) => Ok(s),
+            token => Err(error(offset, &format!("expected a string, got {:?}", token))),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, PythiaError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, PythiaError> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, PythiaError> {
+        let offset = self.offset();
+        match self.next()? {
+            Token::Not => Ok(Expr::Not(Box::new(self.unary()?))),
+            Token::Open => {
+                let expr = self.or()?;
+                self.expect(Token::Close)?;
+                Ok(expr)
+            }
+            Token::Ident(name) => self.predicate(&name, offset),
+            token => Err(error(offset, &format!("unexpected {:?}", token))),
+        }
+    }
+
+    fn predicate(&mut self, name: &str, offset: usize) -> Result<Expr, PythiaError> {
+        let field = match name {
+            "request_type" => Some(TextField::RequestType),
+            "id" => Some(TextField::Id),
+            "host" => Some(TextField::Host),
+            _ => None,
+        };
+        match name {
+            "duration" => {
+                let op_offset = self.offset();
+                let op = match self.next()? {
+                    Token::Op(op) => op,
+                    token => return Err(error(op_offset, &format!("expected a comparison, got {:?}", token))),
+                };
+                let value_offset = self.offset();
+                match self.next()? {
+                    Token::Duration(d) => Ok(Expr::Duration(op, d)),
+                    token => Err(error(value_offset, &format!("expected a duration, got {:?}", token))),
+                }
+            }
+            _ if field.is_some() => {
+                let op_offset = self.offset();
+                let equal = match self.next()? {
+                    Token::Op(Comparison::Eq) => true,
+                    Token::Op(Comparison::Ne) => false,
+                    token => {
+                        return Err(error(
+                            op_offset,
+                            &format!("{} can only be compared with == or !=, got {:?}", name, token),
+                        ))
+                    }
+                };
+                Ok(Expr::Text(field.unwrap(), equal, self.string()?))
+            }
+            "contains" => {
+                self.expect(Token::Open)?;
+                let pattern = self.string()?;
+                self.expect(Token::Close)?;
+                Ok(Expr::Contains(pattern_parts(&pattern)))
+            }
+            "kv" => {
+                self.expect(Token::Open)?;
+                let key = self.string()?;
+                let value = if self.peek() == Some(&Token::Comma) {
+                    self.pos += 1;
+                    Some(self.string()?)
+                } else {
+                    None
+                };
+                self.expect(Token::Close)?;
+                Ok(Expr::KeyValue(key, value))
+            }
+            _ => Err(error(offset, &format!("unknown field or function {}", name))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{span, span_trace};
+
+    #[test]
+    fn evaluates_expressions() {
+        let spans = vec![
+            span("a", "", "nova", "cp-1", "api", 0, 3000),
+            span("b", "a", "nova/compute", "cp-2", "manager.py:1972", 0, 2000),
+        ];
+        let mut trace = span_trace("t1", spans).to_critical_path();
+        trace.duration = Duration::from_secs(3);
+        let rt = trace.request_type.to_string();
+        let tracepoints: Vec<String> = trace.g.node_weights().map(|e| e.tracepoint_id.to_string()).collect();
+        assert!(tracepoints.iter().any(|t| matches_pattern(t, &["nova/".into(), "1972".into()])));
+
+        let matching = format!(
+            "request_type == \"{}\" && duration > 2s && contains(\"nova/...1972\") && host == \"cp-2\"",
+            rt
+        );
+        assert!(Filter::parse(&matching).unwrap().matches_trace(&trace));
+        for expr in &[
+            "duration <= 2500ms",
+            "!(host == \"cp-1\")",
+            "host != \"cp-2\" || id == \"t2\"",
+            "contains(\"1972...nova\")",
+        ] {
+            assert!(!Filter::parse(expr).unwrap().matches_trace(&trace), "{}", expr);
+        }
+
+        let err = Filter::parse("duration > 2 && host == \"x\"").unwrap_err();
+        assert!(err.to_string().contains("at character 11"), "{}", err);
+        assert!(Filter::parse("host > \"x\"").is_err());
+        assert!(Filter::parse("(id == \"t1\"").is_err());
+        let err = Filter::parse("duration > 99999999999999999999h").unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{}", err);
+    }
+}
@@ -8,12 +8,11 @@ All rights reserved.
 
 //! Code related to grouping critical paths
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Display;
 use std::time::Duration;
 
-use petgraph::dot::Dot;
 use petgraph::graph::EdgeIndex;
 use petgraph::graph::NodeIndex;
 use petgraph::stable_graph::StableGraph;
@@ -25,18 +24,111 @@ use pythia_common::{OSPRequestType, RequestType};
 
 use crate::critical::CriticalPath;
 use crate::critical::Path;
+use crate::diff::align_by;
+use crate::render::{Highlights, ToDot};
+use crate::timing::{span_timings, SpanTimes};
+use crate::trace::EventType;
+use crate::trace::Trace;
 use crate::trace::TraceNode;
 //use crate::trace::TraceNode::key_value_pair;
 use crate::trace::TracepointID;
 use crate::trace::Value;
+use crate::PythiaError;
 
 use histogram::Histogram;
 
+/// How critical paths are put into groups
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupingMode {
+    /// Only paths with the same hash share a group
+    Exact,
+    /// A path joins the most similar group of its request type if the edit distance of their
+    /// tracepoint sequences leaves at least this similarity: the fraction of the events of both
+    /// that the other one has too
+    EditDistance(f64),
+    /// A path joins the most similar group of its request type if the Jaccard similarity of their
+    /// sets of tracepoints is at least this
+    Jaccard(f64),
+}
+
+impl GroupingMode {
+    /// `name` is exact, edit_distance or jaccard; `min_similarity` is between 0 and 1
+    pub fn new(name: &str, min_similarity: f64) -> Result<GroupingMode, PythiaError> {
+        if !(0.0..=1.0).contains(&min_similarity) {
+            return Err(PythiaError(format!(
+                "A grouping similarity must be between 0 and 1, got {}",
+                min_similarity
+            )));
+        }
+        match name {
+            "exact" => Ok(GroupingMode::Exact),
+            "edit_distance" => Ok(GroupingMode::EditDistance(min_similarity)),
+            "jaccard" => Ok(GroupingMode::Jaccard(min_similarity)),
+            _ => Err(PythiaError(format!("Unknown grouping mode {}", name))),
+        }
+    }
+
+    /// Similarity of two tracepoint sequences from 0 to 1, if it is enough to group them
+    fn similarity(&self, a: &[NodeKey], b: &[NodeKey]) -> Option<f64> {
+        let (similarity, min_similarity) = match *self {
+            GroupingMode::Exact => return None,
+            GroupingMode::EditDistance(min) => {
+                let common = align_by(a.len(), b.len(), |i, j| a[i] == b[j]).len();
+                (2.0 * common as f64 / (a.len() + b.len()) as f64, min)
+            }
+            GroupingMode::Jaccard(min) => {
+                let a: HashSet<&NodeKey> = a.iter().collect();
+                let b: HashSet<&NodeKey> = b.iter().collect();
+                let union = a.union(&b).count();
+                (a.intersection(&b).count() as f64 / union as f64, min)
+            }
+        };
+        if similarity >= min_similarity {
+            Some(similarity)
+        } else {
+            None
+        }
+    }
+}
+
+/// Fewest traces a value of an attribute needs for `Group::attribute_splits` to consider it
+const MIN_PARTITION_TRACES: usize = 2;
+
+/// What two events need to share to be aligned across paths
+type NodeKey = (TracepointID, EventType);
+
+fn path_events(path: &CriticalPath) -> Vec<NodeIndex> {
+    let mut result = vec![path.start_node];
+    while let Some(nidx) = path.next_node(*result.last().unwrap()) {
+        result.push(nidx);
+    }
+    result
+}
+
+/// The hash of the group most similar to the path among those similar enough to take it
+fn similar_group(groups: &HashMap<String, Group>, path: &CriticalPath, mode: GroupingMode) -> Option<String> {
+    if mode == GroupingMode::Exact {
+        return None;
+    }
+    let keys: Vec<NodeKey> = path_events(path)
+        .iter()
+        .map(|&n| (path.g.g[n].tracepoint_id, path.g.g[n].variant))
+        .collect();
+    groups
+        .iter()
+        .filter(|(_, g)| g.request_type == path.request_type)
+        .filter_map(|(hash, g)| mode.similarity(&g.keys(), &keys).map(|s| (s, hash)))
+        // The hash breaks ties so that the choice does not depend on the order of the map
+        .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then_with(|| b.1.cmp(a.1)))
+        .map(|(_, hash)| hash.clone())
+}
+
 /// A group of critical paths
 // #[derive(Clone, Debug, Copy)]
 #[derive(Clone, Debug)]
 pub struct Group {
-    /// Representative path and the relevant latency etc. statistics
+    /// Representative path and the relevant latency etc. statistics. With approximate grouping,
+    /// this is the first path of the group, and the others are aligned to it.
     pub g: StableGraph<TraceNode, GroupEdge>,
     hash: String,
     pub start_node: NodeIndex,
@@ -50,6 +142,8 @@ pub struct Group {
    // tsl: Group means to calculate CVs
    pub mean: f64,
    pub is_used: bool,
+    /// Inclusive/exclusive time and slack of the spans of the full traces, if they were added
+    pub span_times: SpanTimes,
 
 
     //   //tsl: Disable strategy - if a groups stops being problematic, disable all the tracepoints for that
@@ -80,7 +174,7 @@ impl Display for GroupEdge {
 
 impl Group {
     pub fn dot(&self) -> String {
-        format!("{}", Dot::new(&self.g))
+        self.to_dot(&Highlights::none())
     }
 
         /// tsl: add enabled tracepoints for the groups
@@ -91,16 +185,12 @@ impl Group {
     //     }
     // }
 
-    pub fn from_critical_paths(paths: Vec<CriticalPath>) -> Vec<Group> {
-        let mut hash_map = HashMap::<String, Group>::new();
-        for path in paths {
-            match hash_map.get_mut(path.hash()) {
-                Some(v) => v.add_trace(&path),
-                None => {
-                    hash_map.insert(path.hash().to_string(), Group::new(path));
-                }
-            }
+    pub fn from_critical_paths(paths: Vec<CriticalPath>, mode: GroupingMode) -> Vec<Group> {
+        let mut manager = GroupManager::with_mode(mode);
+        for path in paths.iter() {
+            manager.insert(path);
         }
+        let mut hash_map = manager.groups;
         let mut zeros = 0;
         for (_, group) in hash_map.iter_mut() {
             group.calculate_variance();
@@ -110,7 +200,7 @@ impl Group {
             }
         }
         println!("{} groups had 0 variance", zeros);
-        hash_map.values().cloned().collect::<Vec<Group>>()
+        hash_map.into_values().collect::<Vec<Group>>()
     }
 
     fn new(path: CriticalPath) -> Group {
@@ -156,16 +246,24 @@ impl Group {
             variance: 0.0,
             mean: 0.0,
             is_used: false,
+            span_times: SpanTimes::default(),
             // enabled_tps: Vec<(TracepointID, Option<RequestType>)> = Vec::new(),
             //cv: 0.0,
           //  key_value_pairs: TraceNode::get_key_values(),
         }
     }
 
+    /// Adds the span timings of the full trace that one of the paths of this group came from.
+    /// Critical paths alone do not have the off-path branches needed for slack.
+    pub fn add_span_timings(&mut self, trace: &Trace) {
+        self.span_times.add(&span_timings(trace));
+    }
+
     /// After we use a group for diagnosis, we reset the group. This function is incomplete, and we
     /// should ideally modify the edges as well.
     pub fn used(&mut self) {
         self.traces = Vec::new();
+        self.span_times = SpanTimes::default();
         self.variance = 0.0;
         self.is_used = true;
     }
@@ -213,8 +311,10 @@ impl Group {
         let mut prev_node = None;
         let mut cur_dag_nidx = self.start_node;
         let mut prev_dag_nidx = None;
-        // Loop through edges and push durations
+        // Loop through edges and push durations, and nodes and push their key/value pairs
         loop {
+            let node = TraceNode::from_event(&path.g.g[cur_node]);
+            self.g[cur_dag_nidx].add_key_values(&node);
             if !prev_dag_nidx.is_none() {
                 match path.g.g.find_edge(prev_node.unwrap(), cur_node) {
                     Some(edge) => {
@@ -236,6 +336,104 @@ impl Group {
             cur_dag_nidx = self.next_node(cur_dag_nidx).unwrap();
         }
     }
+
+    /// The nodes of the representative path in order
+    fn nodes(&self) -> Vec<NodeIndex> {
+        let mut result = vec![self.start_node];
+        while let Some(nidx) = self.next_node(*result.last().unwrap()) {
+            result.push(nidx);
+        }
+        result
+    }
+
+    fn keys(&self) -> Vec<NodeKey> {
+        self.nodes()
+            .iter()
+            .map(|&n| (self.g[n].tracepoint_id, self.g[n].variant))
+            .collect()
+    }
+
+    /// Adds a trace whose path differs from the representative path. The events of the path are
+    /// aligned with the nodes of the group, and each edge of the group whose two nodes were both
+    /// matched gets the time the path took between the matching events. Edges next to nodes the
+    /// path does not have get no sample from it.
+    fn add_similar_trace(&mut self, path: &CriticalPath) {
+        println!("**** A trace {:?} merged into group{:?}", path.g.base_id, self.hash);
+        self.traces.push(path.clone());
+        let nodes = self.nodes();
+        let events = path_events(path);
+        let pairs = align_by(nodes.len(), events.len(), |i, j| path.g.g[events[j]] == self.g[nodes[i]]);
+        for &(i, j) in &pairs {
+            let node = TraceNode::from_event(&path.g.g[events[j]]);
+            self.g[nodes[i]].add_key_values(&node);
+        }
+        for pair in pairs.windows(2) {
+            let ((from, from_event), (to, to_event)) = (pair[0], pair[1]);
+            if to != from + 1 {
+                continue;
+            }
+            let duration = events[from_event..=to_event]
+                .windows(2)
+                .map(|e| path.g.g[path.g.g.find_edge(e[0], e[1]).unwrap()].duration)
+                .sum();
+            let edge = self.g.find_edge(nodes[from], nodes[to]).unwrap();
+            self.g[edge].duration.push(duration);
+        }
+    }
+    /// Partitions the traces of the group by each attribute they carry and ranks the attributes
+    /// by the fraction of latency variance the partition explains, adjusted for the number of
+    /// partitions (adjusted R²). Traces lacking an attribute form their own partition.
+    ///
+    /// An attribute with nearly one value per trace explains almost all of the variance by
+    /// overfitting, so attributes with a single value, or with a value shared by fewer than
+    /// `MIN_PARTITION_TRACES` traces, are left out, as are those explaining nothing once adjusted.
+    pub fn attribute_splits(&self) -> Vec<AttributeSplit> {
+        let latencies: Vec<f64> = self.traces.iter().map(|t| t.duration.as_nanos() as f64).collect();
+        let n = latencies.len();
+        let overall = latencies.iter().sum::<f64>() / n as f64;
+        let total: f64 = latencies.iter().map(|l| (l - overall).powi(2)).sum();
+        if n < 2 || total == 0.0 {
+            return Vec::new();
+        }
+        let per_trace: Vec<HashMap<String, String>> = self.traces.iter().map(path_attributes).collect();
+        let mut names: Vec<&String> = per_trace.iter().flat_map(|a| a.keys()).collect();
+        names.sort();
+        names.dedup();
+
+        let mut result = Vec::new();
+        for name in names {
+            let mut partitions: HashMap<&str, Vec<f64>> = HashMap::new();
+            for (attributes, &latency) in per_trace.iter().zip(latencies.iter()) {
+                let value = attributes.get(name).map(|v| v.as_str()).unwrap_or("<none>");
+                partitions.entry(value).or_default().push(latency);
+            }
+            let k = partitions.len();
+            if k < 2 || partitions.values().any(|ls| ls.len() < MIN_PARTITION_TRACES) {
+                continue;
+            }
+            let mut values: Vec<(String, usize, f64)> = partitions
+                .into_iter()
+                .map(|(value, ls)| (value.to_string(), ls.len(), ls.iter().sum::<f64>() / ls.len() as f64))
+                .collect();
+            values.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+            let between: f64 = values
+                .iter()
+                .map(|(_, count, mean)| *count as f64 * (mean - overall).powi(2))
+                .sum();
+            let explained = 1.0 - (1.0 - between / total) * (n - 1) as f64 / (n - k) as f64;
+            if explained <= 0.0 {
+                continue;
+            }
+            result.push(AttributeSplit {
+                attribute: name.clone(),
+                explained,
+                values,
+            });
+        }
+        result.sort_by(|a, b| b.explained.partial_cmp(&a.explained).unwrap());
+        result
+    }
+
     // tsl: calculate mean of the group
     fn calculate_mean(&mut self) {
         // change below variance to mean
@@ -258,6 +456,63 @@ impl Group {
     }
 }
 
+/// How well one attribute of the traces of a group explains the variance of their latency
+#[derive(Clone, Debug)]
+pub struct AttributeSplit {
+    /// `<tracepoint>: <key>` for event key/value pairs, `<from> -> <to>: host|service` for edges
+    pub attribute: String,
+    /// Fraction of the latency variance explained by partitioning on the attribute, adjusted for
+    /// the number of values
+    pub explained: f64,
+    /// Every value of the attribute, with the number of traces and their mean latency in ns
+    pub values: Vec<(String, usize, f64)>,
+}
+
+impl Display for AttributeSplit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} explains {:.1}% of variance:", self.attribute, self.explained * 100.0)?;
+        for (value, count, mean) in &self.values {
+            write!(f, " {}={:.3}ms ({} traces)", value, mean / 1000000.0, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Attribute values of a critical path, keyed by attribute name
+fn path_attributes(path: &CriticalPath) -> HashMap<String, String> {
+    let mut attributes: HashMap<String, Vec<String>> = HashMap::new();
+    for nidx in path.g.g.node_indices() {
+        let node = TraceNode::from_event(&path.g.g[nidx]);
+        for (key, values) in node.key_value_pair {
+            attributes
+                .entry(format!("{}: {}", node.tracepoint_id, key))
+                .or_default()
+                .extend(values.iter().map(|v| v.to_string()));
+        }
+    }
+    for edge in path.g.g.edge_indices() {
+        let (from, to) = path.g.g.edge_endpoints(edge).unwrap();
+        let name = format!("{} -> {}", path.g.g[from].tracepoint_id, path.g.g[to].tracepoint_id);
+        let weight = &path.g.g[edge];
+        for (field, value) in [("host", &weight.host), ("service", &weight.service)] {
+            if let Some(value) = value {
+                attributes
+                    .entry(format!("{}: {}", name, field))
+                    .or_default()
+                    .push(value.clone());
+            }
+        }
+    }
+    attributes
+        .into_iter()
+        .map(|(name, mut values)| {
+            values.sort();
+            values.dedup();
+            (name, values.join(","))
+        })
+        .collect()
+}
+
 // # key value = hostname = client | server  ---> Append trace_id 0000> 
 // 1231-123_hostname = "client" , 1233331-123_hostname = "client"
 
@@ -303,12 +558,49 @@ impl Path for Group {
 #[derive(Debug)]
 pub struct GroupManager {
     groups: HashMap<String, Group>,
+    /// Hashes of the paths merged into a group with another hash, and the hash of that group
+    aliases: HashMap<String, String>,
+    mode: GroupingMode,
 }
 
 impl GroupManager {
+    /// A manager grouping only paths with the same hash
     pub fn new() -> Self {
+        GroupManager::with_mode(GroupingMode::Exact)
+    }
+
+    pub fn with_mode(mode: GroupingMode) -> Self {
         GroupManager {
             groups: HashMap::new(),
+            aliases: HashMap::new(),
+            mode,
+        }
+    }
+
+    /// Adds a path to its group, creating the group if there is none, and returns the group hash
+    fn insert(&mut self, path: &CriticalPath) -> String {
+        // Extract group corresponding to path (based on path hash) and add trace to that group
+        if let Some(group) = self.groups.get_mut(path.hash()) {
+            group.add_trace(path);
+            return path.hash().to_string();
+        }
+        let similar = match self.aliases.get(path.hash()) {
+            Some(hash) => Some(hash.clone()),
+            None => similar_group(&self.groups, path, self.mode),
+        };
+        match similar {
+            Some(hash) => {
+                self.groups.get_mut(&hash).unwrap().add_similar_trace(path);
+                self.aliases.insert(path.hash().to_string(), hash.clone());
+                hash
+            }
+            // Create new group if one does not already exist
+            None => {
+                println!("**** A trace {:?} created a group{:?}",path.g.base_id, path.hash().to_string());
+                self.groups
+                    .insert(path.hash().to_string(), Group::new(path.clone()));
+                path.hash().to_string()
+            }
         }
     }
 
@@ -316,21 +608,26 @@ impl GroupManager {
     pub fn update(&mut self, paths: &Vec<CriticalPath>) {
         let mut updated_groups = Vec::new();
         for path in paths {
-            // Extract group corresponding to path (based on path hash) and add trace to that group
-            // Create new group if one does not already exist
-            match self.groups.get_mut(path.hash()) {
-                Some(v) => v.add_trace(&path),
-                None => {
-                    println!("**** A trace {:?} created a group{:?}",path.g.base_id, path.hash().to_string());
-                    self.groups
-                        .insert(path.hash().to_string(), Group::new(path.clone()));
-                }
-            }
-            updated_groups.push(path.hash().clone());
+            updated_groups.push(self.insert(path));
+        }
+        self.recalculate(updated_groups);
+    }
+
+    /// Like `update`, also adding the span timings of the full trace each path came from
+    pub fn update_with_traces(&mut self, traces: &[(Trace, CriticalPath)]) {
+        let mut updated_groups = Vec::new();
+        for (trace, path) in traces {
+            let hash = self.insert(path);
+            self.groups.get_mut(&hash).unwrap().add_span_timings(trace);
+            updated_groups.push(hash);
         }
+        self.recalculate(updated_groups);
+    }
+
+    fn recalculate(&mut self, updated_groups: Vec<String>) {
         for h in updated_groups {
-            self.groups.get_mut(h).unwrap().calculate_variance();
-            self.groups.get_mut(h).unwrap().calculate_mean();
+            self.groups.get_mut(&h).unwrap().calculate_variance();
+            self.groups.get_mut(&h).unwrap().calculate_mean();
         }
     }
 
@@ -399,6 +696,14 @@ impl GroupManager {
         sorted_groups
     }
 
+    /// Attributes that explain the latency variance of a group, best first
+    pub fn attribute_splits(&self, group: &str) -> Vec<AttributeSplit> {
+        match self.groups.get(group) {
+            Some(g) => g.attribute_splits(),
+            None => Vec::new(),
+        }
+    }
+
     /// Mark a group as "used": reset its performance data
     pub fn used(&mut self, group: &str) {
         self.groups.get_mut(group).unwrap().used();
@@ -434,3 +739,109 @@ impl Display for GroupManager {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::critical::CriticalPathAlgorithm;
+    use crate::testutil::{chain, span, span_trace};
+
+    /// The path of a chain of tracepoints, each reached the given milliseconds after the last
+    fn path(id: &str, tracepoints: &[(&str, i64)]) -> CriticalPath {
+        let mut elapsed = 0;
+        let steps: Vec<(&str, i64)> = tracepoints
+            .iter()
+            .map(|&(tp, ms)| {
+                elapsed += ms;
+                (tp, elapsed)
+            })
+            .collect();
+        CriticalPath::from_trace_with(&chain(id, &steps), CriticalPathAlgorithm::LatestPredecessor).unwrap()
+    }
+
+    #[test]
+    fn merges_paths_with_an_optional_tracepoint() {
+        let paths = vec![
+            path("t1", &[("api", 0), ("db", 10), ("reply", 20)]),
+            path("t2", &[("api", 0), ("db", 12), ("cache", 5), ("reply", 10)]),
+            path("t3", &[("api", 0), ("db", 11), ("reply", 30)]),
+        ];
+        let group_count = |mode| {
+            let mut manager = GroupManager::with_mode(mode);
+            manager.update(&paths);
+            manager.groups.len()
+        };
+        assert_eq!(group_count(GroupingMode::Exact), 2);
+        // Three of the four tracepoints are shared
+        assert_eq!(group_count(GroupingMode::Jaccard(0.9)), 2);
+        assert_eq!(group_count(GroupingMode::Jaccard(0.75)), 1);
+
+        let mut manager = GroupManager::with_mode(GroupingMode::EditDistance(0.8));
+        manager.update(&paths);
+        let groups = manager.all_groups();
+        assert_eq!(groups.len(), 1);
+        let group = groups[0];
+        assert_eq!(group.traces.len(), 3);
+        assert_eq!(group.hash(), paths[0].hash());
+        let nodes = group.nodes();
+        let durations = |from: usize| {
+            let edge = group.g.find_edge(nodes[from], nodes[from + 1]).unwrap();
+            group.g[edge].duration.iter().map(|d| d.as_millis()).collect::<Vec<_>>()
+        };
+        assert_eq!(durations(0), vec![10, 12, 11]);
+        // The optional tracepoint's time counts towards the edge it was inserted into
+        assert_eq!(durations(1), vec![20, 15, 30]);
+        assert!(GroupingMode::new("jaccard", 1.5).is_err());
+    }
+
+    #[test]
+    fn near_unique_attribute_does_not_win() {
+        // Reads on db host h2 are slow; the api host is almost unique per trace and happens to be
+        // shared by the two traces closest in latency
+        let traces = [
+            ("a1", "h1", 10),
+            ("a1", "h1", 11),
+            ("a2", "h1", 12),
+            ("a3", "h2", 30),
+            ("a4", "h2", 31),
+            ("a5", "h2", 29),
+        ];
+        let paths = traces
+            .iter()
+            .enumerate()
+            .map(|(idx, &(api_host, db_host, ms))| {
+                let mut trace = chain(&format!("t{}", idx), &[("api", 0), ("db", 1), ("reply", ms)]);
+                for nidx in trace.g.node_indices().collect::<Vec<_>>() {
+                    let host = match trace.g[nidx].tracepoint_id.to_string().as_str() {
+                        "api" => api_host,
+                        "db" => db_host,
+                        _ => continue,
+                    };
+                    trace.g[nidx].key_value_pair.insert("host".to_string(), Value::Str(host.to_string()));
+                }
+                CriticalPath::from_trace(&trace).unwrap()
+            })
+            .collect();
+        let groups = Group::from_critical_paths(paths, GroupingMode::Exact);
+        assert_eq!(groups.len(), 1);
+        let splits = groups[0].attribute_splits();
+        assert_eq!(splits[0].attribute, "db: host");
+        assert!(splits[0].explained > 0.9);
+        assert!(splits.iter().all(|s| s.attribute != "api: host"));
+    }
+
+    #[test]
+    fn updates_with_traces_keep_span_timings() {
+        let trace = span_trace(
+            "t1",
+            vec![span("a", "", "api", "h1", "get", 0, 20), span("b", "a", "db", "h2", "read", 5, 10)],
+        )
+        .to_trace();
+        let path = CriticalPath::from_trace_with(&trace, CriticalPathAlgorithm::LatestPredecessor).unwrap();
+        let mut manager = GroupManager::with_mode(GroupingMode::Exact);
+        manager.update(&vec![path.clone()]);
+        assert!(manager.all_groups()[0].span_times.is_empty());
+        manager.update_with_traces(&[(trace, path)]);
+        assert!(!manager.all_groups()[0].span_times.is_empty());
+    }
+}
@@ -44,7 +44,16 @@ All rights reserved.
 //! stage. Another way to run it is `cargo install --path .` and then use `pythia`. Some important ones:
 //! * `pythia get-trace <trace_id>` read a single trace and print the dot file
 //! * `pythia [enable|disable]-all` to enable/disable all tracepoints
+//! * `pythia export --format chrome|folded <folder|file|trace_id>...` convert traces for Perfetto
+//!   or chrome://tracing, or critical paths into folded stacks for flame graphs
+//! * `pythia what-if <folder> --edge <from> <to> --speedup 50` predict the latency of the group
+//!   with the highest variance if an edge was 50% faster
+//! * `pythia dot <trace_id> [--view crit] [--svg out.svg]` draw a trace or its critical path with
+//!   latencies as colors; `group-folder --dot <file>` draws the group with the highest variance
 //! * `pythia manifest-stats` construct a manifest and print all the stats used for the paper.
+//! * `pythia group-folder <folder> --filter '<expression>'` only use traces matching a filter
+//!   expression, see [`filter`](filter/index.html) for the syntax. `group-ids`, `manifest-folder`
+//!   and `dump-traces` take `--filter` too.
 //!
 //! # Running Pythia loop
 //! 1. Make sure everything is configured correctly, read the comments in the toml files
@@ -67,9 +76,12 @@ extern crate lazy_static;
 pub mod budget;
 pub mod controller;
 pub mod critical;
+pub mod diff;
 pub mod grouping;
 pub mod manifest;
 pub mod reader;
+pub mod render;
+pub mod receiver;
 pub mod rpclib;
 pub mod search;
 pub mod settings;
@@ -79,14 +91,27 @@ pub mod provenance;
 pub mod control_msg;
 pub mod classification;
 pub mod spantrace;
+pub mod encoding;
+pub mod export;
+pub mod filter;
+pub mod rules;
+pub mod skew;
+pub mod store;
+pub mod synthetic;
+pub mod timing;
+pub mod validate;
+pub mod whatif;
 
-use std::collections::HashSet;
+#[cfg(test)]
+mod testutil;
+
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::stdin;
 use std::io::{self, BufRead};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::Duration;
 use std::time::Instant;
@@ -97,13 +122,28 @@ use procinfo::pid::statm_self;
 use pythia_common::{OSPRequestType, RequestType};
 
 use crate::controller::controller_from_settings;
-use crate::critical::CriticalPath;
+use crate::critical::{CriticalPath, CriticalPathAlgorithm};
+use crate::critical::Path as _;
+use crate::encoding::StorageFormat;
+use crate::export::ExportFormat;
+use crate::filter::Filter;
 use crate::grouping::Group;
+use crate::manifest::HierarchicalCriticalPath;
 use crate::manifest::Manifest;
-use crate::reader::reader_from_settings;
+use crate::reader::{reader_from_settings, Reader};
+use crate::render::{Highlights, ToDot};
 use crate::settings::ApplicationType;
 use crate::settings::Settings;
+use crate::spantrace::SpanTrace;
+use crate::store::{Query, TraceStore};
+use crate::synthetic::{Topology, TraceGenerator};
 use crate::trace::{IDType, Trace};
+use crate::whatif::Speedup;
+
+/// Problem edges highlighted when drawing a group
+const HIGHLIGHTED_EDGES: usize = 5;
+/// Spans with less median slack than this are reported as near-critical
+const NEAR_CRITICAL_SLACK: Duration = Duration::from_millis(10);
 
 // use rand::seq::SliceRandom;
 // use crate::cct::CCT;
@@ -280,16 +320,20 @@ pub fn manifest_stats(manfile: &str) {
         let elapsed = now.elapsed();
         println!("Overwriting manifest file");
         let manifest_file = settings.manifest_file;
-        manifest.to_file(manifest_file.as_path());
+        if let Err(e) = manifest.to_file(manifest_file.as_path(), settings.storage_format) {
+            eprintln!("Could not write {}: {}", manifest_file.display(), e);
+            return;
+        }
         // let prev_stats = statm_self().unwrap();
         let manifest = Manifest::from_file(manifest_file.as_path())
             .expect("Couldn't read manifest from cache");
         // let after_stats = statm_self().unwrap();
+        let algorithm = settings.critical_path_algorithm;
         let critical_paths = traces
             .iter()
-            .filter_map(|t| CriticalPath::from_trace(t).ok())
+            .filter_map(|t| CriticalPath::from_trace_with(t, algorithm).ok())
             .collect::<Vec<CriticalPath>>();
-        let groups = Group::from_critical_paths(critical_paths);
+        let groups = Group::from_critical_paths(critical_paths, settings.grouping_mode);
 
         // Start outputting stats
         eprintln!(
@@ -406,18 +450,21 @@ pub fn show_manifest(request_type: &str) {
     );
 }
 
-pub fn dump_traces(tracefile: &str) {
+pub fn dump_traces(tracefile: &str, filter: Option<&Filter>) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
-    for trace in reader.read_trace_file(tracefile) {
+    let traces = filter_traces(reader.read_trace_file(tracefile), filter);
+    for trace in traces {
         let mut outfile = dirs::home_dir().unwrap();
         match &trace.base_id {
             IDType::UUID(u) => outfile.push(u.hyphenated().to_string()),
             IDType::STRING(s) => outfile.push(s),
         }
-        outfile.set_extension("json");
+        outfile.set_extension(settings.storage_format.extension());
         // let trace_copy = trace.clone();
-        trace.to_file(&outfile);
+        if let Err(e) = trace.to_file(&outfile, settings.storage_format) {
+            eprintln!("Could not write {}: {}", outfile.display(), e);
+        }
     }
 }
 
@@ -431,24 +478,26 @@ pub fn get_manifest(manfile: &str, overwrite: bool) {
             trace.prune();
         }
     }
-    manifest_from_traces(&traces, overwrite, &settings.manifest_file);
+    manifest_from_traces(&traces, overwrite, &settings);
 }
 
-pub fn manifest_from_folder(trace_folder: &str) {
+pub fn manifest_from_folder(trace_folder: &str, filter: Option<&Filter>) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
     reader.for_searchspace();
-    let mut traces = reader.read_dir(trace_folder);
+    let traces = reader.read_dir(trace_folder);
     println!("Read {} traces", traces.len());
+    let mut traces = filter_traces(traces, filter);
     if settings.application == ApplicationType::HDFS {
         for trace in &mut traces {
             trace.prune();
         }
     }
-    manifest_from_traces(&traces, false, &settings.manifest_file);
+    manifest_from_traces(&traces, false, &settings);
 }
 
-fn manifest_from_traces(traces: &Vec<Trace>, overwrite: bool, manifest_file: &PathBuf) {
+fn manifest_from_traces(traces: &Vec<Trace>, overwrite: bool, settings: &Settings) {
+    let manifest_file = &settings.manifest_file;
     let now = Instant::now();
     let manifest = Manifest::from_trace_list(&traces);
     let elapsed = now.elapsed();
@@ -467,7 +516,10 @@ fn manifest_from_traces(traces: &Vec<Trace>, overwrite: bool, manifest_file: &Pa
         }
         println!("Overwriting");
     }
-    manifest.to_file(manifest_file.as_path());
+    if let Err(e) = manifest.to_file(manifest_file.as_path(), settings.storage_format) {
+        eprintln!("Could not write {}: {}", manifest_file.display(), e);
+        return;
+    }
     eprintln!("Manifest construction took {:?}", elapsed);
 }
 
@@ -482,16 +534,29 @@ pub fn measure_search_space_feasibility(trace_file: &str) {
     println!("{}", Manifest::from_trace_list(&vec![trace]));
 }
 
-pub fn group_folder(trace_folder: &str) {
+/// Keeps the traces matching the filter, if there is one
+fn filter_traces(traces: Vec<Trace>, filter: Option<&Filter>) -> Vec<Trace> {
+    match filter {
+        Some(filter) => {
+            let total = traces.len();
+            let kept: Vec<Trace> = traces.into_iter().filter(|t| filter.matches_trace(t)).collect();
+            println!("{} of {} traces match {}", kept.len(), total, filter);
+            kept
+        }
+        None => traces,
+    }
+}
+
+pub fn group_folder(trace_folder: &str, filter: Option<&Filter>, dot_file: Option<&str>) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
     // println!(trace_folder);
     let traces = reader.read_dir(trace_folder);
     println!("Read {} traces", traces.len());
-    group_traces(traces);
+    group_traces(&settings, traces, filter, dot_file);
 }
 
-pub fn group_from_ids(id_file: &str) {
+pub fn group_from_ids(id_file: &str, filter: Option<&Filter>, dot_file: Option<&str>) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
     println!("{}",id_file);
@@ -502,16 +567,28 @@ pub fn group_from_ids(id_file: &str) {
         .map(|x| reader.get_trace_from_base_id(&x.unwrap()).unwrap())
         .collect::<Vec<_>>();
     println!("Read {} traces", traces.len());
-    group_traces(traces);
+    group_traces(&settings, traces, filter, dot_file);
 }
 
-fn group_traces(traces: Vec<Trace>) {
-    let critical_paths = traces
+/// Prints statistics of the groups the traces fall into. The group with the highest variance is
+/// drawn to `dot_file` (as SVG if the name ends with `.svg`) with its problem edges highlighted.
+fn group_traces(settings: &Settings, traces: Vec<Trace>, filter: Option<&Filter>, dot_file: Option<&str>) {
+    let traces = filter_traces(traces, filter);
+    let paths = traces
         .iter()
-        .filter_map(|t| CriticalPath::from_trace(t).ok())
-        .collect::<Vec<CriticalPath>>();
-    println!("Got {} paths", critical_paths.len());
-    let mut groups = Group::from_critical_paths(critical_paths);
+        .filter_map(|t| CriticalPath::from_trace_with(t, settings.critical_path_algorithm).ok().map(|p| (t, p)))
+        .collect::<Vec<(&Trace, CriticalPath)>>();
+    println!("Got {} paths", paths.len());
+    let traces_by_id: HashMap<IDType, &Trace> =
+        paths.iter().map(|(t, p)| (p.request_id.clone(), *t)).collect();
+    let critical_paths = paths.into_iter().map(|(_, p)| p).collect();
+    let mut groups = Group::from_critical_paths(critical_paths, settings.grouping_mode);
+    for group in groups.iter_mut() {
+        let ids: Vec<IDType> = group.traces.iter().map(|p| p.request_id.clone()).collect();
+        for id in ids {
+            group.add_span_timings(traces_by_id[&id]);
+        }
+    }
     println!("Got {} groups", groups.len());
     groups.sort_by(|a, b| b.traces.len().partial_cmp(&a.traces.len()).unwrap()); // descending order
     println!(
@@ -580,6 +657,39 @@ fn group_traces(traces: Vec<Trace>) {
             groups[0].g[endpoints.0], groups[0].g[endpoints.1], groups[0].g[*edge]
         );
     }
+    println!("\nSpan times of the group:\n{}", groups[0].span_times);
+    println!(
+        "Near-critical spans (median slack under {:?}):",
+        NEAR_CRITICAL_SLACK
+    );
+    for ((entry, exit), dist) in groups[0].span_times.near_critical(NEAR_CRITICAL_SLACK) {
+        println!(
+            "({} -> {}): slack p50 {:?}, critical in {:.0}% of traces",
+            entry,
+            exit,
+            timing::percentile(&dist.slack, 0.5),
+            dist.critical_ratio() * 100.0
+        );
+    }
+    if let Some(file) = dot_file {
+        let highlights = Highlights::new(
+            problem_edges
+                .iter()
+                .take(HIGHLIGHTED_EDGES)
+                .map(|&edge| {
+                    let (from, to) = groups[0].g.edge_endpoints(edge).unwrap();
+                    (groups[0].g[from].tracepoint_id, groups[0].g[to].tracepoint_id)
+                })
+                .collect(),
+        );
+        let dot = groups[0].to_dot(&highlights);
+        if file.ends_with(".svg") {
+            write_dot(&dot, Some(file));
+        } else {
+            std::fs::write(file, dot).unwrap();
+            eprintln!("Wrote {}", file);
+        }
+    }
 }
 
 pub fn read_trace_file(trace_file: &str) {
@@ -591,6 +701,14 @@ pub fn read_trace_file(trace_file: &str) {
         trace.prune();
     }
     println!("{}", trace);
+    for d in validate::validate_trace(&trace) {
+        eprintln!("{}", d);
+    }
+    validate::checked_critical_path(&trace, settings.critical_path_algorithm);
+    eprintln!("Trace quality: {}", validate::take_quality_stats());
+    for offset in skew::offset_estimates() {
+        eprintln!("Clock offset: {}", offset);
+    }
 }
 
 pub fn get_trace(trace_id: &str, to_file: bool, prune: bool) {
@@ -608,9 +726,11 @@ pub fn get_trace(trace_id: &str, to_file: bool, prune: bool) {
     if to_file {
         let mut tracefile = dirs::home_dir().unwrap();
         tracefile.push(trace_id);
-        tracefile.set_extension("json");
-        trace.to_file(tracefile.as_path());
-        eprintln!("Wrote trace to {}", tracefile.to_str().unwrap());
+        tracefile.set_extension(settings.storage_format.extension());
+        match trace.to_file(tracefile.as_path(), settings.storage_format) {
+            Ok(()) => eprintln!("Wrote trace to {}", tracefile.to_str().unwrap()),
+            Err(e) => eprintln!("Could not write {}: {}", tracefile.display(), e),
+        }
     }
 }
 
@@ -623,19 +743,368 @@ pub fn show_key_value_pairs(trace_id: &str) {
     println!("{:?}", trace_id);
 }
 
-pub fn get_crit(trace_id: &str) {
+/// Prints the critical path of a trace given as a trace file or a request ID, extracted with
+/// `algorithm` or else the one from the settings
+pub fn get_crit(source: &str, algorithm: Option<CriticalPathAlgorithm>) {
     let settings = Settings::read();
     let mut reader = reader_from_settings(&settings);
-    let trace = reader.get_trace_from_base_id(trace_id).unwrap();
-    let crit = CriticalPath::from_trace(&trace).unwrap();
+    let trace = load_trace(&mut reader, &settings, source);
+    let algorithm = algorithm.unwrap_or(settings.critical_path_algorithm);
+    let crit = CriticalPath::from_trace_with(&trace, algorithm).unwrap();
     println!("{}", crit.g);
 }
 
+/// Extracts the critical path of a trace with every algorithm and shows how the paths differ
+pub fn compare_crit(source: &str) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+    let trace = load_trace(&mut reader, &settings, source);
+    let mut paths = Vec::new();
+    for &algorithm in &[
+        CriticalPathAlgorithm::LatestPredecessor,
+        CriticalPathAlgorithm::LongestPath,
+    ] {
+        match CriticalPath::from_trace_with(&trace, algorithm) {
+            Ok(path) => {
+                let edge_sum: Duration = path.g.g.edge_weights().map(|e| e.duration).sum();
+                println!(
+                    "{}: {} events, duration {:?}, sum of edge durations {:?}, hash {}",
+                    algorithm.as_str(),
+                    path.g.g.node_count(),
+                    path.duration,
+                    edge_sum,
+                    path.hash()
+                );
+                paths.push(path);
+            }
+            Err(e) => println!("{}: failed with {}", algorithm.as_str(), e),
+        }
+    }
+    if let [left, right] = &paths[..] {
+        if left.hash() == right.hash() {
+            println!("Both algorithms found the same path");
+        } else {
+            print!("{}", diff::diff_paths(left, right));
+        }
+    }
+}
+
+/// Reads a trace given as a trace file or a request ID
+fn load_trace(reader: &mut Box<dyn Reader>, settings: &Settings, source: &str) -> Trace {
+    let mut trace = if Path::new(source).is_file() {
+        reader.read_file(source)
+    } else {
+        reader.get_trace_from_base_id(source).unwrap()
+    };
+    if settings.application == ApplicationType::HDFS {
+        trace.prune();
+    }
+    trace
+}
+
+/// Prints the structural diff of two traces, each given as a trace file or a request ID
+pub fn diff(left: &str, right: &str) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+    let left = load_trace(&mut reader, &settings, left);
+    let right = load_trace(&mut reader, &settings, right);
+    match diff::diff_traces(&left, &right, settings.critical_path_algorithm) {
+        Ok(d) => print!("{}", d),
+        Err(e) => eprintln!("Could not diff the traces: {}", e),
+    }
+}
+
+/// Reads the traces of each source, which is a trace folder, a trace file or a request ID
+fn load_sources(settings: &Settings, sources: &[&str], filter: Option<&Filter>) -> Vec<Trace> {
+    let mut reader = reader_from_settings(settings);
+    let mut traces = Vec::new();
+    for source in sources {
+        if Path::new(source).is_dir() {
+            traces.extend(reader.read_dir(source));
+        } else {
+            traces.push(load_trace(&mut reader, settings, source));
+        }
+    }
+    filter_traces(traces, filter)
+}
+
+/// Reads the span traces of Jaeger, Zipkin or OTLP files or folders. A filter is matched against
+/// the full trace of the spans.
+fn load_span_sources(settings: &Settings, sources: &[&str], filter: Option<&Filter>) -> Vec<SpanTrace> {
+    let mut reader = reader_from_settings(settings);
+    let mut traces = Vec::new();
+    for source in sources {
+        match reader.read_span_traces(source) {
+            Ok(t) => traces.extend(t),
+            Err(e) => eprintln!("Could not read span traces from {}: {}", source, e),
+        }
+    }
+    match filter {
+        Some(filter) => {
+            let total = traces.len();
+            traces.retain(|st| filter.matches_trace(&st.to_trace()));
+            println!("{} of {} traces match {}", traces.len(), total, filter);
+            traces
+        }
+        None => traces,
+    }
+}
+
+/// Exports traces for trace viewers. With `spans`, the sources are read as span traces and each
+/// span becomes one slice of the Chrome trace-event JSON.
+pub fn export(
+    sources: &[&str],
+    format: ExportFormat,
+    spans: bool,
+    output: Option<&str>,
+    filter: Option<&Filter>,
+) {
+    let settings = Settings::read();
+    let exported = match format {
+        ExportFormat::Chrome if spans => {
+            let traces = load_span_sources(&settings, sources, filter);
+            serde_json::to_string(&export::chrome_span_trace_events(&traces)).unwrap()
+        }
+        ExportFormat::Chrome => {
+            let traces = load_sources(&settings, sources, filter);
+            serde_json::to_string(&export::chrome_trace_events(&traces)).unwrap()
+        }
+        ExportFormat::Folded => {
+            let traces = load_sources(&settings, sources, filter);
+            let paths = traces
+                .iter()
+                .filter_map(|t| CriticalPath::from_trace_with(t, settings.critical_path_algorithm).ok())
+                .collect::<Vec<_>>();
+            eprintln!("Got {} paths from {} traces", paths.len(), traces.len());
+            export::folded_stacks(&paths)
+        }
+    };
+    match output {
+        Some(file) => {
+            std::fs::write(file, exported).unwrap();
+            eprintln!("Wrote {}", file);
+        }
+        None => print!("{}", exported),
+    }
+}
+
+/// Predicts the latency distribution of a group if some edges were faster. The group is the one
+/// whose hash starts with `group`, or the one with the highest variance.
+pub fn what_if(sources: &[&str], speedups: &[Speedup], group: Option<&str>, filter: Option<&Filter>) {
+    let settings = Settings::read();
+    let traces = load_sources(&settings, sources, filter);
+    let paths = traces
+        .iter()
+        .filter_map(|t| CriticalPath::from_trace_with(t, settings.critical_path_algorithm).ok())
+        .collect();
+    let mut groups = Group::from_critical_paths(paths, settings.grouping_mode);
+    groups.sort_by(|a, b| b.variance.partial_cmp(&a.variance).unwrap()); // descending order
+    let group = match group {
+        Some(prefix) => groups.iter().find(|g| g.hash().starts_with(prefix)),
+        None => groups.first(),
+    };
+    let group = match group {
+        Some(g) => g,
+        None => {
+            eprintln!("No matching group among {} groups", groups.len());
+            return;
+        }
+    };
+    println!("{}", group);
+    let traces_by_id: HashMap<&IDType, &Trace> = traces.iter().map(|t| (&t.base_id, t)).collect();
+    println!("{}", whatif::predict_group(group, |id| traces_by_id.get(id).copied(), speedups));
+}
+
+/// What `render_trace` draws of a trace
+pub enum RenderView {
+    Trace,
+    CriticalPath,
+    Hierarchical,
+}
+
+/// Prints the DOT graph of a trace given as a trace file or a request ID, or writes it as SVG
+pub fn render_trace(source: &str, view: RenderView, svg_file: Option<&str>) {
+    let settings = Settings::read();
+    let mut reader = reader_from_settings(&settings);
+    let trace = load_trace(&mut reader, &settings, source);
+    let highlights = Highlights::none();
+    let dot = match view {
+        RenderView::Trace => trace.to_dot(&highlights),
+        RenderView::CriticalPath => {
+            CriticalPath::from_trace_with(&trace, settings.critical_path_algorithm)
+                .unwrap()
+                .to_dot(&highlights)
+        }
+        RenderView::Hierarchical => HierarchicalCriticalPath::from_path(
+            &CriticalPath::from_trace_with(&trace, settings.critical_path_algorithm).unwrap(),
+        )
+        .to_dot(&highlights),
+    };
+    write_dot(&dot, svg_file);
+}
+
+/// Writes DOT text as SVG if a file is given, printing it otherwise
+fn write_dot(dot: &str, svg_file: Option<&str>) {
+    match svg_file {
+        Some(file) => match render::write_svg(dot, Path::new(file)) {
+            Ok(()) => eprintln!("Wrote {}", file),
+            Err(e) => {
+                eprintln!("{}, printing DOT instead", e);
+                print!("{}", dot);
+            }
+        },
+        None => print!("{}", dot),
+    }
+}
+
+/// Writes `count` traces generated from the topology to `out_dir`, one serialized Trace per
+/// file, and the faults injected into each of them to `ground_truth` if given
+pub fn generate_traces(topology_file: &str, out_dir: &str, count: usize, ground_truth: Option<&str>) {
+    let settings = Settings::read();
+    let topology = Topology::from_file(Path::new(topology_file)).unwrap();
+    let mut generator = TraceGenerator::new(topology, settings.application);
+    std::fs::create_dir_all(out_dir).unwrap();
+    let mut faults = HashMap::new();
+    let mut fault_counts: HashMap<String, usize> = HashMap::new();
+    for generated in generator.generate_n(count) {
+        let trace_id = generated.span_trace.req_id.clone();
+        let mut outfile = PathBuf::from(out_dir);
+        outfile.push(&trace_id);
+        outfile.set_extension("json");
+        if let Err(e) = generated.span_trace.to_trace().to_file(&outfile, StorageFormat::Json) {
+            eprintln!("Could not write {}: {}", outfile.display(), e);
+        }
+        for fault in &generated.faults {
+            *fault_counts.entry(fault.clone()).or_insert(0) += 1;
+        }
+        faults.insert(trace_id, generated.faults);
+    }
+    println!("Wrote {} traces to {}", count, out_dir);
+    println!("Traces per injected fault: {:?}", fault_counts);
+    if let Some(ground_truth) = ground_truth {
+        let writer = File::create(ground_truth).unwrap();
+        serde_json::to_writer(writer, &faults).unwrap();
+        println!("Wrote the injected faults to {}", ground_truth);
+    }
+}
+
 pub fn show_config() {
     let settings = Settings::read();
     println!("{:?}", settings);
 }
 
+/// Where `pythia store ingest` takes traces from
+pub enum StoreSource<'a> {
+    /// A trace file or a folder of them, in any format the reader understands
+    Path(&'a str),
+    /// A file with one request ID per line, fetched from the application
+    IdFile(&'a str),
+    /// Whatever the application reports as recent
+    Recent,
+}
+
+fn open_store(settings: &Settings) -> TraceStore {
+    TraceStore::open(&settings.store_dir).expect("Could not open the trace store")
+}
+
+fn store_query(settings: &Settings, request_type: Option<&str>, mut query: Query) -> Query {
+    query.request_type = request_type.map(|rt| {
+        RequestType::from_str(rt, settings.application.as_str()).unwrap()
+    });
+    query
+}
+
+/// Adds traces to the local trace store
+pub fn store_ingest(source: StoreSource) {
+    let settings = Settings::read();
+    if settings.read_from_store {
+        panic!("Ingesting into the trace store while reading from it, set read_from_store = \"false\"");
+    }
+    let mut reader = reader_from_settings(&settings);
+    let mut store = open_store(&settings);
+    let mut span_traces = Vec::new();
+    let traces = match source {
+        StoreSource::Path(path) => {
+            if Path::new(path).is_dir() {
+                reader.read_dir(path)
+            } else {
+                vec![reader.read_file(path)]
+            }
+        }
+        StoreSource::IdFile(file) => reader.read_trace_file(file),
+        StoreSource::Recent => {
+            span_traces = reader.get_recent_span_traces();
+            reader.get_recent_traces()
+        }
+    };
+    let mut stored = 0;
+    for trace in &traces {
+        match store.insert_trace(trace) {
+            Ok(_) => stored += 1,
+            Err(e) => eprintln!("Storing {} failed with {:?}", trace.base_id, e),
+        }
+    }
+    for trace in &span_traces {
+        match store.insert_span_trace(trace) {
+            Ok(_) => stored += 1,
+            Err(e) => eprintln!("Storing {} failed with {:?}", trace.req_id, e),
+        }
+    }
+    store.flush().unwrap();
+    println!("Stored {} traces, {} in the store", stored, store.len());
+}
+
+/// Lists the stored traces matching the query
+pub fn store_list(request_type: Option<&str>, query: Query) {
+    let settings = Settings::read();
+    let store = open_store(&settings);
+    let query = store_query(&settings, request_type, query);
+    let matches = store.query(&query);
+    for summary in &matches {
+        println!("{}", summary);
+    }
+    println!("{} of {} stored traces match", matches.len(), store.len());
+    for (rt, count) in store.request_types() {
+        eprintln!("{}: {} traces", rt, count);
+    }
+}
+
+/// Writes the stored traces matching the query to `out_dir`, one file per trace
+pub fn store_export(out_dir: &str, request_type: Option<&str>, query: Query) {
+    let settings = Settings::read();
+    let store = open_store(&settings);
+    let query = store_query(&settings, request_type, query);
+    std::fs::create_dir_all(out_dir).unwrap();
+    let matches = store.query(&query);
+    for summary in &matches {
+        // Escaped like the store's own files, so IDs with `/` or `..` stay inside out_dir
+        let mut outfile = PathBuf::from(out_dir);
+        outfile.push(store::file_name(&summary.id));
+        outfile.set_extension(settings.storage_format.extension());
+        let written = store
+            .get_trace(&summary.id)
+            .unwrap()
+            .to_file(&outfile, settings.storage_format);
+        if let Err(e) = written {
+            eprintln!("Could not write {}: {}", outfile.display(), e);
+        }
+    }
+    println!("Wrote {} traces to {}", matches.len(), out_dir);
+}
+
+/// Removes the stored traces matching the query
+pub fn store_prune(request_type: Option<&str>, query: Query) {
+    let settings = Settings::read();
+    let mut store = open_store(&settings);
+    let query = store_query(&settings, request_type, query);
+    if query.is_empty() {
+        panic!("Refusing to prune the whole store, give at least one condition");
+    }
+    let removed = store.prune(&query).unwrap();
+    store.flush().unwrap();
+    println!("Removed {} traces, {} left in the store", removed, store.len());
+}
+
 #[derive(Debug)]
 pub struct PythiaError(String);
 
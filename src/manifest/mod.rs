@@ -14,6 +14,7 @@ mod searchspace;
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
 use std::path::Path;
@@ -27,6 +28,8 @@ use serde::{Deserialize, Serialize};
 use pythia_common::{OSPRequestType, RequestType};
 use pythia_common::REQUEST_TYPE_REGEXES;
 
+use crate::encoding;
+use crate::encoding::StorageFormat;
 use crate::grouping::Group;
 use crate::manifest::searchspace::SearchSpace;
 use crate::trace::Trace;
@@ -37,10 +40,36 @@ pub use crate::manifest::searchspace::HierarchicalCriticalPath;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Manifest {
     // pub per_request_type: HashMap<OSPRequestType, SearchSpace>,
+    #[serde(with = "request_type_entries")]
     pub per_request_type: HashMap<RequestType, SearchSpace>,
     pub request_type_tracepoints: Vec<TracepointID>,
 }
 
+/// Request types are not plain strings, so they cannot be map keys in the serialized manifest;
+/// the search spaces are stored as a list of (request type, search space) pairs instead
+mod request_type_entries {
+    use std::collections::HashMap;
+
+    use pythia_common::RequestType;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::manifest::searchspace::SearchSpace;
+
+    pub fn serialize<S: Serializer>(
+        map: &HashMap<RequestType, SearchSpace>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<HashMap<RequestType, SearchSpace>, D::Error> {
+        let entries: Vec<(RequestType, SearchSpace)> = Vec::deserialize(d)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
 impl Manifest {
     /// Returns the set of trace points seen for each request type
     // pub fn get_per_request_types(&self) -> HashMap<OSPRequestType, HashSet<TracepointID>> {
@@ -156,14 +185,13 @@ impl Manifest {
         }
     }
 
-    pub fn to_file(&self, file: &Path) {
-        let writer = std::fs::File::create(file).unwrap();
-        serde_json::to_writer(writer, self).ok();
+    pub fn to_file(&self, file: &Path, format: StorageFormat) -> Result<(), Box<dyn Error>> {
+        encoding::write(self, file, format)
     }
 
+    /// Reads a manifest in either format, detected from the file
     pub fn from_file(file: &Path) -> Option<Manifest> {
-        let reader = std::fs::File::open(file).unwrap();
-        serde_json::from_reader(reader).unwrap()
+        encoding::read(file).unwrap()
     }
 
     /// This is where a skeleton is defined. Adding/removing things to skeleton and
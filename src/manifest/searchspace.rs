@@ -63,6 +63,10 @@ impl HierarchicalEdge {
             variant: EdgeType::HappensBefore,
         }
     }
+
+    pub fn is_hierarchical(&self) -> bool {
+        self.variant == EdgeType::Hierarchical
+    }
 }
 
 /// This is the search space described in the paper.
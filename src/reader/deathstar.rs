@@ -26,7 +26,7 @@ use petgraph::graph::NodeIndex;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use pythia_common::RequestType;
 
 use crate::reader::HexID;
@@ -169,8 +169,7 @@ impl Reader for DEATHSTARReader {
     }
 
     fn read_file(&mut self, file: &str) -> Trace {
-        let reader = std::fs::File::open(file).unwrap();
-        match serde_json::from_reader(reader) {
+        match Trace::from_file(Path::new(file)) {
             // We either have a saved file, or saved xtrace output
             Ok(v) => v,
             Err(_) => {
@@ -343,7 +342,7 @@ impl DEATHSTARReader {
                                 DAGEdge {
                                     duration: (mynode.timestamp - mydag.g[parent_nidx].timestamp)
                                         .to_std()
-                                        .unwrap(),
+                                        .unwrap_or_default(),
                                     variant: EdgeType::ChildOf,
                                     host: None,
                                     service: None
@@ -364,7 +363,7 @@ impl DEATHSTARReader {
                                         duration: (mynode.timestamp
                                             - mydag.g[parent_nidx].timestamp)
                                             .to_std()
-                                            .unwrap(),
+                                            .unwrap_or_default(),
                                         variant: EdgeType::ChildOf,
                                         host: None,
                                         service: None
@@ -378,9 +377,10 @@ impl DEATHSTARReader {
         }
         
         mydag.end_node = nidx;
+        crate::skew::correct_trace(&mut mydag);
         mydag.duration = (mydag.g[mydag.end_node].timestamp - mydag.g[mydag.start_node].timestamp)
             .to_std()
-            .unwrap();
+            .unwrap_or_default();
         mydag
     }
 }
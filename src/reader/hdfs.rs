@@ -10,6 +10,7 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::convert::TryInto;
 use std::error::Error;
+use std::path::Path;
 use std::time::Duration;
 
 use byteorder::BigEndian;
@@ -136,8 +137,7 @@ impl Reader for HDFSReader {
     }
 
     fn read_file(&mut self, file: &str) -> Trace {
-        let reader = std::fs::File::open(file).unwrap();
-        match serde_json::from_reader(reader) {
+        match Trace::from_file(Path::new(file)) {
             // We either have a saved file, or saved xtrace output
             Ok(v) => v,
             Err(_) => {
@@ -311,7 +311,7 @@ impl HDFSReader {
                                 DAGEdge {
                                     duration: (mynode.timestamp - mydag.g[parent_nidx].timestamp)
                                         .to_std()
-                                        .unwrap(),
+                                        .unwrap_or_default(),
                                     variant: EdgeType::ChildOf,
                                     host: None,
                                     service: None
@@ -332,7 +332,7 @@ impl HDFSReader {
                                         duration: (mynode.timestamp
                                             - mydag.g[parent_nidx].timestamp)
                                             .to_std()
-                                            .unwrap(),
+                                            .unwrap_or_default(),
                                         variant: EdgeType::ChildOf,
                                         host: None,
                                         service: None
@@ -345,9 +345,10 @@ impl HDFSReader {
             }
         }
         mydag.end_node = nidx;
+        crate::skew::correct_trace(&mut mydag);
         mydag.duration = (mydag.g[mydag.end_node].timestamp - mydag.g[mydag.start_node].timestamp)
             .to_std()
-            .unwrap();
+            .unwrap_or_default();
         mydag
     }
 }
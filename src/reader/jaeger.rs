@@ -8,7 +8,6 @@ All rights reserved.
 
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::{fs, vec};
 use std::slice::SplitN;
 use std::time::{Duration, SystemTime};
 use chrono::{DateTime, NaiveDate, NaiveDateTime};
@@ -17,11 +16,12 @@ use hyper::http;
 use itertools::Itertools;
 use pythia_common::jaeger::JaegerRequestType;
 use pythia_common::RequestType;
-use crate::reader::Reader;
-use crate::{Settings, Trace};
-use crate::spantrace::{Span, SpanCache, SpanTrace};
+use crate::reader::{ParsedSpanTraces, Reader, TraceFileReader};
+use crate::settings::ApplicationType;
+use crate::{PythiaError, Settings, Trace};
+use crate::spantrace::{Span, SpanCache, SpanLog, SpanReference, SpanTrace};
 use serde::{Serialize, Deserialize};
-use crate::trace::Event;
+use crate::trace::{EdgeType, Value};
 use url::form_urlencoded;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,6 +41,17 @@ struct JaegerSpan {
     duration: i64,
     references: Vec<JaegerReference>,
     processID: String,
+    #[serde(default)]
+    tags: Vec<JPTag>,
+    #[serde(default)]
+    logs: Vec<JaegerLog>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JaegerLog {
+    /// Epoch microseconds
+    timestamp: i64,
+    fields: Vec<JPTag>,
 }
 
 impl JaegerSpan {
@@ -49,7 +60,7 @@ impl JaegerSpan {
         let mut host_name = "".to_string();
         for tag in process_tags {
             if tag.key == "hostname".to_string() {
-                host_name = tag.value.clone();
+                host_name = tag.value.as_str().unwrap_or_default().to_string();
             }
         }
         return Span{
@@ -58,20 +69,60 @@ impl JaegerSpan {
                 0 => "".to_string(),
                 _ => self.references[0].spanID.clone()
             },
+            references: self.references.iter().map(|r| SpanReference {
+                span_id: r.spanID.clone(),
+                ref_type: match r.refType.as_str() {
+                    "FOLLOWS_FROM" => EdgeType::FollowsFrom,
+                    _ => EdgeType::ChildOf,
+                },
+            }).collect(),
             service: processes.get(self.processID.as_str()).unwrap().serviceName.clone(),
             host: host_name,
             operation: self.operationName.clone(),
             start: DateTime::from_timestamp_nanos(
                 self.startTime*1000).naive_utc(),
-            duration: Duration::from_micros(self.duration as u64)
+            duration: Duration::from_micros(self.duration as u64),
+            kind: None,
+            tags: tags_to_map(&self.tags),
+            logs: self.logs.iter().map(|l| SpanLog {
+                timestamp: DateTime::from_timestamp_nanos(l.timestamp*1000).naive_utc(),
+                fields: tags_to_map(&l.fields),
+            }).collect(),
         }
     }
 }
 
+/// A key/value pair of a span, log or process; `value` is typed according to `type`
 #[derive(Debug, Serialize, Deserialize)]
 struct JPTag {
     key: String,
-    value: String,
+    #[serde(default, rename = "type")]
+    value_type: String,
+    #[serde(default)]
+    value: serde_json::Value,
+}
+
+impl JPTag {
+    /// Converts the value to the variant of its declared type. Exports may encode 64-bit
+    /// integers, floats and booleans as strings; values that do not parse as their type, and tags
+    /// without a type, are converted from their JSON form.
+    fn to_value(&self) -> Value {
+        let typed = match (self.value_type.as_str(), &self.value) {
+            ("string", serde_json::Value::String(s)) => Some(Value::Str(s.clone())),
+            ("string", v) => Some(Value::Str(v.to_string())),
+            ("int64", serde_json::Value::String(s)) => s.parse().ok().map(Value::SignedInt),
+            ("int64", v) => v.as_i64().map(Value::SignedInt),
+            ("float64", serde_json::Value::String(s)) => s.parse().ok().map(Value::Float),
+            ("float64", v) => v.as_f64().map(Value::Float),
+            ("bool", serde_json::Value::String(s)) => s.parse().ok().map(Value::Bool),
+            _ => None,
+        };
+        typed.unwrap_or_else(|| Value::from_json(&self.value))
+    }
+}
+
+fn tags_to_map(tags: &[JPTag]) -> HashMap<String, Value> {
+    tags.iter().map(|t| (t.key.clone(), t.to_value())).collect()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -89,21 +140,18 @@ struct JaegerTrace {
 
 impl JaegerTrace {
     pub fn to_trace(&self, cache: &mut SpanCache) -> Result<SpanTrace, String> {
-        let spans: Vec<Span> = self.spans.iter().map(|span| span.to_span(&self.processes)).collect();
+        let mut spans: Vec<Span> = self.spans.iter().map(|span| span.to_span(&self.processes)).collect();
         // if self.spans.len() == 0 {
         //     println!("{}", self.traceID);
         // }
         // let root_span: &JaegerSpan = self.spans.iter().filter(|&span| span.traceID == span.spanID).collect::<Vec<_>>()[0];
-        let root_span_list: Vec<&JaegerSpan> = self.spans.iter()
-            .filter(|&span| span.references.len() == 0).collect::<Vec<_>>();
-
-        if root_span_list.len() == 0 {
-            // println!("TRACE WITH NO ROOT:");
-            // println!("{:?}", self.spans);
-            println!("Could not find a root span!");
-
-            return Err("Could not find a root span!".to_string());
-        }
+        let root_span = match SpanTrace::resolve_references(&mut spans) {
+            Some(idx) => spans[idx].clone(),
+            None => {
+                println!("Could not find a root span!");
+                return Err("Could not find a root span!".to_string());
+            }
+        };
         // let mut span_parents: HashMap<String, String> = HashMap::new();
         // for span in &self.spans {
         //     span_parents.insert(span.spanID, span.)
@@ -122,17 +170,12 @@ impl JaegerTrace {
         //     children: Default::default()
         // }
 
-        for span in &spans {
-            cache.add_span(span.clone(), self.traceID.clone())
-        }
-
-        let root_span = root_span_list[0];
-
-        Ok(SpanTrace::from_span_list(
-            spans, self.processes.get(
-                root_span.processID.as_str()).unwrap().serviceName.clone() + ":" +
-                root_span.operationName.as_str(),
-            root_span.spanID.clone(), self.traceID.clone()))
+        // Cache the spans after from_span_list has corrected their clock skew
+        let trace = SpanTrace::from_span_list(
+            spans, root_span.service.clone() + ":" + root_span.operation.as_str(),
+            root_span.span_id.clone(), self.traceID.clone(), &ApplicationType::Jaeger);
+        cache.add_trace(&trace);
+        Ok(trace)
     }
 }
 
@@ -154,11 +197,15 @@ pub struct JaegerReader {
 
 impl Reader for JaegerReader {
     fn read_file(&mut self, filename: &str) -> Trace {
-        todo!()
+        self.read_first_trace(filename)
     }
 
     fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
-        todo!()
+        self.read_every_file(foldername)
+    }
+
+    fn read_span_traces(&mut self, path: &str) -> Result<Vec<SpanTrace>, Box<dyn Error>> {
+        self.read_span_trace_files(path)
     }
 
     fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
@@ -304,13 +351,17 @@ impl Reader for JaegerReader {
     fn all_operations(&mut self) -> Vec<RequestType> {
         let mut to_set_types = HashSet::new();
 
-        let resp: reqwest::blocking::Response =
-            reqwest::blocking::get(
-                format!("{}/api/services", self.fetch_url)).unwrap();
-
-        let resp_obj: JaegerServicesPayload =
-            serde_json::from_str(
-                (resp.text().unwrap() as String).as_str()).unwrap();
+        // Settings::read() calls this, so a missing query service must not be fatal: reading
+        // Jaeger dumps from disk doesn't need one
+        let resp_obj: JaegerServicesPayload = match reqwest::blocking::get(
+            format!("{}/api/services", self.fetch_url))
+            .and_then(|resp| resp.json()) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Failed to get Jaeger services: {:?}", e);
+                return Vec::new();
+            }
+        };
 
         // println!("Services:");
         // println!("{:?}", resp_obj.data.clone());
@@ -462,4 +513,80 @@ impl JaegerReader {
             })
             .map(|st_ok| st_ok.unwrap()).collect()
     }
-}
\ No newline at end of file
+}
+
+impl TraceFileReader for JaegerReader {
+    /// Parses a Jaeger export (`{"data": [...]}`, as returned by the query service and the
+    /// Jaeger UI's "Download JSON")
+    fn parse_span_traces(&mut self, contents: &str) -> Result<ParsedSpanTraces, Box<dyn Error>> {
+        let payload: JaegerPayload = serde_json::from_str(contents)?;
+        Ok(payload
+            .data
+            .iter()
+            .map(|jt| jt.to_trace(&mut self.span_cache).map_err(|e| PythiaError(e).into()))
+            .collect())
+    }
+
+    fn reads_for_searchspace(&self) -> bool {
+        self.for_searchspace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_reader() -> JaegerReader {
+        JaegerReader {
+            fetch_url: String::new(),
+            problem_type: RequestType::Unknown,
+            fetch_all: false,
+            for_searchspace: false,
+            cycle_lookback: 0,
+            span_cache: SpanCache::init_cache(),
+        }
+    }
+
+    #[test]
+    fn reads_the_shipped_exports() {
+        let mut reader = file_reader();
+        let base = reader.read_file("test/test_trace_base_case.json");
+        let nested = reader.read_file("test/test_trace_nested_concurrent_case.json");
+        assert!(nested.g.node_count() > base.g.node_count());
+        assert_eq!(base.base_id.to_string(), "A_SPAN");
+        assert_eq!(
+            base.request_type,
+            RequestType::from_str("SERVICE_A:A_SPAN_START", "Jaeger").unwrap()
+        );
+
+        let traces = reader.read_dir("test");
+        assert_eq!(traces.len(), 6);
+        assert!(traces.iter().any(|t| t.g.node_count() == nested.g.node_count()));
+    }
+
+    #[test]
+    fn converts_tags_by_their_type() {
+        let tags: Vec<JPTag> = serde_json::from_str(
+            r#"[
+                {"key": "count", "type": "int64", "value": 7},
+                {"key": "big", "type": "int64", "value": "-9007199254740993"},
+                {"key": "ratio", "type": "float64", "value": 2},
+                {"key": "load", "type": "float64", "value": "0.5"},
+                {"key": "error", "type": "bool", "value": "true"},
+                {"key": "port", "type": "string", "value": 8080},
+                {"key": "odd", "type": "int64", "value": "many"},
+                {"key": "untyped", "value": 3}
+            ]"#,
+        )
+        .unwrap();
+        let map = tags_to_map(&tags);
+        assert_eq!(map["count"], Value::SignedInt(7));
+        assert_eq!(map["big"], Value::SignedInt(-9007199254740993));
+        assert_eq!(map["ratio"], Value::Float(2.0));
+        assert_eq!(map["load"], Value::Float(0.5));
+        assert_eq!(map["error"], Value::Bool(true));
+        assert_eq!(map["port"], Value::Str("8080".to_string()));
+        assert_eq!(map["odd"], Value::Str("many".to_string()));
+        assert_eq!(map["untyped"], Value::UnsignedInt(3));
+    }
+}
@@ -15,9 +15,13 @@ mod uber;
 mod zipkin;
 mod jaeger;
 mod skywalking;
+pub(crate) mod otlp;
+mod replay;
+mod store;
 
 use std::error::Error;
 use std::fmt;
+use std::path::Path;
 
 use hex;
 use itertools::Itertools;
@@ -26,17 +30,23 @@ use serde::de;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::encoding;
 use crate::reader::hdfs::HDFSReader;
 use crate::reader::deathstar::DEATHSTARReader;
 use crate::reader::jaeger::JaegerReader;
 use crate::reader::osprofiler::OSProfilerReader;
+use crate::reader::otlp::OTLPReader;
+use crate::reader::replay::{RecordingReader, ReplayReader};
 use crate::reader::skywalking::SWReader;
+use crate::reader::store::StoreReader;
 use crate::reader::uber::UberReader;
 use crate::reader::zipkin::ZipkinReader;
 use crate::settings::ApplicationType;
 use crate::settings::Settings;
 use crate::spantrace::SpanTrace;
+use crate::synthetic::SyntheticReader;
 use crate::trace::{Event, Trace};
+use crate::PythiaError;
 
 pub trait Reader {
     /// The file can contain a trace json, written by serde or by the tracing
@@ -87,15 +97,141 @@ pub trait Reader {
 
     fn set_fetch_all(&mut self);
 
+    /// Reads the span traces of a file or folder in the format of a span-based tracing backend
+    fn read_span_traces(&mut self, path: &str) -> Result<Vec<SpanTrace>, Box<dyn Error>> {
+        Err(Box::new(PythiaError(format!(
+            "Span traces cannot be read from {} for this application",
+            path
+        ))))
+    }
+
     // Returns pairs of (traceID, spanID) for overlapping spans of a target
     fn get_candidate_events(&self, start: u64, end: u64, host: String) -> Vec<(String, String)> {
         Vec::new()
     }
 }
 
+/// The span traces of a file, each of which may have failed to build
+pub(crate) type ParsedSpanTraces = Vec<Result<SpanTrace, Box<dyn Error>>>;
+
+/// Readers of tracing backends whose files, and so folders, can hold several traces
+pub(crate) trait TraceFileReader {
+    /// Builds the span traces in the text of a file in the format of the tracing backend. Traces
+    /// that could not be built are kept as errors so that the others can still be used.
+    fn parse_span_traces(&mut self, contents: &str) -> Result<ParsedSpanTraces, Box<dyn Error>>;
+
+    /// Whether `Reader::for_searchspace` was called
+    fn reads_for_searchspace(&self) -> bool;
+
+    /// Reads every trace in the file, which is either a Trace saved by Pythia or in the format
+    /// of the tracing backend
+    fn try_read_file(&mut self, filename: &str) -> Result<Vec<Trace>, Box<dyn Error>> {
+        let contents = match TraceFile::open(filename)? {
+            TraceFile::Saved(t) => return Ok(vec![t]),
+            TraceFile::Text(contents) => contents,
+        };
+        let for_searchspace = self.reads_for_searchspace();
+        Ok(usable_span_traces(self.parse_span_traces(&contents)?)
+            .iter()
+            .map(|st| st.to_reader_trace(for_searchspace))
+            .collect())
+    }
+
+    fn read_first_trace(&mut self, filename: &str) -> Trace {
+        let mut traces = self.try_read_file(filename).unwrap();
+        assert!(!traces.is_empty(), "No traces in {}", filename);
+        traces.remove(0)
+    }
+
+    fn read_every_file(&mut self, foldername: &str) -> Vec<Trace> {
+        let mut results = Vec::new();
+        for entry in std::fs::read_dir(foldername).unwrap() {
+            let path = entry.unwrap().path();
+            eprintln!("Reading {}", path.to_str().unwrap());
+            match self.try_read_file(path.to_str().unwrap()) {
+                Ok(mut t) => results.append(&mut t),
+                Err(e) => {
+                    eprintln!("Parsing failed with {:?}", e);
+                }
+            }
+        }
+        results
+    }
+
+    /// Reads the span traces of a file in the format of the tracing backend, or of every such
+    /// file of a folder; files of a folder that cannot be parsed are skipped
+    fn read_span_trace_files(&mut self, path: &str) -> Result<Vec<SpanTrace>, Box<dyn Error>> {
+        if !Path::new(path).is_dir() {
+            let contents = std::fs::read_to_string(path)?;
+            return Ok(usable_span_traces(self.parse_span_traces(&contents)?));
+        }
+        let mut results = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let file = entry?.path();
+            eprintln!("Reading {}", file.to_str().unwrap());
+            match self.read_span_trace_files(file.to_str().unwrap()) {
+                Ok(mut t) => results.append(&mut t),
+                Err(e) => {
+                    eprintln!("Parsing failed with {:?}", e);
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// The parsing of readers without a tracing backend of their own, which can only read files
+/// with a Trace saved by Pythia
+pub(crate) fn saved_traces_only() -> Result<ParsedSpanTraces, Box<dyn Error>> {
+    Err(Box::new(PythiaError("Only traces saved by Pythia can be read".to_string())))
+}
+
+/// The contents of a trace file
+pub(crate) enum TraceFile {
+    /// A Trace saved by Pythia, in either storage format
+    Saved(Trace),
+    /// Text for the reader to parse in the format of its tracing backend
+    Text(String),
+}
+
+impl TraceFile {
+    pub(crate) fn open(filename: &str) -> Result<TraceFile, Box<dyn Error>> {
+        if encoding::is_binary(Path::new(filename)) {
+            return Ok(TraceFile::Saved(Trace::from_file(Path::new(filename))?));
+        }
+        let contents = std::fs::read_to_string(filename)?;
+        match serde_json::from_str::<Trace>(&contents) {
+            Ok(t) => Ok(TraceFile::Saved(t)),
+            Err(_) => Ok(TraceFile::Text(contents)),
+        }
+    }
+}
+
+/// Keeps the span traces that could be built from a file, skipping the others so that one bad
+/// trace does not cost the rest
+fn usable_span_traces(span_traces: ParsedSpanTraces) -> Vec<SpanTrace> {
+    span_traces
+        .into_iter()
+        .filter_map(|st| match st {
+            Ok(st) => Some(st),
+            Err(e) => {
+                eprintln!("Skipping trace: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
 /// Constructor for Reader
 pub fn reader_from_settings(settings: &Settings) -> Box<dyn Reader> {
-    match &settings.application {
+    if let Some(archive) = &settings.replay_file {
+        return Box::new(ReplayReader::from_file(archive));
+    }
+    if settings.read_from_store {
+        return Box::new(StoreReader::open(&settings.store_dir));
+    }
+    let reader: Box<dyn Reader> = match &settings.application {
+        _ if settings.synthetic_topology.is_some() => Box::new(SyntheticReader::from_settings(settings)),
         ApplicationType::OpenStack => Box::new(OSProfilerReader::from_settings(settings)),
         ApplicationType::HDFS => Box::new(HDFSReader::from_settings(settings)),
         ApplicationType::DEATHSTAR => Box::new(DEATHSTARReader::from_settings(settings)),
@@ -103,6 +239,11 @@ pub fn reader_from_settings(settings: &Settings) -> Box<dyn Reader> {
         ApplicationType::Zipkin => Box::new(ZipkinReader::from_settings(settings)),
         ApplicationType::Jaeger => Box::new(JaegerReader::from_settings(settings)),
         ApplicationType::SkyWalking => Box::new(SWReader::from_settings(settings)),
+        ApplicationType::OTLP => Box::new(OTLPReader::from_settings(settings)),
+    };
+    match &settings.record_file {
+        Some(archive) => Box::new(RecordingReader::new(reader, archive)),
+        None => reader,
     }
 }
 
@@ -12,6 +12,7 @@ use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
+use std::path::Path;
 use std::time::Duration;
 
 use petgraph::graph::NodeIndex;
@@ -28,6 +29,7 @@ use pythia_common::REQUEST_TYPES;
 use pythia_common::REQUEST_TYPE_REGEXES;
 
 use crate::critical::CriticalPath;
+use crate::encoding;
 use crate::reader::Reader;
 
 use crate::rpclib::free_keys;
@@ -180,6 +182,9 @@ impl Reader for OSProfilerReader {
     }
 
     fn read_file(&mut self, file: &str) -> Trace {
+        if encoding::is_binary(Path::new(file)) {
+            return Trace::from_file(Path::new(file)).unwrap();
+        }
         let reader = std::fs::File::open(file).unwrap();
         let t: Vec<OSProfilerSpan> = serde_json::from_reader(reader).unwrap();
         self.from_event_list(Uuid::nil(), t).unwrap()
@@ -295,7 +300,7 @@ impl Reader for OSProfilerReader {
 
     fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
         eprintln!("Working on {}", id);
-        let mut result = match Uuid::parse_str(id) {
+        let result = match Uuid::parse_str(id) {
             Ok(uuid) => {
                 let event_list = self.get_all_matches(&uuid);
                 if event_list.len() == 0 {
@@ -314,10 +319,6 @@ impl Reader for OSProfilerReader {
         if result.request_type == RequestType::Unknown {
             eprintln!("Warning: couldn't get type for request {}", id);
         }
-        result.duration = (result.g[result.end_node].timestamp
-            - result.g[result.start_node].timestamp)
-            .to_std()
-            .unwrap();
         Ok(result)
     }
 }
@@ -354,6 +355,11 @@ impl OSProfilerReader {
     ) -> Result<Trace, Box<dyn Error>> {
         let mut mydag = Trace::new(&IDType::UUID(id));
         self.add_events(&mut mydag, &mut event_list, None)?;
+        crate::skew::correct_trace(&mut mydag);
+        mydag.duration = (mydag.g[mydag.end_node].timestamp
+            - mydag.g[mydag.start_node].timestamp)
+            .to_std()
+            .unwrap_or_default();
         Ok(mydag)
     }
 
@@ -478,7 +484,7 @@ impl OSProfilerReader {
                     DAGEdge {
                         duration: (event.timestamp - dag.g[parent_of_trace.unwrap()].timestamp)
                             .to_std()
-                            .unwrap(),
+                            .unwrap_or_default(),
                         variant: EdgeType::FollowsFrom,
                         host: None,
                         service: None
@@ -508,7 +514,7 @@ impl OSProfilerReader {
                                         duration: (event.timestamp
                                             - dag.g[*sibling_node].timestamp)
                                             .to_std()
-                                            .unwrap(),
+                                            .unwrap_or_default(),
                                         variant: EdgeType::ChildOf,
                                         host: None,
                                         service: None
@@ -529,7 +535,7 @@ impl OSProfilerReader {
                                             duration: (event.timestamp
                                                 - dag.g[*sibling_node].timestamp)
                                                 .to_std()
-                                                .unwrap(),
+                                                .unwrap_or_default(),
                                             variant: EdgeType::ChildOf,
                                             host: None,
                                             service: None
@@ -545,7 +551,7 @@ impl OSProfilerReader {
                                             duration: (event.timestamp
                                                 - dag.g[*parent_node].timestamp)
                                                 .to_std()
-                                                .unwrap(),
+                                                .unwrap_or_default(),
                                             variant: EdgeType::ChildOf,
                                             host: None,
                                             service: None
@@ -602,7 +608,7 @@ impl OSProfilerReader {
                                         duration: (event.timestamp
                                             - dag.g[*sibling_node].timestamp)
                                             .to_std()
-                                            .unwrap(),
+                                            .unwrap_or_default(),
                                         variant: EdgeType::ChildOf,
                                         host: None,
                                         service: None
@@ -621,7 +627,7 @@ impl OSProfilerReader {
                                             duration: (event.timestamp
                                                 - dag.g[*parent_node].timestamp)
                                                 .to_std()
-                                                .unwrap(),
+                                                .unwrap_or_default(),
                                             variant: EdgeType::ChildOf,
                                             host: None,
                                             service: None
@@ -646,7 +652,7 @@ impl OSProfilerReader {
                                     DAGEdge {
                                         duration: (event.timestamp - dag.g[*child_node].timestamp)
                                             .to_std()
-                                            .unwrap(),
+                                            .unwrap_or_default(),
                                         variant: EdgeType::ChildOf,
                                         host: None,
                                         service: None
@@ -660,7 +666,7 @@ impl OSProfilerReader {
                                     DAGEdge {
                                         duration: (event.timestamp - dag.g[start_span].timestamp)
                                             .to_std()
-                                            .unwrap(),
+                                            .unwrap_or_default(),
                                         variant: EdgeType::ChildOf,
                                         host: None,
                                         service: None
@@ -692,7 +698,7 @@ impl OSProfilerReader {
                         DAGEdge {
                             duration: (dag.g[**parent].timestamp - dag.g[last_node].timestamp)
                                 .to_std()
-                                .unwrap(),
+                                .unwrap_or_default(),
                             variant: EdgeType::FollowsFrom,
                             host: None,
                             service: None
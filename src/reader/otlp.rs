@@ -0,0 +1,727 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Reader for OpenTelemetry OTLP/JSON trace exports.
+//!
+//! Accepts a single `ExportTraceServiceRequest` (`{"resourceSpans": [...]}`) per file, or the
+//! JSON-lines output of the collector's file exporter (one request per line). Spans are grouped
+//! by trace ID and converted into `SpanTrace`s, which go through `SpanTrace::to_critical_path`.
+//! The protobuf encoding is also understood, for the OTLP/HTTP receiver.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+
+use chrono::DateTime;
+use prost::Message;
+use pythia_common::RequestType;
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+
+use crate::reader::{ParsedSpanTraces, Reader, TraceFileReader};
+use crate::settings::ApplicationType;
+use crate::spantrace::{Span, SpanCache, SpanLog, SpanReference, SpanTrace};
+use crate::trace::{EdgeType, Value as AttributeValue};
+use crate::{PythiaError, Settings, Trace};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OTLPKeyValue {
+    key: String,
+    #[serde(default)]
+    value: Value,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub(crate) struct OTLPResource {
+    #[serde(default)]
+    attributes: Vec<OTLPKeyValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OTLPSpan {
+    trace_id: String,
+    span_id: String,
+    #[serde(default)]
+    parent_span_id: String,
+    name: String,
+    #[serde(default)]
+    kind: Value,
+    #[serde(deserialize_with = "de_u64")]
+    start_time_unix_nano: u64,
+    #[serde(deserialize_with = "de_u64")]
+    end_time_unix_nano: u64,
+    #[serde(default)]
+    attributes: Vec<OTLPKeyValue>,
+    #[serde(default)]
+    events: Vec<OTLPEvent>,
+    #[serde(default)]
+    links: Vec<OTLPLink>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OTLPEvent {
+    #[serde(deserialize_with = "de_u64")]
+    time_unix_nano: u64,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    attributes: Vec<OTLPKeyValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OTLPLink {
+    trace_id: String,
+    span_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct OTLPScopeSpans {
+    #[serde(default)]
+    spans: Vec<OTLPSpan>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OTLPResourceSpans {
+    #[serde(default)]
+    resource: OTLPResource,
+    /// Older exporters call this `instrumentationLibrarySpans`
+    #[serde(default, alias = "instrumentationLibrarySpans")]
+    scope_spans: Vec<OTLPScopeSpans>,
+}
+
+/// The body of an OTLP `ExportTraceServiceRequest`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct OTLPTraceRequest {
+    #[serde(default)]
+    resource_spans: Vec<OTLPResourceSpans>,
+}
+
+/// The parts of the OTLP protobuf schema (`opentelemetry/proto/trace/v1`) that we read
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportTraceServiceRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub resource_spans: Vec<ResourceSpans>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ResourceSpans {
+        #[prost(message, optional, tag = "1")]
+        pub resource: Option<Resource>,
+        #[prost(message, repeated, tag = "2")]
+        pub scope_spans: Vec<ScopeSpans>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Resource {
+        #[prost(message, repeated, tag = "1")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ScopeSpans {
+        #[prost(message, repeated, tag = "2")]
+        pub spans: Vec<Span>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Span {
+        #[prost(bytes = "vec", tag = "1")]
+        pub trace_id: Vec<u8>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub span_id: Vec<u8>,
+        #[prost(bytes = "vec", tag = "4")]
+        pub parent_span_id: Vec<u8>,
+        #[prost(string, tag = "5")]
+        pub name: String,
+        #[prost(int32, tag = "6")]
+        pub kind: i32,
+        #[prost(fixed64, tag = "7")]
+        pub start_time_unix_nano: u64,
+        #[prost(fixed64, tag = "8")]
+        pub end_time_unix_nano: u64,
+        #[prost(message, repeated, tag = "9")]
+        pub attributes: Vec<KeyValue>,
+        #[prost(message, repeated, tag = "11")]
+        pub events: Vec<Event>,
+        #[prost(message, repeated, tag = "13")]
+        pub links: Vec<Link>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Event {
+        #[prost(fixed64, tag = "1")]
+        pub time_unix_nano: u64,
+        #[prost(string, tag = "2")]
+        pub name: String,
+        #[prost(message, repeated, tag = "3")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Link {
+        #[prost(bytes = "vec", tag = "1")]
+        pub trace_id: Vec<u8>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub span_id: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyValue {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(message, optional, tag = "2")]
+        pub value: Option<AnyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AnyValue {
+        #[prost(oneof = "any_value::Value", tags = "1, 2, 3, 4, 5, 6, 7")]
+        pub value: Option<any_value::Value>,
+    }
+
+    pub mod any_value {
+        #[allow(clippy::enum_variant_names)]
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value {
+            #[prost(string, tag = "1")]
+            StringValue(String),
+            #[prost(bool, tag = "2")]
+            BoolValue(bool),
+            #[prost(int64, tag = "3")]
+            IntValue(i64),
+            #[prost(double, tag = "4")]
+            DoubleValue(f64),
+            #[prost(message, tag = "5")]
+            ArrayValue(super::ArrayValue),
+            #[prost(message, tag = "6")]
+            KvlistValue(super::KeyValueList),
+            #[prost(bytes = "vec", tag = "7")]
+            BytesValue(Vec<u8>),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ArrayValue {
+        #[prost(message, repeated, tag = "1")]
+        pub values: Vec<AnyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyValueList {
+        #[prost(message, repeated, tag = "1")]
+        pub values: Vec<KeyValue>,
+    }
+}
+
+/// Converts a protobuf `AnyValue` into its OTLP/JSON form
+fn proto_any_value_to_json(value: &proto::AnyValue) -> Value {
+    use proto::any_value::Value as V;
+    match &value.value {
+        Some(V::StringValue(s)) => json!({ "stringValue": s }),
+        Some(V::BoolValue(b)) => json!({ "boolValue": b }),
+        Some(V::IntValue(i)) => json!({ "intValue": i.to_string() }),
+        Some(V::DoubleValue(d)) => json!({ "doubleValue": d }),
+        Some(V::ArrayValue(a)) => json!({
+            "arrayValue": { "values": a.values.iter().map(proto_any_value_to_json).collect::<Vec<_>>() }
+        }),
+        Some(V::KvlistValue(kvs)) => json!({
+            "kvlistValue": { "values": kvs.values.iter().map(|kv| json!({
+                "key": kv.key,
+                "value": kv.value.as_ref().map(proto_any_value_to_json).unwrap_or_default()
+            })).collect::<Vec<_>>() }
+        }),
+        Some(V::BytesValue(b)) => json!({ "bytesValue": hex::encode(b) }),
+        None => Value::Null,
+    }
+}
+
+fn proto_attributes(attributes: Vec<proto::KeyValue>) -> Vec<OTLPKeyValue> {
+    attributes
+        .into_iter()
+        .map(|kv| OTLPKeyValue {
+            value: kv.value.as_ref().map(proto_any_value_to_json).unwrap_or_default(),
+            key: kv.key,
+        })
+        .collect()
+}
+
+impl From<proto::ExportTraceServiceRequest> for OTLPTraceRequest {
+    fn from(req: proto::ExportTraceServiceRequest) -> Self {
+        OTLPTraceRequest {
+            resource_spans: req
+                .resource_spans
+                .into_iter()
+                .map(|rs| OTLPResourceSpans {
+                    resource: OTLPResource {
+                        attributes: proto_attributes(
+                            rs.resource.map(|r| r.attributes).unwrap_or_default(),
+                        ),
+                    },
+                    scope_spans: rs
+                        .scope_spans
+                        .into_iter()
+                        .map(|ss| OTLPScopeSpans {
+                            spans: ss
+                                .spans
+                                .into_iter()
+                                .map(|span| OTLPSpan {
+                                    trace_id: hex::encode(&span.trace_id),
+                                    span_id: hex::encode(&span.span_id),
+                                    parent_span_id: hex::encode(&span.parent_span_id),
+                                    name: span.name,
+                                    kind: Value::from(span.kind),
+                                    start_time_unix_nano: span.start_time_unix_nano,
+                                    end_time_unix_nano: span.end_time_unix_nano,
+                                    attributes: proto_attributes(span.attributes),
+                                    events: span
+                                        .events
+                                        .into_iter()
+                                        .map(|e| OTLPEvent {
+                                            time_unix_nano: e.time_unix_nano,
+                                            name: e.name,
+                                            attributes: proto_attributes(e.attributes),
+                                        })
+                                        .collect(),
+                                    links: span
+                                        .links
+                                        .into_iter()
+                                        .map(|l| OTLPLink {
+                                            trace_id: hex::encode(&l.trace_id),
+                                            span_id: hex::encode(&l.span_id),
+                                        })
+                                        .collect(),
+                                })
+                                .collect(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parses the body of an OTLP/HTTP export request, either protobuf or JSON encoded
+pub(crate) fn parse_export_request(
+    body: &[u8],
+    protobuf: bool,
+) -> Result<OTLPTraceRequest, Box<dyn Error>> {
+    if protobuf {
+        Ok(proto::ExportTraceServiceRequest::decode(body)?.into())
+    } else {
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+/// uint64 fields are strings in proto3 JSON, but some exporters write them as numbers
+fn de_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::Number(n) => n
+            .as_u64()
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid timestamp {}", n))),
+        Value::String(s) => s.parse().map_err(serde::de::Error::custom),
+        other => Err(serde::de::Error::custom(format!("Invalid timestamp {}", other))),
+    }
+}
+
+/// Converts an OTLP `AnyValue` into a typed Value. Key/value lists and bytes are kept as their
+/// JSON text.
+fn any_value(value: &Value) -> AttributeValue {
+    let obj = match value.as_object() {
+        Some(o) => o,
+        None => return AttributeValue::Str("".to_string()),
+    };
+    if let Some(v) = obj.get("stringValue") {
+        return AttributeValue::Str(v.as_str().unwrap_or_default().to_string());
+    }
+    if let Some(v) = obj.get("boolValue") {
+        return AttributeValue::Bool(v.as_bool().unwrap_or_default());
+    }
+    // int64 is a string in proto3 JSON
+    if let Some(v) = obj.get("intValue") {
+        let parsed = match v {
+            Value::String(s) => s.parse().ok(),
+            _ => v.as_i64(),
+        };
+        return match parsed {
+            Some(i) => AttributeValue::SignedInt(i),
+            None => AttributeValue::Str(v.to_string()),
+        };
+    }
+    if let Some(v) = obj.get("doubleValue") {
+        return match v {
+            Value::String(s) => s
+                .parse()
+                .map(AttributeValue::Float)
+                .unwrap_or_else(|_| AttributeValue::Str(s.clone())),
+            _ => AttributeValue::Float(v.as_f64().unwrap_or_default()),
+        };
+    }
+    if let Some(v) = obj.get("arrayValue") {
+        let values = v["values"].as_array().cloned().unwrap_or_default();
+        return AttributeValue::List(values.iter().map(any_value).collect());
+    }
+    match obj.get("kvlistValue").or(obj.get("bytesValue")) {
+        Some(v) => AttributeValue::Str(v.to_string()),
+        None => AttributeValue::Str("".to_string()),
+    }
+}
+
+fn attributes_to_map(attributes: &[OTLPKeyValue]) -> HashMap<String, AttributeValue> {
+    attributes
+        .iter()
+        .map(|kv| (kv.key.clone(), any_value(&kv.value)))
+        .collect()
+}
+
+/// Span kinds are integers in OTLP/JSON, but some exporters write the enum names
+fn span_kind(kind: &Value) -> Option<String> {
+    let name = match kind {
+        Value::Number(n) => match n.as_u64() {
+            Some(1) => "INTERNAL",
+            Some(2) => "SERVER",
+            Some(3) => "CLIENT",
+            Some(4) => "PRODUCER",
+            Some(5) => "CONSUMER",
+            _ => return None,
+        },
+        Value::String(s) => s.trim_start_matches("SPAN_KIND_"),
+        _ => return None,
+    };
+    match name {
+        "" | "UNSPECIFIED" => None,
+        _ => Some(name.to_string()),
+    }
+}
+
+impl OTLPSpan {
+    /// The parent, which messaging consumers follow from rather than run within, and the spans of
+    /// the same trace this span links to
+    fn references(&self, kind: &Option<String>) -> Vec<SpanReference> {
+        let mut references = Vec::new();
+        if !self.parent_span_id.is_empty() {
+            references.push(SpanReference {
+                span_id: self.parent_span_id.clone(),
+                ref_type: match kind.as_deref() {
+                    Some("CONSUMER") => EdgeType::FollowsFrom,
+                    _ => EdgeType::ChildOf,
+                },
+            });
+        }
+        for link in self.links.iter().filter(|l| l.trace_id == self.trace_id) {
+            references.push(SpanReference {
+                span_id: link.span_id.clone(),
+                ref_type: EdgeType::FollowsFrom,
+            });
+        }
+        references
+    }
+
+    fn to_span(&self, service: &str, host: &str) -> Span {
+        let kind = span_kind(&self.kind);
+        Span {
+            span_id: self.span_id.clone(),
+            parent: self.parent_span_id.clone(),
+            references: self.references(&kind),
+            service: service.to_string(),
+            host: host.to_string(),
+            operation: self.name.clone(),
+            start: DateTime::from_timestamp_nanos(self.start_time_unix_nano as i64).naive_utc(),
+            duration: Duration::from_nanos(
+                self.end_time_unix_nano.saturating_sub(self.start_time_unix_nano),
+            ),
+            kind,
+            tags: attributes_to_map(&self.attributes),
+            logs: self
+                .events
+                .iter()
+                .map(|e| {
+                    let mut fields = attributes_to_map(&e.attributes);
+                    fields.insert("event".to_string(), AttributeValue::Str(e.name.clone()));
+                    SpanLog {
+                        timestamp: DateTime::from_timestamp_nanos(e.time_unix_nano as i64)
+                            .naive_utc(),
+                        fields,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl OTLPTraceRequest {
+    /// Returns the spans of the request grouped by trace ID, in order of first appearance
+    pub(crate) fn spans_by_trace(self) -> Vec<(String, Vec<Span>)> {
+        let mut order = Vec::new();
+        let mut by_trace: HashMap<String, Vec<Span>> = HashMap::new();
+        for rs in self.resource_spans {
+            let resource = attributes_to_map(&rs.resource.attributes);
+            let service = resource
+                .get("service.name")
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            let host = resource
+                .get("host.name")
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| service.clone());
+            for ss in rs.scope_spans {
+                for span in ss.spans {
+                    if !by_trace.contains_key(&span.trace_id) {
+                        order.push(span.trace_id.clone());
+                    }
+                    by_trace
+                        .entry(span.trace_id.clone())
+                        .or_default()
+                        .push(span.to_span(&service, &host));
+                }
+            }
+        }
+        order
+            .into_iter()
+            .map(|id| {
+                let spans = by_trace.remove(&id).unwrap();
+                (id, spans)
+            })
+            .collect()
+    }
+}
+
+pub struct OTLPReader {
+    for_searchspace: bool,
+    span_cache: SpanCache,
+}
+
+impl Reader for OTLPReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        self.read_first_trace(filename)
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        self.read_every_file(foldername)
+    }
+
+    fn read_span_traces(&mut self, path: &str) -> Result<Vec<SpanTrace>, Box<dyn Error>> {
+        self.read_span_trace_files(path)
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        Err(Box::new(PythiaError(format!(
+            "OTLP traces can only be read from files, cannot fetch {}",
+            id
+        ))))
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        Vec::new()
+    }
+
+    fn get_recent_span_traces(&mut self) -> Vec<SpanTrace> {
+        Vec::new()
+    }
+
+    fn reset_state(&mut self) {}
+
+    fn for_searchspace(&mut self) {
+        self.for_searchspace = true;
+    }
+
+    fn all_operations(&mut self) -> Vec<RequestType> {
+        Vec::new()
+    }
+
+    fn set_fetch_all(&mut self) {}
+
+    fn get_candidate_events(&self, start: u64, end: u64, host: String) -> Vec<(String, String)> {
+        self.span_cache.find_overlaps_raw(start, end, host)
+    }
+}
+
+impl OTLPReader {
+    pub fn from_settings(_settings: &Settings) -> OTLPReader {
+        OTLPReader {
+            for_searchspace: false,
+            span_cache: SpanCache::init_cache(),
+        }
+    }
+}
+
+impl TraceFileReader for OTLPReader {
+    /// Parses a single OTLP/JSON request, or the JSON lines written by the collector's file
+    /// exporter
+    fn parse_span_traces(&mut self, contents: &str) -> Result<ParsedSpanTraces, Box<dyn Error>> {
+        let requests: Vec<OTLPTraceRequest> = match serde_json::from_str(contents) {
+            Ok(r) => vec![r],
+            Err(_) => contents
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<_, _>>()?,
+        };
+        // A trace may be split across several requests
+        let mut merged = OTLPTraceRequest {
+            resource_spans: Vec::new(),
+        };
+        for mut r in requests {
+            merged.resource_spans.append(&mut r.resource_spans);
+        }
+        Ok(merged
+            .spans_by_trace()
+            .into_iter()
+            .map(|(trace_id, spans)| {
+                SpanTrace::from_spans(trace_id, spans, &ApplicationType::OTLP, &mut self.span_cache)
+            })
+            .collect())
+    }
+
+    fn reads_for_searchspace(&self) -> bool {
+        self.for_searchspace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::critical::CriticalPathAlgorithm;
+    use crate::validate::checked_span_critical_path;
+
+    const JSON_REQUEST: &str = r#"{"resourceSpans": [{
+        "resource": {"attributes": [
+            {"key": "service.name", "value": {"stringValue": "api"}},
+            {"key": "host.name", "value": {"stringValue": "node1"}}
+        ]},
+        "scopeSpans": [{"spans": [
+            {"traceId": "0a0b", "spanId": "01", "name": "GET /", "kind": 2,
+             "startTimeUnixNano": "1000000000", "endTimeUnixNano": 1003000000,
+             "attributes": [{"key": "retries", "value": {"intValue": "2"}}]},
+            {"traceId": "0a0b", "spanId": "02", "parentSpanId": "01", "name": "query", "kind": 3,
+             "startTimeUnixNano": "1001000000", "endTimeUnixNano": "1002000000"}
+        ]}]
+    }]}"#;
+
+    fn proto_span(span_id: u8, parent: Option<u8>, name: &str, kind: i32, start_ms: u64, end_ms: u64) -> proto::Span {
+        proto::Span {
+            trace_id: vec![0x0a, 0x0b],
+            span_id: vec![span_id],
+            parent_span_id: parent.into_iter().collect(),
+            name: name.to_string(),
+            kind,
+            start_time_unix_nano: start_ms * 1_000_000,
+            end_time_unix_nano: end_ms * 1_000_000,
+            attributes: Vec::new(),
+            events: Vec::new(),
+            links: Vec::new(),
+        }
+    }
+
+    fn string_attribute(key: &str, value: &str) -> proto::KeyValue {
+        proto::KeyValue {
+            key: key.to_string(),
+            value: Some(proto::AnyValue {
+                value: Some(proto::any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    /// The protobuf encoding of JSON_REQUEST
+    fn protobuf_request() -> Vec<u8> {
+        let mut root = proto_span(1, None, "GET /", 2, 1000, 1003);
+        root.attributes.push(proto::KeyValue {
+            key: "retries".to_string(),
+            value: Some(proto::AnyValue {
+                value: Some(proto::any_value::Value::IntValue(2)),
+            }),
+        });
+        proto::ExportTraceServiceRequest {
+            resource_spans: vec![proto::ResourceSpans {
+                resource: Some(proto::Resource {
+                    attributes: vec![
+                        string_attribute("service.name", "api"),
+                        string_attribute("host.name", "node1"),
+                    ],
+                }),
+                scope_spans: vec![proto::ScopeSpans {
+                    spans: vec![root, proto_span(2, Some(1), "query", 3, 1001, 1002)],
+                }],
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    fn check_request(request: OTLPTraceRequest) {
+        let traces = request.spans_by_trace();
+        assert_eq!(traces.len(), 1);
+        let (trace_id, spans) = &traces[0];
+        assert_eq!(trace_id, "0a0b");
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].span_id, "01");
+        assert_eq!(spans[0].service, "api");
+        assert_eq!(spans[0].host, "node1");
+        assert_eq!(spans[0].duration, Duration::from_millis(3));
+        assert_eq!(spans[0].tags.get("retries"), Some(&AttributeValue::SignedInt(2)));
+        assert_eq!(spans[1].parent, "01");
+        assert_eq!(spans[1].operation, "query");
+        assert_eq!(spans[1].start - spans[0].start, chrono::Duration::milliseconds(1));
+    }
+
+    #[test]
+    fn parses_json_requests() {
+        check_request(parse_export_request(JSON_REQUEST.as_bytes(), false).unwrap());
+    }
+
+    #[test]
+    fn parses_protobuf_requests() {
+        check_request(parse_export_request(&protobuf_request(), true).unwrap());
+        assert!(parse_export_request(JSON_REQUEST.as_bytes(), true).is_err());
+    }
+
+    #[test]
+    fn consumers_and_links_follow_from() {
+        let request = r#"{"resourceSpans": [{"scopeSpans": [{"spans": [
+            {"traceId": "0a0b", "spanId": "01", "name": "publish", "kind": 4,
+             "startTimeUnixNano": 0, "endTimeUnixNano": 1000},
+            {"traceId": "0a0b", "spanId": "02", "parentSpanId": "01", "name": "consume",
+             "kind": "SPAN_KIND_CONSUMER", "startTimeUnixNano": 2000, "endTimeUnixNano": 3000,
+             "links": [{"traceId": "0a0b", "spanId": "01"}, {"traceId": "0c0d", "spanId": "09"}]},
+            {"traceId": "0a0b", "spanId": "03", "parentSpanId": "01", "name": "ack", "kind": 5,
+             "startTimeUnixNano": 2500, "endTimeUnixNano": 2600}
+        ]}]}]}"#;
+        let traces = parse_export_request(request.as_bytes(), false).unwrap().spans_by_trace();
+        let spans = &traces[0].1;
+        assert_eq!(spans[0].kind.as_deref(), Some("PRODUCER"));
+        for span in &spans[1..] {
+            assert_eq!(span.kind.as_deref(), Some("CONSUMER"));
+            assert_eq!(span.parent_ref_type(), EdgeType::FollowsFrom);
+        }
+        // Links to other traces are dropped
+        let references: Vec<_> = spans[1]
+            .references
+            .iter()
+            .map(|r| (r.span_id.as_str(), r.ref_type.clone()))
+            .collect();
+        assert_eq!(references, vec![("01", EdgeType::FollowsFrom), ("01", EdgeType::FollowsFrom)]);
+    }
+
+    #[test]
+    fn paths_have_the_otlp_request_type() {
+        let mut reader = OTLPReader {
+            for_searchspace: false,
+            span_cache: SpanCache::init_cache(),
+        };
+        let traces = reader.parse_span_traces(JSON_REQUEST).unwrap();
+        let trace = traces.into_iter().next().unwrap().unwrap();
+        let path = checked_span_critical_path(&trace, CriticalPathAlgorithm::LatestPredecessor).unwrap();
+        // As the controller parses `problem_type` for `application = "OTLP"`
+        let problem_type = RequestType::from_str("api:GET /", "OTLP").unwrap();
+        assert_eq!(path.request_type, problem_type);
+    }
+}
@@ -0,0 +1,356 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Recording and replaying of the traces a Reader fetches.
+//!
+//! `RecordingReader` wraps another Reader and appends every batch returned from
+//! `get_recent_traces`/`get_recent_span_traces` to an archive, one JSON line per fetch together
+//! with the time of the fetch. `ReplayReader` serves the batches of such an archive back in the
+//! order they were fetched, one batch per call, keeping the recorded pacing: a batch is only
+//! served once as much time passed since the first replayed batch as had passed between their
+//! fetches, and calls made before that get no traces.
+//!
+//! All readers of the same archive within a process share it, so the worker threads of the
+//! controller together record (or replay) a single stream of fetches.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use pythia_common::RequestType;
+use serde::{Deserialize, Serialize};
+
+use crate::reader::{saved_traces_only, ParsedSpanTraces, Reader, TraceFileReader};
+use crate::spantrace::{SpanCache, SpanTrace};
+use crate::trace::Trace;
+use crate::PythiaError;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum RecordedBatch {
+    Traces(Vec<Trace>),
+    SpanTraces(Vec<SpanTrace>),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RecordedFetch {
+    fetched_at: DateTime<Utc>,
+    batch: RecordedBatch,
+}
+
+#[derive(Default)]
+struct ReplayState {
+    traces: VecDeque<(DateTime<Utc>, Vec<Trace>)>,
+    span_traces: VecDeque<(DateTime<Utc>, Vec<SpanTrace>)>,
+    /// Everything in the archive, for lookups by ID
+    by_id: HashMap<String, Trace>,
+    request_types: HashSet<RequestType>,
+    /// When the first batch was replayed, and when that batch had been fetched
+    clock: Option<(Instant, DateTime<Utc>)>,
+}
+
+impl ReplayState {
+    /// Whether a batch fetched at `fetched_at` is due for replay
+    fn is_due(&mut self, fetched_at: DateTime<Utc>) -> bool {
+        let (started, first_fetch) = *self.clock.get_or_insert((Instant::now(), fetched_at));
+        match (fetched_at - first_fetch).to_std() {
+            Ok(offset) => started.elapsed() >= offset,
+            Err(_) => true,
+        }
+    }
+}
+
+lazy_static! {
+    static ref RECORDINGS: Mutex<HashMap<PathBuf, Arc<Mutex<File>>>> = Mutex::new(HashMap::new());
+    static ref REPLAYS: Mutex<HashMap<PathBuf, Arc<Mutex<ReplayState>>>> =
+        Mutex::new(HashMap::new());
+}
+
+pub struct RecordingReader {
+    inner: Box<dyn Reader>,
+    archive: Arc<Mutex<File>>,
+}
+
+impl RecordingReader {
+    pub fn new(inner: Box<dyn Reader>, archive: &Path) -> RecordingReader {
+        let archive = RECORDINGS
+            .lock()
+            .unwrap()
+            .entry(archive.to_path_buf())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(archive)
+                        .expect("Could not open the recording archive"),
+                ))
+            })
+            .clone();
+        RecordingReader { inner, archive }
+    }
+
+    fn record(&self, batch: RecordedBatch) {
+        let fetch = RecordedFetch {
+            fetched_at: Utc::now(),
+            batch,
+        };
+        let mut line = serde_json::to_string(&fetch).unwrap();
+        line.push('\n');
+        let mut archive = self.archive.lock().unwrap();
+        if let Err(e) = archive.write_all(line.as_bytes()).and_then(|_| archive.flush()) {
+            eprintln!("Failed to record fetch: {:?}", e);
+        }
+    }
+}
+
+impl Reader for RecordingReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        self.inner.read_file(filename)
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        self.inner.read_dir(foldername)
+    }
+
+    fn read_span_traces(&mut self, path: &str) -> Result<Vec<SpanTrace>, Box<dyn Error>> {
+        self.inner.read_span_traces(path)
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        self.inner.get_trace_from_base_id(id)
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        let traces = self.inner.get_recent_traces();
+        self.record(RecordedBatch::Traces(traces.clone()));
+        traces
+    }
+
+    fn get_recent_span_traces(&mut self) -> Vec<SpanTrace> {
+        let traces = self.inner.get_recent_span_traces();
+        self.record(RecordedBatch::SpanTraces(traces.clone()));
+        traces
+    }
+
+    fn reset_state(&mut self) {
+        self.inner.reset_state()
+    }
+
+    fn for_searchspace(&mut self) {
+        self.inner.for_searchspace()
+    }
+
+    fn all_operations(&mut self) -> Vec<RequestType> {
+        self.inner.all_operations()
+    }
+
+    fn set_fetch_all(&mut self) {
+        self.inner.set_fetch_all()
+    }
+
+    fn get_candidate_events(&self, start: u64, end: u64, host: String) -> Vec<(String, String)> {
+        self.inner.get_candidate_events(start, end, host)
+    }
+}
+
+pub struct ReplayReader {
+    state: Arc<Mutex<ReplayState>>,
+    span_cache: SpanCache,
+}
+
+impl ReplayReader {
+    pub fn from_file(archive: &Path) -> ReplayReader {
+        let state = REPLAYS
+            .lock()
+            .unwrap()
+            .entry(archive.to_path_buf())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(
+                    Self::load(archive).expect("Could not read the replay archive"),
+                ))
+            })
+            .clone();
+        ReplayReader {
+            state,
+            span_cache: SpanCache::init_cache(),
+        }
+    }
+
+    fn load(archive: &Path) -> Result<ReplayState, Box<dyn Error>> {
+        let mut state = ReplayState::default();
+        for (line_no, line) in BufReader::new(File::open(archive)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fetch: RecordedFetch = serde_json::from_str(&line).map_err(|e| {
+                PythiaError(format!("{}:{}: {}", archive.display(), line_no + 1, e))
+            })?;
+            match fetch.batch {
+                RecordedBatch::Traces(traces) => {
+                    for trace in &traces {
+                        state.request_types.insert(trace.request_type.clone());
+                        state.by_id.insert(trace.base_id.to_string(), trace.clone());
+                    }
+                    state.traces.push_back((fetch.fetched_at, traces));
+                }
+                RecordedBatch::SpanTraces(traces) => {
+                    for trace in &traces {
+                        let trace = trace.to_critical_path();
+                        state.request_types.insert(trace.request_type.clone());
+                        state.by_id.insert(trace.base_id.to_string(), trace);
+                    }
+                    state.span_traces.push_back((fetch.fetched_at, traces));
+                }
+            }
+        }
+        eprintln!(
+            "Replaying {} trace fetches and {} span trace fetches from {}",
+            state.traces.len(),
+            state.span_traces.len(),
+            archive.display()
+        );
+        Ok(state)
+    }
+}
+
+impl Reader for ReplayReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        self.read_first_trace(filename)
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        self.read_every_file(foldername)
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        match self.state.lock().unwrap().by_id.get(id) {
+            Some(t) => Ok(t.clone()),
+            None => Err(Box::new(PythiaError(format!(
+                "Trace {} is not in the replay archive",
+                id
+            )))),
+        }
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        let mut state = self.state.lock().unwrap();
+        match state.traces.front().map(|(fetched_at, _)| *fetched_at) {
+            Some(fetched_at) if state.is_due(fetched_at) => {
+                let (_, traces) = state.traces.pop_front().unwrap();
+                eprintln!("Replaying {} traces fetched at {}", traces.len(), fetched_at);
+                traces
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn get_recent_span_traces(&mut self) -> Vec<SpanTrace> {
+        let mut state = self.state.lock().unwrap();
+        match state.span_traces.front().map(|(fetched_at, _)| *fetched_at) {
+            Some(fetched_at) if state.is_due(fetched_at) => {
+                let (_, traces) = state.span_traces.pop_front().unwrap();
+                eprintln!("Replaying {} span traces fetched at {}", traces.len(), fetched_at);
+                for trace in &traces {
+                    self.span_cache.add_trace(trace);
+                }
+                traces
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn reset_state(&mut self) {}
+
+    fn for_searchspace(&mut self) {}
+
+    fn all_operations(&mut self) -> Vec<RequestType> {
+        self.state
+            .lock()
+            .unwrap()
+            .request_types
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn set_fetch_all(&mut self) {}
+
+    fn get_candidate_events(&self, start: u64, end: u64, host: String) -> Vec<(String, String)> {
+        self.span_cache.find_overlaps_raw(start, end, host)
+    }
+}
+
+impl TraceFileReader for ReplayReader {
+    fn parse_span_traces(&mut self, _contents: &str) -> Result<ParsedSpanTraces, Box<dyn Error>> {
+        saved_traces_only()
+    }
+
+    fn reads_for_searchspace(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::settings::ApplicationType;
+    use crate::synthetic::{SyntheticReader, Topology};
+
+    const TOPOLOGY: &str = r#"
+        root = "fe:get"
+        seed = 7
+
+        [[operations]]
+        service = "fe"
+        operation = "get"
+        latency = { distribution = "constant", ms = 2.0 }
+    "#;
+
+    #[test]
+    fn replays_recorded_fetches_at_their_pace() {
+        let dir = std::env::temp_dir().join(format!("pythia-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let topology_file = dir.join("topology.toml");
+        std::fs::write(&topology_file, TOPOLOGY).unwrap();
+        let topology = Topology::from_file(&topology_file).unwrap();
+        let request_type = RequestType::from_str("fe:get", "Jaeger").unwrap();
+        let archive = dir.join("fetches.jsonl");
+
+        let mut recorder = RecordingReader::new(
+            Box::new(SyntheticReader::new(topology, 2, ApplicationType::Jaeger)),
+            &archive,
+        );
+        let first = recorder.get_recent_span_traces();
+        std::thread::sleep(Duration::from_millis(300));
+        let second = recorder.get_recent_span_traces();
+
+        let mut replay = ReplayReader::from_file(&archive);
+        let ids = |traces: Vec<SpanTrace>| -> Vec<String> {
+            traces.into_iter().map(|t| t.req_id).collect()
+        };
+        assert_eq!(ids(replay.get_recent_span_traces()), ids(first));
+        // The second fetch came 300ms after the first
+        assert!(replay.get_recent_span_traces().is_empty());
+        std::thread::sleep(Duration::from_millis(350));
+        let second_ids = ids(second);
+        assert_eq!(ids(replay.get_recent_span_traces()), second_ids);
+        assert!(replay.get_recent_span_traces().is_empty());
+
+        assert_eq!(replay.all_operations(), vec![request_type]);
+        assert!(replay.get_trace_from_base_id(&second_ids[0]).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,31 +6,53 @@ Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
 All rights reserved.
 */
 
+//! Reader for SkyWalking's GraphQL query API (`<skywalking_url>/graphql`).
+//!
+//! Trace IDs are listed with `queryBasicTraces`, one time window and page at a time, and the
+//! traces themselves are fetched in batches of aliased `queryTrace` calls.
+
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::{fs, vec};
-use std::slice::SplitN;
-use std::time::{Duration, SystemTime};
-use chrono::{DateTime, NaiveDate, NaiveDateTime, Datelike, Timelike};
-use futures::Sink;
-use hyper::http;
+use std::time::Duration;
+use chrono::{DateTime, Local};
 use itertools::Itertools;
-use petgraph::Graph;
 use pythia_common::skywalking::SWRequestType;
 use pythia_common::RequestType;
 use crate::reader::Reader;
-use crate::{Settings, Trace};
-use crate::spantrace::{Span, SpanCache, SpanTrace};
+use crate::settings::ApplicationType;
+use crate::{PythiaError, Settings, Trace};
+use crate::spantrace::{Span, SpanCache, SpanLog, SpanReference, SpanTrace};
+use crate::trace::{EdgeType, Value};
 use serde::{Serialize, Deserialize};
-use crate::trace::Event;
-use url::form_urlencoded;
-use crate::reader::jaeger::JaegerReader;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+/// Page size used for queryBasicTraces
+const SW_PAGE_SIZE: usize = 10000;
+/// Number of traces requested in a single GraphQL query
+const SW_TRACE_BATCH_SIZE: usize = 1000;
+/// Width of the time windows queryBasicTraces is called with
+const SW_QUERY_WINDOW: Duration = Duration::from_secs(60);
+/// How far back all_operations() looks for traces
+const SW_OPERATIONS_LOOKBACK: Duration = Duration::from_secs(60 * 20);
+const SW_QUERY_TIMEOUT: Duration = Duration::from_secs(180);
+
+const BASIC_TRACES_QUERY: &str = "query queryTraces($condition: TraceQueryCondition) { \
+    traceData: queryBasicTraces(condition: $condition) { \
+    traces { traceIds } total } }";
+const SERVICES_QUERY: &str = "query queryServices($duration: Duration!, $keyword: String!) { \
+    services: getAllServices(duration: $duration, group: $keyword) { key: id label: name } }";
+const TRACE_FIELDS: &str = "spans { traceId segmentId spanId parentSpanId serviceCode startTime \
+    endTime endpointName type peer component isError layer \
+    refs { traceId parentSegmentId parentSpanId type } \
+    tags { key value } logs { time data { key value } } }";
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SWRef {
     traceId: String,
     parentSegmentId: String,
     parentSpanId: i64,
+    #[serde(rename = "type")]
     refType: String
 }
 
@@ -44,31 +66,49 @@ struct SWSpan {
     startTime: u64,
     endTime: u64,
     endpointName: String,
+    #[serde(rename = "type")]
     spanType: String,
-    peer: String,
-    component: String,
+    peer: Option<String>,
+    component: Option<String>,
     isError: bool,
-    layer: String,
-    refs: Vec<SWRef>
+    layer: Option<String>,
+    refs: Vec<SWRef>,
+    #[serde(default)]
+    tags: Vec<SWKeyValue>,
+    #[serde(default)]
+    logs: Vec<SWLog>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SWResult {
-    spans: Vec<SWSpan>
+struct SWKeyValue {
+    key: String,
+    value: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SWPayload {
-    success: bool,
-    data: Vec<SWResult>,
-    message: String
+struct SWLog {
+    /// Epoch milliseconds
+    time: i64,
+    #[serde(default)]
+    data: Vec<SWKeyValue>,
+}
+
+fn key_values_to_map(kvs: &[SWKeyValue]) -> HashMap<String, Value> {
+    kvs.iter()
+        .map(|kv| (kv.key.clone(), Value::Str(kv.value.clone().unwrap_or_default())))
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SWResult {
+    spans: Vec<SWSpan>
 }
 
 impl SWSpan {
     pub fn to_span(&self) -> Span {
         // TODO: Re-enable this to log all SkyWalking span IDs
         // println!("SPAN ID = {}.{}", self.segmentId, self.spanId);
-        
+
         Span{
             span_id: format!("{}.{}", self.segmentId, self.spanId),
             parent: match self.refs.len() {
@@ -78,6 +118,7 @@ impl SWSpan {
                 },
                 _ => format!("{}.{}", self.refs[0].parentSegmentId, self.refs[0].parentSpanId)
             },
+            references: self.references(),
             service: self.serviceCode.clone(),
             host: self.serviceCode.clone(),
             operation: (|s: String| {
@@ -96,126 +137,100 @@ impl SWSpan {
                 parts.join("/")
             })(self.endpointName.clone()),
             start: DateTime::from_timestamp_millis(self.startTime as i64).unwrap().naive_utc(),
-            duration: Duration::from_millis(self.endTime - self.startTime)
+            duration: Duration::from_millis(self.endTime - self.startTime),
+            kind: Some(self.spanType.clone()),
+            tags: key_values_to_map(&self.tags),
+            logs: self.logs.iter().map(|l| SpanLog {
+                timestamp: DateTime::from_timestamp_millis(l.time).unwrap_or_default().naive_utc(),
+                fields: key_values_to_map(&l.data),
+            }).collect(),
         }
     }
-}
-
-////////////////////////////////////////
-
-#[derive(Debug, Serialize, Deserialize)]
-struct SWBasicSpan {
-    key: String,
-    endpointNames: Vec<String>,
-    duration: u64,
-    start: String,
-    isError: bool,
-    traceIds: Vec<String>
-}
 
-// #[derive(Debug, Serialize, Deserialize)]
-// struct SWBSInner {
-//     traces: Vec<SWBasicSpan>,
-//     total: u64
-// }
-//
-// #[derive(Debug, Serialize, Deserialize)]
-// struct SWBSOuter {
-//     data: SWBSInner
-// }
-//
-// #[derive(Debug, Serialize, Deserialize)]
-// struct SWBSPayload {
-//     data: SWBSOuter
-// }
+    /// The parent span within the segment, and the spans of other segments this one continues.
+    /// Cross-thread references are asynchronous hand-offs within a process.
+    fn references(&self) -> Vec<SpanReference> {
+        let mut references = Vec::new();
+        if self.parentSpanId != -1 {
+            references.push(SpanReference {
+                span_id: format!("{}.{}", self.segmentId, self.parentSpanId),
+                ref_type: EdgeType::ChildOf,
+            });
+        }
+        for r in &self.refs {
+            references.push(SpanReference {
+                span_id: format!("{}.{}", r.parentSegmentId, r.parentSpanId),
+                ref_type: match r.refType.as_str() {
+                    "CROSS_THREAD" => EdgeType::FollowsFrom,
+                    _ => EdgeType::ChildOf,
+                },
+            });
+        }
+        references
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SWBSPayload {
-    success: bool,
-    traces: Vec<SWBasicSpan>,
-    total: u64,
-    message: String,
+    /// Strips the port off the peer address. Exit spans whose peer is a TrainTicket service
+    /// (`ts-*-service:port`) are attributed to that service.
+    fn normalize_peer(&mut self) {
+        let host = match &self.peer {
+            Some(peer) if peer.contains(':') => peer.split(':').next().unwrap().to_string(),
+            _ => return,
+        };
+        if host.starts_with("ts-") && host.ends_with("-service") {
+            self.serviceCode = host;
+        } else {
+            self.peer = Some(host);
+        }
+    }
 }
 
-////////////////////////////////////////
-
 #[derive(Debug, Serialize, Deserialize)]
-struct TFTraceItem {
-    key: String,
-    endpointNames: Vec<String>,
-    duration: u64,
-    start: u64,
-    isError: bool,
+struct SWBasicTrace {
     traceIds: Vec<String>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct TFTraceData {
-    traces: Vec<TFTraceItem>,
+struct SWBasicTraces {
+    traces: Vec<SWBasicTrace>,
     total: u64
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SWTFInner {
-    traceData: TFTraceData
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct SWTimedFetch {
-    data: SWTFInner
+struct SWBasicTracesData {
+    #[serde(rename = "traceData")]
+    trace_data: SWBasicTraces
 }
 
-////////////////////////////////////////
-
 #[derive(Debug, Serialize, Deserialize)]
 struct SWService {
     key: String,
-    label: String,
-    group: String
+    label: String
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SWSInner {
+struct SWServicesData {
     services: Vec<SWService>
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SWServices {
-    data: SWSInner
+#[derive(Debug, Deserialize)]
+struct GraphQLError {
+    message: String
 }
 
-////////////////////////////////////////
-
-// struct QueryPartNode {
-//     part: String,
-//     count: u64,
-//     children: Vec<String>,
-// }
-// 
-// impl QueryPartNode {
-//     pub fn 
-// }
-// 
-// struct QueryPartTree {
-//     base_part: QueryPartNode,
-//     query_parts: HashMap<String, QueryPartNode>,
-// }
-// 
-// impl QueryPartTree {
-//     
-// }
-
-////////////////////////////////////////
+#[derive(Debug, Deserialize)]
+struct GraphQLResponse<T> {
+    data: Option<T>,
+    #[serde(default)]
+    errors: Vec<GraphQLError>
+}
 
 pub struct SWReader {
-    // connection: JaegerConnection // TODO: implement this
     fetch_url: String,
     problem_type: RequestType,
     fetch_all: bool,
     for_searchspace: bool,
     cycle_lookback: u128,
     span_cache: SpanCache,
-    // current_traces: HashMap<String, i64>
     operations: Vec<String>,
     op_prefixes: Vec<(String, String)>
 }
@@ -230,7 +245,11 @@ impl Reader for SWReader {
     }
 
     fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
-        todo!()
+        let traces = self.query_traces(&[id.to_string()])?;
+        match self.build_span_traces(traces).into_iter().next() {
+            Some(st) => Ok(st.to_reader_trace(self.for_searchspace)),
+            None => Err(Box::new(PythiaError(format!("No spans found for trace {}", id)))),
+        }
     }
 
     fn get_recent_traces(&mut self) -> Vec<Trace> {
@@ -238,122 +257,15 @@ impl Reader for SWReader {
     }
 
     fn get_recent_span_traces(&mut self) -> Vec<SpanTrace> {
-        let fmt_two_digit = |i: u32| {
-            if i < 10 {
-                return format!("0{}", i);
+        let lookback = Duration::from_micros(self.cycle_lookback as u64);
+        let trace_ids = match self.query_trace_ids(lookback, None) {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("Failed to list SkyWalking traces: {:?}", e);
+                return Vec::new();
             }
-
-            return format!("{}", i);
         };
 
-        // if self.fetch_all {
-        let cur_date = chrono::Utc::now();
-        // let start_time = cur_date - Duration::from_secs(60*20);
-        let start_time = cur_date - Duration::from_secs(60*25);
-        // let end_time = cur_date + Duration::from_secs(60*20);
-        // let end_time = cur_date + Duration::from_secs(60*60*24);
-        let end_time = cur_date;
-
-        #[derive(Serialize)]
-        struct SpanQueryFormatReq {
-            start_year: i32,
-            start_month: String,
-            start_day: String,
-            start_hour: String,
-            start_minute: String,
-            end_year: i32,
-            end_month: String,
-            end_day: String,
-            end_hour: String,
-            end_minute: String,
-            page_num: u64,
-        }
-
-        let mut client = reqwest::blocking::Client::new();
-
-        let mut resp: reqwest::blocking::Response;
-
-        let mut resp_text: String;
-
-        let mut resp_obj: SWBSPayload;
-
-        let mut all_trace_ids: Vec<String> = Vec::new();
-
-        let mut page_num = 1u64;
-        let mut start_time_iter = end_time - Duration::from_secs(5);
-        let mut end_time_iter = end_time;
-
-        let mut next_page = true;
-        let mut print_iter = 1;
-        loop {
-            println!("SpanQuery Retrieval Loop #{}", print_iter);
-            print_iter += 1;
-
-            resp = client.post("http://localhost:3000/spanquery")
-                .json(&SpanQueryFormatReq{
-                    start_year: start_time_iter.naive_local().year(),
-                    start_month: fmt_two_digit(start_time_iter.naive_local().month()),
-                    start_day: fmt_two_digit(start_time_iter.naive_local().day()),
-                    start_hour: fmt_two_digit(start_time_iter.naive_local().hour()),
-                    start_minute: fmt_two_digit(start_time_iter.naive_local().minute()),
-                    end_year: end_time_iter.naive_local().year(),
-                    end_month: fmt_two_digit(end_time_iter.naive_local().month()),
-                    end_day: fmt_two_digit(end_time_iter.naive_local().day()),
-                    end_hour: fmt_two_digit(end_time_iter.naive_local().hour()),
-                    end_minute: fmt_two_digit(end_time_iter.naive_local().minute()),
-                    page_num,
-                }).send().unwrap();
-
-            resp_text = resp.text().unwrap();
-
-            resp_obj = serde_json::from_str(resp_text.as_str()).unwrap();
-
-            let mut trace_ids = resp_obj.traces.into_iter()
-                .map(|bs| bs.traceIds[0].clone()).collect::<Vec<String>>();
-
-            // if trace_ids.len() < 10000 {
-            //     to_break = true;
-            // }
-            if trace_ids.len() == 0 {
-                next_page = false;
-            }
-
-            all_trace_ids.append(&mut trace_ids);
-
-            if next_page {
-                page_num += 1;
-            } else {
-                if start_time_iter == start_time {
-                    break;
-                } else {
-                    start_time_iter = start_time_iter - Duration::from_secs(5);
-                    end_time_iter = end_time_iter - Duration::from_secs(5);
-                    page_num = 1;
-                }
-            }
-        }
-
-        let mut trace_ids_set = HashSet::new();
-        for trace_id in all_trace_ids {
-            trace_ids_set.insert(trace_id.clone());
-        }
-
-        all_trace_ids = trace_ids_set.into_iter().collect::<Vec<String>>();
-
-        #[derive(Serialize)]
-        struct TraceQueryPayload {
-            traceIds: Vec<String>
-        }
-
-        // trace_ids = trace_ids.drain(..100).collect::<Vec<String>>();
-
-        client = reqwest::blocking::Client::new();
-
-        let mut traces: Vec<SWResult> = Vec::new();
-
-        let loop_iters = ((all_trace_ids.len() as f64)/1000.0).ceil() as u64;
-        let mut i = 0;
-
         println!();
         println!();
         println!("GENERIC OPERATIONS:");
@@ -363,137 +275,8 @@ impl Reader for SWReader {
         println!();
         println!();
 
-        loop {
-            println!("Trace retrieval loop {}/{}", i, loop_iters);
-            i += 1;
-            
-            if all_trace_ids.len() == 0 {
-                break;
-            }
-
-            let mut min_range_end = 1000;
-            if all_trace_ids.len() < 1000 {
-                min_range_end = all_trace_ids.len();
-            }
-            let cur_trace_ids = all_trace_ids.drain(..min_range_end).collect::<Vec<String>>();
-
-            resp = client.post("http://localhost:3000/traces")
-                .json(&TraceQueryPayload{
-                    traceIds: cur_trace_ids
-                })
-                .timeout(Duration::from_secs(180))
-                .send().unwrap();
-
-            resp_text = resp.text().unwrap();
-
-            let mut traces_payload: SWPayload =
-                serde_json::from_str(resp_text.as_str()).unwrap();
-
-            traces.append(&mut (traces_payload.data));
-        }
-
-        // let traces = traces_payload.data;
-
-        let mut to_return = Vec::new();
-
-        // let mut generics = HashSet::new();
-        // for operation in &self.operations {
-        //     let yeet = operation.split("/{").collect::<Vec<&str>>()[0].to_string();
-        //     generics.insert((
-        //         yeet,
-        //         operation.clone()
-        //     ));
-        // }
-
-        for trace in traces {
-            // let mut spans = trace.spans.into_iter()
-            //     .map(|s| s.to_span()).collect::<Vec<Span>>();
-            let spans = trace.spans.into_iter()
-                .map(|s| s.to_span()).collect::<Vec<Span>>();
-            
-            // spans = spans.into_iter().map(|s| {
-            //     let mut to_return = s;
-            //
-            //     for (op_p, op) in &self.op_prefixes {
-            //         if to_return.operation.contains(op_p.as_str()) {
-            //             to_return.operation = op.clone();
-            //             break;
-            //         }
-            //     }
-            //
-            //     to_return
-            // }).collect::<Vec<Span>>();
-
-            let mut root_span = spans[0].clone();
-            for span in &spans {
-                if span.parent.is_empty() {
-                    root_span = span.clone();
-                }
-            }
-
-            let root_id_parts = root_span.span_id.split(".").collect::<Vec<&str>>();
-            let mut trace_id_parts = Vec::new();
-            let mut i = 1;
-            loop {
-                if i == root_id_parts.len() {
-                    break;
-                } else {
-                    trace_id_parts.push(root_id_parts[i-1]);
-                }
-                
-                i += 1;
-            }
-
-            let trace_id = trace_id_parts.iter().join(".");
-
-            for span in &spans {
-                self.span_cache.add_span(span.clone(), trace_id.clone());
-            }
-            
-            // TODO: Re-enable this to gain more information
-            // println!("skywalking.rs - get_recent_span_traces - root_span.operation = {}", root_span.operation);
-
-            to_return.push(
-                SpanTrace::from_span_list(
-                    spans,
-                    format!("{}:{}", root_span.service, root_span.operation),
-                    root_span.span_id,
-                    trace_id
-                )
-            );
-        }
-
-        to_return
-        // } else {
-        //     let cur_date = chrono::Utc::now();
-        //     let start_time = cur_date - Duration::from_secs(60*20);
-        //     let end_time = cur_date + Duration::from_secs(60*20);
-        //
-        //     let services_query_str = format!(
-        //         "{{ \"query\": \"query queryServices($duration: Duration!,$keyword: String!) {{ services: getAllServices(duration: $duration, group: $keyword) {{ key: id label: name group }} }}\", \"variables\": {{ \"duration\": {{ \"start\": \"{}-{}-{} {}{}\",\"end\": \"{}-{}-{} {}{}\", \"step\": \"MINUTE\" }}, \"keyword\":\"\" }} }}",
-        //         start_time.year(), start_time.month(), start_time.day(),
-        //         fmt_two_digit(start_time.hour()), fmt_two_digit(start_time.minute()),
-        //         end_time.year(), end_time.month(), end_time.day()
-        //         fmt_two_digit(end_time.hour()), fmt_two_digit(end_time.minute()),
-        //     );
-        //
-        //     let client = reqwest::blocking::Client::new();
-        //
-        //     let resp: reqwest::blocking::Response = client.post("http://localhost:12800/graphql")
-        //         .body(services_query_str)
-        //         .send().unwrap();
-        //
-        //     let resp_text = resp.text().unwrap();
-        //
-        //     let resp_obj: SWServices =
-        //         serde_json::from_str(resp_text.as_str()).unwrap();
-        //
-        //     let service_names = resp_obj.data.services.into_iter().map(
-        //         |service| service.label
-        //     ).collect::<Vec<String>>();
-        //
-        //     Vec::new()
-        // }
+        let traces = self.fetch_traces(trace_ids);
+        self.build_span_traces(traces)
     }
 
     fn reset_state(&mut self) {
@@ -511,105 +294,25 @@ impl Reader for SWReader {
         println!("==========\nSKYWALKING READER -- GETTING ALL OPERATIONS\n==========");
         println!();
         println!();
-        
-        let fmt_two_digit = |i: u32| {
-            if i < 10 {
-                return format!("0{}", i);
-            }
 
-            return format!("{}", i);
-        };
-
-        // if self.fetch_all {
-        let cur_date = chrono::Utc::now();
-        // let start_time = cur_date - Duration::from_secs(60*60*24);
-        let start_time = cur_date - Duration::from_secs(60*20);
-        // let end_time = cur_date + Duration::from_secs(60*60*24);
-        let end_time = cur_date + Duration::from_secs(60*60*24);
-
-        #[derive(Serialize)]
-        struct SpanQueryFormatReq {
-            start_year: i32,
-            start_month: u32,
-            start_day: u32,
-            start_hour: String,
-            start_minute: String,
-            end_year: i32,
-            end_month: u32,
-            end_day: u32,
-            end_hour: String,
-            end_minute: String,
-        }
-
-        let mut client = reqwest::blocking::Client::new();
-
-        let mut resp: reqwest::blocking::Response  = client.post("http://localhost:3000/spanquery")
-            .json(&SpanQueryFormatReq{
-                start_year: start_time.year(),
-                start_month: start_time.month(),
-                start_day: start_time.day(),
-                start_hour: fmt_two_digit(start_time.hour()),
-                start_minute: fmt_two_digit(start_time.minute()),
-                end_year: end_time.year(),
-                end_month: end_time.month(),
-                end_day: end_time.day(),
-                end_hour: fmt_two_digit(end_time.hour()),
-                end_minute: fmt_two_digit(end_time.minute()),
-            }).send().unwrap();
-
-        let mut resp_text = resp.text().unwrap();
-
-        let resp_obj: SWBSPayload =
-            serde_json::from_str(resp_text.as_str()).unwrap();
-
-        let mut trace_ids = resp_obj.traces.into_iter()
-            .map(|bs| bs.traceIds[0].clone()).collect::<Vec<String>>();
-
-        #[derive(Serialize)]
-        struct TraceQueryPayload {
-            traceIds: Vec<String>
-        }
-
-        client = reqwest::blocking::Client::new();
-
-        let mut traces: Vec<SWResult> = Vec::new();
-        
-        let loop_iters = ((trace_ids.len() as f64)/1000.0).ceil() as u64;
-        let mut i = 0;
-
-        loop {
-            println!("Trace retrieval loop {}/{}", i, loop_iters);
-            i += 1;
-            
-            if trace_ids.len() == 0 {
-                break;
+        // Query each service separately so that low-traffic services are not crowded out
+        let services = match self.query_services(SW_OPERATIONS_LOOKBACK) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to get SkyWalking services: {:?}", e);
+                return Vec::new();
             }
-
-            let mut min_range_end = 1000;
-            if trace_ids.len() < 1000 {
-                min_range_end = trace_ids.len();
+        };
+        let mut trace_ids = Vec::new();
+        for service in services {
+            match self.query_trace_ids(SW_OPERATIONS_LOOKBACK, Some(&service.key)) {
+                Ok(mut ids) => trace_ids.append(&mut ids),
+                Err(e) => eprintln!("Failed to list traces of {}: {:?}", service.label, e),
             }
-            let cur_trace_ids = trace_ids.drain(..min_range_end).collect::<Vec<String>>();
-
-            resp = client.post("http://localhost:3000/traces")
-                .json(&TraceQueryPayload{
-                    traceIds: cur_trace_ids
-                })
-                .timeout(Duration::from_secs(120))
-                .send().unwrap();
-
-            resp_text = resp.text().unwrap();
-
-            let mut traces_payload: SWPayload =
-                serde_json::from_str(resp_text.as_str()).unwrap();
-
-            traces.append(&mut (traces_payload.data));
         }
+        let trace_ids = trace_ids.into_iter().unique().collect::<Vec<String>>();
 
-        let traces_payload: SWPayload =
-            serde_json::from_str(resp_text.as_str()).unwrap();
-
-        let traces = traces_payload.data;
+        let traces = self.fetch_traces(trace_ids);
 
         let mut rt_set = HashSet::new();
 
@@ -626,50 +329,39 @@ impl Reader for SWReader {
 
         // rt_set.into_iter().map(|rt_str: String| RequestType::SW(SWRequestType{ rt: rt_str }))
         //     .collect::<Vec<RequestType>>()
-        
+
         let mut with_generics = HashSet::new();
         let mut without_generics = HashSet::new();
         for rt in rt_set {
             let num_generics = (rt.split("/{").collect::<Vec<&str>>().len() - 1) as u64;
-            
+
             if num_generics > 0 {
                 with_generics.insert(rt);
             } else {
                 without_generics.insert(rt);
             }
         }
-        
+
         let mut to_return = with_generics.clone();
         let mut generic_prefixes = HashSet::new();
-        // for rt in &with_generics {
-        //     generic_prefixes.insert(
-        //         (rt.split("/{").collect::<Vec<&str>>()[0], rt.clone()));
-        // }
 
         for rt in &with_generics {
             generic_prefixes.insert(rt.split("/{").collect::<Vec<&str>>()[0],);
         }
-        
+
         for rt in without_generics {
             let mut contains_generic = false;
-            // let mut generic = "".to_string();
             for gp in &generic_prefixes {
                 if rt.contains(gp) {
                     contains_generic = true;
-                    // generic = g.clone();
                     break;
                 }
             }
-            // if contains_generic {
-            //     to_return.insert(generic);
-            // } else {
-            //     to_return.insert(rt);
-            // }
             if !contains_generic {
                 to_return.insert(rt);
             }
         }
-        
+
         self.operations = to_return.clone().into_iter().collect();
         self.op_prefixes = self.operations.clone().into_iter().map(|op| {
             (op.split("/{").collect::<Vec<&str>>()[0].to_string(), op)
@@ -690,7 +382,7 @@ impl Reader for SWReader {
 
 impl SWReader {
     pub fn from_settings(settings: &Settings) -> SWReader {
-        let mut to_return = SWReader{
+        SWReader{
             fetch_url: settings.skywalking_url.clone(),
             problem_type: settings.problem_type.clone(),
             for_searchspace: false,
@@ -699,9 +391,325 @@ impl SWReader {
             span_cache: SpanCache::init_cache(),
             operations: Vec::new(),
             op_prefixes: Vec::new(),
-        };
-        
+        }
+    }
+
+    fn graphql<T: DeserializeOwned>(&self, query: String, variables: serde_json::Value)
+        -> Result<T, Box<dyn Error>> {
+        let client = reqwest::blocking::Client::new();
+        let resp = client.post(format!("{}/graphql", self.fetch_url))
+            .json(&json!({ "query": query, "variables": variables }))
+            .timeout(SW_QUERY_TIMEOUT)
+            .send()?;
+        if !resp.status().is_success() {
+            return Err(Box::new(PythiaError(
+                format!("SkyWalking returned {}", resp.status()))));
+        }
+        let resp_obj: GraphQLResponse<T> = resp.json()?;
+        if !resp_obj.errors.is_empty() {
+            return Err(Box::new(PythiaError(
+                resp_obj.errors.into_iter().map(|e| e.message).join("; "))));
+        }
+        resp_obj.data.ok_or_else(|| -> Box<dyn Error> {
+            Box::new(PythiaError("SkyWalking returned no data".to_string()))
+        })
+    }
+
+    /// SkyWalking durations are in the OAP server's local time, at minute granularity
+    fn query_duration(start: DateTime<Local>, end: DateTime<Local>) -> serde_json::Value {
+        json!({
+            "start": start.format("%Y-%m-%d %H%M").to_string(),
+            "end": end.format("%Y-%m-%d %H%M").to_string(),
+            "step": "MINUTE"
+        })
+    }
+
+    fn query_services(&self, lookback: Duration) -> Result<Vec<SWService>, Box<dyn Error>> {
+        let end_time = Local::now();
+        let resp: SWServicesData = self.graphql(SERVICES_QUERY.to_string(), json!({
+            "duration": Self::query_duration(end_time - lookback, end_time),
+            "keyword": ""
+        }))?;
+        Ok(resp.services)
+    }
+
+    /// Lists the IDs of the traces that started within `lookback` of now (optionally only those
+    /// of `service_id`), walking back one window at a time and paging through each window
+    fn query_trace_ids(&self, lookback: Duration, service_id: Option<&str>)
+        -> Result<Vec<String>, Box<dyn Error>> {
+        let end_time = Local::now();
+        let start_time = end_time - lookback;
+        let mut seen = HashSet::new();
+        let mut trace_ids = Vec::new();
+
+        let mut window_end = end_time;
+        while window_end > start_time {
+            let window_start = std::cmp::max(window_end - SW_QUERY_WINDOW, start_time);
+            let mut page_num = 1;
+            loop {
+                let mut condition = json!({
+                    "queryDuration": Self::query_duration(window_start, window_end),
+                    "traceState": "ALL",
+                    "paging": {
+                        "pageNum": page_num,
+                        "pageSize": SW_PAGE_SIZE,
+                        "needTotal": true
+                    },
+                    "queryOrder": "BY_DURATION"
+                });
+                if let Some(id) = service_id {
+                    condition["serviceId"] = json!(id);
+                }
+                let resp: SWBasicTracesData = self.graphql(
+                    BASIC_TRACES_QUERY.to_string(), json!({ "condition": condition }))?;
+                let page_len = resp.trace_data.traces.len();
+                for basic_trace in resp.trace_data.traces {
+                    if let Some(id) = basic_trace.traceIds.into_iter().next() {
+                        if seen.insert(id.clone()) {
+                            trace_ids.push(id);
+                        }
+                    }
+                }
+                if page_len < SW_PAGE_SIZE ||
+                    (page_num * SW_PAGE_SIZE) as u64 >= resp.trace_data.total {
+                    break;
+                }
+                page_num += 1;
+            }
+            window_end = window_start;
+        }
+
+        Ok(trace_ids)
+    }
+
+    /// Fetches the given traces with one aliased queryTrace per trace ID
+    fn query_traces(&self, trace_ids: &[String]) -> Result<Vec<SWResult>, Box<dyn Error>> {
+        let header = (0..trace_ids.len())
+            .map(|i| format!("$traceId{}: ID!", i)).join(", ");
+        let body = (0..trace_ids.len())
+            .map(|i| format!("res{}: queryTrace(traceId: $traceId{}) {{ {} }}", i, i, TRACE_FIELDS))
+            .join(" ");
+        let variables: serde_json::Map<String, serde_json::Value> = trace_ids.iter().enumerate()
+            .map(|(i, id)| (format!("traceId{}", i), json!(id))).collect();
+        let resp: HashMap<String, SWResult> = self.graphql(
+            format!("query multiResult({}) {{ {} }}", header, body),
+            serde_json::Value::Object(variables))?;
+        Ok(resp.into_values().map(|mut trace| {
+            for span in &mut trace.spans {
+                span.normalize_peer();
+            }
+            trace
+        }).collect())
+    }
+
+    fn fetch_traces(&self, trace_ids: Vec<String>) -> Vec<SWResult> {
+        let mut traces = Vec::new();
+        let loop_iters = ((trace_ids.len() as f64) / (SW_TRACE_BATCH_SIZE as f64)).ceil() as u64;
+        for (i, batch) in trace_ids.chunks(SW_TRACE_BATCH_SIZE).enumerate() {
+            println!("Trace retrieval loop {}/{}", i + 1, loop_iters);
+            match self.query_traces(batch) {
+                Ok(mut t) => traces.append(&mut t),
+                Err(e) => eprintln!("Failed to fetch SkyWalking traces: {:?}", e),
+            }
+        }
+        traces
+    }
+
+    fn build_span_traces(&mut self, traces: Vec<SWResult>) -> Vec<SpanTrace> {
+        let mut to_return = Vec::new();
+
+        for trace in traces {
+            let mut spans = trace.spans.into_iter()
+                .map(|s| s.to_span()).collect::<Vec<Span>>();
+            let root_span = match SpanTrace::resolve_references(&mut spans) {
+                Some(idx) => spans[idx].clone(),
+                None => continue,
+            };
+
+            // Span IDs are <segment ID>.<span ID>; the root's segment ID identifies the trace
+            let root_id_parts = root_span.span_id.split(".").collect::<Vec<&str>>();
+            let trace_id = root_id_parts[..root_id_parts.len() - 1].iter().join(".");
+
+            // Cache the spans after from_span_list has corrected their clock skew
+            let span_trace = SpanTrace::from_span_list(
+                spans,
+                format!("{}:{}", root_span.service, root_span.operation),
+                root_span.span_id,
+                trace_id,
+                &ApplicationType::SkyWalking,
+            );
+            self.span_cache.add_trace(&span_trace);
+            to_return.push(span_trace);
+        }
+
         to_return
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Serves one canned GraphQL response per request, in order, and keeps the request bodies
+    fn graphql_server(
+        responses: Vec<serde_json::Value>,
+    ) -> (String, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                received.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                let response = json!({ "data": response }).to_string();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn reader(fetch_url: String) -> SWReader {
+        SWReader {
+            fetch_url,
+            problem_type: RequestType::Unknown,
+            fetch_all: false,
+            for_searchspace: false,
+            cycle_lookback: 0,
+            span_cache: SpanCache::init_cache(),
+            operations: Vec::new(),
+            op_prefixes: Vec::new(),
+        }
+    }
+
+    fn trace_page(ids: Vec<String>, total: usize) -> serde_json::Value {
+        let traces: Vec<_> = ids.into_iter().map(|id| json!({ "traceIds": [id] })).collect();
+        json!({ "traceData": { "traces": traces, "total": total } })
+    }
+
+    #[test]
+    fn lists_trace_ids_by_window_and_page() {
+        let full_page: Vec<String> = (0..SW_PAGE_SIZE).map(|i| format!("a{}", i)).collect();
+        let (url, requests) = graphql_server(vec![
+            // The latest window has one more trace than fits a page
+            trace_page(full_page, SW_PAGE_SIZE + 1),
+            trace_page(vec!["b".into()], SW_PAGE_SIZE + 1),
+            // Traces that span two windows are listed in both
+            trace_page(vec!["b".into(), "c".into()], 2),
+            trace_page(Vec::new(), 0),
+        ]);
+        // Two and a half windows
+        let lookback = SW_QUERY_WINDOW * 5 / 2;
+        let ids = reader(url).query_trace_ids(lookback, Some("svc-1")).unwrap();
+        assert_eq!(ids.len(), SW_PAGE_SIZE + 2);
+        assert_eq!(ids[SW_PAGE_SIZE..].to_vec(), vec!["b".to_string(), "c".to_string()]);
+
+        let requests = requests.lock().unwrap();
+        let conditions: Vec<&serde_json::Value> =
+            requests.iter().map(|r| &r["variables"]["condition"]).collect();
+        let pages: Vec<u64> = conditions
+            .iter()
+            .map(|c| c["paging"]["pageNum"].as_u64().unwrap())
+            .collect();
+        assert_eq!(pages, vec![1, 2, 1, 1]);
+        assert!(conditions.iter().all(|c| c["serviceId"] == "svc-1"));
+        // Each window ends where the one after it starts
+        assert_eq!(conditions[0]["queryDuration"], conditions[1]["queryDuration"]);
+        for pair in conditions[1..].windows(2) {
+            assert_eq!(pair[1]["queryDuration"]["end"], pair[0]["queryDuration"]["start"]);
+        }
+    }
+
+    #[test]
+    fn fetches_traces_with_aliased_queries() {
+        let span = |trace: &str, peer: &str| {
+            json!({"traceId": trace, "segmentId": format!("{}-s", trace), "spanId": 0,
+                   "parentSpanId": -1, "serviceCode": "ts-order-service", "startTime": 1000,
+                   "endTime": 1050, "endpointName": "/order", "type": "Exit", "peer": peer,
+                   "component": null, "isError": false, "layer": "Http", "refs": []})
+        };
+        let (url, requests) = graphql_server(vec![json!({
+            "res0": { "spans": [span("t0", "ts-user-service:8080")] },
+            "res1": { "spans": [span("t1", "mysql:3306")] },
+        })]);
+        let traces = reader(url).query_traces(&["t0".to_string(), "t1".to_string()]).unwrap();
+
+        let request = &requests.lock().unwrap()[0];
+        let query = request["query"].as_str().unwrap();
+        assert!(query.starts_with("query multiResult($traceId0: ID!, $traceId1: ID!)"));
+        assert!(query.contains("res1: queryTrace(traceId: $traceId1)"));
+        assert_eq!(request["variables"], json!({ "traceId0": "t0", "traceId1": "t1" }));
+
+        let mut spans: Vec<&SWSpan> = traces.iter().flat_map(|t| t.spans.iter()).collect();
+        spans.sort_by_key(|s| s.traceId.clone());
+        // Peers lose their port, and TrainTicket services take the exit span
+        assert_eq!(spans[0].serviceCode, "ts-user-service");
+        assert_eq!(spans[1].serviceCode, "ts-order-service");
+        assert_eq!(spans[1].peer.as_deref(), Some("mysql"));
+    }
+
+    #[test]
+    fn cross_thread_references_follow_from() {
+        let result: SWResult = serde_json::from_str(
+            r#"{"spans": [
+                {"traceId": "t1", "segmentId": "s1", "spanId": 0, "parentSpanId": -1,
+                 "serviceCode": "ts-order-service", "startTime": 1000, "endTime": 1050,
+                 "endpointName": "/order", "type": "Entry", "peer": null, "component": null,
+                 "isError": false, "layer": "Http", "refs": []},
+                {"traceId": "t1", "segmentId": "s1", "spanId": 1, "parentSpanId": 0,
+                 "serviceCode": "ts-order-service", "startTime": 1010, "endTime": 1020,
+                 "endpointName": "submit", "type": "Local", "peer": null, "component": null,
+                 "isError": false, "layer": null, "refs": []},
+                {"traceId": "t1", "segmentId": "s2", "spanId": 0, "parentSpanId": -1,
+                 "serviceCode": "ts-order-service", "startTime": 1015, "endTime": 1080,
+                 "endpointName": "notify", "type": "Local", "peer": null, "component": null,
+                 "isError": false, "layer": null,
+                 "refs": [{"traceId": "t1", "parentSegmentId": "s1", "parentSpanId": 1,
+                           "type": "CROSS_THREAD"}]},
+                {"traceId": "t1", "segmentId": "s3", "spanId": 0, "parentSpanId": -1,
+                 "serviceCode": "ts-user-service", "startTime": 1012, "endTime": 1018,
+                 "endpointName": "/user", "type": "Entry", "peer": null, "component": null,
+                 "isError": false, "layer": "Http",
+                 "refs": [{"traceId": "t1", "parentSegmentId": "s1", "parentSpanId": 1,
+                           "type": "CROSS_PROCESS"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let mut spans: Vec<Span> = result.spans.iter().map(|s| s.to_span()).collect();
+        let root = SpanTrace::resolve_references(&mut spans).unwrap();
+        assert_eq!(spans[root].span_id, "s1.0");
+        assert_eq!(spans[1].parent, "s1.0");
+        assert_eq!(spans[1].parent_ref_type(), EdgeType::ChildOf);
+        assert_eq!(spans[2].parent, "s1.1");
+        assert_eq!(spans[2].parent_ref_type(), EdgeType::FollowsFrom);
+        assert_eq!(spans[3].parent, "s1.1");
+        assert_eq!(spans[3].parent_ref_type(), EdgeType::ChildOf);
+    }
+}
@@ -0,0 +1,162 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! A Reader serving traces from the local trace store.
+//!
+//! Stored traces are handed out in the order they started, `STORE_BATCH_SIZE` per call to
+//! `get_recent_traces`/`get_recent_span_traces`, so the controller can be run over history.
+//! Traces stored as span traces come out of `get_recent_span_traces`, the others out of
+//! `get_recent_traces`. Like replays, all readers of the same store within a process share one
+//! stream, so the worker threads of the controller do not see a trace twice.
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use pythia_common::RequestType;
+
+use crate::reader::{saved_traces_only, ParsedSpanTraces, Reader, TraceFileReader};
+use crate::spantrace::{SpanCache, SpanTrace};
+use crate::store::{Query, StoredTrace, TraceStore};
+use crate::trace::Trace;
+
+/// Traces returned per call
+const STORE_BATCH_SIZE: usize = 100;
+
+struct StoreState {
+    store: TraceStore,
+    traces: VecDeque<String>,
+    span_traces: VecDeque<String>,
+}
+
+lazy_static! {
+    static ref STORES: Mutex<HashMap<PathBuf, Arc<Mutex<StoreState>>>> = Mutex::new(HashMap::new());
+}
+
+pub struct StoreReader {
+    state: Arc<Mutex<StoreState>>,
+    span_cache: SpanCache,
+}
+
+impl StoreReader {
+    pub fn open(dir: &Path) -> StoreReader {
+        let state = STORES
+            .lock()
+            .unwrap()
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| {
+                let store = TraceStore::open(dir).expect("Could not open the trace store");
+                let (spans, traces): (Vec<_>, Vec<_>) = store
+                    .query(&Query::default())
+                    .into_iter()
+                    .partition(|s| s.is_span_trace);
+                eprintln!(
+                    "Serving {} traces and {} span traces from {}",
+                    traces.len(),
+                    spans.len(),
+                    dir.display()
+                );
+                let traces = traces.iter().map(|s| s.id.clone()).collect();
+                let span_traces = spans.iter().map(|s| s.id.clone()).collect();
+                Arc::new(Mutex::new(StoreState {
+                    store,
+                    traces,
+                    span_traces,
+                }))
+            })
+            .clone();
+        StoreReader {
+            state,
+            span_cache: SpanCache::init_cache(),
+        }
+    }
+
+    fn next_batch(state: &mut StoreState, span_traces: bool) -> Vec<StoredTrace> {
+        let mut batch = Vec::new();
+        while batch.len() < STORE_BATCH_SIZE {
+            let queue = if span_traces {
+                &mut state.span_traces
+            } else {
+                &mut state.traces
+            };
+            let id = match queue.pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            match state.store.get(&id) {
+                Ok(t) => batch.push(t),
+                Err(e) => eprintln!("Reading stored trace {} failed with {:?}", id, e),
+            }
+        }
+        batch
+    }
+}
+
+impl Reader for StoreReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        self.read_first_trace(filename)
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        self.read_every_file(foldername)
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        self.state.lock().unwrap().store.get_trace(id)
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        Self::next_batch(&mut self.state.lock().unwrap(), false)
+            .iter()
+            .map(|t| t.to_trace())
+            .collect()
+    }
+
+    fn get_recent_span_traces(&mut self) -> Vec<SpanTrace> {
+        let mut result = Vec::new();
+        for stored in Self::next_batch(&mut self.state.lock().unwrap(), true) {
+            if let StoredTrace::SpanTrace(trace) = stored {
+                self.span_cache.add_trace(&trace);
+                result.push(trace);
+            }
+        }
+        result
+    }
+
+    fn reset_state(&mut self) {}
+
+    fn for_searchspace(&mut self) {}
+
+    fn all_operations(&mut self) -> Vec<RequestType> {
+        self.state
+            .lock()
+            .unwrap()
+            .store
+            .request_types()
+            .into_iter()
+            .map(|(rt, _)| rt)
+            .collect()
+    }
+
+    fn set_fetch_all(&mut self) {}
+
+    fn get_candidate_events(&self, start: u64, end: u64, host: String) -> Vec<(String, String)> {
+        self.span_cache.find_overlaps_raw(start, end, host)
+    }
+}
+
+impl TraceFileReader for StoreReader {
+    fn parse_span_traces(&mut self, _contents: &str) -> Result<ParsedSpanTraces, Box<dyn Error>> {
+        saved_traces_only()
+    }
+
+    fn reads_for_searchspace(&self) -> bool {
+        false
+    }
+}
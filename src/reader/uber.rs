@@ -10,7 +10,7 @@ use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use chrono::Duration;
 use chrono::NaiveDateTime;
@@ -120,8 +120,7 @@ impl UberReader {
     }
 
     fn try_read_file(&mut self, filename: &str) -> Result<Trace, Box<dyn Error>> {
-        let reader = std::fs::File::open(filename).unwrap();
-        match serde_json::from_reader(reader) {
+        match Trace::from_file(Path::new(filename)) {
             // We either have a saved file, or saved xtrace output
             Ok(v) => Ok(v),
             Err(_) => {
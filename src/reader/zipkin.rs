@@ -6,101 +6,430 @@ Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
 All rights reserved.
 */
 
-use std::collections::HashMap;
+//! Reader for the Zipkin v2 JSON API.
+//!
+//! Traces are fetched from `/api/v2/traces` and `/api/v2/trace/{id}` and converted into
+//! `SpanTrace`s, which are then turned into `Trace`s through `SpanTrace::to_critical_path`.
+
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use futures::Sink;
-use hyper::http;
+use std::time::{Duration, SystemTime};
+
+use chrono::DateTime;
+use pythia_common::zipkin::ZipkinRequestType;
 use pythia_common::RequestType;
-use crate::reader::Reader;
-use crate::{Settings, Trace};
-use crate::spantrace::{Span, SpanTrace};
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+
+use crate::reader::{ParsedSpanTraces, Reader, TraceFileReader};
+use crate::settings::ApplicationType;
+use crate::spantrace::{Span, SpanCache, SpanLog, SpanReference, SpanTrace};
+use crate::trace::{EdgeType, Value};
+use crate::{PythiaError, Settings, Trace};
+
+/// Zipkin returns only 10 traces per query by default
+const ZIPKIN_QUERY_LIMIT: usize = 100000;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ZipkinEndpoint {
+    service_name: Option<String>,
+    ipv4: Option<String>,
+    ipv6: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ZipkinAnnotation {
+    /// Epoch microseconds
+    timestamp: i64,
+    value: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ZipkinSpan {
+    trace_id: String,
+    id: String,
+    parent_id: Option<String>,
+    name: Option<String>,
+    kind: Option<String>,
+    /// Epoch microseconds
+    timestamp: Option<i64>,
+    /// Microseconds
+    duration: Option<u64>,
+    local_endpoint: Option<ZipkinEndpoint>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    #[serde(default)]
+    annotations: Vec<ZipkinAnnotation>,
+    #[serde(default)]
+    shared: bool,
+}
+
+impl ZipkinSpan {
+    /// Zipkin lets the server side of an RPC reuse the client's span ID (marked as `shared`),
+    /// so we give it a distinct ID and make it a child of the client span.
+    fn span_id(&self) -> String {
+        if self.shared && self.kind.as_deref() == Some("SERVER") {
+            format!("{}:server", self.id)
+        } else {
+            self.id.clone()
+        }
+    }
+
+    fn parent_id(&self) -> String {
+        if self.shared && self.kind.as_deref() == Some("SERVER") {
+            self.id.clone()
+        } else {
+            self.parent_id.clone().unwrap_or_default()
+        }
+    }
+
+    pub fn to_span(&self) -> Span {
+        let (service, host) = match &self.local_endpoint {
+            Some(ep) => (
+                ep.service_name.clone().unwrap_or_default(),
+                ep.ipv4
+                    .clone()
+                    .or_else(|| ep.ipv6.clone())
+                    .or_else(|| ep.service_name.clone())
+                    .unwrap_or_default(),
+            ),
+            None => ("".to_string(), "".to_string()),
+        };
+        let parent = self.parent_id();
+        // Zipkin has no follows-from references, but messaging consumers run asynchronously
+        // from the producer they are parented to
+        let references = match parent.as_str() {
+            "" => Vec::new(),
+            _ => vec![SpanReference {
+                span_id: parent.clone(),
+                ref_type: match self.kind.as_deref() {
+                    Some("CONSUMER") => EdgeType::FollowsFrom,
+                    _ => EdgeType::ChildOf,
+                },
+            }],
+        };
+        Span {
+            span_id: self.span_id(),
+            parent,
+            references,
+            service,
+            host,
+            operation: self.name.clone().unwrap_or_default(),
+            start: DateTime::from_timestamp_nanos(self.timestamp.unwrap_or(0) * 1000).naive_utc(),
+            duration: Duration::from_micros(self.duration.unwrap_or(0)),
+            kind: self.kind.clone(),
+            tags: self
+                .tags
+                .iter()
+                .map(|(k, v)| (k.clone(), Value::Str(v.clone())))
+                .collect(),
+            logs: self
+                .annotations
+                .iter()
+                .map(|a| SpanLog {
+                    timestamp: DateTime::from_timestamp_nanos(a.timestamp * 1000).naive_utc(),
+                    fields: HashMap::from([("event".to_string(), Value::Str(a.value.clone()))]),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Converts the spans of a single trace into a SpanTrace
+fn to_span_trace(zspans: &[ZipkinSpan], cache: &mut SpanCache) -> Result<SpanTrace, Box<dyn Error>> {
+    if zspans.is_empty() {
+        return Err(Box::new(PythiaError("Empty Zipkin trace".to_string())));
+    }
+    let spans = zspans.iter().map(|s| s.to_span()).collect();
+    SpanTrace::from_spans(zspans[0].trace_id.clone(), spans, &ApplicationType::Zipkin, cache)
+}
+
+/// The Zipkin files we accept besides saved Traces: the output of `/api/v2/trace/{id}` (a list
+/// of spans), or the output of `/api/v2/traces` (a list of traces).
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ZipkinFile {
+    Spans(Vec<ZipkinSpan>),
+    Traces(Vec<Vec<ZipkinSpan>>),
+}
 
 pub struct ZipkinReader {
-    // connection: ZipkinConnection // TODO: implement this
-    fetch_url: String
+    fetch_url: String,
+    problem_type: RequestType,
+    fetch_all: bool,
+    for_searchspace: bool,
+    cycle_lookback: u128,
+    span_cache: SpanCache,
 }
 
 impl Reader for ZipkinReader {
     fn all_operations(&mut self) -> Vec<RequestType> {
-        Vec::new()
+        let mut to_return = Vec::new();
+        let services: Vec<String> =
+            match self.get_json(&format!("{}/api/v2/services", self.fetch_url)) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Failed to get Zipkin services: {:?}", e);
+                    return to_return;
+                }
+            };
+        for service in services {
+            let operations: Vec<String> = match self.get_json(&format!(
+                "{}/api/v2/spans?serviceName={}",
+                self.fetch_url,
+                form_urlencoded::byte_serialize(service.as_bytes()).collect::<String>()
+            )) {
+                Ok(o) => o,
+                Err(e) => {
+                    eprintln!("Failed to get operations of {}: {:?}", service, e);
+                    continue;
+                }
+            };
+            for operation in operations {
+                to_return.push(RequestType::Zipkin(ZipkinRequestType {
+                    rt: format!("{}:{}", service, operation),
+                }));
+            }
+        }
+        to_return
     }
 
-    fn set_fetch_all(&mut self) {}
+    fn set_fetch_all(&mut self) {
+        self.fetch_all = true;
+    }
 
     fn get_recent_span_traces(&mut self) -> Vec<SpanTrace> {
-        return Vec::new();
+        if self.fetch_all {
+            let services: Vec<String> =
+                match self.get_json(&format!("{}/api/v2/services", self.fetch_url)) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        eprintln!("Failed to get Zipkin services: {:?}", e);
+                        return Vec::new();
+                    }
+                };
+            let mut seen = HashSet::new();
+            let mut to_return = Vec::new();
+            for service in services {
+                for trace in self.get_span_traces(&service, None) {
+                    if seen.insert(trace.req_id.clone()) {
+                        to_return.push(trace);
+                    }
+                }
+            }
+            to_return
+        } else {
+            let problem_type = self.problem_type.to_string();
+            let mut parts = problem_type.splitn(2, ':');
+            let service = parts.next().unwrap().to_string();
+            let operation = parts.next().map(|s| s.to_string());
+            self.get_span_traces(&service, operation)
+        }
     }
 
     fn read_file(&mut self, filename: &str) -> Trace {
-        todo!()
+        self.read_first_trace(filename)
     }
 
     fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
-        todo!()
+        self.read_every_file(foldername)
+    }
+
+    fn read_span_traces(&mut self, path: &str) -> Result<Vec<SpanTrace>, Box<dyn Error>> {
+        self.read_span_trace_files(path)
     }
 
     fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
-        // eprintln!("Working on {}", id);
-        // let mut result = match Uuid::parse_str(id) {
-        //     Ok(uuid) => {
-        //         let event_list = self.get_all_matches(&uuid);
-        //         if event_list.len() == 0 {
-        //             return Err(Box::new(PythiaError(
-        //                 format!("No traces match the uuid {}", uuid).into(),
-        //             )));
-        //         }
-        //         let dag = self.from_event_list(Uuid::parse_str(id).unwrap(), event_list)?;
-        //         dag
-        //     }
-        //     Err(_) => {
-        //         panic!("Malformed UUID received as base ID: {}", id);
-        //     }
-        // };
-        // if result.request_type == RequestType::Unknown {
-        //     eprintln!("Warning: couldn't get type for request {}", id);
-        // }
-        // result.duration = (result.g[result.end_node].timestamp
-        //     - result.g[result.start_node].timestamp)
-        //     .to_std()
-        //     .unwrap();
-        // Ok(result)
-        todo!()
-    }
-
-    // #[tokio:main]
+        let spans: Vec<ZipkinSpan> =
+            self.get_json(&format!("{}/api/v2/trace/{}", self.fetch_url, id))?;
+        let st = to_span_trace(&spans, &mut self.span_cache)?;
+        Ok(st.to_reader_trace(self.for_searchspace))
+    }
+
     fn get_recent_traces(&mut self) -> Vec<Trace> {
-        // let mut ids = Vec::new();
+        self.get_recent_span_traces()
+            .iter()
+            .map(|st| st.to_reader_trace(self.for_searchspace))
+            .collect()
+    }
 
-        let mut traces: HashMap<String, Vec<Span>> = HashMap::new();
+    fn reset_state(&mut self) {}
 
-        // let resp: reqwest::blocking::Response = reqwest::blocking::get("https://httpbin.org/ip").unwrap();
-        let resp: reqwest::blocking::Response =
-            reqwest::blocking::get(self.fetch_url.clone())
-                .unwrap();
-        // match resp {
-        //     Ok()
-        // }
+    fn for_searchspace(&mut self) {
+        self.for_searchspace = true;
+    }
 
-        eprintln!("RESPONSE = {:?}", resp.text());
+    fn get_candidate_events(&self, start: u64, end: u64, host: String) -> Vec<(String, String)> {
+        self.span_cache.find_overlaps_raw(start, end, host)
+    }
+}
 
-        return Vec::new();
+impl ZipkinReader {
+    pub fn from_settings(settings: &Settings) -> ZipkinReader {
+        ZipkinReader {
+            fetch_url: settings.zipkin_url.clone(),
+            problem_type: settings.problem_type.clone(),
+            fetch_all: false,
+            for_searchspace: false,
+            cycle_lookback: settings.cycle_lookback,
+            span_cache: SpanCache::init_cache(),
+        }
     }
 
-    fn reset_state(&mut self) {
-        // TODO
-        return
+    fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, Box<dyn Error>> {
+        let resp = reqwest::blocking::get(url)?;
+        if !resp.status().is_success() {
+            return Err(Box::new(PythiaError(format!(
+                "Zipkin returned {} for {}",
+                resp.status(),
+                url
+            ))));
+        }
+        Ok(serde_json::from_str(resp.text()?.as_str())?)
     }
 
-    fn for_searchspace(&mut self) {
-        // TODO
-        return
+    /// Get the traces of the last `cycle_lookback` microseconds that pass through `service`
+    /// (and `operation`, if given)
+    fn get_span_traces(&mut self, service: &str, operation: Option<String>) -> Vec<SpanTrace> {
+        let end_ts = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let query_str = format!(
+            "{}/api/v2/traces?serviceName={}{}&endTs={}&lookback={}&limit={}",
+            self.fetch_url,
+            form_urlencoded::byte_serialize(service.as_bytes()).collect::<String>(),
+            match operation {
+                Some(s) => format!(
+                    "&spanName={}",
+                    form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>()
+                ),
+                None => "".to_string(),
+            },
+            end_ts,
+            self.cycle_lookback / 1000,
+            ZIPKIN_QUERY_LIMIT
+        );
+        let traces: Vec<Vec<ZipkinSpan>> = match self.get_json(&query_str) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Zipkin query {} failed with {:?}", query_str, e);
+                return Vec::new();
+            }
+        };
+        traces
+            .iter()
+            .filter_map(|t| match to_span_trace(t, &mut self.span_cache) {
+                Ok(st) => Some(st),
+                Err(e) => {
+                    eprintln!("Skipping Zipkin trace: {:?}", e);
+                    None
+                }
+            })
+            .collect()
     }
 }
 
-impl ZipkinReader {
-    pub fn from_settings(settings: &Settings) -> ZipkinReader {
-        return ZipkinReader{
-            fetch_url: settings.zipkin_url.clone(),
+impl TraceFileReader for ZipkinReader {
+    fn parse_span_traces(&mut self, contents: &str) -> Result<ParsedSpanTraces, Box<dyn Error>> {
+        let traces = match serde_json::from_str(contents)? {
+            ZipkinFile::Spans(spans) => vec![spans],
+            ZipkinFile::Traces(traces) => traces,
+        };
+        Ok(traces
+            .iter()
+            .map(|spans| to_span_trace(spans, &mut self.span_cache))
+            .collect())
+    }
+
+    fn reads_for_searchspace(&self) -> bool {
+        self.for_searchspace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::critical::CriticalPathAlgorithm;
+    use crate::validate::checked_span_critical_path;
+    use petgraph::visit::EdgeRef;
+
+    const TRACES: &str = r#"[
+        [
+            {"traceId": "t1", "id": "a", "name": "get", "kind": "SERVER", "timestamp": 1000000,
+             "duration": 30000, "localEndpoint": {"serviceName": "frontend", "ipv4": "10.0.0.1"}},
+            {"traceId": "t1", "id": "b", "parentId": "a", "name": "lookup", "kind": "CLIENT",
+             "timestamp": 1002000, "duration": 8000,
+             "localEndpoint": {"serviceName": "frontend", "ipv4": "10.0.0.1"}},
+            {"traceId": "t1", "id": "b", "name": "lookup", "kind": "SERVER", "shared": true,
+             "timestamp": 1003000, "duration": 6000, "tags": {"table": "users"},
+             "localEndpoint": {"serviceName": "db", "ipv4": "10.0.0.2"}},
+            {"traceId": "t1", "id": "c", "parentId": "a", "name": "consume", "kind": "CONSUMER",
+             "timestamp": 1012000, "duration": 10000,
+             "annotations": [{"timestamp": 1015000, "value": "done"}],
+             "localEndpoint": {"serviceName": "worker"}}
+        ],
+        []
+    ]"#;
+
+    fn reader() -> ZipkinReader {
+        ZipkinReader {
+            fetch_url: String::new(),
+            problem_type: RequestType::Unknown,
+            fetch_all: false,
+            for_searchspace: true,
+            cycle_lookback: 0,
+            span_cache: SpanCache::init_cache(),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn converts_spans() {
+        let traces: Vec<Vec<ZipkinSpan>> = serde_json::from_str(TRACES).unwrap();
+        let spans: Vec<Span> = traces[0].iter().map(|s| s.to_span()).collect();
+        // The server half of the shared span becomes a child of the client half
+        assert_eq!((spans[2].span_id.as_str(), spans[2].parent.as_str()), ("b:server", "b"));
+        assert_eq!((spans[2].service.as_str(), spans[2].host.as_str()), ("db", "10.0.0.2"));
+        assert_eq!(spans[2].duration, Duration::from_millis(6));
+        assert_eq!(spans[2].tags["table"], Value::Str("users".to_string()));
+        // Without an address, the service stands in for the host
+        assert_eq!(spans[3].host, "worker");
+        assert_eq!(spans[3].references[0].ref_type, EdgeType::FollowsFrom);
+        assert_eq!(spans[3].logs[0].fields["event"], Value::Str("done".to_string()));
+    }
+
+    #[test]
+    fn skips_bad_traces_in_a_file() {
+        let file = std::env::temp_dir().join(format!("pythia-zipkin-{}.json", std::process::id()));
+        std::fs::write(&file, TRACES).unwrap();
+        let mut reader = reader();
+        let traces = reader.try_read_file(file.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        // The empty trace is dropped, the other one is read in full
+        assert_eq!(traces.len(), 1);
+        let trace = &traces[0];
+        assert_eq!(trace.g.node_count(), 9);
+        let consume = trace
+            .g
+            .node_indices()
+            .find(|&n| trace.g[n].tracepoint_id.to_string() == "worker:consume_start")
+            .unwrap();
+        let into_consume: Vec<_> = trace.g.edges_directed(consume, petgraph::Incoming).collect();
+        assert_eq!(into_consume.len(), 1);
+        assert_eq!(into_consume[0].weight().variant, EdgeType::FollowsFrom);
+        assert!(reader.span_cache.span_times.contains_key("10.0.0.2"));
+    }
+
+    #[test]
+    fn paths_have_the_zipkin_request_type() {
+        let traces: Vec<Vec<ZipkinSpan>> = serde_json::from_str(TRACES).unwrap();
+        let trace = to_span_trace(&traces[0], &mut SpanCache::init_cache()).unwrap();
+        let path = checked_span_critical_path(&trace, CriticalPathAlgorithm::LatestPredecessor).unwrap();
+        // As the controller parses `problem_type` for `application = "Zipkin"`
+        let problem_type = RequestType::from_str("frontend:get", "Zipkin").unwrap();
+        assert_eq!(path.request_type, problem_type);
+    }
+}
@@ -0,0 +1,314 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Embedded OTLP/HTTP receiver.
+//!
+//! Accepts spans pushed to `POST /v1/traces` by an OpenTelemetry SDK or collector, in either the
+//! JSON or the protobuf encoding (optionally gzipped). Spans are buffered per trace ID, and a trace
+//! is considered complete once no new span for it arrived for `Settings::otlp_trace_timeout`. At
+//! most `Settings::otlp_max_pending_traces` traces are buffered at once.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flate2::read::GzDecoder;
+use futures::future;
+use futures::future::Future;
+use futures::stream::Stream;
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{rt, Body, Method, Request, Response, Server, StatusCode};
+
+use crate::reader::otlp::{parse_export_request, OTLPTraceRequest};
+use crate::settings::{ApplicationType, Settings};
+use crate::spantrace::{Span, SpanCache, SpanTrace};
+use crate::PythiaError;
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+struct PendingTrace {
+    spans: Vec<Span>,
+    last_update: Instant,
+}
+
+/// Spans received so far, keyed by trace ID
+type PendingTraces = Arc<Mutex<HashMap<String, PendingTrace>>>;
+
+/// Starts the receiver on `settings.otlp_receiver_addr` in the background. Every completed
+/// trace is passed to `on_trace`.
+pub fn start_otlp_receiver<F>(settings: &Settings, on_trace: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(SpanTrace) + Send + 'static,
+{
+    let addr = match &settings.otlp_receiver_addr {
+        Some(a) => a.clone(),
+        None => {
+            return Err(Box::new(PythiaError(
+                "otlp_receiver_addr is not set".to_string(),
+            )))
+        }
+    };
+    let listener = TcpListener::bind(addr.as_str())?;
+    receive(
+        listener,
+        settings.otlp_trace_timeout,
+        settings.otlp_max_pending_traces,
+        on_trace,
+    );
+    Ok(())
+}
+
+/// Serves the receiver on `listener`, and assembles the traces of the received spans
+fn receive<F>(listener: TcpListener, timeout: Duration, max_pending: usize, mut on_trace: F)
+where
+    F: FnMut(SpanTrace) + Send + 'static,
+{
+    let pending: PendingTraces = Arc::new(Mutex::new(HashMap::new()));
+
+    let server_pending = pending.clone();
+    thread::spawn(move || {
+        rt::run(future::lazy(move || {
+            let new_service = move || {
+                let pending = server_pending.clone();
+                service_fn(move |req| handle_request(req, pending.clone(), max_pending))
+            };
+            Server::from_tcp(listener)
+                .expect("Could not use the OTLP receiver socket")
+                .serve(new_service)
+                .map_err(|e| eprintln!("OTLP receiver failed: {}", e))
+        }));
+    });
+
+    thread::spawn(move || loop {
+        thread::sleep(std::cmp::min(timeout, Duration::from_secs(1)));
+        let completed = take_completed(&mut pending.lock().unwrap(), timeout);
+        for (trace_id, trace) in completed {
+            // Nothing looks up candidate events among pushed spans, so they are not cached
+            let mut cache = SpanCache::init_cache();
+            match SpanTrace::from_spans(trace_id, trace.spans, &ApplicationType::OTLP, &mut cache) {
+                Ok(st) => on_trace(st),
+                Err(e) => eprintln!("Dropping incomplete OTLP trace: {:?}", e),
+            }
+        }
+    });
+}
+
+/// Adds received spans to their pending traces. Spans of traces that are not pending yet are
+/// dropped once `max_pending` traces are; returns how many spans were dropped.
+fn add_pending(
+    pending: &mut HashMap<String, PendingTrace>,
+    spans_by_trace: Vec<(String, Vec<Span>)>,
+    max_pending: usize,
+) -> usize {
+    let now = Instant::now();
+    let mut dropped = 0;
+    for (trace_id, mut spans) in spans_by_trace {
+        if !pending.contains_key(&trace_id) && pending.len() >= max_pending {
+            dropped += spans.len();
+            continue;
+        }
+        let trace = pending.entry(trace_id).or_insert(PendingTrace {
+            spans: Vec::new(),
+            last_update: now,
+        });
+        trace.spans.append(&mut spans);
+        trace.last_update = now;
+    }
+    dropped
+}
+
+/// Removes the traces no span arrived for during `timeout`
+fn take_completed(
+    pending: &mut HashMap<String, PendingTrace>,
+    timeout: Duration,
+) -> Vec<(String, PendingTrace)> {
+    let done: Vec<String> = pending
+        .iter()
+        .filter(|(_, t)| t.last_update.elapsed() >= timeout)
+        .map(|(id, _)| id.clone())
+        .collect();
+    done.into_iter()
+        .map(|id| {
+            let trace = pending.remove(&id).unwrap();
+            (id, trace)
+        })
+        .collect()
+}
+
+fn respond(status: StatusCode, content_type: &str, body: Body) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(body)
+        .unwrap()
+}
+
+fn handle_request(req: Request<Body>, pending: PendingTraces, max_pending: usize) -> ResponseFuture {
+    if req.method() != Method::POST || req.uri().path() != "/v1/traces" {
+        return Box::new(future::ok(respond(
+            StatusCode::NOT_FOUND,
+            "text/plain",
+            Body::from("Only POST /v1/traces is supported"),
+        )));
+    }
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|v: &hyper::header::HeaderValue| v.to_str().ok())
+            .unwrap_or("")
+            .to_string()
+    };
+    let protobuf = header(CONTENT_TYPE).starts_with("application/x-protobuf");
+    let gzip = header(CONTENT_ENCODING) == "gzip";
+
+    Box::new(req.into_body().concat2().map(move |chunk| {
+        match decode_body(&chunk, gzip, protobuf) {
+            Ok(request) => {
+                let dropped =
+                    add_pending(&mut pending.lock().unwrap(), request.spans_by_trace(), max_pending);
+                if dropped > 0 {
+                    eprintln!(
+                        "Dropped {} OTLP spans, {} traces are already pending",
+                        dropped, max_pending
+                    );
+                }
+                // An empty ExportTraceServiceResponse
+                if protobuf {
+                    respond(StatusCode::OK, "application/x-protobuf", Body::empty())
+                } else {
+                    respond(StatusCode::OK, "application/json", Body::from("{}"))
+                }
+            }
+            Err(e) => respond(
+                StatusCode::BAD_REQUEST,
+                "text/plain",
+                Body::from(format!("Could not parse OTLP request: {}", e)),
+            ),
+        }
+    }))
+}
+
+fn decode_body(body: &[u8], gzip: bool, protobuf: bool) -> Result<OTLPTraceRequest, Box<dyn Error>> {
+    if gzip {
+        let mut decoded = Vec::new();
+        GzDecoder::new(body).read_to_end(&mut decoded)?;
+        parse_export_request(&decoded, protobuf)
+    } else {
+        parse_export_request(body, protobuf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::mpsc::channel;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use pythia_common::RequestType;
+
+    use super::*;
+
+    const REQUEST: &str = r#"{"resourceSpans": [{
+        "resource": {"attributes": [{"key": "service.name", "value": {"stringValue": "api"}}]},
+        "scopeSpans": [{"spans": [
+            {"traceId": "0a0b", "spanId": "01", "name": "GET /",
+             "startTimeUnixNano": "1000000000", "endTimeUnixNano": "1003000000"}
+        ]}]
+    }]}"#;
+
+    fn span_ids(request: OTLPTraceRequest) -> Vec<(String, Vec<String>)> {
+        request
+            .spans_by_trace()
+            .into_iter()
+            .map(|(id, spans)| (id, spans.into_iter().map(|s| s.span_id).collect()))
+            .collect()
+    }
+
+    fn span_list(request: &str) -> Vec<Span> {
+        let request = decode_body(request.as_bytes(), false, false).unwrap();
+        request.spans_by_trace().into_iter().next().unwrap().1
+    }
+
+    #[test]
+    fn decodes_gzipped_bodies() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(REQUEST.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let expected = vec![("0a0b".to_string(), vec!["01".to_string()])];
+        assert_eq!(span_ids(decode_body(REQUEST.as_bytes(), false, false).unwrap()), expected);
+        assert_eq!(span_ids(decode_body(&gzipped, true, false).unwrap()), expected);
+        assert!(decode_body(REQUEST.as_bytes(), true, false).is_err());
+    }
+
+    #[test]
+    fn only_quiet_traces_are_completed() {
+        let mut pending = HashMap::new();
+        let quiet = Instant::now() - Duration::from_secs(10);
+        for (id, last_update) in [("quiet", quiet), ("busy", Instant::now())] {
+            pending.insert(
+                id.to_string(),
+                PendingTrace {
+                    spans: Vec::new(),
+                    last_update,
+                },
+            );
+        }
+        let completed = take_completed(&mut pending, Duration::from_secs(5));
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].0, "quiet");
+        assert!(pending.contains_key("busy"));
+    }
+
+    #[test]
+    fn pending_traces_are_capped() {
+        let mut pending = HashMap::new();
+        let spans = |id: &str| (id.to_string(), span_list(REQUEST));
+        assert_eq!(add_pending(&mut pending, vec![spans("a"), spans("b")], 2), 0);
+        // Known traces still get their spans, new ones are dropped
+        assert_eq!(add_pending(&mut pending, vec![spans("a"), spans("c")], 2), 1);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending["a"].spans.len(), 2);
+    }
+
+    #[test]
+    fn posted_spans_become_span_traces() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+        receive(listener, Duration::from_millis(50), 10, move |trace| {
+            tx.send(trace).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            addr,
+            REQUEST.len(),
+            REQUEST
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        let trace = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(trace.req_id, "0a0b");
+        assert_eq!(trace.spans["01"].operation, "GET /");
+        assert_eq!(trace.request_type, RequestType::from_str("api:GET /", "OTLP").unwrap());
+    }
+}
@@ -0,0 +1,319 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Graphviz rendering of traces, critical paths, groups and search space paths.
+//!
+//! Nodes are labeled with their tracepoint names. Edges are colored by their mean latency, from
+//! blue for the fastest edge of the graph to red for the slowest, and drawn thicker the more
+//! their latency varies across the traces of a group. Edges that a ranker singled out (for
+//! example `Group::problem_edges`, or the PCC and covariance rankings of the controller) can be
+//! passed in as `Highlights`; they are drawn bold and labeled with their rank.
+//!
+//! `write_svg` turns the DOT text into an SVG with a local Graphviz `dot`, if there is one.
+
+use std::error::Error;
+use std::io::Write;
+use std::path::Path as FilePath;
+use std::process::{Command, Stdio};
+
+use petgraph::stable_graph::StableGraph;
+use petgraph::visit::{EdgeRef, IntoEdgeReferences};
+use stats::{mean, stddev};
+
+use crate::critical::CriticalPath;
+use crate::grouping::Group;
+use crate::manifest::HierarchicalCriticalPath;
+use crate::trace::{Trace, TraceNode, TracepointID};
+use crate::PythiaError;
+
+/// Thickest edge drawn, in points
+const MAX_PEN_WIDTH: f64 = 6.0;
+
+/// Ranked edges to point out, identified by the tracepoints at their ends
+#[derive(Debug, Clone, Default)]
+pub struct Highlights {
+    ranked: Vec<(TracepointID, TracepointID)>,
+}
+
+impl Highlights {
+    pub fn none() -> Highlights {
+        Highlights::default()
+    }
+
+    /// The first edge is ranked first
+    pub fn new(ranked: Vec<(TracepointID, TracepointID)>) -> Highlights {
+        Highlights { ranked }
+    }
+
+    /// Highlights edges given by tracepoint names, like the `start::end` keys of edge groups
+    pub fn from_names(ranked: &[(String, String)]) -> Highlights {
+        Highlights::new(
+            ranked
+                .iter()
+                .map(|(from, to)| (TracepointID::from_str(from), TracepointID::from_str(to)))
+                .collect(),
+        )
+    }
+
+    fn rank(&self, from: TracepointID, to: TracepointID) -> Option<usize> {
+        self.ranked.iter().position(|&e| e == (from, to)).map(|r| r + 1)
+    }
+}
+
+/// Things that can be drawn with Graphviz
+pub trait ToDot {
+    fn to_dot(&self, highlights: &Highlights) -> String;
+}
+
+struct DotNode {
+    id: usize,
+    tracepoint: TracepointID,
+    label: String,
+    synthetic: bool,
+}
+
+struct DotEdge {
+    from: usize,
+    to: usize,
+    /// Latencies of the edge in seconds, one per trace; empty if unknown
+    latencies: Vec<f64>,
+    dashed: bool,
+}
+
+struct DotGraph {
+    name: String,
+    nodes: Vec<DotNode>,
+    edges: Vec<DotEdge>,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Formats seconds with a unit that keeps the number short
+fn format_secs(secs: f64) -> String {
+    let abs = secs.abs();
+    if abs >= 1.0 {
+        format!("{:.2}s", secs)
+    } else if abs >= 1e-3 {
+        format!("{:.2}ms", secs * 1e3)
+    } else {
+        format!("{:.1}us", secs * 1e6)
+    }
+}
+
+impl DotGraph {
+    fn from_trace(name: String, trace: &Trace) -> DotGraph {
+        let nodes = trace
+            .g
+            .node_indices()
+            .map(|nidx| {
+                let event = &trace.g[nidx];
+                DotNode {
+                    id: nidx.index(),
+                    tracepoint: event.tracepoint_id,
+                    label: TraceNode::from_event(event).to_string(),
+                    synthetic: event.is_synthetic,
+                }
+            })
+            .collect();
+        let edges = trace
+            .g
+            .edge_references()
+            .map(|e| {
+                let elapsed = trace.g[e.target()].timestamp - trace.g[e.source()].timestamp;
+                DotEdge {
+                    from: e.source().index(),
+                    to: e.target().index(),
+                    latencies: vec![elapsed.to_std().unwrap_or_default().as_secs_f64()],
+                    dashed: false,
+                }
+            })
+            .collect();
+        DotGraph { name, nodes, edges }
+    }
+
+    fn from_trace_nodes<E>(
+        name: String,
+        g: &StableGraph<TraceNode, E>,
+        edge: impl Fn(&E) -> (Vec<f64>, bool),
+    ) -> DotGraph {
+        let nodes = g
+            .node_indices()
+            .map(|nidx| DotNode {
+                id: nidx.index(),
+                tracepoint: g[nidx].tracepoint_id,
+                label: g[nidx].to_string(),
+                synthetic: false,
+            })
+            .collect();
+        let edges = g
+            .edge_references()
+            .map(|e| {
+                let (latencies, dashed) = edge(e.weight());
+                DotEdge {
+                    from: e.source().index(),
+                    to: e.target().index(),
+                    latencies,
+                    dashed,
+                }
+            })
+            .collect();
+        DotGraph { name, nodes, edges }
+    }
+
+    fn render(&self, highlights: &Highlights) -> String {
+        let stats = |e: &DotEdge| match e.latencies.len() {
+            0 => None,
+            1 => Some((e.latencies[0], 0.0)),
+            _ => Some((
+                mean(e.latencies.iter().cloned()),
+                stddev(e.latencies.iter().cloned()),
+            )),
+        };
+        let max_mean = self
+            .edges
+            .iter()
+            .filter_map(|e| stats(e).map(|s| s.0))
+            .fold(0.0, f64::max);
+        let max_stddev = self
+            .edges
+            .iter()
+            .filter_map(|e| stats(e).map(|s| s.1))
+            .fold(0.0, f64::max);
+        let tracepoints: std::collections::HashMap<usize, TracepointID> =
+            self.nodes.iter().map(|n| (n.id, n.tracepoint)).collect();
+
+        let mut out = format!("digraph \"{}\" {{\n", escape(&self.name));
+        out.push_str("    rankdir=TB;\n");
+        out.push_str("    node [shape=box, style=rounded, fontname=\"Helvetica\"];\n");
+        out.push_str("    edge [fontname=\"Helvetica\", fontsize=10];\n");
+        for node in &self.nodes {
+            let style = if node.synthetic { ", style=\"rounded,dashed\"" } else { "" };
+            out.push_str(&format!(
+                "    n{} [label=\"{}\"{}];\n",
+                node.id,
+                escape(&node.label),
+                style
+            ));
+        }
+        for edge in &self.edges {
+            let mut attrs = Vec::new();
+            let mut label = String::new();
+            let mut pen_width = 1.0;
+            if let Some((m, sd)) = stats(edge) {
+                let heat = if max_mean > 0.0 { m / max_mean } else { 0.0 };
+                // Hue 0.66 is blue and 0 is red
+                attrs.push(format!("color=\"{:.3} 0.850 0.850\"", 0.66 * (1.0 - heat)));
+                let spread = if max_stddev > 0.0 { sd / max_stddev } else { 0.0 };
+                pen_width += (MAX_PEN_WIDTH - 1.0) * spread;
+                label = if edge.latencies.len() > 1 {
+                    format!("{} ± {} (n={})", format_secs(m), format_secs(sd), edge.latencies.len())
+                } else {
+                    format_secs(m)
+                };
+            }
+            let mut style = if edge.dashed { "dashed" } else { "solid" };
+            if let Some(rank) = highlights.rank(tracepoints[&edge.from], tracepoints[&edge.to]) {
+                style = "bold";
+                pen_width = MAX_PEN_WIDTH;
+                attrs.push("fontcolor=\"red\"".to_string());
+                label = format!("#{} {}", rank, label).trim_end().to_string();
+            }
+            attrs.push(format!("penwidth={:.2}", pen_width));
+            attrs.push(format!("style={}", style));
+            if !label.is_empty() {
+                attrs.push(format!("label=\"{}\"", escape(&label)));
+            }
+            out.push_str(&format!(
+                "    n{} -> n{} [{}];\n",
+                edge.from,
+                edge.to,
+                attrs.join(", ")
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl ToDot for Trace {
+    fn to_dot(&self, highlights: &Highlights) -> String {
+        DotGraph::from_trace(format!("{} {}", self.request_type, self.base_id), self).render(highlights)
+    }
+}
+
+impl ToDot for CriticalPath {
+    fn to_dot(&self, highlights: &Highlights) -> String {
+        DotGraph::from_trace(
+            format!("{} {} critical path", self.request_type, self.request_id),
+            &self.g,
+        )
+        .render(highlights)
+    }
+}
+
+impl ToDot for Group {
+    fn to_dot(&self, highlights: &Highlights) -> String {
+        DotGraph::from_trace_nodes(format!("{}", self), &self.g, |e| {
+            (e.duration.iter().map(|d| d.as_secs_f64()).collect(), false)
+        })
+        .render(highlights)
+    }
+}
+
+impl ToDot for HierarchicalCriticalPath {
+    fn to_dot(&self, highlights: &Highlights) -> String {
+        DotGraph::from_trace_nodes(format!("{} search space path", self.request_type), &self.g, |e| {
+            (Vec::new(), e.is_hierarchical())
+        })
+        .render(highlights)
+    }
+}
+
+/// Renders DOT text to an SVG file with the local Graphviz installation
+pub fn write_svg(dot: &str, file: &FilePath) -> Result<(), Box<dyn Error>> {
+    let mut child = match Command::new("dot")
+        .arg("-Tsvg")
+        .arg("-o")
+        .arg(file)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            return Err(Box::new(PythiaError(format!(
+                "Could not run Graphviz dot, is it installed? ({})",
+                e
+            ))))
+        }
+    };
+    child.stdin.take().unwrap().write_all(dot.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Box::new(PythiaError(format!("dot exited with {}", status))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::chain;
+
+    #[test]
+    fn colors_and_highlights_edges() {
+        let trace = chain("t1", &[("api \"v2\"", 0), ("db", 1), ("reply", 101)]);
+        let highlights = Highlights::from_names(&[("db".to_string(), "reply".to_string())]);
+        let dot = trace.to_dot(&highlights);
+        assert!(dot.contains("n0 [label=\"api \\\"v2\\\"\"]"), "{}", dot);
+        assert!(dot.contains("n0 -> n1 [color=\"0.653 0.850 0.850\""), "{}", dot);
+        assert!(dot.contains("n1 -> n2 [color=\"0.000 0.850 0.850\""), "{}", dot);
+        assert!(dot.contains("label=\"#1 100.00ms\""), "{}", dot);
+    }
+}
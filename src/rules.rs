@@ -0,0 +1,332 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Rule learning over request attributes.
+//!
+//! Each critical path is turned into a set of features: its request type, the hosts it visited,
+//! the key/value pairs kept on its events (see `KeyValueRetention`) and the number of other
+//! requests in flight when it started. A small decision tree is then grown to separate the victims
+//! (the slowest requests) from the survivors, and every leaf where victims dominate becomes a
+//! human-readable rule such as `host=cp-2 AND lock_queue>3 → p95 4.1s vs 0.8s`.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::critical::CriticalPath;
+use crate::trace::{IDType, TraceNode, Value};
+
+/// Maximum number of conditions in a rule
+const MAX_DEPTH: usize = 3;
+/// Fewest requests a rule may cover
+const MIN_LEAF_SIZE: usize = 3;
+/// Most thresholds tried per numeric feature
+const MAX_THRESHOLDS: usize = 32;
+
+/// The features of one request
+#[derive(Debug, Clone, Default)]
+pub struct RequestFeatures {
+    /// Every value seen for each categorical feature
+    pub categorical: HashMap<String, HashSet<String>>,
+    /// The largest value seen for each numeric feature
+    pub numeric: HashMap<String, f64>,
+}
+
+impl RequestFeatures {
+    fn add_value(&mut self, key: &str, value: &Value) {
+        let number = match value {
+            Value::UnsignedInt(u) => Some(*u as f64),
+            Value::SignedInt(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        };
+        match number {
+            Some(n) => {
+                let max = self.numeric.entry(key.to_string()).or_insert(n);
+                *max = max.max(n);
+            }
+            None => {
+                self.categorical
+                    .entry(key.to_string())
+                    .or_default()
+                    .insert(value.to_string());
+            }
+        }
+    }
+}
+
+/// Extracts the features of every path; concurrency is counted among the given paths
+pub fn extract_features(paths: &[CriticalPath]) -> Vec<RequestFeatures> {
+    let intervals: Vec<(i64, i64)> = paths
+        .iter()
+        .map(|p| {
+            let start = p.g.g[p.start_node].timestamp.and_utc().timestamp_nanos_opt().unwrap();
+            (start, start + p.duration.as_nanos() as i64)
+        })
+        .collect();
+    paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let mut features = RequestFeatures::default();
+            features.add_value("request_type", &Value::Str(path.request_type.to_string()));
+            for nidx in path.g.g.node_indices() {
+                let node = TraceNode::from_event(&path.g.g[nidx]);
+                for (key, values) in node.key_value_pair.iter() {
+                    for value in values {
+                        features.add_value(key, value);
+                    }
+                }
+            }
+            for edge in path.g.g.edge_weights() {
+                if let Some(host) = &edge.host {
+                    features.add_value("host", &Value::Str(host.clone()));
+                }
+            }
+            let (start, _) = intervals[i];
+            let concurrent = intervals
+                .iter()
+                .enumerate()
+                .filter(|&(j, &(s, e))| j != i && s <= start && start < e)
+                .count();
+            features.add_value("concurrency", &Value::UnsignedInt(concurrent as u64));
+            features
+        })
+        .collect()
+}
+
+/// A test on the features of a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Equals(String, String),
+    NotEquals(String, String),
+    GreaterThan(String, f64),
+    AtMost(String, f64),
+}
+
+impl Condition {
+    fn holds(&self, features: &RequestFeatures) -> bool {
+        let has = |key: &str, value: &str| {
+            features
+                .categorical
+                .get(key)
+                .is_some_and(|values| values.contains(value))
+        };
+        match self {
+            Condition::Equals(k, v) => has(k, v),
+            Condition::NotEquals(k, v) => !has(k, v),
+            Condition::GreaterThan(k, t) => features.numeric.get(k).is_some_and(|n| n > t),
+            Condition::AtMost(k, t) => !features.numeric.get(k).is_some_and(|n| n > t),
+        }
+    }
+
+    fn negate(&self) -> Condition {
+        match self.clone() {
+            Condition::Equals(k, v) => Condition::NotEquals(k, v),
+            Condition::NotEquals(k, v) => Condition::Equals(k, v),
+            Condition::GreaterThan(k, t) => Condition::AtMost(k, t),
+            Condition::AtMost(k, t) => Condition::GreaterThan(k, t),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Equals(k, v) => write!(f, "{}={}", k, v),
+            Condition::NotEquals(k, v) => write!(f, "{}!={}", k, v),
+            Condition::GreaterThan(k, t) => write!(f, "{}>{}", k, t),
+            Condition::AtMost(k, t) => write!(f, "{}<={}", k, t),
+        }
+    }
+}
+
+/// A conjunction of conditions that picks out slow requests
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub conditions: Vec<Condition>,
+    /// Requests the rule matches, and how many of them are victims
+    pub matched: usize,
+    pub victims: usize,
+    /// p95 latency of the requests the rule matches, and of all others
+    pub p95: Duration,
+    pub rest_p95: Duration,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} → p95 {:.1?} vs {:.1?} ({}/{} victims)",
+            self.conditions
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(" AND "),
+            self.p95,
+            self.rest_p95,
+            self.victims,
+            self.matched
+        )
+    }
+}
+
+fn gini(victims: usize, total: usize) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    let p = victims as f64 / total as f64;
+    2.0 * p * (1.0 - p)
+}
+
+fn p95(mut durations: Vec<Duration>) -> Duration {
+    if durations.is_empty() {
+        return Duration::default();
+    }
+    durations.sort();
+    let idx = ((durations.len() as f64) * 0.95).ceil() as usize;
+    durations[idx.saturating_sub(1).min(durations.len() - 1)]
+}
+
+/// Every condition worth trying on the given requests
+fn candidate_conditions(features: &[&RequestFeatures]) -> Vec<Condition> {
+    let mut categorical: HashMap<&String, HashSet<&String>> = HashMap::new();
+    let mut numeric: HashMap<&String, Vec<f64>> = HashMap::new();
+    for f in features {
+        for (key, values) in &f.categorical {
+            categorical.entry(key).or_default().extend(values.iter());
+        }
+        for (key, value) in &f.numeric {
+            numeric.entry(key).or_default().push(*value);
+        }
+    }
+    let mut candidates = Vec::new();
+    for (key, values) in categorical {
+        for value in values {
+            candidates.push(Condition::Equals(key.clone(), value.clone()));
+        }
+    }
+    for (key, mut values) in numeric {
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        values.dedup();
+        let step = std::cmp::max(1, values.len() / MAX_THRESHOLDS);
+        for pair in values.windows(2).step_by(step) {
+            candidates.push(Condition::GreaterThan(key.clone(), (pair[0] + pair[1]) / 2.0));
+        }
+    }
+    // Break ties between equally good conditions the same way every time
+    candidates.sort_by_key(|c| c.to_string());
+    candidates
+}
+
+/// Grows the tree below a node, collecting the conditions of leaves where victims dominate
+fn grow(
+    indices: Vec<usize>,
+    features: &[RequestFeatures],
+    is_victim: &[bool],
+    path: Vec<Condition>,
+    leaves: &mut Vec<(Vec<Condition>, Vec<usize>)>,
+) {
+    let victims = indices.iter().filter(|&&i| is_victim[i]).count();
+    let impurity = gini(victims, indices.len());
+    let mut best: Option<(f64, Condition, Vec<usize>, Vec<usize>)> = None;
+    if path.len() < MAX_DEPTH && impurity > 0.0 && indices.len() >= 2 * MIN_LEAF_SIZE {
+        let node_features: Vec<&RequestFeatures> = indices.iter().map(|&i| &features[i]).collect();
+        for condition in candidate_conditions(&node_features) {
+            let (yes, no): (Vec<usize>, Vec<usize>) =
+                indices.iter().partition(|&&i| condition.holds(&features[i]));
+            if yes.len() < MIN_LEAF_SIZE || no.len() < MIN_LEAF_SIZE {
+                continue;
+            }
+            let split_impurity = |side: &[usize]| {
+                let v = side.iter().filter(|&&i| is_victim[i]).count();
+                gini(v, side.len()) * side.len() as f64 / indices.len() as f64
+            };
+            let gain = impurity - split_impurity(&yes) - split_impurity(&no);
+            if gain > 1e-9 && best.as_ref().is_none_or(|b| gain > b.0) {
+                best = Some((gain, condition, yes, no));
+            }
+        }
+    }
+    match best {
+        Some((_, condition, yes, no)) => {
+            let mut yes_path = path.clone();
+            yes_path.push(condition.clone());
+            grow(yes, features, is_victim, yes_path, leaves);
+            let mut no_path = path;
+            no_path.push(condition.negate());
+            grow(no, features, is_victim, no_path, leaves);
+        }
+        None => {
+            if !path.is_empty() && victims * 2 > indices.len() {
+                leaves.push((path, indices));
+            }
+        }
+    }
+}
+
+/// Learns rules that separate the victims among `paths` from the survivors, ordered by how many
+/// victims they cover
+pub fn learn_rules(paths: &[CriticalPath], victims: &HashSet<IDType>) -> Vec<Rule> {
+    let features = extract_features(paths);
+    let is_victim: Vec<bool> = paths.iter().map(|p| victims.contains(&p.request_id)).collect();
+    let mut leaves = Vec::new();
+    grow((0..paths.len()).collect(), &features, &is_victim, Vec::new(), &mut leaves);
+
+    let mut rules: Vec<Rule> = leaves
+        .into_iter()
+        .map(|(conditions, indices)| {
+            let matched: HashSet<usize> = indices.iter().cloned().collect();
+            let (inside, outside): (Vec<_>, Vec<_>) =
+                (0..paths.len()).partition(|i| matched.contains(i));
+            Rule {
+                conditions,
+                matched: inside.len(),
+                victims: inside.iter().filter(|&&i| is_victim[i]).count(),
+                p95: p95(inside.iter().map(|&i| paths[i].duration).collect()),
+                rest_p95: p95(outside.iter().map(|&i| paths[i].duration).collect()),
+            }
+        })
+        .collect();
+    rules.sort_by(|a, b| b.victims.cmp(&a.victims).then(b.p95.cmp(&a.p95)));
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{span, span_trace};
+
+    #[test]
+    fn finds_slow_host() {
+        let mut paths = Vec::new();
+        let mut victims = HashSet::new();
+        for i in 0..40 {
+            let slow = i % 8 == 0;
+            let host = if slow { "cp-2".to_string() } else { format!("cp-{}", 1 + 2 * (i % 2)) };
+            let latency = if slow { 400 } else { 20 + i % 5 };
+            let start = i * 1000;
+            let trace = span_trace(
+                &i.to_string(),
+                vec![
+                    span("a", "", "fe", "fe", "get", start, latency as u64 + 2),
+                    span("b", "a", "db", &host, "query", start, latency as u64),
+                ],
+            );
+            let path = CriticalPath::from_cp_trace(&trace.to_critical_path());
+            if slow {
+                victims.insert(path.request_id.clone());
+            }
+            paths.push(path);
+        }
+        let rules = learn_rules(&paths, &victims);
+        assert_eq!(rules[0].conditions, vec![Condition::Equals("host".into(), "cp-2".into())]);
+        assert_eq!((rules[0].victims, rules[0].matched), (5, 5));
+    }
+}
@@ -19,7 +19,11 @@ use pythia_common::{OSPRequestType, RequestType, REQUEST_TYPES};
 use reqwest::get;
 use crate::reader::reader_from_settings;
 
+use crate::critical::CriticalPathAlgorithm;
+use crate::encoding::StorageFormat;
+use crate::grouping::GroupingMode;
 use crate::search::SearchStrategyType;
+use crate::trace::KeyValueRetention;
 
 const SETTINGS_PATH: &str = "./etc/pythia/controller.toml";
 const DECISION_EPOCH: Duration = Duration::from_secs(120);
@@ -32,6 +36,23 @@ const DISABLE_RATIO: f32 = 0.1;
 const TRACE_SIZE_LIMIT: u32 = 100000000;
 const N_WORKERS: usize = 4;
 const FREE_KEYS: bool = false;
+/// Spans of a trace pushed to the OTLP receiver are assembled once none arrived for this many ms
+const OTLP_TRACE_TIMEOUT_MS: &str = "5000";
+/// Most traces the OTLP receiver buffers spans of at once; spans of further traces are dropped
+const OTLP_MAX_PENDING_TRACES: &str = "10000";
+/// Traces served per fetch by the synthetic trace generator
+const SYNTHETIC_BATCH_SIZE: &str = "10";
+/// A problem group is not searched if one attribute explains this fraction of its variance
+const ATTRIBUTE_SPLIT_THRESHOLD: f64 = 0.8;
+/// Format of manifests and traces written to disk; files in either format can be read
+const STORAGE_FORMAT: &str = "binary";
+/// Where `pythia store` keeps traces
+const STORE_DIR: &str = "/opt/stack/trace-store";
+/// How critical paths are extracted from traces
+const CRITICAL_PATH_ALGORITHM: &str = "latest_predecessor";
+/// How critical paths are grouped, and how similar paths need to be to share an approximate group
+const GROUPING: &str = "exact";
+const GROUPING_SIMILARITY: &str = "0.9";
 
 #[derive(Debug)]
 pub struct Settings {
@@ -64,9 +85,29 @@ pub struct Settings {
 
     pub all_request_types: Vec<RequestType>,
     pub cycle_lookback: u128,
+
+    /// Address for the embedded OTLP/HTTP receiver to listen on; the receiver is disabled if None
+    pub otlp_receiver_addr: Option<String>,
+    pub otlp_trace_timeout: Duration,
+    pub otlp_max_pending_traces: usize,
+
+    /// Archive to record every batch of fetched traces to, if any
+    pub record_file: Option<PathBuf>,
+    /// Archive to replay fetched traces from instead of querying the application
+    pub replay_file: Option<PathBuf>,
+    /// Topology to generate traces from instead of querying the application
+    pub synthetic_topology: Option<PathBuf>,
+    pub synthetic_batch_size: usize,
+
+    pub attribute_split_threshold: f64,
+    pub storage_format: StorageFormat,
+    pub store_dir: PathBuf,
+    pub read_from_store: bool,
+    pub critical_path_algorithm: CriticalPathAlgorithm,
+    pub grouping_mode: GroupingMode,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ApplicationType {
     HDFS,
     OpenStack,
@@ -75,6 +116,7 @@ pub enum ApplicationType {
     Zipkin,
     Jaeger,
     SkyWalking,
+    OTLP,
 }
 
 impl ApplicationType {
@@ -87,10 +129,41 @@ impl ApplicationType {
             ApplicationType::Jaeger => "Jaeger",
             ApplicationType::Zipkin => "Zipkin",
             ApplicationType::SkyWalking => "SkyWalking",
+            ApplicationType::OTLP => "OTLP",
         }
     }
 }
 
+/// Reads the `[key_values.<application>]` table of the settings file. Lists missing from it are
+/// taken from `[key_values.default]`, and a missing include list keeps the built-in keys.
+fn key_value_retention(settings: &Config, application: &str) -> KeyValueRetention {
+    let patterns = |list: &str| {
+        [application, "default"].iter().find_map(|table| {
+            settings
+                .get::<Vec<String>>(&format!("key_values.{}.{}", table, list))
+                .ok()
+        })
+    };
+    let builtin = KeyValueRetention::default();
+    KeyValueRetention::new(
+        patterns("include").unwrap_or_else(|| builtin.include().to_vec()),
+        patterns("exclude").unwrap_or_default(),
+    )
+}
+
+/// Reads the key/value retention of the configured application from the settings file. It is
+/// not a field of `Settings` because `TraceNode::from_event` needs it wherever traces are grouped;
+/// the built-in retention is used if the file cannot be read
+pub(crate) fn configured_key_value_retention() -> KeyValueRetention {
+    match Config::builder().add_source(File::new(SETTINGS_PATH, FileFormat::Toml)).build() {
+        Ok(settings) => {
+            let application = settings.get::<String>("application").unwrap_or_default();
+            key_value_retention(&settings, &application)
+        }
+        Err(_) => KeyValueRetention::default(),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct JaegerServicesPayload {
     data: Vec<String>,
@@ -105,6 +178,19 @@ impl Settings {
         //     .unwrap();
         let mut settings_builder = Config::builder()
             .set_default("default", "1").unwrap()
+            .set_default("otlp_receiver_addr", "").unwrap()
+            .set_default("otlp_trace_timeout_ms", OTLP_TRACE_TIMEOUT_MS).unwrap()
+            .set_default("otlp_max_pending_traces", OTLP_MAX_PENDING_TRACES).unwrap()
+            .set_default("record_file", "").unwrap()
+            .set_default("replay_file", "").unwrap()
+            .set_default("synthetic_topology", "").unwrap()
+            .set_default("synthetic_batch_size", SYNTHETIC_BATCH_SIZE).unwrap()
+            .set_default("storage_format", STORAGE_FORMAT).unwrap()
+            .set_default("store_dir", STORE_DIR).unwrap()
+            .set_default("read_from_store", "false").unwrap()
+            .set_default("critical_path_algorithm", CRITICAL_PATH_ALGORITHM).unwrap()
+            .set_default("grouping", GROUPING).unwrap()
+            .set_default("grouping_similarity", GROUPING_SIMILARITY).unwrap()
             .add_source(File::new(SETTINGS_PATH, FileFormat::Toml))
             .set_override("override", "1").unwrap();
         let mut settings = settings_builder.build().unwrap();
@@ -154,6 +240,7 @@ impl Settings {
                 "Zipkin" => ApplicationType::Zipkin,
                 "Jaeger" => ApplicationType::Jaeger,
                 "SkyWalking" => ApplicationType::SkyWalking,
+                "OTLP" => ApplicationType::OTLP,
                 _ => panic!(
                     format!(
                         "Unknown application type - {}",
@@ -186,13 +273,49 @@ impl Settings {
                 get_setting("problem_type").as_str(),
                 get_setting("application").as_str()).unwrap(),
             all_request_types: Vec::new(),
-            cycle_lookback: get_setting("cycle_lookback").parse::<u128>().unwrap()
+            cycle_lookback: get_setting("cycle_lookback").parse::<u128>().unwrap(),
+            otlp_receiver_addr: match get_setting("otlp_receiver_addr").as_str() {
+                "" => None,
+                addr => Some(addr.to_string())
+            },
+            otlp_trace_timeout: Duration::from_millis(
+                get_setting("otlp_trace_timeout_ms").parse::<u64>().unwrap()
+            ),
+            otlp_max_pending_traces: get_setting("otlp_max_pending_traces").parse::<usize>().unwrap(),
+            record_file: match get_setting("record_file").as_str() {
+                "" => None,
+                path => Some(PathBuf::from(path))
+            },
+            replay_file: match get_setting("replay_file").as_str() {
+                "" => None,
+                path => Some(PathBuf::from(path))
+            },
+            synthetic_topology: match get_setting("synthetic_topology").as_str() {
+                "" => None,
+                path => Some(PathBuf::from(path))
+            },
+            synthetic_batch_size: get_setting("synthetic_batch_size").parse::<usize>().unwrap(),
+            attribute_split_threshold: ATTRIBUTE_SPLIT_THRESHOLD,
+            storage_format: get_setting("storage_format").parse::<StorageFormat>().unwrap(),
+            store_dir: PathBuf::from(get_setting("store_dir")),
+            read_from_store: get_setting("read_from_store") == "true",
+            critical_path_algorithm: get_setting("critical_path_algorithm")
+                .parse::<CriticalPathAlgorithm>()
+                .unwrap(),
+            grouping_mode: GroupingMode::new(
+                get_setting("grouping").as_str(),
+                get_setting("grouping_similarity").parse::<f64>().unwrap(),
+            )
+            .unwrap(),
         };
 
         to_return.all_request_types = match get_setting("application").as_str() {
+            _ if to_return.synthetic_topology.is_some() => {
+                reader_from_settings(&to_return).all_operations()
+            },
             "OpenStack" =>  REQUEST_TYPES.clone().into_iter()
                 .map(|rt| RequestType::OSP(rt)).collect(),
-            "Jaeger" => {
+            "Jaeger" | "Zipkin" => {
                 println!("Calling all_operations() - settings.rs:187");
                 reader_from_settings(&to_return).all_operations()
             },
@@ -211,6 +334,19 @@ impl Settings {
         //     .unwrap();
         let mut settings_builder = Config::builder()
             .set_default("default", "1").unwrap()
+            .set_default("otlp_receiver_addr", "").unwrap()
+            .set_default("otlp_trace_timeout_ms", OTLP_TRACE_TIMEOUT_MS).unwrap()
+            .set_default("otlp_max_pending_traces", OTLP_MAX_PENDING_TRACES).unwrap()
+            .set_default("record_file", "").unwrap()
+            .set_default("replay_file", "").unwrap()
+            .set_default("synthetic_topology", "").unwrap()
+            .set_default("synthetic_batch_size", SYNTHETIC_BATCH_SIZE).unwrap()
+            .set_default("storage_format", STORAGE_FORMAT).unwrap()
+            .set_default("store_dir", STORE_DIR).unwrap()
+            .set_default("read_from_store", "false").unwrap()
+            .set_default("critical_path_algorithm", CRITICAL_PATH_ALGORITHM).unwrap()
+            .set_default("grouping", GROUPING).unwrap()
+            .set_default("grouping_similarity", GROUPING_SIMILARITY).unwrap()
             .add_source(File::new(SETTINGS_PATH, FileFormat::Toml))
             .set_override("override", "1").unwrap();
         let mut settings = settings_builder.build().unwrap();
@@ -260,6 +396,7 @@ impl Settings {
                 "Zipkin" => ApplicationType::Zipkin,
                 "Jaeger" => ApplicationType::Jaeger,
                 "SkyWalking" => ApplicationType::SkyWalking,
+                "OTLP" => ApplicationType::OTLP,
                 _ => panic!(
                     format!(
                         "Unknown application type - {}",
@@ -292,13 +429,49 @@ impl Settings {
                 problem_type.as_str(),
                 get_setting("application").as_str()).unwrap(),
             all_request_types: Vec::new(),
-            cycle_lookback: get_setting("cycle_lookback").parse::<u128>().unwrap()
+            cycle_lookback: get_setting("cycle_lookback").parse::<u128>().unwrap(),
+            otlp_receiver_addr: match get_setting("otlp_receiver_addr").as_str() {
+                "" => None,
+                addr => Some(addr.to_string())
+            },
+            otlp_trace_timeout: Duration::from_millis(
+                get_setting("otlp_trace_timeout_ms").parse::<u64>().unwrap()
+            ),
+            otlp_max_pending_traces: get_setting("otlp_max_pending_traces").parse::<usize>().unwrap(),
+            record_file: match get_setting("record_file").as_str() {
+                "" => None,
+                path => Some(PathBuf::from(path))
+            },
+            replay_file: match get_setting("replay_file").as_str() {
+                "" => None,
+                path => Some(PathBuf::from(path))
+            },
+            synthetic_topology: match get_setting("synthetic_topology").as_str() {
+                "" => None,
+                path => Some(PathBuf::from(path))
+            },
+            synthetic_batch_size: get_setting("synthetic_batch_size").parse::<usize>().unwrap(),
+            attribute_split_threshold: ATTRIBUTE_SPLIT_THRESHOLD,
+            storage_format: get_setting("storage_format").parse::<StorageFormat>().unwrap(),
+            store_dir: PathBuf::from(get_setting("store_dir")),
+            read_from_store: get_setting("read_from_store") == "true",
+            critical_path_algorithm: get_setting("critical_path_algorithm")
+                .parse::<CriticalPathAlgorithm>()
+                .unwrap(),
+            grouping_mode: GroupingMode::new(
+                get_setting("grouping").as_str(),
+                get_setting("grouping_similarity").parse::<f64>().unwrap(),
+            )
+            .unwrap(),
         };
 
         to_return.all_request_types = match get_setting("application").as_str() {
+            _ if to_return.synthetic_topology.is_some() => {
+                reader_from_settings(&to_return).all_operations()
+            },
             "OpenStack" =>  REQUEST_TYPES.clone().into_iter()
                 .map(|rt| RequestType::OSP(rt)).collect(),
-            "Jaeger" => {
+            "Jaeger" | "Zipkin" => {
                 println!("Calling all_operations() - settings.rs:286");
                 reader_from_settings(&to_return).all_operations()
             },
@@ -0,0 +1,271 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Cross-host clock-skew correction.
+//!
+//! Timestamps of a trace come from the clocks of every host it touched, so durations of edges
+//! that cross hosts are only as good as the synchronization of those clocks. This module shifts
+//! the timestamps of each host by an offset so that happens-before holds along RPC edges: a child
+//! does not start before its parent, and (when possible) it finishes before its parent does.
+//!
+//! Like Jaeger's clock-skew adjuster, the offset of a host is left at zero when its timestamps
+//! are already consistent with its caller's. Otherwise it is chosen to center the callee within
+//! the caller, which splits the network latency evenly between the request and the response.
+//! Offsets are relative to the host of the first event of the trace, and every offset applied is
+//! recorded so per-host estimates can be inspected with `offset_estimates`.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::sync::Mutex;
+
+use chrono::Duration as ChronoDuration;
+use petgraph::graph::NodeIndex;
+
+use crate::spantrace::SpanTrace;
+use crate::trace::{EdgeType, Trace, Value};
+
+/// Running estimate of the correction applied to the timestamps of `host` to line them up with
+/// the clock of `reference`
+#[derive(Debug, Clone)]
+pub struct HostOffset {
+    pub reference: String,
+    pub host: String,
+    /// Mean correction in nanoseconds; positive means `host`'s clock is behind
+    pub mean_nanos: f64,
+    pub samples: u64,
+}
+
+impl Display for HostOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} vs {}: {:.3}ms over {} traces",
+            self.host,
+            self.reference,
+            self.mean_nanos / 1_000_000.0,
+            self.samples
+        )
+    }
+}
+
+lazy_static! {
+    static ref OFFSET_ESTIMATES: Mutex<HashMap<(String, String), HostOffset>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Returns the per-host offsets estimated from all traces corrected so far
+pub fn offset_estimates() -> Vec<HostOffset> {
+    let mut estimates: Vec<HostOffset> = OFFSET_ESTIMATES.lock().unwrap().values().cloned().collect();
+    estimates.sort_by(|a, b| (&a.reference, &a.host).cmp(&(&b.reference, &b.host)));
+    estimates
+}
+
+fn record_offsets(reference: &str, offsets: &HashMap<String, i64>) {
+    let mut estimates = OFFSET_ESTIMATES.lock().unwrap();
+    for (host, &offset) in offsets {
+        if host == reference {
+            continue;
+        }
+        let estimate = estimates
+            .entry((reference.to_string(), host.clone()))
+            .or_insert_with(|| HostOffset {
+                reference: reference.to_string(),
+                host: host.clone(),
+                mean_nanos: 0.0,
+                samples: 0,
+            });
+        estimate.samples += 1;
+        estimate.mean_nanos += (offset as f64 - estimate.mean_nanos) / estimate.samples as f64;
+    }
+}
+
+/// Bounds on the difference of the offsets of two hosts, gathered from one trace
+#[derive(Default)]
+struct SkewConstraints {
+    /// Keyed by the host pair in lexicographic order, bounds on `offset(.1) - offset(.0)`
+    bounds: HashMap<(String, String), (i64, i64)>,
+}
+
+impl SkewConstraints {
+    /// Requires `offset(to) - offset(from)` to lie within `[lower, upper]`
+    fn add(&mut self, from: &str, to: &str, lower: i64, upper: i64) {
+        if from.is_empty() || to.is_empty() || from == to {
+            return;
+        }
+        let (key, lower, upper) = if from < to {
+            ((from.to_string(), to.to_string()), lower, upper)
+        } else {
+            (
+                (to.to_string(), from.to_string()),
+                upper.saturating_neg(),
+                lower.saturating_neg(),
+            )
+        };
+        let bounds = self.bounds.entry(key).or_insert((i64::MIN, i64::MAX));
+        bounds.0 = bounds.0.max(lower);
+        bounds.1 = bounds.1.min(upper);
+    }
+
+    /// Picks `offset(.1) - offset(.0)` for a pair: zero if that is consistent, the middle of the
+    /// bounds otherwise, or the violated bound if only one side is constrained
+    fn difference(lower: i64, upper: i64) -> i64 {
+        if lower <= 0 && 0 <= upper {
+            0
+        } else if upper == i64::MAX {
+            lower
+        } else if lower == i64::MIN {
+            upper
+        } else {
+            lower / 2 + upper / 2
+        }
+    }
+
+    /// Offsets of every host reachable from `reference`, which itself gets zero
+    fn offsets(&self, reference: &str) -> HashMap<String, i64> {
+        let mut offsets = HashMap::new();
+        offsets.insert(reference.to_string(), 0);
+        let mut pending: Vec<(&(String, String), i64)> = self
+            .bounds
+            .iter()
+            .map(|(pair, &(lower, upper))| (pair, Self::difference(lower, upper)))
+            .collect();
+        // Spread offsets from the reference host one pair at a time
+        while !pending.is_empty() {
+            let before = pending.len();
+            pending.retain(|((a, b), diff)| match (offsets.get(a), offsets.get(b)) {
+                (Some(_), Some(_)) => false,
+                (Some(&off), None) => {
+                    offsets.insert(b.clone(), off + diff);
+                    false
+                }
+                (None, Some(&off)) => {
+                    offsets.insert(a.clone(), off - diff);
+                    false
+                }
+                (None, None) => true,
+            });
+            if pending.len() == before {
+                break;
+            }
+        }
+        offsets
+    }
+}
+
+fn nanos(d: ChronoDuration) -> i64 {
+    d.num_nanoseconds().unwrap_or(if d < ChronoDuration::zero() {
+        i64::MIN
+    } else {
+        i64::MAX
+    })
+}
+
+/// Shifts the spans of the trace so that no span starts before its parent, and returns the
+/// offset applied to each host
+pub fn correct_span_trace(trace: &mut SpanTrace) -> HashMap<String, i64> {
+    let mut constraints = SkewConstraints::default();
+    for span in trace.spans.values() {
+        let parent = match trace.spans.get(&span.parent) {
+            Some(p) => p,
+            None => continue,
+        };
+        let lower = nanos(parent.start - span.start);
+        // Spans that merely follow from their parent may outlive it
+        let upper = match span.parent_ref_type() {
+            EdgeType::ChildOf => parent.end().saturating_sub(span.end()),
+            EdgeType::FollowsFrom => i64::MAX,
+        };
+        constraints.add(&parent.host, &span.host, lower, upper);
+    }
+    let reference = match trace.spans.get(&trace.root_span_id) {
+        Some(root) => root.host.clone(),
+        None => return HashMap::new(),
+    };
+    let offsets = constraints.offsets(&reference);
+    if offsets.values().any(|&o| o != 0) {
+        for span in trace.spans.values_mut() {
+            if let Some(&offset) = offsets.get(&span.host) {
+                span.start += ChronoDuration::nanoseconds(offset);
+            }
+        }
+        for children in trace.children.values_mut() {
+            for child in children.iter_mut() {
+                child.start = trace.spans[&child.span_id].start;
+            }
+        }
+    }
+    if !reference.is_empty() {
+        record_offsets(&reference, &offsets);
+    }
+    offsets
+}
+
+/// The host an event happened on, or an empty string if it does not say
+pub(crate) fn event_host(trace: &Trace, node: NodeIndex) -> &str {
+    let kv = &trace.g[node].key_value_pair;
+    match kv.get("host").or_else(|| kv.get("Host")) {
+        Some(Value::Str(h)) => h.as_str(),
+        _ => "",
+    }
+}
+
+/// Shifts the events of the trace so that no edge between hosts goes back in time, recomputes
+/// the edge durations and returns the offset applied to each host. Events without a host are
+/// left alone.
+pub fn correct_trace(trace: &mut Trace) -> HashMap<String, i64> {
+    let mut constraints = SkewConstraints::default();
+    for edge in trace.g.edge_indices() {
+        let (from, to) = trace.g.edge_endpoints(edge).unwrap();
+        let lower = nanos(trace.g[from].timestamp - trace.g[to].timestamp);
+        constraints.add(event_host(trace, from), event_host(trace, to), lower, i64::MAX);
+    }
+    let reference = match trace.g.node_weight(trace.start_node) {
+        Some(_) => event_host(trace, trace.start_node).to_string(),
+        None => return HashMap::new(),
+    };
+    let offsets = constraints.offsets(&reference);
+    if offsets.values().any(|&o| o != 0) {
+        let nodes: Vec<_> = trace.g.node_indices().collect();
+        for node in nodes {
+            if let Some(&offset) = offsets.get(event_host(trace, node)) {
+                trace.g[node].timestamp += ChronoDuration::nanoseconds(offset);
+            }
+        }
+        let edges: Vec<_> = trace.g.edge_indices().collect();
+        for edge in edges {
+            let (from, to) = trace.g.edge_endpoints(edge).unwrap();
+            trace.g[edge].duration = (trace.g[to].timestamp - trace.g[from].timestamp)
+                .to_std()
+                .unwrap_or_default();
+        }
+    }
+    if !reference.is_empty() {
+        record_offsets(&reference, &offsets);
+    }
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{span, span_trace};
+
+    #[test]
+    fn centers_skewed_child() {
+        // The server's clock is 500ms ahead, so its span starts after the client's ended
+        let spans = vec![
+            span("a", "", "client", "client", "op", 0, 100),
+            span("b", "a", "server", "server", "op", 520, 60),
+        ];
+        let trace = span_trace("t", spans);
+        let (client, server) = (&trace.spans["a"], &trace.spans["b"]);
+        assert_eq!(server.start - client.start, ChronoDuration::milliseconds(20));
+        assert_eq!(client.end() - server.end(), 20_000_000);
+        assert_eq!(trace.children["a"][0].start, server.start);
+    }
+}
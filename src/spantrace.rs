@@ -9,7 +9,8 @@ All rights reserved.
 //! Span-based trace implementation
 //!
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -19,12 +20,13 @@ use std::time::Duration;
 
 use chrono::{DateTime, NaiveDateTime};
 use indexmap::Equivalent;
-use pythia_common::jaeger::JaegerRequestType;
+use petgraph::graph::NodeIndex;
 use pythia_common::RequestType;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use crate::{IDType, Trace};
-use crate::trace::{DAGEdge, EdgeType, Event, EventType, TracepointID};
+use crate::{IDType, PythiaError, Trace};
+use crate::settings::ApplicationType;
+use crate::trace::{DAGEdge, EdgeType, Event, EventType, TracepointID, Value};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Feature {
@@ -42,6 +44,20 @@ pub struct Feature2 {
     // pub child_kv: HashMap<String, String>,
 }
 
+/// A typed reference from a span to another span of the same trace
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct SpanReference {
+    pub span_id: String,
+    pub ref_type: EdgeType,
+}
+
+/// A timestamped log record of a span
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpanLog {
+    pub timestamp: NaiveDateTime,
+    pub fields: HashMap<String, Value>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Span {
     pub span_id: String,
@@ -51,6 +67,14 @@ pub struct Span {
     pub operation: String,
     pub start: NaiveDateTime,
     pub duration: Duration,
+    /// Span kind as reported by the tracer (e.g., CLIENT, SERVER), if any
+    pub kind: Option<String>,
+    pub tags: HashMap<String, Value>,
+    #[serde(default)]
+    pub logs: Vec<SpanLog>,
+    /// All spans this span refers to; `parent` is the one it hangs off in the span tree
+    #[serde(default)]
+    pub references: Vec<SpanReference>,
     // pub children: Vec<*Span>
 }
 
@@ -70,6 +94,10 @@ impl Span {
             operation: oper,
             start,
             duration: Default::default(),
+            kind: None,
+            tags: HashMap::new(),
+            logs: Vec::new(),
+            references: Vec::new(),
             // children: Vec::new()
         }
     }
@@ -78,6 +106,19 @@ impl Span {
         self.parent = parent;
     }
 
+    pub fn add_reference(&mut self, span_id: String, ref_type: EdgeType) {
+        self.references.push(SpanReference { span_id, ref_type });
+    }
+
+    /// How this span relates to its parent; spans without typed references are children of it
+    pub fn parent_ref_type(&self) -> EdgeType {
+        self.references
+            .iter()
+            .find(|r| r.span_id == self.parent)
+            .map(|r| r.ref_type.clone())
+            .unwrap_or(EdgeType::ChildOf)
+    }
+
     // pub fn add_child(&mut self, mut to_add: &Span) {
     //     self.children.push(to_add);
     //     to_add.add_parent(self);
@@ -112,6 +153,10 @@ impl Span {
         par_serv: String
     ) {
         let mut sorted_children: Vec<Span> = st.children.get(self.span_id.as_str()).unwrap().clone();
+        // Asynchronous work that outlives this span is not on its critical path
+        sorted_children.retain(|c| {
+            c.parent_ref_type() == EdgeType::ChildOf || c.end() <= self.end()
+        });
         sorted_children.sort_by(
             |a, b| b.end().partial_cmp(&a.end()).unwrap());
 
@@ -169,8 +214,8 @@ impl Span {
                 res.start_node.clone(),
                 connect_node.clone(),
                 DAGEdge {
-                    duration: Duration::from_nanos(edge_duration as u64),
-                    variant: EdgeType::ChildOf,
+                    duration: Duration::from_nanos(edge_duration.max(0) as u64),
+                    variant: self.parent_ref_type(),
                     host: Some(par_host),
                     service: Some(par_serv)
                 }
@@ -204,7 +249,7 @@ impl Span {
                 timestamp: self.start,
                 variant: EventType::Entry,
                 is_synthetic: false,
-                key_value_pair: HashMap::new(),
+                key_value_pair: self.key_values(),
             }
         );
         let edge_duration = res.g.node_weight(connect_node).unwrap().timestamp.timestamp_nanos() - self.start.timestamp_nanos();
@@ -212,7 +257,7 @@ impl Span {
             res.start_node.clone(),
             connect_node.clone(),
             DAGEdge {
-                duration: Duration::from_nanos(edge_duration as u64),
+                duration: Duration::from_nanos(edge_duration.max(0) as u64),
                 variant: EdgeType::ChildOf,
                 host: Some(self.host.clone()),
                 service: Some(self.service.clone())
@@ -220,6 +265,66 @@ impl Span {
         );
     }
     
+    fn to_event(&self, variant: EventType) -> Event {
+        let name = self.service.clone() + ":" + self.operation.as_str();
+        let (suffix, timestamp) = match variant {
+            EventType::Exit => ("_end", DateTime::from_timestamp_nanos(self.end()).naive_utc()),
+            _ => ("_start", self.start),
+        };
+        let key_value_pair = match variant {
+            EventType::Entry => self.key_values(),
+            _ => HashMap::from([("host".to_string(), Value::Str(self.host.clone()))]),
+        };
+        Event {
+            trace_id: IDType::STRING(self.span_id.clone()),
+            tracepoint_id: TracepointID::from_str((name + suffix).as_str()),
+            timestamp,
+            variant,
+            is_synthetic: false,
+            key_value_pair,
+        }
+    }
+
+    /// The host and tags of the span, as carried by its start event
+    fn key_values(&self) -> HashMap<String, Value> {
+        let mut key_values = self.tags.clone();
+        key_values.insert("host".to_string(), Value::Str(self.host.clone()));
+        key_values
+    }
+
+    /// An annotation event for one of the logs of this span
+    fn log_event(&self, log: &SpanLog) -> Event {
+        let mut key_value_pair = log.fields.clone();
+        key_value_pair.insert("host".to_string(), Value::Str(self.host.clone()));
+        Event {
+            trace_id: IDType::STRING(self.span_id.clone()),
+            tracepoint_id: TracepointID::from_str(
+                (self.service.clone() + ":" + self.operation.as_str() + "_log").as_str(),
+            ),
+            timestamp: log.timestamp,
+            variant: EventType::Annotation,
+            is_synthetic: false,
+            key_value_pair,
+        }
+    }
+
+    /// Adds an edge between two events that happen within this span
+    fn add_edge(&self, trace: &mut Trace, from: NodeIndex, to: NodeIndex, variant: EdgeType) {
+        let duration = (trace.g[to].timestamp - trace.g[from].timestamp)
+            .to_std()
+            .unwrap_or_default();
+        trace.g.add_edge(
+            from,
+            to,
+            DAGEdge {
+                duration,
+                variant,
+                host: Some(self.host.clone()),
+                service: Some(self.service.clone()),
+            },
+        );
+    }
+
     pub fn get_features(&self) -> Vec<Feature> {
         let mut to_return = Vec::new();
         
@@ -246,6 +351,15 @@ impl Span {
     }
 }
 
+/// A child span or a log on the timeline of its parent span, while building the event DAG
+struct TimelineItem {
+    start: i64,
+    end: i64,
+    start_node: NodeIndex,
+    end_node: NodeIndex,
+    ref_type: EdgeType,
+}
+
 // pub struct SpanTrace {
 //     pub endpoint_type: String, // maybe change this to a special RequestType implementation later
 //     pub root_span: Span,
@@ -259,22 +373,57 @@ pub struct SpanTrace {
     pub root_span_id: String,
     pub spans: HashMap<String, Span>,
     pub children: HashMap<String, Vec<Span>>,
+    /// The endpoint type as a request type of the application the spans were read from
+    pub request_type: RequestType,
 }
 
 impl SpanTrace {
+    /// Builds the span trace of spans read from a tracing backend, rooted at the earliest span
+    /// without a parent, and adds its skew-corrected spans to the cache
+    pub fn from_spans(
+        trace_id: String,
+        mut spans: Vec<Span>,
+        application: &ApplicationType,
+        cache: &mut SpanCache,
+    ) -> Result<SpanTrace, Box<dyn Error>> {
+        let root_span = match SpanTrace::resolve_references(&mut spans) {
+            Some(idx) => spans[idx].clone(),
+            None => {
+                return Err(Box::new(PythiaError(format!(
+                    "Could not find a root span in trace {}",
+                    trace_id
+                ))));
+            }
+        };
+        let trace = SpanTrace::from_span_list(
+            spans,
+            format!("{}:{}", root_span.service, root_span.operation),
+            root_span.span_id,
+            trace_id,
+            application,
+        );
+        cache.add_trace(&trace);
+        Ok(trace)
+    }
+
     pub fn from_span_list(
         spans: Vec<Span>,
         // parents: HashMap<String, String>,
         oper_name: String,
         root_span_id: String,
-        trace_id: String
+        trace_id: String,
+        application: &ApplicationType,
     ) -> SpanTrace {
+        // Span traces come from span-based applications, which take any endpoint as request type
+        let request_type =
+            RequestType::from_str(&oper_name, application.as_str()).unwrap_or(RequestType::Unknown);
         let mut to_ret_trace = SpanTrace{
             endpoint_type: oper_name,
             req_id: trace_id,
             root_span_id,
             spans: HashMap::new(),
-            children: HashMap::new()
+            children: HashMap::new(),
+            request_type,
         };
 
         // let span_parents: HashMap<String, String> = HashMap::new();
@@ -286,10 +435,55 @@ impl SpanTrace {
             // to_ret_trace.add_span(span.clone(), parents.get(span.span_id.as_str()).unwrap().clone());
             to_ret_trace.add_span(span.clone(), span.parent.clone());
         }
+        crate::skew::correct_span_trace(&mut to_ret_trace);
 
         to_ret_trace
     }
 
+    /// Picks the parent of every span among the spans of the trace it references, preferring
+    /// `ChildOf` over `FollowsFrom` references, and returns the index of the root span.
+    ///
+    /// References to spans missing from the trace are ignored. Spans whose `parent` is set but
+    /// which carry no typed references are treated as children of their parent. If several spans
+    /// are left without a parent, the earliest one is the root and the others follow from it.
+    pub fn resolve_references(spans: &mut [Span]) -> Option<usize> {
+        let ids: HashSet<String> = spans.iter().map(|s| s.span_id.clone()).collect();
+        for span in spans.iter_mut() {
+            if span.references.is_empty() && !span.parent.is_empty() {
+                let parent = span.parent.clone();
+                span.add_reference(parent, EdgeType::ChildOf);
+            }
+            let parent = {
+                let present = |ref_type: EdgeType| {
+                    span.references
+                        .iter()
+                        .find(|r| {
+                            r.ref_type == ref_type
+                                && r.span_id != span.span_id
+                                && ids.contains(&r.span_id)
+                        })
+                        .map(|r| r.span_id.clone())
+                };
+                present(EdgeType::ChildOf)
+                    .or_else(|| present(EdgeType::FollowsFrom))
+                    .unwrap_or_default()
+            };
+            span.parent = parent;
+        }
+
+        let root = (0..spans.len())
+            .filter(|&i| spans[i].parent.is_empty())
+            .min_by_key(|&i| spans[i].start)?;
+        let root_id = spans[root].span_id.clone();
+        for (i, span) in spans.iter_mut().enumerate() {
+            if i != root && span.parent.is_empty() {
+                span.add_reference(root_id.clone(), EdgeType::FollowsFrom);
+                span.parent = root_id.clone();
+            }
+        }
+        Some(root)
+    }
+
     fn add_span(&mut self, to_add: Span, parent: String) {
         self.spans.insert(to_add.clone().span_id, to_add.clone());
         // If no parent entry present, insert one and begin populating
@@ -325,10 +519,7 @@ impl SpanTrace {
 
     pub fn to_critical_path(&self) -> Trace {
         let mut to_ret_trace = Trace::new(&IDType::STRING(self.req_id.clone()));
-        // TODO: Change the attribute "endpoint_type" to a RequestType type later
-        to_ret_trace.request_type = RequestType::Jaeger(JaegerRequestType{
-            rt: self.endpoint_type.clone()
-        });
+        to_ret_trace.request_type = self.request_type.clone();
         self.spans.get(self.root_span_id.as_str()).unwrap().to_critical_path(
             self, &mut to_ret_trace, "".to_string(), "".to_string());
 
@@ -337,6 +528,126 @@ impl SpanTrace {
         return to_ret_trace;
     }
 
+    /// What readers return for the span trace: the full DAG when building the search space,
+    /// which needs every event, and only the critical path otherwise
+    pub fn to_reader_trace(&self, for_searchspace: bool) -> Trace {
+        if for_searchspace {
+            self.to_trace()
+        } else {
+            self.to_critical_path()
+        }
+    }
+
+    /// Converts the whole span tree into an event DAG, with a start and an end event per span.
+    ///
+    /// Children of a span that overlap in time become parallel branches: each one forks from the
+    /// start of the previous sibling, and all of them join into whatever follows them in the
+    /// parent (the next sibling or the parent's end). Children that outlive their parent are
+    /// attached with a `FollowsFrom` edge and do not rejoin: their end events are sinks, so they
+    /// are never on the critical path of the parent, and validation does not count them as stray.
+    pub fn to_trace(&self) -> Trace {
+        let mut trace = Trace::new(&IDType::STRING(self.req_id.clone()));
+        trace.request_type = self.request_type.clone();
+        let root = self.spans.get(self.root_span_id.as_str()).unwrap();
+        let mut nodes = HashMap::new();
+        let (start, end) = self.add_span_events(root, &mut trace, &mut nodes);
+        trace.start_node = start;
+        trace.end_node = end;
+        trace.duration = root.duration;
+
+        // References other than the parent link the span to the latest event of the referenced
+        // span that precedes it
+        for span in self.spans.values() {
+            let span_start = match nodes.get(span.span_id.as_str()) {
+                Some(&(span_start, _)) => span_start,
+                None => continue,
+            };
+            for reference in span.references.iter().filter(|r| r.span_id != span.parent) {
+                let (ref_start, ref_end) = match nodes.get(reference.span_id.as_str()) {
+                    Some(&n) => n,
+                    None => continue,
+                };
+                let from = if trace.g[ref_end].timestamp <= trace.g[span_start].timestamp {
+                    ref_end
+                } else {
+                    ref_start
+                };
+                if trace.g[from].timestamp <= trace.g[span_start].timestamp {
+                    span.add_edge(&mut trace, from, span_start, reference.ref_type.clone());
+                }
+            }
+        }
+        trace
+    }
+
+    /// Adds the events of `span` and its descendants, returning the start and end nodes of `span`
+    fn add_span_events(
+        &self,
+        span: &Span,
+        trace: &mut Trace,
+        nodes: &mut HashMap<String, (NodeIndex, NodeIndex)>,
+    ) -> (NodeIndex, NodeIndex) {
+        let start = trace.g.add_node(span.to_event(EventType::Entry));
+        let end = trace.g.add_node(span.to_event(EventType::Exit));
+        nodes.insert(span.span_id.clone(), (start, end));
+
+        // Children and logs along the timeline of the span; logs are zero-length items
+        let mut items = Vec::new();
+        let mut children = self.children.get(span.span_id.as_str()).cloned().unwrap_or_default();
+        children.sort_by_key(|c| c.start);
+        for child in children {
+            let (child_start, child_end) = self.add_span_events(&child, trace, nodes);
+            items.push(TimelineItem {
+                start: child.start.and_utc().timestamp_nanos_opt().unwrap(),
+                end: child.end(),
+                start_node: child_start,
+                end_node: child_end,
+                ref_type: child.parent_ref_type(),
+            });
+        }
+        for log in &span.logs {
+            let node = trace.g.add_node(span.log_event(log));
+            let at = log.timestamp.and_utc().timestamp_nanos_opt().unwrap();
+            items.push(TimelineItem {
+                start: at,
+                end: at,
+                start_node: node,
+                end_node: node,
+                ref_type: EdgeType::ChildOf,
+            });
+        }
+        items.sort_by_key(|i| i.start);
+
+        let mut fork = start;
+        // Ends of the items overlapping with each other, to join before the next event
+        let mut branch_ends = Vec::new();
+        let mut branches_end = i64::MIN;
+        for item in items {
+            if item.end > span.end() {
+                span.add_edge(trace, fork, item.start_node, EdgeType::FollowsFrom);
+                continue;
+            }
+            let overlapping = item.start < branches_end;
+            if branch_ends.is_empty() || overlapping {
+                span.add_edge(trace, fork, item.start_node, item.ref_type);
+            } else {
+                for branch_end in branch_ends.drain(..) {
+                    span.add_edge(trace, branch_end, item.start_node, item.ref_type.clone());
+                }
+            }
+            fork = item.start_node;
+            branch_ends.push(item.end_node);
+            branches_end = std::cmp::max(branches_end, item.end);
+        }
+        if branch_ends.is_empty() {
+            span.add_edge(trace, start, end, EdgeType::ChildOf);
+        }
+        for branch_end in branch_ends {
+            span.add_edge(trace, branch_end, end, EdgeType::ChildOf);
+        }
+        (start, end)
+    }
+
     pub fn get_backtrace(&self, from: String) -> Vec<Span> {
         let mut to_return = Vec::new();
         let mut cur_id = from;
@@ -426,9 +737,9 @@ impl SpanCache {
         };
     }
 
-    pub fn add_trace(&mut self, to_add: SpanTrace) {
-        for (_, span) in to_add.spans {
-            self.add_span(span, to_add.req_id.clone());
+    pub fn add_trace(&mut self, to_add: &SpanTrace) {
+        for span in to_add.spans.values() {
+            self.add_span(span.clone(), to_add.req_id.clone());
         }
     }
 
@@ -524,3 +835,39 @@ impl SpanCache {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::span;
+
+    #[test]
+    fn resolves_references() {
+        let span = |id, parent, start_ms| span(id, parent, "svc", "host", "op", start_ms, 10);
+        let mut spans = vec![
+            // Reported before the root, but starts after it
+            span("late", "", 5),
+            span("root", "", 0),
+            span("child", "root", 1),
+            // Its parent was never reported
+            span("orphan", "gone", 2),
+            span("typed", "", 3),
+        ];
+        spans[4].add_reference("typed".to_string(), EdgeType::ChildOf);
+        spans[4].add_reference("late".to_string(), EdgeType::FollowsFrom);
+        spans[4].add_reference("child".to_string(), EdgeType::ChildOf);
+
+        let root = SpanTrace::resolve_references(&mut spans).unwrap();
+        assert_eq!(spans[root].span_id, "root");
+        assert_eq!(spans[2].parent, "root");
+        assert_eq!(spans[2].parent_ref_type(), EdgeType::ChildOf);
+        // Self references are skipped and ChildOf wins over FollowsFrom
+        assert_eq!(spans[4].parent, "child");
+        // Other parentless spans follow from the earliest one
+        for idx in [0, 3] {
+            assert_eq!(spans[idx].parent, "root");
+            assert_eq!(spans[idx].parent_ref_type(), EdgeType::FollowsFrom);
+        }
+        assert_eq!(SpanTrace::resolve_references(&mut []), None);
+    }
+}
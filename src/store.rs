@@ -0,0 +1,455 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Local trace store.
+//!
+//! Keeps traces on disk so analyses can run over long stretches of history without fetching them
+//! from the tracing backend again. The store is a directory with one file per trace (in the
+//! binary format of `encoding`) and an index file holding a `TraceSummary` per trace. When the
+//! store is opened the summaries are loaded and indexed by request type, start time, host and
+//! tracepoint, and queries use whichever of these narrows the candidates down the most.
+//!
+//! Both `Trace`s and `SpanTrace`s can be stored; span traces are kept as they are and indexed
+//! through the trace built from them.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use pythia_common::RequestType;
+use serde::{Deserialize, Serialize};
+
+use crate::encoding;
+use crate::encoding::StorageFormat;
+use crate::spantrace::SpanTrace;
+use crate::trace::Trace;
+use crate::PythiaError;
+
+const INDEX_FILE: &str = "index.pyt";
+const TRACE_DIR: &str = "traces";
+
+/// What is kept in the file of each trace
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StoredTrace {
+    Trace(Trace),
+    SpanTrace(SpanTrace),
+}
+
+impl StoredTrace {
+    pub fn to_trace(&self) -> Trace {
+        match self {
+            StoredTrace::Trace(t) => t.clone(),
+            StoredTrace::SpanTrace(st) => st.to_trace(),
+        }
+    }
+}
+
+/// The indexed attributes of a stored trace
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TraceSummary {
+    pub id: String,
+    pub request_type: RequestType,
+    pub start: NaiveDateTime,
+    pub duration: Duration,
+    pub hosts: BTreeSet<String>,
+    pub tracepoints: BTreeSet<String>,
+    pub is_span_trace: bool,
+    file: String,
+}
+
+impl TraceSummary {
+    fn new(id: String, trace: &Trace, is_span_trace: bool) -> TraceSummary {
+        let tracepoints = trace
+            .g
+            .node_weights()
+            .map(|event| event.tracepoint_id.to_string())
+            .collect();
+        let (start, duration) = match (
+            trace.g.node_weight(trace.start_node),
+            trace.g.node_weight(trace.end_node),
+        ) {
+            (Some(s), Some(e)) => (
+                s.timestamp,
+                (e.timestamp - s.timestamp).to_std().unwrap_or(trace.duration),
+            ),
+            _ => (NaiveDateTime::default(), trace.duration),
+        };
+        TraceSummary {
+            file: format!("{}/{}.pyt", TRACE_DIR, file_name(&id)),
+            id,
+            request_type: trace.request_type.clone(),
+            start,
+            duration,
+            hosts: trace.hosts(),
+            tracepoints,
+            is_span_trace,
+        }
+    }
+}
+
+impl Display for TraceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {:?} hosts: {:?}, {} tracepoints",
+            self.id,
+            self.request_type,
+            self.start,
+            self.duration,
+            self.hosts,
+            self.tracepoints.len()
+        )
+    }
+}
+
+/// Keeps IDs usable as file names by percent-escaping every byte other than ASCII letters,
+/// digits, `-` and `_`, so that different IDs never share a file
+pub(crate) fn file_name(id: &str) -> String {
+    let mut name = String::with_capacity(id.len());
+    for byte in id.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    name
+}
+
+/// Conditions a stored trace has to meet; unset fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub request_type: Option<RequestType>,
+    /// Traces that started at or after this time
+    pub since: Option<NaiveDateTime>,
+    /// Traces that started before this time
+    pub until: Option<NaiveDateTime>,
+    pub min_duration: Option<Duration>,
+    pub max_duration: Option<Duration>,
+    pub host: Option<String>,
+    pub tracepoint: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    pub fn matches(&self, s: &TraceSummary) -> bool {
+        self.request_type.as_ref().is_none_or(|rt| *rt == s.request_type)
+            && self.since.is_none_or(|t| s.start >= t)
+            && self.until.is_none_or(|t| s.start < t)
+            && self.min_duration.is_none_or(|d| s.duration >= d)
+            && self.max_duration.is_none_or(|d| s.duration <= d)
+            && self.host.as_ref().is_none_or(|h| s.hosts.contains(h))
+            && self.tracepoint.as_ref().is_none_or(|tp| s.tracepoints.contains(tp))
+    }
+
+    /// Whether the query has any condition at all
+    pub fn is_empty(&self) -> bool {
+        self.request_type.is_none()
+            && self.since.is_none()
+            && self.until.is_none()
+            && self.min_duration.is_none()
+            && self.max_duration.is_none()
+            && self.host.is_none()
+            && self.tracepoint.is_none()
+    }
+}
+
+/// Parses a time given as RFC 3339, as `%Y-%m-%d %H:%M:%S` in UTC, or as an age such as `90s`,
+/// `30m`, `12h` or `7d` before now
+pub fn parse_time(s: &str) -> Result<NaiveDateTime, Box<dyn Error>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.naive_utc());
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S%.f"].iter() {
+        if let Ok(t) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(t);
+        }
+    }
+    let (amount, unit) = s.split_at(s.len().saturating_sub(1));
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(Box::new(PythiaError(format!("Cannot parse time {}", s)))),
+    };
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| PythiaError(format!("Cannot parse time {}", s)))?;
+    Ok((Utc::now() - chrono::Duration::seconds(amount * seconds)).naive_utc())
+}
+
+fn unindex<K: Hash + Eq>(index: &mut HashMap<K, HashSet<String>>, key: &K, id: &str) {
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(id);
+        if ids.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+pub struct TraceStore {
+    dir: PathBuf,
+    summaries: HashMap<String, TraceSummary>,
+    by_start: BTreeSet<(NaiveDateTime, String)>,
+    by_request_type: HashMap<RequestType, HashSet<String>>,
+    by_host: HashMap<String, HashSet<String>>,
+    by_tracepoint: HashMap<String, HashSet<String>>,
+}
+
+impl TraceStore {
+    /// Opens the store in `dir`, creating it if needed
+    pub fn open(dir: &Path) -> Result<TraceStore, Box<dyn Error>> {
+        std::fs::create_dir_all(dir.join(TRACE_DIR))?;
+        let mut store = TraceStore {
+            dir: dir.to_path_buf(),
+            summaries: HashMap::new(),
+            by_start: BTreeSet::new(),
+            by_request_type: HashMap::new(),
+            by_host: HashMap::new(),
+            by_tracepoint: HashMap::new(),
+        };
+        let index = dir.join(INDEX_FILE);
+        if index.exists() {
+            let summaries: Vec<TraceSummary> = encoding::read(&index)?;
+            for summary in summaries {
+                store.add_summary(summary);
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn len(&self) -> usize {
+        self.summaries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.summaries.is_empty()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.summaries.contains_key(id)
+    }
+
+    fn add_summary(&mut self, summary: TraceSummary) {
+        if let Some(old) = self.summaries.get(&summary.id).cloned() {
+            self.remove_summary(&old);
+        }
+        let id = summary.id.clone();
+        self.by_start.insert((summary.start, id.clone()));
+        self.by_request_type
+            .entry(summary.request_type.clone())
+            .or_default()
+            .insert(id.clone());
+        for host in &summary.hosts {
+            self.by_host.entry(host.clone()).or_default().insert(id.clone());
+        }
+        for tp in &summary.tracepoints {
+            self.by_tracepoint.entry(tp.clone()).or_default().insert(id.clone());
+        }
+        self.summaries.insert(id, summary);
+    }
+
+    fn remove_summary(&mut self, summary: &TraceSummary) {
+        let id = &summary.id;
+        self.by_start.remove(&(summary.start, id.clone()));
+        unindex(&mut self.by_request_type, &summary.request_type, id);
+        for host in &summary.hosts {
+            unindex(&mut self.by_host, host, id);
+        }
+        for tp in &summary.tracepoints {
+            unindex(&mut self.by_tracepoint, tp, id);
+        }
+        self.summaries.remove(id);
+    }
+
+    fn insert(&mut self, summary: TraceSummary, stored: &StoredTrace) -> Result<(), Box<dyn Error>> {
+        encoding::write(stored, &self.dir.join(&summary.file), StorageFormat::Binary)?;
+        self.add_summary(summary);
+        Ok(())
+    }
+
+    /// Stores the trace, replacing any trace with the same ID
+    pub fn insert_trace(&mut self, trace: &Trace) -> Result<(), Box<dyn Error>> {
+        let summary = TraceSummary::new(trace.base_id.to_string(), trace, false);
+        self.insert(summary, &StoredTrace::Trace(trace.clone()))
+    }
+
+    /// Stores the span trace, replacing any trace with the same ID
+    pub fn insert_span_trace(&mut self, trace: &SpanTrace) -> Result<(), Box<dyn Error>> {
+        if !trace.spans.contains_key(&trace.root_span_id) {
+            return Err(Box::new(PythiaError(format!(
+                "Trace {} has no root span",
+                trace.req_id
+            ))));
+        }
+        let summary = TraceSummary::new(trace.req_id.clone(), &trace.to_trace(), true);
+        self.insert(summary, &StoredTrace::SpanTrace(trace.clone()))
+    }
+
+    pub fn summary(&self, id: &str) -> Option<&TraceSummary> {
+        self.summaries.get(id)
+    }
+
+    pub fn get(&self, id: &str) -> Result<StoredTrace, Box<dyn Error>> {
+        match self.summaries.get(id) {
+            Some(s) => encoding::read(&self.dir.join(&s.file)),
+            None => Err(Box::new(PythiaError(format!("Trace {} is not in the store", id)))),
+        }
+    }
+
+    pub fn get_trace(&self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        Ok(self.get(id)?.to_trace())
+    }
+
+    /// Summaries of the traces matching the query, in order of their start
+    pub fn query(&self, query: &Query) -> Vec<&TraceSummary> {
+        // Start from the smallest set of candidates any index gives
+        let mut candidates: Option<&HashSet<String>> = None;
+        let empty = HashSet::new();
+        let indexed = [
+            query
+                .request_type
+                .as_ref()
+                .map(|rt| self.by_request_type.get(rt).unwrap_or(&empty)),
+            query.host.as_ref().map(|h| self.by_host.get(h).unwrap_or(&empty)),
+            query
+                .tracepoint
+                .as_ref()
+                .map(|tp| self.by_tracepoint.get(tp).unwrap_or(&empty)),
+        ];
+        for ids in indexed.iter().flatten() {
+            if candidates.is_none_or(|c| ids.len() < c.len()) {
+                candidates = Some(ids);
+            }
+        }
+        let mut result: Vec<&TraceSummary> = match candidates {
+            Some(ids) => {
+                let mut r: Vec<&TraceSummary> = ids
+                    .iter()
+                    .map(|id| &self.summaries[id])
+                    .filter(|s| query.matches(s))
+                    .collect();
+                r.sort_by(|a, b| (a.start, &a.id).cmp(&(b.start, &b.id)));
+                r
+            }
+            None => {
+                let lower = (query.since.unwrap_or(NaiveDateTime::MIN), String::new());
+                self.by_start
+                    .range(lower..)
+                    .take_while(|(start, _)| query.until.is_none_or(|t| *start < t))
+                    .map(|(_, id)| &self.summaries[id])
+                    .filter(|s| query.matches(s))
+                    .collect()
+            }
+        };
+        if let Some(limit) = query.limit {
+            result.truncate(limit);
+        }
+        result
+    }
+
+    /// Removes the traces matching the query and returns how many were removed
+    pub fn prune(&mut self, query: &Query) -> Result<usize, Box<dyn Error>> {
+        let to_remove: Vec<TraceSummary> = self.query(query).into_iter().cloned().collect();
+        for summary in &to_remove {
+            let file = self.dir.join(&summary.file);
+            if file.exists() {
+                std::fs::remove_file(file)?;
+            }
+            self.remove_summary(summary);
+        }
+        Ok(to_remove.len())
+    }
+
+    /// Stored request types and how many traces each has
+    pub fn request_types(&self) -> Vec<(RequestType, usize)> {
+        let mut types: Vec<_> = self
+            .by_request_type
+            .iter()
+            .map(|(rt, ids)| (rt.clone(), ids.len()))
+            .collect();
+        types.sort_by_key(|(rt, _)| rt.to_string());
+        types
+    }
+
+    /// Writes the index; inserted and pruned traces are only visible to other processes after this
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let mut summaries: Vec<&TraceSummary> = self.summaries.values().collect();
+        summaries.sort_by(|a, b| (a.start, &a.id).cmp(&(b.start, &b.id)));
+        let tmp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        encoding::write(&summaries, &tmp, StorageFormat::Binary)?;
+        std::fs::rename(tmp, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{span, span_trace, time};
+
+    fn request(id: &str, host: &str, start_s: i64, duration_ms: u64) -> SpanTrace {
+        let start = start_s * 1000;
+        span_trace(
+            id,
+            vec![
+                span("a", "", "fe", "fe-1", "get", start, duration_ms + 2),
+                span("b", "a", "db", host, "query", start, duration_ms),
+            ],
+        )
+    }
+
+    #[test]
+    fn queries_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("pythia-store-{}", std::process::id()));
+        let mut store = TraceStore::open(&dir).unwrap();
+        for i in 0..10 {
+            let host = if i % 3 == 0 { "db-2" } else { "db-1" };
+            store
+                .insert_span_trace(&request(&format!("t{}", i), host, i * 60, 10 + i as u64))
+                .unwrap();
+        }
+        store.flush().unwrap();
+
+        let mut store = TraceStore::open(&dir).unwrap();
+        let slow_on_db2 = Query {
+            host: Some("db-2".into()),
+            min_duration: Some(Duration::from_millis(16)),
+            ..Default::default()
+        };
+        let ids: Vec<_> = store.query(&slow_on_db2).iter().map(|s| s.id.clone()).collect();
+        assert_eq!(ids, vec!["t6", "t9"]);
+        let window = Query {
+            since: Some(time(120_000)),
+            until: Some(time(300_000)),
+            ..Default::default()
+        };
+        assert_eq!(store.query(&window).len(), 3);
+        assert_eq!(store.get_trace("t6").unwrap().g.node_count(), 4);
+
+        assert_eq!(store.prune(&slow_on_db2).unwrap(), 2);
+        assert!(store.query(&slow_on_db2).is_empty());
+        assert_eq!(store.len(), 8);
+
+        // IDs that only differ in characters file names cannot hold get files of their own
+        for id in ["t.1", "t/1", "t_1", "t%2E1"] {
+            store.insert_span_trace(&request(id, id, 0, 10)).unwrap();
+        }
+        for id in ["t.1", "t/1", "t_1", "t%2E1"] {
+            assert!(store.get_trace(id).unwrap().hosts().contains(id));
+        }
+        assert_eq!(file_name("../t/1"), "%2E%2E%2Ft%2F1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,587 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Synthetic trace generation from a declarative service topology.
+//!
+//! A topology lists operations (a service plus an endpoint), the calls each operation makes, and
+//! the latency distribution of the work it does itself. Faults add latency to the spans of an
+//! operation, optionally only when it is called by a given caller, in a fraction of the requests.
+//! Every generated trace records which faults fired in it, so the analyses can be checked against
+//! a known ground truth.
+//!
+//! Topologies are read with the `config` crate, so both TOML and JSON files work:
+//!
+//! ```toml
+//! root = "frontend:GET /home"
+//! seed = 42
+//!
+//! [[operations]]
+//! service = "frontend"
+//! operation = "GET /home"
+//! hosts = ["fe-1", "fe-2"]
+//! latency = { distribution = "normal", mean_ms = 5.0, stddev_ms = 1.0 }
+//! calls = [{ target = "cart:get", fanout = 3, concurrency = 3 }]
+//!
+//! [[operations]]
+//! service = "cart"
+//! operation = "get"
+//! latency = { distribution = "exponential", mean_ms = 2.0 }
+//!
+//! [[faults]]
+//! name = "slow-cart"
+//! caller = "frontend:GET /home"
+//! target = "cart:get"
+//! delay_ms = 200.0
+//! probability = 0.1
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use config::{Config, File};
+use pythia_common::RequestType;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::reader::Reader;
+use crate::settings::{ApplicationType, Settings};
+use crate::spantrace::{Span, SpanCache, SpanTrace};
+use crate::trace::{Trace, Value};
+use crate::PythiaError;
+
+/// Time between the starts of two consecutive requests, unless the topology says otherwise
+const DEFAULT_REQUEST_INTERVAL_MS: f64 = 100.0;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Topology {
+    /// The operation that serves every request, as "service:operation"
+    pub root: String,
+    pub operations: Vec<OperationSpec>,
+    #[serde(default)]
+    pub faults: Vec<FaultSpec>,
+    /// Seed for the random generator; runs with the same seed produce the same traces
+    pub seed: Option<u64>,
+    /// Start time of the first request, defaults to the current time
+    pub start_time: Option<DateTime<Utc>>,
+    pub request_interval_ms: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OperationSpec {
+    pub service: String,
+    pub operation: String,
+    /// Hosts the operation runs on, one of which is picked for every span; defaults to the
+    /// service name
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Time spent in the operation itself, half of it before and half after its calls
+    pub latency: LatencyDistribution,
+    #[serde(default)]
+    pub calls: Vec<CallSpec>,
+}
+
+impl OperationSpec {
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.service, self.operation)
+    }
+}
+
+/// A group of identical calls made by an operation. Call groups run one after the other.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CallSpec {
+    /// The called operation, as "service:operation"
+    pub target: String,
+    /// How many times the target is called
+    #[serde(default = "default_one")]
+    pub fanout: usize,
+    /// How many of those calls are in flight at the same time
+    #[serde(default = "default_one")]
+    pub concurrency: usize,
+    /// Probability that the calls are made at all in a given request
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "distribution", rename_all = "lowercase")]
+pub enum LatencyDistribution {
+    Constant { ms: f64 },
+    Uniform { min_ms: f64, max_ms: f64 },
+    Normal { mean_ms: f64, stddev_ms: f64 },
+    /// Log-normal with the given median and shape parameter
+    LogNormal { median_ms: f64, sigma: f64 },
+    Exponential { mean_ms: f64 },
+}
+
+impl LatencyDistribution {
+    /// Draws a latency in milliseconds; negative draws are clamped to zero
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> f64 {
+        let ms = match self {
+            LatencyDistribution::Constant { ms } => *ms,
+            LatencyDistribution::Uniform { min_ms, max_ms } => {
+                min_ms + (max_ms - min_ms) * rng.gen::<f64>()
+            }
+            LatencyDistribution::Normal { mean_ms, stddev_ms } => {
+                mean_ms + stddev_ms * standard_normal(rng)
+            }
+            LatencyDistribution::LogNormal { median_ms, sigma } => {
+                median_ms * (sigma * standard_normal(rng)).exp()
+            }
+            LatencyDistribution::Exponential { mean_ms } => {
+                -mean_ms * (1.0 - rng.gen::<f64>()).ln()
+            }
+        };
+        ms.max(0.0)
+    }
+}
+
+/// Adds `delay_ms` to the spans of `target` (called from `caller`, if given) in a `probability`
+/// fraction of the requests
+#[derive(Deserialize, Debug, Clone)]
+pub struct FaultSpec {
+    pub name: String,
+    pub target: String,
+    pub caller: Option<String>,
+    pub delay_ms: f64,
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+}
+
+fn default_one() -> usize {
+    1
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+/// Box-Muller transform
+fn standard_normal<R: Rng>(rng: &mut R) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+fn millis(ms: f64) -> chrono::Duration {
+    chrono::Duration::nanoseconds((ms * 1_000_000.0) as i64)
+}
+
+impl Topology {
+    pub fn from_file(path: &Path) -> Result<Topology, Box<dyn Error>> {
+        let topology: Topology = Config::builder()
+            .add_source(File::from(path))
+            .build()?
+            .try_deserialize()?;
+        topology.validate()?;
+        Ok(topology)
+    }
+
+    /// Checks that all referenced operations exist and that the call graph has no cycles
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let operations = self.operations_by_name();
+        let known = |name: &str, what: &str| match operations.get(name) {
+            Some(_) => Ok(()),
+            None => Err(PythiaError(format!("Unknown {} operation {}", what, name))),
+        };
+        known(&self.root, "root")?;
+        for op in &self.operations {
+            for call in &op.calls {
+                known(&call.target, "call target")?;
+            }
+        }
+        for fault in &self.faults {
+            known(&fault.target, "fault target")?;
+            if let Some(caller) = &fault.caller {
+                known(caller, "fault caller")?;
+            }
+        }
+        let mut stack = vec![self.root.clone()];
+        self.check_acyclic(&operations, &mut stack)?;
+        Ok(())
+    }
+
+    fn check_acyclic(
+        &self,
+        operations: &HashMap<String, &OperationSpec>,
+        stack: &mut Vec<String>,
+    ) -> Result<(), PythiaError> {
+        let current = operations[stack.last().unwrap()];
+        for call in &current.calls {
+            if stack.contains(&call.target) {
+                return Err(PythiaError(format!(
+                    "Call cycle through {}",
+                    call.target
+                )));
+            }
+            stack.push(call.target.clone());
+            self.check_acyclic(operations, stack)?;
+            stack.pop();
+        }
+        Ok(())
+    }
+
+    fn operations_by_name(&self) -> HashMap<String, &OperationSpec> {
+        self.operations.iter().map(|o| (o.name(), o)).collect()
+    }
+}
+
+/// A generated trace along with the names of the faults injected into it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeneratedTrace {
+    pub span_trace: SpanTrace,
+    pub faults: Vec<String>,
+}
+
+pub struct TraceGenerator {
+    topology: Topology,
+    application: ApplicationType,
+    rng: StdRng,
+    next_start: NaiveDateTime,
+}
+
+impl TraceGenerator {
+    /// Generates traces whose request type is the root operation, as a request type of
+    /// `application`
+    pub fn new(topology: Topology, application: ApplicationType) -> TraceGenerator {
+        let rng = match topology.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let next_start = topology.start_time.unwrap_or_else(Utc::now).naive_utc();
+        TraceGenerator {
+            topology,
+            application,
+            rng,
+            next_start,
+        }
+    }
+
+    pub fn generate(&mut self) -> GeneratedTrace {
+        let active: Vec<FaultSpec> = self
+            .topology
+            .faults
+            .clone()
+            .into_iter()
+            .filter(|f| self.rng.gen::<f64>() < f.probability)
+            .collect();
+        let trace_id = format!("{:032x}", self.rng.gen::<u128>());
+        let start = self.next_start;
+        self.next_start += millis(
+            self.topology
+                .request_interval_ms
+                .unwrap_or(DEFAULT_REQUEST_INTERVAL_MS),
+        );
+
+        let root = self.topology.root.clone();
+        let mut spans = Vec::new();
+        self.generate_span(&root, "", "", start, &active, &mut spans);
+        let root_span_id = spans[0].span_id.clone();
+        GeneratedTrace {
+            span_trace: SpanTrace::from_span_list(
+                spans,
+                root,
+                root_span_id,
+                trace_id,
+                &self.application,
+            ),
+            faults: active.into_iter().map(|f| f.name).collect(),
+        }
+    }
+
+    pub fn generate_n(&mut self, count: usize) -> Vec<GeneratedTrace> {
+        (0..count).map(|_| self.generate()).collect()
+    }
+
+    /// Generates the span of `op_name` starting at `start` and all spans below it, returning
+    /// the end time of the span
+    fn generate_span(
+        &mut self,
+        op_name: &str,
+        parent_id: &str,
+        caller: &str,
+        start: NaiveDateTime,
+        active: &[FaultSpec],
+        spans: &mut Vec<Span>,
+    ) -> NaiveDateTime {
+        let op = self
+            .topology
+            .operations
+            .iter()
+            .find(|o| o.name() == op_name)
+            .unwrap()
+            .clone();
+        let host = match op.hosts.len() {
+            0 => op.service.clone(),
+            n => op.hosts[self.rng.gen_range(0, n)].clone(),
+        };
+        let mut span = Span::from_data(
+            format!("{:016x}", spans.len() + 1),
+            op.service.clone(),
+            host,
+            op.operation.clone(),
+            start,
+        );
+        span.add_parent(parent_id.to_string());
+        span.kind = Some("SERVER".to_string());
+
+        let own_latency = op.latency.sample(&mut self.rng);
+        let mut delay = 0.0;
+        let mut injected = Vec::new();
+        for fault in active {
+            let caller_matches = match &fault.caller {
+                Some(c) => c == caller,
+                None => true,
+            };
+            if fault.target == op_name && caller_matches {
+                delay += fault.delay_ms;
+                injected.push(fault.name.clone());
+            }
+        }
+        if !injected.is_empty() {
+            span.tags.insert("fault".to_string(), Value::Str(injected.join(",")));
+        }
+
+        let span_id = span.span_id.clone();
+        let idx = spans.len();
+        spans.push(span);
+
+        let mut cursor = start + millis(own_latency / 2.0 + delay);
+        for call in &op.calls {
+            if self.rng.gen::<f64>() >= call.probability {
+                continue;
+            }
+            let mut remaining = call.fanout;
+            while remaining > 0 {
+                let batch = std::cmp::min(remaining, std::cmp::max(call.concurrency, 1));
+                let mut batch_end = cursor;
+                for _ in 0..batch {
+                    let end =
+                        self.generate_span(&call.target, &span_id, op_name, cursor, active, spans);
+                    batch_end = std::cmp::max(batch_end, end);
+                }
+                cursor = batch_end;
+                remaining -= batch;
+            }
+        }
+        let end = cursor + millis(own_latency / 2.0);
+        spans[idx].duration = (end - start).to_std().unwrap_or_default();
+        end
+    }
+}
+
+/// Serves generated traces as if they were fetched from a tracing backend, `batch_size` per call
+pub struct SyntheticReader {
+    generator: TraceGenerator,
+    batch_size: usize,
+    request_type: RequestType,
+    generated: HashMap<String, GeneratedTrace>,
+    span_cache: SpanCache,
+}
+
+impl SyntheticReader {
+    /// Serves traces of the root operation of the topology, as a request type of `application`
+    pub fn new(topology: Topology, batch_size: usize, application: ApplicationType) -> SyntheticReader {
+        let request_type = RequestType::from_str(&topology.root, application.as_str())
+            .unwrap_or(RequestType::Unknown);
+        SyntheticReader {
+            generator: TraceGenerator::new(topology, application),
+            batch_size,
+            request_type,
+            generated: HashMap::new(),
+            span_cache: SpanCache::init_cache(),
+        }
+    }
+
+    /// Generates traces from `settings.synthetic_topology`, whose root operation is named as a
+    /// request type of the configured application
+    pub fn from_settings(settings: &Settings) -> SyntheticReader {
+        let path = settings
+            .synthetic_topology
+            .as_ref()
+            .expect("synthetic_topology is not set");
+        let topology = Topology::from_file(path).unwrap();
+        SyntheticReader::new(topology, settings.synthetic_batch_size, settings.application)
+    }
+
+    /// Names of the faults injected into each trace served so far, by trace ID
+    pub fn ground_truth(&self) -> HashMap<String, Vec<String>> {
+        self.generated
+            .iter()
+            .map(|(id, t)| (id.clone(), t.faults.clone()))
+            .collect()
+    }
+
+    fn next_batch(&mut self) -> Vec<SpanTrace> {
+        let batch = self.generator.generate_n(self.batch_size);
+        let mut result = Vec::new();
+        for generated in batch {
+            self.span_cache.add_trace(&generated.span_trace);
+            result.push(generated.span_trace.clone());
+            self.generated
+                .insert(generated.span_trace.req_id.clone(), generated);
+        }
+        result
+    }
+}
+
+impl Reader for SyntheticReader {
+    fn read_file(&mut self, filename: &str) -> Trace {
+        Trace::from_file(Path::new(filename)).unwrap()
+    }
+
+    fn read_dir(&mut self, foldername: &str) -> Vec<Trace> {
+        fs::read_dir(foldername)
+            .unwrap()
+            .map(|entry| self.read_file(entry.unwrap().path().to_str().unwrap()))
+            .collect()
+    }
+
+    fn get_trace_from_base_id(&mut self, id: &str) -> Result<Trace, Box<dyn Error>> {
+        match self.generated.get(id) {
+            Some(t) => Ok(t.span_trace.to_critical_path()),
+            None => Err(Box::new(PythiaError(format!(
+                "Trace {} was not generated",
+                id
+            )))),
+        }
+    }
+
+    fn get_recent_traces(&mut self) -> Vec<Trace> {
+        self.next_batch().iter().map(|st| st.to_critical_path()).collect()
+    }
+
+    fn get_recent_span_traces(&mut self) -> Vec<SpanTrace> {
+        self.next_batch()
+    }
+
+    fn reset_state(&mut self) {}
+
+    fn for_searchspace(&mut self) {}
+
+    fn all_operations(&mut self) -> Vec<RequestType> {
+        vec![self.request_type.clone()]
+    }
+
+    fn set_fetch_all(&mut self) {}
+
+    fn get_candidate_events(&self, start: u64, end: u64, host: String) -> Vec<(String, String)> {
+        self.span_cache.find_overlaps_raw(start, end, host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::critical::{CriticalPath, CriticalPathAlgorithm};
+    use crate::validate::checked_span_critical_path;
+    use std::time::Duration;
+
+    fn topology(probability: f64) -> Topology {
+        Topology {
+            root: "fe:get".to_string(),
+            operations: vec![
+                OperationSpec {
+                    service: "fe".to_string(),
+                    operation: "get".to_string(),
+                    hosts: Vec::new(),
+                    latency: LatencyDistribution::Constant { ms: 2.0 },
+                    calls: vec![CallSpec {
+                        target: "db:read".to_string(),
+                        fanout: 4,
+                        concurrency: 2,
+                        probability: 1.0,
+                    }],
+                },
+                OperationSpec {
+                    service: "db".to_string(),
+                    operation: "read".to_string(),
+                    hosts: Vec::new(),
+                    latency: LatencyDistribution::Constant { ms: 10.0 },
+                    calls: Vec::new(),
+                },
+            ],
+            faults: vec![FaultSpec {
+                name: "slow-db".to_string(),
+                target: "db:read".to_string(),
+                caller: Some("fe:get".to_string()),
+                delay_ms: 100.0,
+                probability,
+            }],
+            seed: Some(1),
+            start_time: None,
+            request_interval_ms: None,
+        }
+    }
+
+    #[test]
+    fn generates_fanout_and_faults() {
+        let mut generator = TraceGenerator::new(topology(0.0), ApplicationType::Jaeger);
+        let healthy = generator.generate();
+        assert!(healthy.faults.is_empty());
+        assert_eq!(healthy.span_trace.spans.len(), 5);
+        // Two batches of two concurrent 10ms calls plus 2ms of own work
+        let root = &healthy.span_trace.spans[&healthy.span_trace.root_span_id];
+        assert_eq!(root.duration, Duration::from_millis(22));
+
+        let mut generator = TraceGenerator::new(topology(1.0), ApplicationType::Jaeger);
+        let faulty = generator.generate();
+        assert_eq!(faulty.faults, vec!["slow-db".to_string()]);
+        let root = &faulty.span_trace.spans[&faulty.span_trace.root_span_id];
+        assert_eq!(root.duration, Duration::from_millis(222));
+    }
+
+    #[test]
+    fn full_dag_has_every_span() {
+        let mut generator = TraceGenerator::new(topology(0.0), ApplicationType::Jaeger);
+        let trace = generator.generate().span_trace.to_trace();
+        assert_eq!(trace.g.node_count(), 10);
+        // Two pairs of concurrent calls fork from the root, join, and join again at its end
+        assert_eq!(
+            trace.g.neighbors_directed(trace.end_node, petgraph::Incoming).count(),
+            2
+        );
+        let path = CriticalPath::from_trace(&trace).unwrap();
+        assert_eq!(path.duration, Duration::from_millis(22));
+        assert_eq!(CriticalPath::all_possible_paths(&trace).count(), 4);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let mut cyclic = topology(0.0);
+        cyclic.operations[1].calls.push(CallSpec {
+            target: "fe:get".to_string(),
+            fanout: 1,
+            concurrency: 1,
+            probability: 1.0,
+        });
+        assert!(cyclic.validate().is_err());
+    }
+
+    #[test]
+    fn reader_serves_the_configured_request_type() {
+        let request_type = RequestType::from_str("fe:get", "Zipkin").unwrap();
+        let mut reader = SyntheticReader::new(topology(1.0), 3, ApplicationType::Zipkin);
+        assert_eq!(reader.all_operations(), vec![request_type.clone()]);
+        let traces = reader.get_recent_traces();
+        assert_eq!(traces.len(), 3);
+        assert!(traces.iter().all(|t| t.request_type == request_type));
+        // The controller polls span traces and takes the type of the paths built from them
+        for span_trace in reader.get_recent_span_traces() {
+            assert_eq!(span_trace.request_type, request_type);
+            let path =
+                checked_span_critical_path(&span_trace, CriticalPathAlgorithm::LatestPredecessor)
+                    .unwrap();
+            assert_eq!(path.request_type, request_type);
+        }
+        assert_eq!(reader.ground_truth().len(), 6);
+    }
+}
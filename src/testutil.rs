@@ -0,0 +1,100 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Builders for the traces and spans that unit tests run on. Times are given in milliseconds
+//! after a fixed instant.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime};
+
+use crate::settings::ApplicationType;
+use crate::spantrace::{Span, SpanTrace};
+use crate::trace::{DAGEdge, EdgeType, Event, EventType, IDType, Trace, TracepointID};
+
+pub fn time(ms: i64) -> NaiveDateTime {
+    DateTime::from_timestamp(1_000, 0).unwrap().naive_utc() + chrono::Duration::milliseconds(ms)
+}
+
+/// An event of the span `span`
+pub fn event(span: &str, tracepoint: &str, variant: EventType, ms: i64) -> Event {
+    Event {
+        trace_id: IDType::STRING(span.to_string()),
+        tracepoint_id: TracepointID::from_str(tracepoint),
+        timestamp: time(ms),
+        is_synthetic: false,
+        variant,
+        key_value_pair: HashMap::new(),
+    }
+}
+
+/// An annotation whose span is named after its tracepoint
+pub fn annotation(tracepoint: &str, ms: i64) -> Event {
+    event(tracepoint, tracepoint, EventType::Annotation, ms)
+}
+
+/// The entry and exit events of a span, at the `<name>_start` and `<name>_end` tracepoints
+pub fn span_events(name: &str, start_ms: i64, end_ms: i64) -> [Event; 2] {
+    [
+        event(name, &format!("{}_start", name), EventType::Entry, start_ms),
+        event(name, &format!("{}_end", name), EventType::Exit, end_ms),
+    ]
+}
+
+/// A trace of the events connected by the edges, given as indices into `events`. Like the
+/// readers, edges last from one event to the next, and the trace goes from the first event to
+/// the last.
+pub fn trace(id: &str, events: Vec<Event>, edges: &[(usize, usize)]) -> Trace {
+    let mut trace = Trace::new(&IDType::STRING(id.to_string()));
+    let nodes: Vec<_> = events.into_iter().map(|e| trace.g.add_node(e)).collect();
+    for &(from, to) in edges {
+        let elapsed = trace.g[nodes[to]].timestamp - trace.g[nodes[from]].timestamp;
+        trace.g.add_edge(
+            nodes[from],
+            nodes[to],
+            DAGEdge {
+                duration: elapsed.to_std().unwrap_or_default(),
+                variant: EdgeType::ChildOf,
+                service: None,
+                host: None,
+            },
+        );
+    }
+    trace.start_node = nodes[0];
+    trace.end_node = *nodes.last().unwrap();
+    trace
+}
+
+/// A trace going through the tracepoints in order, at the given milliseconds
+pub fn chain(id: &str, steps: &[(&str, i64)]) -> Trace {
+    let events = steps.iter().map(|&(tp, ms)| annotation(tp, ms)).collect();
+    let edges: Vec<_> = (1..steps.len()).map(|idx| (idx - 1, idx)).collect();
+    trace(id, events, &edges)
+}
+
+/// A span of `service` on `host`, the root if `parent` is empty
+pub fn span(id: &str, parent: &str, service: &str, host: &str, op: &str, start_ms: i64, ms: u64) -> Span {
+    let mut span = Span::from_data(
+        id.to_string(),
+        service.to_string(),
+        host.to_string(),
+        op.to_string(),
+        time(start_ms),
+    );
+    span.add_parent(parent.to_string());
+    span.duration = Duration::from_millis(ms);
+    span
+}
+
+/// A span trace rooted at the first span
+pub fn span_trace(id: &str, spans: Vec<Span>) -> SpanTrace {
+    let root = spans[0].span_id.clone();
+    let op = spans[0].operation.clone();
+    SpanTrace::from_span_list(spans, op, root, id.to_string(), &ApplicationType::Jaeger)
+}
@@ -0,0 +1,270 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Inclusive time, exclusive time and slack of spans.
+//!
+//! A span is a matching pair of entry and exit events, identified across traces by their two
+//! tracepoints. Its *inclusive* time is from entry to exit; its *exclusive* time leaves out the
+//! time covered by its child spans (the spans it contains in time). Its *slack* is how much
+//! later its exit could have happened without delaying the end of the request: at every join,
+//! only the latest predecessor is waited on, so the other branches could have taken longer. Spans
+//! on the critical path have no slack, and near-critical branches have little.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+use petgraph::algo::toposort;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+
+use crate::trace::{EventType, Trace, TracepointID};
+
+/// The timing of one span of a trace
+#[derive(Debug, Clone)]
+pub struct SpanTiming {
+    pub entry: TracepointID,
+    pub exit: TracepointID,
+    pub inclusive: Duration,
+    pub exclusive: Duration,
+    pub slack: Duration,
+}
+
+impl SpanTiming {
+    pub fn is_critical(&self) -> bool {
+        self.slack == Duration::from_nanos(0)
+    }
+}
+
+fn nanos(trace: &Trace, nidx: NodeIndex) -> i64 {
+    trace.g[nidx]
+        .timestamp
+        .and_utc()
+        .timestamp_nanos_opt()
+        .unwrap_or_default()
+}
+
+/// How much later each event could have happened without delaying the end of the trace, in ns
+fn event_slack(trace: &Trace) -> HashMap<NodeIndex, i64> {
+    let mut slack = HashMap::new();
+    let order = match toposort(&trace.g, None) {
+        Ok(order) => order,
+        Err(_) => return slack,
+    };
+    let end = nanos(trace, trace.end_node);
+    for &nidx in order.iter().rev() {
+        let ts = nanos(trace, nidx);
+        let value = trace
+            .g
+            .neighbors_directed(nidx, Direction::Outgoing)
+            .map(|next| {
+                // The next event waits for its latest predecessor
+                let latest = trace
+                    .g
+                    .neighbors_directed(next, Direction::Incoming)
+                    .map(|p| nanos(trace, p))
+                    .max()
+                    .unwrap();
+                latest - ts + slack[&next]
+            })
+            .min()
+            .unwrap_or(if nidx == trace.end_node { 0 } else { end - ts });
+        slack.insert(nidx, value.max(0));
+    }
+    slack
+}
+
+/// Computes the timing of every span of the trace, in the order the spans start
+pub fn span_timings(trace: &Trace) -> Vec<SpanTiming> {
+    let slack = event_slack(trace);
+    let mut nodes: Vec<NodeIndex> = trace.g.node_indices().collect();
+    nodes.sort_by_key(|&n| trace.g[n].timestamp);
+    let mut entries = HashMap::new();
+    // (start, end, entry, exit) of each span
+    let mut spans = Vec::new();
+    for nidx in nodes {
+        let event = &trace.g[nidx];
+        match event.variant {
+            EventType::Entry => {
+                entries.insert(&event.trace_id, nidx);
+            }
+            EventType::Exit => {
+                if let Some(entry) = entries.remove(&event.trace_id) {
+                    spans.push((nanos(trace, entry), nanos(trace, nidx), entry, nidx));
+                }
+            }
+            EventType::Annotation => {}
+        }
+    }
+    spans.sort_by_key(|&(start, end, _, _)| (start, -end));
+
+    // The direct children of each span are the spans it is the innermost container of
+    let mut children: Vec<Vec<(i64, i64)>> = vec![Vec::new(); spans.len()];
+    let mut open: Vec<usize> = Vec::new();
+    for (idx, &(start, end, _, _)) in spans.iter().enumerate() {
+        // Concurrent siblings overlap without nesting, so the innermost container is not
+        // necessarily the last span opened
+        open.retain(|&p| spans[p].1 > start);
+        if let Some(&parent) = open.iter().rev().find(|&&p| spans[p].1 >= end) {
+            children[parent].push((start, end));
+        }
+        open.push(idx);
+    }
+
+    spans
+        .iter()
+        .zip(children)
+        .map(|(&(start, end, entry, exit), children)| {
+            // Children are sorted by start, so their union can be merged in one pass
+            let mut covered = 0;
+            let mut reach = start;
+            for (c_start, c_end) in children {
+                let c_start = c_start.max(reach);
+                if c_end > c_start {
+                    covered += c_end - c_start;
+                    reach = c_end;
+                }
+            }
+            let inclusive = end - start;
+            SpanTiming {
+                entry: trace.g[entry].tracepoint_id,
+                exit: trace.g[exit].tracepoint_id,
+                inclusive: Duration::from_nanos(inclusive as u64),
+                exclusive: Duration::from_nanos((inclusive - covered).max(0) as u64),
+                slack: Duration::from_nanos(slack.get(&exit).cloned().unwrap_or_default() as u64),
+            }
+        })
+        .collect()
+}
+
+/// The timings one span had over many traces
+#[derive(Debug, Clone, Default)]
+pub struct SpanDistribution {
+    pub inclusive: Vec<Duration>,
+    pub exclusive: Vec<Duration>,
+    pub slack: Vec<Duration>,
+}
+
+impl SpanDistribution {
+    /// Fraction of the traces where the span was on the critical path
+    pub fn critical_ratio(&self) -> f64 {
+        let critical = self.slack.iter().filter(|s| s.as_nanos() == 0).count();
+        critical as f64 / self.slack.len() as f64
+    }
+}
+
+/// The value below which `p` (between 0 and 1) of the values fall
+pub fn percentile(values: &[Duration], p: f64) -> Duration {
+    if values.is_empty() {
+        return Duration::from_nanos(0);
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort();
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+/// Span timings aggregated over the traces of a group
+#[derive(Debug, Clone, Default)]
+pub struct SpanTimes {
+    pub spans: HashMap<(TracepointID, TracepointID), SpanDistribution>,
+}
+
+impl SpanTimes {
+    pub fn add(&mut self, timings: &[SpanTiming]) {
+        for timing in timings {
+            let dist = self.spans.entry((timing.entry, timing.exit)).or_default();
+            dist.inclusive.push(timing.inclusive);
+            dist.exclusive.push(timing.exclusive);
+            dist.slack.push(timing.slack);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Spans usually off the critical path by at most `max_slack` (median), closest first
+    pub fn near_critical(&self, max_slack: Duration) -> Vec<(&(TracepointID, TracepointID), &SpanDistribution)> {
+        let mut result: Vec<_> = self
+            .spans
+            .iter()
+            .filter(|(_, d)| {
+                let median = percentile(&d.slack, 0.5);
+                median.as_nanos() > 0 && median <= max_slack
+            })
+            .collect();
+        result.sort_by_key(|(_, d)| percentile(&d.slack, 0.5));
+        result
+    }
+}
+
+impl Display for SpanTimes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut spans: Vec<_> = self.spans.iter().collect();
+        spans.sort_by_key(|(_, d)| std::cmp::Reverse(percentile(&d.exclusive, 0.5)));
+        writeln!(
+            f,
+            "span: count, inclusive p50/p95, exclusive p50/p95, slack p50/min, critical"
+        )?;
+        for ((entry, exit), d) in spans {
+            writeln!(
+                f,
+                "({} -> {}): {}, {:?}/{:?}, {:?}/{:?}, {:?}/{:?}, {:.0}%",
+                entry,
+                exit,
+                d.inclusive.len(),
+                percentile(&d.inclusive, 0.5),
+                percentile(&d.inclusive, 0.95),
+                percentile(&d.exclusive, 0.5),
+                percentile(&d.exclusive, 0.95),
+                percentile(&d.slack, 0.5),
+                d.slack.iter().min().unwrap(),
+                d.critical_ratio() * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{span_events, trace};
+
+    #[test]
+    fn finds_slack_of_parallel_branches() {
+        // a forks into b and c, and ends when both are done
+        let [a_s, a_e] = span_events("a", 0, 10);
+        let [b_s, b_e] = span_events("b", 1, 5);
+        let [c_s, c_e] = span_events("c", 2, 8);
+        let trace = trace(
+            "t1",
+            vec![a_s, b_s, b_e, c_s, c_e, a_e],
+            &[(0, 1), (1, 2), (2, 5), (0, 3), (3, 4), (4, 5)],
+        );
+
+        let timings = span_timings(&trace);
+        let ms = Duration::from_millis;
+        let get = |name: &str| {
+            timings
+                .iter()
+                .find(|t| t.entry == TracepointID::from_str(&format!("{}_start", name)))
+                .unwrap()
+        };
+        assert_eq!((get("a").inclusive, get("a").exclusive, get("a").slack), (ms(10), ms(3), ms(0)));
+        assert_eq!((get("b").exclusive, get("b").slack), (ms(4), ms(3)));
+        assert!(get("c").is_critical());
+
+        let mut times = SpanTimes::default();
+        times.add(&timings);
+        let near = times.near_critical(ms(5));
+        assert_eq!(near.len(), 1);
+        assert_eq!(near[0].0 .0, get("b").entry);
+    }
+}
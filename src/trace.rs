@@ -9,6 +9,7 @@ All rights reserved.
 //! General trace implementation
 //!
 
+use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -21,6 +22,7 @@ use petgraph::dot::{Config, Dot};
 use petgraph::graph::{EdgeIndex, NodeIndex};
 use petgraph::stable_graph::StableGraph;
 use petgraph::Direction;
+use regex::Regex;
 use serde::de;
 use serde::ser;
 use serde::{Deserialize, Serialize};
@@ -30,15 +32,60 @@ use stats::variance;
 use pythia_common::OSPRequestType;
 use pythia_common::RequestType;
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::encoding;
+use crate::encoding::StorageFormat;
+use crate::settings::configured_key_value_retention;
+
+/// Distinct values kept per key on the nodes of groups
+const MAX_KEY_VALUES: usize = 16;
 
 //The enum Value contains variants which are added depending on the type of key-value pairs needed
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum Value {
     UnsignedInt(u64),
     Str(String),
     SignedInt(i64),
-    //float(f64),
+    Float(f64),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+impl Value {
+    /// Converts a JSON value, keeping integers, floats and booleans typed. Objects are kept as
+    /// their JSON text.
+    pub fn from_json(value: &serde_json::Value) -> Value {
+        match value {
+            serde_json::Value::Null => Value::Str("".to_string()),
+            serde_json::Value::Bool(b) => Value::Bool(*b),
+            serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(u), _) => Value::UnsignedInt(u),
+                (None, Some(i)) => Value::SignedInt(i),
+                _ => Value::Float(n.as_f64().unwrap_or_default()),
+            },
+            serde_json::Value::String(s) => Value::Str(s.clone()),
+            serde_json::Value::Array(a) => Value::List(a.iter().map(Value::from_json).collect()),
+            serde_json::Value::Object(_) => Value::Str(value.to_string()),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::UnsignedInt(u) => write!(f, "{}", u),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::SignedInt(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(l) => write!(
+                f,
+                "[{}]",
+                l.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+            ),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Hash)]
@@ -93,9 +140,13 @@ impl Trace {
         self.request_type = r;
     }
 
-    pub fn to_file(&self, file: &Path) {
-        let writer = std::fs::File::create(file).unwrap();
-        serde_json::to_writer(writer, self).ok();
+    pub fn to_file(&self, file: &Path, format: StorageFormat) -> Result<(), Box<dyn Error>> {
+        encoding::write(self, file, format)
+    }
+
+    /// Reads a trace written by `to_file` in either format
+    pub fn from_file(file: &Path) -> Result<Trace, Box<dyn Error>> {
+        encoding::read(file)
     }
 
     /// Does a forward-scan of nodes for the node with the given trace_id
@@ -204,6 +255,24 @@ impl Trace {
         eprintln!("Removed {} nodes when pruning", removed_count);
     }
 
+    /// Hosts named by the `host` key of events or recorded on edges
+    pub fn hosts(&self) -> BTreeSet<String> {
+        let mut hosts = BTreeSet::new();
+        for event in self.g.node_weights() {
+            for key in ["host", "Host"].iter() {
+                if let Some(Value::Str(h)) = event.key_value_pair.get(*key) {
+                    hosts.insert(h.clone());
+                }
+            }
+        }
+        for edge in self.g.edge_weights() {
+            if let Some(h) = &edge.host {
+                hosts.insert(h.clone());
+            }
+        }
+        hosts
+    }
+
     pub fn get_keys(&self) {
         for node in self.g.node_indices() {
             self.g[node].print_key_values();
@@ -358,6 +427,56 @@ impl Display for DAGEdge {
     }
 }
 
+/// Which event keys are kept on `TraceNode`s, and so on the nodes of groups. Patterns are key
+/// names or globs (`*` matches any run of characters, `?` any single one), and a key is kept if
+/// it matches an include pattern and no exclude pattern.
+#[derive(Debug, Clone)]
+pub struct KeyValueRetention {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    include_re: Vec<Regex>,
+    exclude_re: Vec<Regex>,
+}
+
+impl Default for KeyValueRetention {
+    fn default() -> Self {
+        KeyValueRetention::new(vec!["host".to_string(), "lock_queue".to_string()], Vec::new())
+    }
+}
+
+impl KeyValueRetention {
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        let compile = |patterns: &[String]| -> Vec<Regex> {
+            patterns
+                .iter()
+                .map(|p| {
+                    let re = regex::escape(p).replace("\\*", ".*").replace("\\?", ".");
+                    Regex::new(&format!("^{}$", re)).unwrap()
+                })
+                .collect()
+        };
+        KeyValueRetention {
+            include_re: compile(&include),
+            exclude_re: compile(&exclude),
+            include,
+            exclude,
+        }
+    }
+
+    pub fn retains(&self, key: &str) -> bool {
+        self.include_re.iter().any(|re| re.is_match(key))
+            && !self.exclude_re.iter().any(|re| re.is_match(key))
+    }
+
+    pub fn include(&self) -> &[String] {
+        &self.include
+    }
+
+    pub fn exclude(&self) -> &[String] {
+        &self.exclude
+    }
+}
+
 /// A trace node is an abstract node, so it doesn't have a timestamp or trace id, it just has a
 /// tracepoint id and variant.
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -377,42 +496,21 @@ impl PartialEq for TraceNode {
 impl Eq for TraceNode {}
 
 impl TraceNode {
-    //building hashmap that contains key value pairs where key is a string, and value is a vector
-    //of Value
+    /// Builds a node from an event, keeping the key/value pairs retained by the
+    /// `KeyValueRetention` of the settings file
     pub fn from_event(event: &Event) -> Self {
+        TraceNode::with_retention(event, &KEY_VALUE_RETENTION)
+    }
+
+    /// Builds a node from an event, keeping the key/value pairs `retention` retains
+    pub fn with_retention(event: &Event, retention: &KeyValueRetention) -> Self {
         let mut map = HashMap::new();
-        let mut vec_value: Vec<Value> = Vec::new();
-        let mut vec_host: Vec<Value> = Vec::new();
-        let mut vec_agent: Vec<Value> = Vec::new();
-        let mut vec_hrt: Vec<Value> = Vec::new();
-        let mut vec_proc_id: Vec<Value> = Vec::new();
-        let mut vec_proc_name: Vec<Value> = Vec::new();
-        let mut vec_thread_id: Vec<Value> = Vec::new();
-        let mut vec_thread_name: Vec<Value> = Vec::new();
-        for (key, value) in event.key_value_pair.clone() {
-            if key == "lock_queue".to_string() {
-                vec_value.push(value);
-            } else if key == "host".to_string() {
-                vec_host.push(value);
-            } else if key == "agent".to_string() {
-                vec_agent.push(value);
-            } else if key == "hrt".to_string() {
-                vec_hrt.push(value);
-            } else if key == "process ID".to_string() {
-                vec_proc_id.push(value);
-            } else if key == "process name" {
-                vec_proc_name.push(value);
-            } else if key == "thread ID" {
-                vec_thread_id.push(value);
-            } else if key == "thread name" {
-                vec_thread_name.push(value);
+        for (key, value) in event.key_value_pair.iter() {
+            if retention.retains(key) {
+                map.insert(key.clone(), vec![value.clone()]);
             }
         }
 
-        map.insert("lock_queue".to_string(), vec_value);
-        map.insert("host".to_string(), vec_host);
-
-       // let mut var = variance()
         TraceNode {
             tracepoint_id: event.tracepoint_id,
             variant: event.variant,
@@ -420,6 +518,23 @@ impl TraceNode {
            // variance: event.pairs_variance(),
         }
     }
+
+    /// Adds the values of another occurrence of this node. Only distinct values are kept, at
+    /// most `MAX_KEY_VALUES` per key, so a key with a value per request does not grow without
+    /// bound.
+    pub fn add_key_values(&mut self, other: &TraceNode) {
+        for (key, values) in other.key_value_pair.iter() {
+            let kept = self.key_value_pair.entry(key.clone()).or_default();
+            for value in values {
+                if kept.len() >= MAX_KEY_VALUES {
+                    break;
+                }
+                if !kept.contains(value) {
+                    kept.push(value.clone());
+                }
+            }
+        }
+    }
 /*
     pub fn pairs_variance(event: &Event) -> f64 {
         let mut varian;
@@ -453,6 +568,7 @@ impl PartialEq<TraceNode> for Event {
 
 lazy_static! {
     static ref TRACEPOINT_ID_MAP: Mutex<BiMap<String, usize>> = Mutex::new(BiMap::new());
+    static ref KEY_VALUE_RETENTION: KeyValueRetention = configured_key_value_retention();
 }
 
 /// We do some tricks to keep tracepoint ids as `usize`s so it uses less memory than strings.
@@ -537,3 +653,51 @@ impl<'de> Deserialize<'de> for TracepointID {
         d.deserialize_str(TracepointIDVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::annotation;
+
+    fn annotation_with(values: &[(&str, Value)]) -> Event {
+        let mut event = annotation("a", 0);
+        for (key, value) in values {
+            event.key_value_pair.insert(key.to_string(), value.clone());
+        }
+        event
+    }
+
+    #[test]
+    fn nodes_keep_retained_keys() {
+        let retention = KeyValueRetention::new(
+            vec!["host".to_string(), "db.*".to_string()],
+            vec!["db.statement".to_string()],
+        );
+        let event = annotation_with(&[
+            ("host", Value::Str("node1".to_string())),
+            ("db.system", Value::Str("mysql".to_string())),
+            ("db.statement", Value::Str("SELECT 1".to_string())),
+            ("request_id", Value::UnsignedInt(7)),
+        ]);
+        let node = TraceNode::with_retention(&event, &retention);
+        let mut keys: Vec<&String> = node.key_value_pair.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["db.system", "host"]);
+    }
+
+    #[test]
+    fn merged_values_are_distinct_and_capped() {
+        let retention = KeyValueRetention::new(vec!["*".to_string()], Vec::new());
+        let first = annotation_with(&[("host", Value::Str("node1".to_string()))]);
+        let mut node = TraceNode::with_retention(&first, &retention);
+        for i in 0..100 {
+            let event = annotation_with(&[
+                ("host", Value::Str("node1".to_string())),
+                ("request_id", Value::UnsignedInt(i)),
+            ]);
+            node.add_key_values(&TraceNode::with_retention(&event, &retention));
+        }
+        assert_eq!(node.key_value_pair["host"], vec![Value::Str("node1".to_string())]);
+        assert_eq!(node.key_value_pair["request_id"].len(), MAX_KEY_VALUES);
+    }
+}
@@ -0,0 +1,388 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! Trace validation.
+//!
+//! Broken instrumentation produces traces that are disjoint, miss their root span, have spans
+//! that start but never end, or edges that go back in time. Such traces used to be skipped with a
+//! message on stderr (or made the controller panic), so there was no way to tell when diagnosis
+//! was working from a degraded sample. This module checks traces against a set of named rules and
+//! keeps per-jiffy counts of how many traces were valid, incomplete, invalid or dropped, and of how
+//! often each rule was violated; only these counts are printed, not every diagnostic.
+//!
+//! A trace with only warnings is *incomplete*: it is still used, but parts of it are missing. A
+//! trace with an error is *invalid* and is not used. A trace is *dropped* when it passed
+//! validation but its critical path could not be extracted, so dropped traces are also counted as
+//! valid or incomplete.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fmt::Display;
+use std::sync::Mutex;
+
+use petgraph::algo::is_cyclic_directed;
+use petgraph::visit::{Dfs, EdgeRef, IntoEdgeReferences, Reversed};
+
+use crate::critical::{CriticalPath, CriticalPathAlgorithm};
+use crate::spantrace::SpanTrace;
+use crate::trace::{EdgeType, EventType, IDType, Trace};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A violation of one of the validation rules
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn warning(rule: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            rule,
+            severity: Severity::Warning,
+            message,
+        }
+    }
+
+    fn error(rule: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            rule,
+            severity: Severity::Error,
+            message,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}[{}]: {}", severity, self.rule, self.message)
+    }
+}
+
+/// Checks the event graph of a trace
+pub fn validate_trace(trace: &Trace) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let has_start = trace.g.node_weight(trace.start_node).is_some();
+    let has_end = trace.g.node_weight(trace.end_node).is_some();
+    if !has_start || !has_end {
+        diagnostics.push(Diagnostic::error(
+            "missing-endpoints",
+            format!("trace {} has no start or end event", trace.base_id),
+        ));
+        return diagnostics;
+    }
+    if is_cyclic_directed(&trace.g) {
+        diagnostics.push(Diagnostic::error(
+            "cycle",
+            format!("trace {} has a cycle", trace.base_id),
+        ));
+    }
+
+    let mut from_start = HashSet::new();
+    let mut dfs = Dfs::new(&trace.g, trace.start_node);
+    while let Some(nidx) = dfs.next(&trace.g) {
+        from_start.insert(nidx);
+    }
+    let mut to_end = HashSet::new();
+    let mut dfs = Dfs::new(Reversed(&trace.g), trace.end_node);
+    while let Some(nidx) = dfs.next(Reversed(&trace.g)) {
+        to_end.insert(nidx);
+    }
+    if !from_start.contains(&trace.end_node) {
+        diagnostics.push(Diagnostic::error(
+            "disjoint",
+            format!("the end of trace {} is not reachable from its start", trace.base_id),
+        ));
+    } else {
+        // Work split off with a FollowsFrom edge, such as a child outliving its parent, need not
+        // rejoin before the end
+        let mut detached = HashSet::new();
+        for edge in (&trace.g).edge_references() {
+            if edge.weight().variant == EdgeType::FollowsFrom && !to_end.contains(&edge.target()) {
+                let mut dfs = Dfs::new(&trace.g, edge.target());
+                while let Some(nidx) = dfs.next(&trace.g) {
+                    detached.insert(nidx);
+                }
+            }
+        }
+        let stray = trace
+            .g
+            .node_indices()
+            .filter(|n| !from_start.contains(n) || !(to_end.contains(n) || detached.contains(n)))
+            .count();
+        if stray > 0 {
+            diagnostics.push(Diagnostic::warning(
+                "stray-events",
+                format!("{} events of trace {} are off every start-to-end path", stray, trace.base_id),
+            ));
+        }
+    }
+
+    let mut balance: HashMap<&IDType, i64> = HashMap::new();
+    for nidx in trace.g.node_indices() {
+        let event = &trace.g[nidx];
+        match event.variant {
+            EventType::Entry => *balance.entry(&event.trace_id).or_default() += 1,
+            EventType::Exit => *balance.entry(&event.trace_id).or_default() -= 1,
+            EventType::Annotation => {}
+        }
+    }
+    let unmatched = balance.values().filter(|&&b| b != 0).count();
+    if unmatched > 0 {
+        diagnostics.push(Diagnostic::warning(
+            "unmatched-span",
+            format!("{} spans of trace {} do not have as many starts as ends", unmatched, trace.base_id),
+        ));
+    }
+
+    let backwards = trace
+        .g
+        .edge_indices()
+        .filter(|&e| {
+            let (from, to) = trace.g.edge_endpoints(e).unwrap();
+            trace.g[to].timestamp < trace.g[from].timestamp
+        })
+        .count();
+    if backwards > 0 {
+        diagnostics.push(Diagnostic::warning(
+            "negative-duration",
+            format!("{} edges of trace {} go back in time", backwards, trace.base_id),
+        ));
+    }
+    diagnostics
+}
+
+/// Checks the span tree of a trace
+pub fn validate_span_trace(trace: &SpanTrace) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    if !trace.spans.contains_key(&trace.root_span_id) {
+        diagnostics.push(Diagnostic::error(
+            "missing-root-span",
+            format!("trace {} has no span {}", trace.req_id, trace.root_span_id),
+        ));
+    }
+    let mut ids: Vec<&String> = trace.spans.keys().collect();
+    ids.sort();
+    let mut orphans = 0;
+    let mut detached = 0;
+    for id in ids {
+        let mut seen = HashSet::new();
+        let mut cur = &trace.spans[id];
+        loop {
+            if cur.span_id == trace.root_span_id {
+                break;
+            }
+            if !seen.insert(&cur.span_id) {
+                diagnostics.push(Diagnostic::error(
+                    "cycle",
+                    format!("span {} of trace {} is its own ancestor", cur.span_id, trace.req_id),
+                ));
+                return diagnostics;
+            }
+            match trace.spans.get(&cur.parent) {
+                Some(parent) => cur = parent,
+                None => {
+                    // Only count the topmost span of each broken subtree
+                    if cur.span_id == *id {
+                        if cur.parent.is_empty() {
+                            detached += 1;
+                        } else {
+                            orphans += 1;
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    if orphans > 0 {
+        diagnostics.push(Diagnostic::warning(
+            "orphan-span",
+            format!("{} spans of trace {} have a parent that was not reported", orphans, trace.req_id),
+        ));
+    }
+    if detached > 0 && trace.spans.contains_key(&trace.root_span_id) {
+        diagnostics.push(Diagnostic::warning(
+            "detached-span",
+            format!("{} spans of trace {} are not under its root span", detached, trace.req_id),
+        ));
+    }
+    diagnostics
+}
+
+/// How many traces of each kind were seen, and how often each rule was violated
+#[derive(Debug, Clone, Default)]
+pub struct QualityStats {
+    pub valid: usize,
+    pub incomplete: usize,
+    pub invalid: usize,
+    /// Valid or incomplete traces whose critical path could not be extracted
+    pub dropped: usize,
+    pub violations: BTreeMap<String, usize>,
+}
+
+impl QualityStats {
+    /// Counts a trace with the given diagnostics; returns whether it is usable
+    pub fn record(&mut self, diagnostics: &[Diagnostic]) -> bool {
+        let rules: HashSet<&str> = diagnostics.iter().map(|d| d.rule).collect();
+        for rule in rules {
+            *self.violations.entry(rule.to_string()).or_default() += 1;
+        }
+        match diagnostics.iter().map(|d| d.severity).max() {
+            None => self.valid += 1,
+            Some(Severity::Warning) => self.incomplete += 1,
+            Some(Severity::Error) => self.invalid += 1,
+        }
+        diagnostics.iter().all(|d| d.severity != Severity::Error)
+    }
+
+    /// Counts a trace whose critical path could not be extracted
+    pub fn record_dropped(&mut self) {
+        self.dropped += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.valid + self.incomplete + self.invalid
+    }
+
+    /// Fraction of traces that could not be used for diagnosis
+    pub fn degraded_ratio(&self) -> f64 {
+        if self.total() == 0 {
+            return 0.0;
+        }
+        (self.invalid + self.dropped) as f64 / self.total() as f64
+    }
+}
+
+impl Display for QualityStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "valid {}, incomplete {}, invalid {} ({} of the valid and incomplete dropped)",
+            self.valid, self.incomplete, self.invalid, self.dropped
+        )?;
+        if !self.violations.is_empty() {
+            write!(
+                f,
+                " ({})",
+                self.violations
+                    .iter()
+                    .map(|(rule, count)| format!("{}: {}", rule, count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref QUALITY_STATS: Mutex<QualityStats> = Mutex::new(QualityStats::default());
+}
+
+/// Returns the counts gathered since the last call and starts over
+pub fn take_quality_stats() -> QualityStats {
+    std::mem::take(&mut *QUALITY_STATS.lock().unwrap())
+}
+
+fn extract(
+    trace: &Trace,
+    diagnostics: Vec<Diagnostic>,
+    algorithm: CriticalPathAlgorithm,
+) -> Option<CriticalPath> {
+    if !QUALITY_STATS.lock().unwrap().record(&diagnostics) {
+        return None;
+    }
+    match CriticalPath::from_trace_with(trace, algorithm) {
+        Ok(path) => Some(path),
+        Err(_) => {
+            QUALITY_STATS.lock().unwrap().record_dropped();
+            None
+        }
+    }
+}
+
+/// Validates the trace, counts it and returns its critical path if the trace is usable
+pub fn checked_critical_path(trace: &Trace, algorithm: CriticalPathAlgorithm) -> Option<CriticalPath> {
+    extract(trace, validate_trace(trace), algorithm)
+}
+
+/// Like `checked_critical_path`, checking the span tree before the event graph built from it
+pub fn checked_span_critical_path(
+    trace: &SpanTrace,
+    algorithm: CriticalPathAlgorithm,
+) -> Option<CriticalPath> {
+    let mut diagnostics = validate_span_trace(trace);
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        QUALITY_STATS.lock().unwrap().record(&diagnostics);
+        return None;
+    }
+    let dag = trace.to_critical_path();
+    diagnostics.extend(validate_trace(&dag));
+    extract(&dag, diagnostics, algorithm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ApplicationType;
+    use crate::testutil::{span, span_trace};
+
+    #[test]
+    fn classifies_broken_span_traces() {
+        let span = |id, parent, start_ms| span(id, parent, "svc", "host", "op", start_ms, 10);
+        let spans = vec![span("a", "", 0), span("b", "a", 1), span("c", "gone", 2)];
+        let trace = span_trace("t1", spans.clone());
+        let diagnostics = validate_span_trace(&trace);
+        assert_eq!(diagnostics.iter().map(|d| d.rule).collect::<Vec<_>>(), vec!["orphan-span"]);
+
+        let trace =
+            SpanTrace::from_span_list(spans, "op".into(), "x".into(), "t2".into(), &ApplicationType::Jaeger);
+        let mut stats = QualityStats::default();
+        assert!(!stats.record(&validate_span_trace(&trace)));
+        assert_eq!((stats.invalid, stats.violations["missing-root-span"]), (1, 1));
+
+        assert!(stats.record(&[]));
+        stats.record_dropped();
+        assert_eq!(stats.total(), 2);
+        assert!(stats.to_string().starts_with("valid 1, incomplete 0, invalid 1 (1 of the valid"));
+    }
+
+    #[test]
+    fn children_outliving_their_parent_are_not_stray() {
+        let trace = span_trace(
+            "t1",
+            vec![
+                span("a", "", "svc", "h1", "get", 0, 10),
+                span("b", "a", "svc", "h1", "query", 2, 5),
+                span("c", "a", "audit", "h1", "log", 4, 20),
+            ],
+        )
+        .to_trace();
+        assert!(validate_trace(&trace).is_empty());
+        // The asynchronous child is a sink: it forks off its parent but is never waited for
+        let c_end = trace
+            .g
+            .node_indices()
+            .find(|&n| trace.g[n].tracepoint_id.to_string() == "audit:log_end")
+            .unwrap();
+        assert_eq!(trace.g.neighbors_directed(c_end, petgraph::Outgoing).count(), 0);
+        let path = CriticalPath::from_trace(&trace).unwrap();
+        assert!(path.g.g.node_weights().all(|e| !e.tracepoint_id.to_string().starts_with("audit")));
+    }
+}
@@ -0,0 +1,271 @@
+/*
+This source code is licensed under the BSD-style license found in the
+LICENSE file in the root directory of this source tree.
+
+Copyright (c) 2022, Diagnosis and Control of Clouds Laboratory
+All rights reserved.
+*/
+
+//! What-if latency prediction.
+//!
+//! Fixing the edge with the most variance only helps if it stays on the critical path; often a
+//! parallel branch becomes critical instead. This module replays the full trace DAGs with some
+//! edges made faster and reports how the end-to-end latency distribution would change.
+//!
+//! The replay assumes every event happens a fixed delay after its latest predecessor, as
+//! observed. An edge from the latest predecessor has that delay as its latency; an edge from any
+//! other predecessor is assumed to take as long, so it only matters once it becomes the latest.
+//! Speeding up an edge shortens its latency, and every event is then re-timed in topological
+//! order as the latest of its predecessors plus the latency of the edge from it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::time::Duration;
+
+use petgraph::algo::toposort;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+
+use crate::filter::{matches_pattern, pattern_parts};
+use crate::grouping::Group;
+use crate::timing::percentile;
+use crate::trace::{IDType, Trace};
+use crate::PythiaError;
+
+/// Edges between two tracepoints made faster. The tracepoints are patterns, where `...` stands
+/// for any text, so `nova/...manager.py:1972` matches `nova/compute/manager.py:1972`.
+#[derive(Debug, Clone)]
+pub struct Speedup {
+    from: Vec<String>,
+    to: Vec<String>,
+    /// What the latency of the edge is multiplied with
+    factor: f64,
+}
+
+impl Speedup {
+    /// `percent` is how much faster the edge gets, 50 halves its latency
+    pub fn new(from: &str, to: &str, percent: f64) -> Result<Speedup, PythiaError> {
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(PythiaError(format!(
+                "A speed-up must be between 0 and 100%, got {}",
+                percent
+            )));
+        }
+        Ok(Speedup {
+            from: pattern_parts(from),
+            to: pattern_parts(to),
+            factor: 1.0 - percent / 100.0,
+        })
+    }
+
+    fn applies(&self, from: &str, to: &str) -> bool {
+        matches_pattern(from, &self.from) && matches_pattern(to, &self.to)
+    }
+}
+
+/// The result of replaying one trace
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub before: Duration,
+    pub after: Duration,
+    /// Whether any edge of the trace was sped up
+    pub touched: bool,
+    /// Whether the critical path goes through other events after the speed-up
+    pub critical_path_changed: bool,
+}
+
+fn nanos(trace: &Trace, nidx: NodeIndex) -> i64 {
+    trace.g[nidx]
+        .timestamp
+        .and_utc()
+        .timestamp_nanos_opt()
+        .unwrap_or_default()
+}
+
+/// Walks back from the end event, always to the predecessor that finished last
+fn critical_events(trace: &Trace, times: &HashMap<NodeIndex, i64>) -> Vec<NodeIndex> {
+    let mut result = vec![trace.end_node];
+    let mut cur = trace.end_node;
+    while let Some(prev) = trace
+        .g
+        .neighbors_directed(cur, Direction::Incoming)
+        .max_by_key(|p| times[p])
+    {
+        result.push(prev);
+        cur = prev;
+    }
+    result
+}
+
+/// Replays a trace with the speed-ups applied, `None` if the trace has a cycle or no endpoints
+pub fn replay(trace: &Trace, speedups: &[Speedup]) -> Option<Replay> {
+    trace.g.node_weight(trace.start_node)?;
+    trace.g.node_weight(trace.end_node)?;
+    let order = toposort(&trace.g, None).ok()?;
+    let original: HashMap<NodeIndex, i64> = order.iter().map(|&n| (n, nanos(trace, n))).collect();
+    let names: HashMap<NodeIndex, String> = order
+        .iter()
+        .map(|&n| (n, trace.g[n].tracepoint_id.to_string()))
+        .collect();
+
+    let mut touched = false;
+    let mut times: HashMap<NodeIndex, i64> = HashMap::new();
+    for &nidx in &order {
+        let latest = trace
+            .g
+            .neighbors_directed(nidx, Direction::Incoming)
+            .map(|p| original[&p])
+            .max();
+        let time = match latest {
+            // Events without predecessors keep their time
+            None => original[&nidx],
+            Some(latest) => {
+                let delay = (original[&nidx] - latest) as f64;
+                trace
+                    .g
+                    .edges_directed(nidx, Direction::Incoming)
+                    .map(|e| {
+                        let factor = speedups
+                            .iter()
+                            .filter(|s| s.applies(&names[&e.source()], &names[&nidx]))
+                            .map(|s| s.factor)
+                            .product::<f64>();
+                        if factor < 1.0 {
+                            touched = true;
+                        }
+                        times[&e.source()] + (delay * factor) as i64
+                    })
+                    .max()
+                    .unwrap()
+            }
+        };
+        times.insert(nidx, time);
+    }
+    let span = |t: &HashMap<NodeIndex, i64>| {
+        Duration::from_nanos((t[&trace.end_node] - t[&trace.start_node]).max(0) as u64)
+    };
+    Some(Replay {
+        before: span(&original),
+        after: span(&times),
+        touched,
+        critical_path_changed: critical_events(trace, &original) != critical_events(trace, &times),
+    })
+}
+
+/// The predicted change of the latency distribution of a set of traces
+#[derive(Debug, Clone, Default)]
+pub struct Prediction {
+    pub replays: Vec<Replay>,
+}
+
+impl Prediction {
+    pub fn before(&self) -> Vec<Duration> {
+        self.replays.iter().map(|r| r.before).collect()
+    }
+
+    pub fn after(&self) -> Vec<Duration> {
+        self.replays.iter().map(|r| r.after).collect()
+    }
+}
+
+/// Replays every trace with the speed-ups applied
+pub fn predict(traces: &[&Trace], speedups: &[Speedup]) -> Prediction {
+    Prediction {
+        replays: traces.iter().filter_map(|t| replay(t, speedups)).collect(),
+    }
+}
+
+/// Replays the full trace of every path of the group, looked up by the path's request ID.
+/// Paths whose trace is not found are skipped.
+pub fn predict_group<'a>(
+    group: &Group,
+    trace_of: impl Fn(&IDType) -> Option<&'a Trace>,
+    speedups: &[Speedup],
+) -> Prediction {
+    let traces: Vec<&Trace> = group.traces.iter().filter_map(|p| trace_of(&p.request_id)).collect();
+    predict(&traces, speedups)
+}
+
+impl Display for Prediction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let before = self.before();
+        let after = self.after();
+        writeln!(f, "{} traces replayed", self.replays.len())?;
+        for &(name, p) in &[("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+            let (b, a) = (percentile(&before, p), percentile(&after, p));
+            writeln!(
+                f,
+                "{}: {:?} -> {:?} ({:+.1}%)",
+                name,
+                b,
+                a,
+                if b.as_nanos() == 0 {
+                    0.0
+                } else {
+                    (a.as_secs_f64() / b.as_secs_f64() - 1.0) * 100.0
+                }
+            )?;
+        }
+        writeln!(
+            f,
+            "{} traces have a sped up edge, the critical path changed in {}",
+            self.replays.iter().filter(|r| r.touched).count(),
+            self.replays.iter().filter(|r| r.critical_path_changed).count()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::critical::{CriticalPath, CriticalPathAlgorithm};
+    use crate::grouping::GroupingMode;
+    use crate::testutil::{annotation, chain, trace};
+
+    #[test]
+    fn parallel_branch_becomes_critical() {
+        // fork at 0, the slow branch ends at 80 and the other at 60, joined at 90
+        let events = vec![
+            annotation("api/fork", 0),
+            annotation("nova/compute/manager.py:1972", 80),
+            annotation("neutron/agent.py:12", 60),
+            annotation("api/join", 90),
+        ];
+        let trace = trace("t1", events, &[(0, 1), (0, 2), (1, 3), (2, 3)]);
+
+        let speedup = Speedup::new("api/fork", "nova/...manager.py:1972", 50.0).unwrap();
+        let result = replay(&trace, &[speedup]).unwrap();
+        // The slow branch now ends at 40, so the other one is waited on
+        assert_eq!(result.before, Duration::from_millis(90));
+        assert_eq!(result.after, Duration::from_millis(70));
+        assert!(result.touched && result.critical_path_changed);
+
+        let none = replay(&trace, &[Speedup::new("nova...", "api/fork", 50.0).unwrap()]).unwrap();
+        assert!(!none.touched);
+        assert_eq!(none.after, none.before);
+        assert!(Speedup::new("a", "b", 150.0).is_err());
+    }
+
+    #[test]
+    fn predicts_a_group_from_looked_up_traces() {
+        let traces = vec![
+            chain("t1", &[("api", 0), ("db", 10), ("reply", 20)]),
+            chain("t2", &[("api", 0), ("db", 30), ("reply", 40)]),
+        ];
+        let paths = traces
+            .iter()
+            .map(|t| CriticalPath::from_trace_with(t, CriticalPathAlgorithm::LatestPredecessor).unwrap())
+            .collect();
+        let groups = Group::from_critical_paths(paths, GroupingMode::Exact);
+        assert_eq!(groups.len(), 1);
+
+        let speedup = Speedup::new("api", "db", 50.0).unwrap();
+        let prediction = predict_group(&groups[0], |id| traces.iter().find(|t| &t.base_id == id), &[speedup]);
+        assert_eq!(prediction.before(), vec![Duration::from_millis(20), Duration::from_millis(40)]);
+        assert_eq!(prediction.after(), vec![Duration::from_millis(15), Duration::from_millis(25)]);
+        // Traces that cannot be looked up are left out
+        assert!(predict_group(&groups[0], |_| None, &[]).replays.is_empty());
+    }
+}